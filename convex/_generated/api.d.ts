@@ -15,6 +15,7 @@ import type {
 } from "convex/server";
 import type * as apiKeys from "../apiKeys.js";
 import type * as health from "../health.js";
+import type * as notifications from "../notifications.js";
 import type * as subscriptions from "../subscriptions.js";
 import type * as usage from "../usage.js";
 import type * as users from "../users.js";
@@ -30,6 +31,7 @@ import type * as users from "../users.js";
 declare const fullApi: ApiFromModules<{
   apiKeys: typeof apiKeys;
   health: typeof health;
+  notifications: typeof notifications;
   subscriptions: typeof subscriptions;
   usage: typeof usage;
   users: typeof users;
@@ -1,7 +1,7 @@
 import { v } from "convex/values";
 import { internal } from "./_generated/api";
 import { action, internalMutation, query, internalQuery } from "./_generated/server";
-import type { Doc } from "./_generated/dataModel";
+import type { Doc, Id } from "./_generated/dataModel";
 import { customAlphabet } from "nanoid";
 
 const ALPHABET = "023456789abcdefghijklnpqrstuvwxyz";
@@ -10,12 +10,21 @@ const API_KEY_LENGTH = 32;
 // Helper to generate a random API key
 const generateApiKey = customAlphabet(ALPHABET, API_KEY_LENGTH);
 
+// Enough of the key to tell keys apart without making it usable.
+const maskApiKey = (key: string) => `${key.slice(0, 8)}...${key.slice(-4)}`;
+
 // --- Public Actions ---
 
+type ApiKeyUser = Doc<"users"> & {
+	apiKeyId: Id<"apiKeys">;
+	scopes: string[] | null;
+	expiresAt: number | null;
+};
+
 export const authenticateAndTrackUsage = action({
 	args: { key: v.string() },
-	handler: async (ctx, args): Promise<Doc<"users"> | null> => {
-		const user: Doc<"users"> | null = await ctx.runQuery(
+	handler: async (ctx, args): Promise<ApiKeyUser | null> => {
+		const user: ApiKeyUser | null = await ctx.runQuery(
 			internal.apiKeys._getUserFromApiKey,
 			{ key: args.key },
 		);
@@ -24,12 +33,22 @@ export const authenticateAndTrackUsage = action({
 			return null;
 		}
 
+		// Expired keys are rejected by the server; don't record them as used.
+		if (user.expiresAt === null || user.expiresAt > Date.now()) {
+			await ctx.runMutation(internal.apiKeys.touch, { id: user.apiKeyId });
+		}
+
 		return user;
 	}
 });
 
 export const generate = action({
-	args: { userId: v.string() }, // Clerk User ID
+	args: {
+		userId: v.string(), // Clerk User ID
+		scopes: v.optional(v.array(v.string())),
+		expiresAt: v.optional(v.number()),
+		name: v.optional(v.string()),
+	},
 	handler: async (ctx, args) => {
 		// Find the user in our database
 		const user = await ctx.runQuery(internal.users.getUserByClerkId, {
@@ -41,13 +60,18 @@ export const generate = action({
 		}
 
 		const newKey = `m1o_${generateApiKey()}`;
+		const preview = maskApiKey(newKey);
 
 		await ctx.runMutation(internal.apiKeys.create, {
 			userId: user._id,
 			key: newKey,
+			preview,
+			name: args.name,
+			scopes: args.scopes,
+			expiresAt: args.expiresAt,
 		});
 
-		return newKey;
+		return { key: newKey, preview };
 	},
 });
 
@@ -74,15 +98,30 @@ export const create = internalMutation({
 	args: {
 		userId: v.id("users"),
 		key: v.string(),
+		preview: v.string(),
+		name: v.optional(v.string()),
+		scopes: v.optional(v.array(v.string())),
+		expiresAt: v.optional(v.number()),
 	},
 	handler: async (ctx, args) => {
 		await ctx.db.insert("apiKeys", {
 			userId: args.userId,
 			key: args.key,
+			preview: args.preview,
+			name: args.name,
+			scopes: args.scopes,
+			expiresAt: args.expiresAt,
 		});
 	},
 });
 
+export const touch = internalMutation({
+	args: { id: v.id("apiKeys") },
+	handler: async (ctx, args) => {
+		await ctx.db.patch(args.id, { lastUsedAt: Date.now() });
+	},
+});
+
 export const remove = internalMutation({
 	args: { id: v.id("apiKeys") },
 	handler: async (ctx, args) => {
@@ -109,7 +148,16 @@ export const list = query({
 			.withIndex("by_userId_and_key", (q) => q.eq("userId", user._id))
 			.collect();
 
-		return keys;
+		// Never return the key itself once it has been created.
+		return keys.map((apiKey) => ({
+			_id: apiKey._id,
+			name: apiKey.name ?? null,
+			preview: apiKey.preview ?? maskApiKey(apiKey.key),
+			createdAt: Math.floor(apiKey._creationTime),
+			lastUsedAt: apiKey.lastUsedAt ?? null,
+			expiresAt: apiKey.expiresAt ?? null,
+			scopes: apiKey.scopes ?? null,
+		}));
 	},
 });
 
@@ -134,6 +182,16 @@ export const _getUserFromApiKey = internalQuery({
 			return null;
 		}
 
-		return await ctx.db.get(apiKey.userId);
+		const user = await ctx.db.get(apiKey.userId);
+		if (!user) {
+			return null;
+		}
+
+		return {
+			...user,
+			apiKeyId: apiKey._id,
+			scopes: apiKey.scopes ?? null,
+			expiresAt: apiKey.expiresAt ?? null,
+		};
 	},
 });
@@ -0,0 +1,112 @@
+import { v } from "convex/values";
+import { query, internalMutation, action } from "./_generated/server";
+import { internal } from "./_generated/api";
+
+// --- Queries ---
+
+// Unread notifications for the frontend banner, newest first.
+export const listUnread = query({
+  args: { userId: v.string() }, // Clerk User ID
+  handler: async (ctx, args) => {
+    const user = await ctx.db
+      .query("users")
+      .withIndex("by_clerk_id", (q) => q.eq("clerkId", args.userId))
+      .unique();
+
+    if (!user) {
+      return [];
+    }
+
+    const notifications = await ctx.db
+      .query("notifications")
+      .withIndex("by_userId", (q) => q.eq("userId", user._id))
+      .order("desc")
+      .take(50);
+    return notifications.filter((notification) => notification.readAt === undefined);
+  },
+});
+
+// --- Internal Mutations ---
+
+// Stripe retries webhooks, so a repeat of the same kind for the same Stripe
+// object refreshes the existing notification instead of adding another.
+export const upsert = internalMutation({
+  args: {
+    userId: v.id("users"),
+    kind: v.string(), // trial_will_end, payment_action_required
+    stripeObjectId: v.string(),
+    dueAt: v.optional(v.number()),
+    actionUrl: v.optional(v.string()),
+  },
+  handler: async (ctx, args) => {
+    const existing = await ctx.db
+      .query("notifications")
+      .withIndex("by_userId_and_stripeObjectId", (q) =>
+        q.eq("userId", args.userId).eq("stripeObjectId", args.stripeObjectId)
+      )
+      .filter((q) => q.eq(q.field("kind"), args.kind))
+      .first();
+
+    if (existing) {
+      await ctx.db.patch(existing._id, {
+        dueAt: args.dueAt,
+        actionUrl: args.actionUrl,
+      });
+      return;
+    }
+
+    await ctx.db.insert("notifications", { ...args, createdAt: Date.now() });
+  },
+});
+
+export const markRead = internalMutation({
+  args: { userId: v.id("users"), notificationId: v.id("notifications") },
+  handler: async (ctx, args) => {
+    const notification = await ctx.db.get(args.notificationId);
+    if (!notification || notification.userId !== args.userId) {
+      throw new Error("Notification not found");
+    }
+    await ctx.db.patch(args.notificationId, { readAt: Date.now() });
+  },
+});
+
+// --- Public Actions ---
+
+export const record = action({
+  args: {
+    clerkId: v.string(),
+    kind: v.string(),
+    stripeObjectId: v.string(),
+    dueAt: v.optional(v.number()),
+    actionUrl: v.optional(v.string()),
+  },
+  handler: async (ctx, args) => {
+    const user = await ctx.runQuery(internal.users.getUserByClerkId, {
+      clerkId: args.clerkId,
+    });
+    if (!user) {
+      throw new Error("User not found");
+    }
+    const { clerkId, ...notification } = args;
+    await ctx.runMutation(internal.notifications.upsert, {
+      userId: user._id,
+      ...notification,
+    });
+  },
+});
+
+export const dismiss = action({
+  args: { clerkId: v.string(), notificationId: v.id("notifications") },
+  handler: async (ctx, args) => {
+    const user = await ctx.runQuery(internal.users.getUserByClerkId, {
+      clerkId: args.clerkId,
+    });
+    if (!user) {
+      throw new Error("User not found");
+    }
+    await ctx.runMutation(internal.notifications.markRead, {
+      userId: user._id,
+      notificationId: args.notificationId,
+    });
+  },
+});
@@ -11,6 +11,11 @@ export default defineSchema({
   apiKeys: defineTable({
     userId: v.id("users"),
     key: v.string(),
+    preview: v.optional(v.string()), // masked key, safe to list
+    name: v.optional(v.string()),
+    scopes: v.optional(v.array(v.string())), // unset = full access
+    expiresAt: v.optional(v.number()), // ms timestamp; unset = never
+    lastUsedAt: v.optional(v.number()),
   }).index("by_userId_and_key", ["userId", "key"])
     .index("by_key", ["key"]), // New index
 
@@ -21,8 +26,20 @@ export default defineSchema({
     endsAt: v.optional(v.number()), // Timestamp for subscription end
     stripeSubscriptionId: v.optional(v.string()),
     stripePriceId: v.optional(v.string()),
+    stripeMeteredItemId: v.optional(v.string()), // subscription item billed for overage
   }).index("by_userId", ["userId"]),
 
+  notifications: defineTable({
+    userId: v.id("users"),
+    kind: v.string(), // trial_will_end, payment_action_required
+    stripeObjectId: v.string(), // subscription or invoice the notice is about
+    dueAt: v.optional(v.number()), // e.g. trial end, ms timestamp
+    actionUrl: v.optional(v.string()), // where the user completes the action
+    createdAt: v.number(),
+    readAt: v.optional(v.number()),
+  }).index("by_userId", ["userId"])
+    .index("by_userId_and_stripeObjectId", ["userId", "stripeObjectId"]),
+
   usage: defineTable({
     userId: v.id("users"),
     date: v.string(), // YYYY-MM-DD format
@@ -38,5 +55,12 @@ export default defineSchema({
     expiresAt: v.number(),
     committedAt: v.optional(v.number()),
     releasedAt: v.optional(v.number()),
-  }).index("by_userId_and_date", ["userId", "date"]),
+  }).index("by_userId_and_date", ["userId", "date"])
+    .index("by_status_and_expiresAt", ["status", "expiresAt"]),
+
+  overageReports: defineTable({
+    userId: v.id("users"),
+    month: v.string(), // YYYY-MM format
+    units: v.number(), // overage already sent to Stripe for the month
+  }).index("by_userId_and_month", ["userId", "month"]),
 });
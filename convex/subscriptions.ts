@@ -32,6 +32,7 @@ export const create = internalMutation({
     status: v.string(),
     stripeSubscriptionId: v.optional(v.string()),
     stripePriceId: v.optional(v.string()),
+    stripeMeteredItemId: v.optional(v.string()),
     endsAt: v.optional(v.number()),
   },
   handler: async (ctx, args) => {
@@ -46,11 +47,19 @@ export const update = internalMutation({
     status: v.optional(v.string()),
     stripeSubscriptionId: v.optional(v.string()),
     stripePriceId: v.optional(v.string()),
-    endsAt: v.optional(v.number()),
+    // null clears a previously stored value.
+    stripeMeteredItemId: v.optional(v.union(v.string(), v.null())),
+    endsAt: v.optional(v.union(v.number(), v.null())),
   },
   handler: async (ctx, args) => {
-    const { subscriptionId, ...rest } = args;
-    await ctx.db.patch(subscriptionId, rest);
+    const { subscriptionId, endsAt, stripeMeteredItemId, ...rest } = args;
+    await ctx.db.patch(subscriptionId, {
+      ...rest,
+      ...(endsAt === undefined ? {} : { endsAt: endsAt ?? undefined }),
+      ...(stripeMeteredItemId === undefined
+        ? {}
+        : { stripeMeteredItemId: stripeMeteredItemId ?? undefined }),
+    });
   },
 });
 
@@ -63,6 +72,7 @@ export const createSubscription = action({
     status: v.string(),
     stripeSubscriptionId: v.optional(v.string()),
     stripePriceId: v.optional(v.string()),
+    stripeMeteredItemId: v.optional(v.string()),
     endsAt: v.optional(v.number()),
   },
   handler: async (ctx, args) => {
@@ -87,7 +97,8 @@ export const updateSubscription = action({
     status: v.optional(v.string()),
     stripeSubscriptionId: v.optional(v.string()),
     stripePriceId: v.optional(v.string()),
-    endsAt: v.optional(v.number()),
+    stripeMeteredItemId: v.optional(v.union(v.string(), v.null())),
+    endsAt: v.optional(v.union(v.number(), v.null())),
   },
   handler: async (ctx, args) => {
     const subscription = await ctx.runQuery(api.subscriptions.get, {
@@ -4,10 +4,14 @@ import { action, internalMutation, query } from "./_generated/server";
 import type { Doc, Id } from "./_generated/dataModel";
 
 const RESERVATION_TTL_MS = 10 * 60 * 1000; // 10 minutes
+const MIN_RESERVATION_TTL_MS = 60 * 1000;
+const MAX_RESERVATION_TTL_MS = 24 * 60 * 60 * 1000;
 
 type ReserveForClerkUserResult = {
   allowed: boolean;
   reservationId?: Id<"usageReservations"> | null;
+  expiresAt?: number | null;
+  nextPendingExpiresAt?: number | null;
   totalThisMonth: number;
   pendingUnits: number;
   monthlyQuota: number | null;
@@ -68,6 +72,150 @@ export const getUsageReservations = query({
   },
 });
 
+// Reservations still pending although they expired between `expiredAfter`
+// and `expiredBefore`, i.e. neither committed nor released by the server.
+export const getExpiredPendingReservations = query({
+  args: {
+    expiredAfter: v.number(),
+    expiredBefore: v.number(),
+    limit: v.number(),
+  },
+  handler: async (ctx, args) => {
+    const reservations = await ctx.db
+      .query("usageReservations")
+      .withIndex("by_status_and_expiresAt", (q) =>
+        q
+          .eq("status", "pending")
+          .gt("expiresAt", args.expiredAfter)
+          .lte("expiresAt", args.expiredBefore)
+      )
+      .take(Math.min(Math.max(args.limit, 1), 500));
+
+    const rows = [];
+    for (const reservation of reservations) {
+      const user = await ctx.db.get(reservation.userId);
+      if (!user) {
+        continue;
+      }
+      rows.push({
+        reservationId: reservation._id,
+        clerkId: user.clerkId,
+        units: reservation.units,
+        expiresAt: reservation.expiresAt,
+      });
+    }
+    return rows;
+  },
+});
+
+// One page of users with their committed units for `month` (YYYY-MM) and the
+// overage already reported for it, for billing reconciliation. Users with no
+// usage that month are left out, so a page can hold fewer rows than `numItems`.
+export const getMonthlyUsageReport = query({
+  args: {
+    month: v.string(),
+    cursor: v.optional(v.string()),
+    numItems: v.number(),
+  },
+  handler: async (ctx, args) => {
+    const page = await ctx.db.query("users").paginate({
+      numItems: Math.min(Math.max(args.numItems, 1), 500),
+      cursor: args.cursor ?? null,
+    });
+
+    const rows = [];
+    for (const user of page.page) {
+      const records = await ctx.db
+        .query("usage")
+        .withIndex("by_userId_and_date", (q) =>
+          q
+            .eq("userId", user._id)
+            .gte("date", `${args.month}-01`)
+            .lte("date", `${args.month}-31`)
+        )
+        .collect();
+      const units = records.reduce((total, record) => total + record.count, 0);
+      if (units === 0) {
+        continue;
+      }
+
+      const subscription = await ctx.db
+        .query("subscriptions")
+        .withIndex("by_userId", (q) => q.eq("userId", user._id))
+        .unique();
+
+      const overageReport = await ctx.db
+        .query("overageReports")
+        .withIndex("by_userId_and_month", (q) =>
+          q.eq("userId", user._id).eq("month", args.month)
+        )
+        .unique();
+
+      rows.push({
+        clerkId: user.clerkId,
+        units,
+        reportedOverage: overageReport?.units ?? 0,
+        subscription: subscription
+          ? {
+              plan: subscription.plan,
+              status: subscription.status,
+              stripeMeteredItemId: subscription.stripeMeteredItemId ?? null,
+            }
+          : null,
+      });
+    }
+
+    return {
+      rows,
+      isDone: page.isDone,
+      continueCursor: page.continueCursor,
+    };
+  },
+});
+
+// Never lowers the recorded total, so a late write from an older run can't
+// cause units to be reported twice.
+export const recordReportedOverage = internalMutation({
+  args: { userId: v.id("users"), month: v.string(), units: v.number() },
+  handler: async (ctx, args) => {
+    const existing = await ctx.db
+      .query("overageReports")
+      .withIndex("by_userId_and_month", (q) =>
+        q.eq("userId", args.userId).eq("month", args.month)
+      )
+      .unique();
+
+    if (existing) {
+      if (args.units > existing.units) {
+        await ctx.db.patch(existing._id, { units: args.units });
+      }
+    } else {
+      await ctx.db.insert("overageReports", {
+        userId: args.userId,
+        month: args.month,
+        units: args.units,
+      });
+    }
+  },
+});
+
+export const recordReportedOverageForClerkUser = action({
+  args: { clerkId: v.string(), month: v.string(), units: v.number() },
+  handler: async (ctx, args) => {
+    const user = await ctx.runQuery(internal.users.getUserByClerkId, {
+      clerkId: args.clerkId,
+    });
+
+    if (user) {
+      await ctx.runMutation(internal.usage.recordReportedOverage, {
+        userId: user._id,
+        month: args.month,
+        units: args.units,
+      });
+    }
+  },
+});
+
 export const increment = internalMutation({
   args: { userId: v.id("users"), units: v.optional(v.number()) },
   handler: async (ctx, args) => {
@@ -119,9 +267,14 @@ export const reserve = internalMutation({
     userId: v.id("users"),
     units: v.number(),
     monthlyQuota: v.optional(v.number()),
+    ttlMs: v.optional(v.number()),
   },
   handler: async (ctx, args) => {
     const unitsToReserve = Math.max(args.units, 0);
+    const ttlMs = Math.min(
+      Math.max(args.ttlMs ?? RESERVATION_TTL_MS, MIN_RESERVATION_TTL_MS),
+      MAX_RESERVATION_TTL_MS,
+    );
     const now = Date.now();
     const today = new Date(now).toISOString().slice(0, 10); // YYYY-MM-DD
     const currentMonth = today.substring(0, 7); // YYYY-MM
@@ -144,6 +297,7 @@ export const reserve = internalMutation({
       .collect();
 
     let pendingUnits = 0;
+    let nextPendingExpiresAt: number | null = null;
     for (const record of reservationRecords) {
       if (!record.date.startsWith(currentMonth)) {
         continue;
@@ -158,6 +312,9 @@ export const reserve = internalMutation({
           continue;
         }
         pendingUnits += record.units;
+        if (nextPendingExpiresAt === null || record.expiresAt < nextPendingExpiresAt) {
+          nextPendingExpiresAt = record.expiresAt;
+        }
       }
     }
 
@@ -168,6 +325,7 @@ export const reserve = internalMutation({
     ) {
       return {
         allowed: false,
+        nextPendingExpiresAt,
         totalThisMonth,
         pendingUnits,
         monthlyQuota,
@@ -178,24 +336,29 @@ export const reserve = internalMutation({
       return {
         allowed: true,
         reservationId: null,
+        expiresAt: null,
+        nextPendingExpiresAt,
         totalThisMonth,
         pendingUnits,
         monthlyQuota,
       };
     }
 
+    const expiresAt = now + ttlMs;
     const reservationId = await ctx.db.insert("usageReservations", {
       userId: args.userId,
       date: today,
       units: unitsToReserve,
       status: "pending",
       createdAt: now,
-      expiresAt: now + RESERVATION_TTL_MS,
+      expiresAt,
     });
 
     return {
       allowed: true,
       reservationId,
+      expiresAt,
+      nextPendingExpiresAt,
       totalThisMonth,
       pendingUnits,
       monthlyQuota,
@@ -289,6 +452,7 @@ export const reserveForClerkUser = action({
     clerkId: v.string(),
     units: v.number(),
     monthlyQuota: v.optional(v.number()),
+    ttlMs: v.optional(v.number()),
   },
   handler: async (ctx, args): Promise<ReserveForClerkUserResult> => {
     const user: Doc<"users"> | null = await ctx.runQuery(
@@ -312,6 +476,7 @@ export const reserveForClerkUser = action({
       userId: user._id,
       units: args.units,
       monthlyQuota: args.monthlyQuota,
+      ttlMs: args.ttlMs,
     });
   },
 });
@@ -42,6 +42,38 @@ export const sync = action({
   },
 });
 
+export const deleteUser = internalMutation({
+  args: { clerkId: v.string() },
+  handler: async (ctx, args) => {
+    const user = await ctx.db
+      .query("users")
+      .withIndex("by_clerk_id", (q) => q.eq("clerkId", args.clerkId))
+      .unique();
+    if (user === null) {
+      return;
+    }
+
+    const keys = await ctx.db
+      .query("apiKeys")
+      .withIndex("by_userId_and_key", (q) => q.eq("userId", user._id))
+      .collect();
+    for (const key of keys) {
+      await ctx.db.delete(key._id);
+    }
+    await ctx.db.delete(user._id);
+  },
+});
+
+// Called from the Clerk webhook when a user is deleted
+export const remove = action({
+  args: { clerkId: v.string() },
+  handler: async (ctx, args) => {
+    await ctx.runMutation(internal.users.deleteUser, {
+      clerkId: args.clerkId,
+    });
+  },
+});
+
 export const getUserByClerkId = internalQuery({
   args: { clerkId: v.string() },
   handler: async (ctx, args) => {
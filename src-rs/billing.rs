@@ -0,0 +1,281 @@
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use chrono::{Datelike, NaiveDate, Utc};
+use serde::Serialize;
+
+use crate::{plans::plan_definition, state::AppState};
+
+const OVERAGE_REPORT_PAGE_SIZE: usize = 200;
+// Each run sends only the overage not yet reported, so Stripe must sum the
+// records. Keeping just the last one would drop a month's overage whenever
+// a billing period spans two calendar months.
+const METERED_AGGREGATE_USAGE: &str = "sum";
+
+#[derive(Debug, Default, Serialize)]
+pub struct OverageReportSummary {
+    pub month: String,
+    #[serde(rename = "usersReported")]
+    pub users_reported: usize,
+    #[serde(rename = "unitsReported")]
+    pub units_reported: i64,
+    #[serde(rename = "usersFailed")]
+    pub users_failed: usize,
+    // Metered users with overage but no metered subscription item stored.
+    #[serde(rename = "usersMissingItem")]
+    pub users_missing_item: usize,
+}
+
+// Checks every configured price ID against Stripe. Problems are logged per ID
+// and counted rather than failing startup, since Stripe may just be unreachable.
+pub async fn verify_price_ids(state: &AppState) -> usize {
+    let mut prices = state
+        .price_map
+        .entries()
+        .map(|(price_id, plan_id)| (price_id.to_string(), plan_id.as_str()))
+        .collect::<Vec<_>>();
+    if let Some(price_id) = &state.config.stripe_metered_price_id {
+        prices.push((price_id.clone(), "metered"));
+    }
+    prices.sort();
+
+    let mut invalid = 0;
+    for (price_id, plan) in prices {
+        match state.stripe.retrieve_price(&price_id).await {
+            // Legacy prices are usually archived once replaced.
+            Ok(price)
+                if price.active == Some(false)
+                    && (plan == "metered"
+                        || state
+                            .price_map
+                            .get_offered_plan_for_price_id(&price_id)
+                            .is_some()) =>
+            {
+                invalid += 1;
+                tracing::error!(price_id = %price_id, plan, "configured Stripe price is archived");
+            }
+            Ok(price)
+                if plan == "metered"
+                    && price.aggregate_usage() != Some(METERED_AGGREGATE_USAGE) =>
+            {
+                invalid += 1;
+                tracing::error!(
+                    price_id = %price_id,
+                    aggregate_usage = price.aggregate_usage().unwrap_or("none"),
+                    "metered Stripe price must use aggregate_usage=sum; overage from earlier reports would be dropped"
+                );
+            }
+            Ok(_) => {
+                tracing::debug!(price_id = %price_id, plan, "configured Stripe price verified");
+            }
+            Err(error) => {
+                invalid += 1;
+                tracing::error!(
+                    error = ?error,
+                    price_id = %price_id,
+                    plan,
+                    "configured Stripe price could not be retrieved"
+                );
+            }
+        }
+    }
+    invalid
+}
+
+// Reports each metered user's overage for `month` (YYYY-MM) to Stripe as the
+// increase since the last report, then records the new total in Convex.
+// Plans without a quota have every committed unit metered. A failed user is
+// logged and skipped so one bad item can't block the rest.
+pub async fn report_overage(state: &AppState, month: &str) -> anyhow::Result<OverageReportSummary> {
+    let metered_plans = &state.config.stripe_metered_plans;
+    let mut summary = OverageReportSummary {
+        month: month.to_string(),
+        ..Default::default()
+    };
+    if metered_plans.is_empty() {
+        return Ok(summary);
+    }
+    let timestamp = report_timestamp(month)?;
+    check_metered_aggregation(state).await?;
+
+    let mut cursor: Option<String> = None;
+    loop {
+        let page = state
+            .convex_api
+            .get_usage_report_page(month, cursor.as_deref(), OVERAGE_REPORT_PAGE_SIZE)
+            .await
+            .context("failed to fetch usage for overage report")?;
+
+        for row in page.rows {
+            let Some(subscription) = row.subscription.filter(|value| value.is_active()) else {
+                continue;
+            };
+            let plan_id = subscription.plan_id();
+            if !metered_plans.contains(&plan_id) {
+                continue;
+            }
+            let included = plan_definition(plan_id).monthly_units.unwrap_or(0);
+            let overage = row.units - included;
+            let unreported = overage - row.reported_overage;
+            if unreported <= 0 {
+                continue;
+            }
+            let Some(item_id) = subscription.stripe_metered_item_id.as_deref() else {
+                tracing::warn!(
+                    clerk_id = %row.clerk_id,
+                    overage,
+                    "overage not billed: no metered subscription item"
+                );
+                summary.users_missing_item += 1;
+                continue;
+            };
+
+            // Keyed on the new total: if recording it in Convex fails, the
+            // next run sends the same increment and Stripe replays the
+            // original response instead of adding it again.
+            let idempotency_key = format!("overage-{}-{}-{}", item_id, month, overage);
+            let reported = state
+                .stripe
+                .report_usage(item_id, unreported, timestamp, &idempotency_key)
+                .await;
+            match reported {
+                Ok(record) => {
+                    tracing::debug!(
+                        clerk_id = %row.clerk_id,
+                        usage_record_id = %record.id,
+                        quantity = record.quantity,
+                        "reported overage to Stripe"
+                    );
+                    summary.users_reported += 1;
+                    summary.units_reported += unreported;
+                    if let Err(error) = state
+                        .convex_api
+                        .record_reported_overage(&row.clerk_id, month, overage)
+                        .await
+                    {
+                        tracing::error!(
+                            error = %error,
+                            clerk_id = %row.clerk_id,
+                            overage,
+                            "failed to record reported overage"
+                        );
+                    }
+                }
+                Err(error) => {
+                    tracing::error!(
+                        error = %error,
+                        clerk_id = %row.clerk_id,
+                        overage,
+                        "failed to report overage to Stripe"
+                    );
+                    summary.users_failed += 1;
+                }
+            }
+        }
+
+        if page.is_done {
+            break;
+        }
+        cursor = Some(page.continue_cursor);
+    }
+
+    tracing::info!(
+        month = %summary.month,
+        users_reported = summary.users_reported,
+        units_reported = summary.units_reported,
+        users_failed = summary.users_failed,
+        users_missing_item = summary.users_missing_item,
+        "overage report finished"
+    );
+    Ok(summary)
+}
+
+// Refuses to report when the metered price would not sum the increments.
+async fn check_metered_aggregation(state: &AppState) -> anyhow::Result<()> {
+    let Some(price_id) = state.config.stripe_metered_price_id.as_deref() else {
+        return Ok(());
+    };
+    let price = state
+        .stripe
+        .retrieve_price(price_id)
+        .await
+        .context("failed to retrieve the metered Stripe price")?;
+    match price.aggregate_usage() {
+        Some(METERED_AGGREGATE_USAGE) => Ok(()),
+        other => bail!(
+            "metered price {} uses aggregate_usage={}; it must be {} so each report adds to the last",
+            price_id,
+            other.unwrap_or("none"),
+            METERED_AGGREGATE_USAGE
+        ),
+    }
+}
+
+// Now for the current month; the month's last second for a closed month so a
+// report right after rollover still lands in that month.
+fn report_timestamp(month: &str) -> anyhow::Result<i64> {
+    let first = NaiveDate::parse_from_str(&format!("{}-01", month), "%Y-%m-%d")
+        .with_context(|| format!("invalid month `{}`", month))?;
+    let next_month = if first.month() == 12 {
+        NaiveDate::from_ymd_opt(first.year() + 1, 1, 1)
+    } else {
+        NaiveDate::from_ymd_opt(first.year(), first.month() + 1, 1)
+    }
+    .context("month out of range")?;
+    let end_of_month = next_month
+        .and_hms_opt(0, 0, 0)
+        .context("month out of range")?
+        .and_utc()
+        .timestamp()
+        - 1;
+    Ok(end_of_month.min(Utc::now().timestamp()))
+}
+
+fn previous_month(month: &str) -> anyhow::Result<String> {
+    let first = NaiveDate::parse_from_str(&format!("{}-01", month), "%Y-%m-%d")
+        .with_context(|| format!("invalid month `{}`", month))?;
+    let last_of_previous = first.pred_opt().context("month out of range")?;
+    Ok(last_of_previous.format("%Y-%m").to_string())
+}
+
+// The first tick, and the ticks after each rollover until it succeeds, also
+// report the month that just ended so usage committed after its last tick
+// still gets billed. Only unreported overage is sent, so repeating a month is
+// harmless.
+pub fn spawn_overage_reporter(state: AppState, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        let mut last_month: Option<String> = None;
+        loop {
+            ticker.tick().await;
+            let month = Utc::now().format("%Y-%m").to_string();
+            if last_month.as_deref() != Some(month.as_str()) {
+                let closed = match previous_month(&month) {
+                    Ok(previous) => report_overage(&state, &previous).await,
+                    Err(error) => Err(error),
+                };
+                match closed {
+                    Ok(_) => last_month = Some(month.clone()),
+                    Err(error) => {
+                        tracing::error!(error = ?error, month = %month, "overage report for the previous month failed")
+                    }
+                }
+            }
+            if let Err(error) = report_overage(&state, &month).await {
+                tracing::error!(error = ?error, month = %month, "overage report failed");
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn previous_month_crosses_year_boundaries() {
+        assert_eq!(previous_month("2026-03").expect("month"), "2026-02");
+        assert_eq!(previous_month("2026-01").expect("month"), "2025-12");
+        assert!(previous_month("2026-13").is_err());
+    }
+}
@@ -1,15 +1,37 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
 use anyhow::{anyhow, Context};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use parking_lot::Mutex;
 use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
 use serde::Deserialize;
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
 
 #[derive(Clone)]
 pub struct ClerkClient {
     http: reqwest::Client,
     api_base: String,
+    webhook_secret: Option<String>,
+    email_cache: Arc<Mutex<HashMap<String, CachedEmail>>>,
+    email_cache_ttl: Duration,
+}
+
+#[derive(Clone)]
+struct CachedEmail {
+    email: Option<String>,
+    fetched_at: Instant,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct ClerkUser {
+    pub id: String,
     pub primary_email_address_id: Option<String>,
     #[serde(default)]
     pub email_addresses: Vec<ClerkEmailAddress>,
@@ -21,8 +43,35 @@ pub struct ClerkEmailAddress {
     pub email_address: String,
 }
 
+impl ClerkUser {
+    pub fn primary_email(self) -> Option<String> {
+        let primary_id = self.primary_email_address_id?;
+        self.email_addresses
+            .into_iter()
+            .find(|entry| entry.id == primary_id)
+            .map(|entry| entry.email_address)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ClerkWebhookEvent {
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub data: serde_json::Value,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ClerkDeletedObject {
+    pub id: Option<String>,
+}
+
 impl ClerkClient {
-    pub fn new(api_base: String, secret_key: Option<&str>) -> anyhow::Result<Self> {
+    pub fn new(
+        api_base: String,
+        secret_key: Option<&str>,
+        webhook_secret: Option<String>,
+        email_cache_ttl: Duration,
+    ) -> anyhow::Result<Self> {
         let mut headers = HeaderMap::new();
         if let Some(secret) = secret_key {
             let value = format!("Bearer {}", secret);
@@ -40,6 +89,9 @@ impl ClerkClient {
         Ok(Self {
             http,
             api_base: api_base.trim_end_matches('/').to_string(),
+            webhook_secret,
+            email_cache: Arc::new(Mutex::new(HashMap::new())),
+            email_cache_ttl,
         })
     }
 
@@ -69,18 +121,121 @@ impl ClerkClient {
     }
 
     pub async fn get_primary_email(&self, user_id: &str) -> anyhow::Result<Option<String>> {
-        let user = self.get_user(user_id).await?;
-        let primary_id = match user.primary_email_address_id {
-            Some(value) => value,
-            None => return Ok(None),
-        };
-
-        let email = user
-            .email_addresses
-            .into_iter()
-            .find(|entry| entry.id == primary_id)
-            .map(|entry| entry.email_address);
+        {
+            let cache = self.email_cache.lock();
+            if let Some(cached) = cache.get(user_id) {
+                if cached.fetched_at.elapsed() < self.email_cache_ttl {
+                    return Ok(cached.email.clone());
+                }
+            }
+        }
+
+        self.refresh_primary_email(user_id).await
+    }
 
+    // Bypasses the cache and stores the fresh value.
+    pub async fn refresh_primary_email(&self, user_id: &str) -> anyhow::Result<Option<String>> {
+        let email = self.get_user(user_id).await?.primary_email();
+        self.cache_primary_email(user_id, email.clone());
         Ok(email)
     }
+
+    pub fn cache_primary_email(&self, user_id: &str, email: Option<String>) {
+        let mut cache = self.email_cache.lock();
+        let now = Instant::now();
+        if cache.len() >= 10_000 {
+            cache.retain(|_, cached| now.duration_since(cached.fetched_at) < self.email_cache_ttl);
+        }
+        cache.insert(
+            user_id.to_string(),
+            CachedEmail {
+                email,
+                fetched_at: now,
+            },
+        );
+    }
+
+    pub fn forget_primary_email(&self, user_id: &str) {
+        self.email_cache.lock().remove(user_id);
+    }
+
+    // Clerk delivers webhooks through Svix: the secret is `whsec_` + base64 key
+    // and each `v1,` signature is base64(HMAC-SHA256("{id}.{timestamp}.{body}")).
+    pub fn verify_webhook_signature(
+        &self,
+        message_id: &str,
+        timestamp: &str,
+        signature_header: &str,
+        payload: &[u8],
+    ) -> anyhow::Result<()> {
+        let webhook_secret = self
+            .webhook_secret
+            .as_ref()
+            .ok_or_else(|| anyhow!("CLERK_WEBHOOK_SECRET is not configured."))?;
+        let key = STANDARD
+            .decode(webhook_secret.trim().trim_start_matches("whsec_"))
+            .context("invalid CLERK_WEBHOOK_SECRET")?;
+
+        let timestamp_value = timestamp
+            .trim()
+            .parse::<i64>()
+            .map_err(|_| anyhow!("Invalid Svix timestamp."))?;
+        let now = Utc::now().timestamp();
+        if (now - timestamp_value).abs() > 300 {
+            return Err(anyhow!("Svix signature timestamp outside tolerance."));
+        }
+
+        let payload_str =
+            std::str::from_utf8(payload).context("invalid UTF-8 payload for Svix signature")?;
+        let signed_payload = format!("{}.{}.{}", message_id, timestamp_value, payload_str);
+
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&key).context("invalid CLERK_WEBHOOK_SECRET")?;
+        mac.update(signed_payload.as_bytes());
+        let expected = STANDARD.encode(mac.finalize().into_bytes());
+
+        let is_match = signature_header
+            .split_whitespace()
+            .filter_map(|entry| entry.strip_prefix("v1,"))
+            .any(|candidate| expected.as_bytes().ct_eq(candidate.as_bytes()).into());
+        if !is_match {
+            return Err(anyhow!("Invalid Svix signature."));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use wiremock::{
+        matchers::{method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn primary_email_is_served_from_cache_within_the_ttl() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/users/user_1"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "id": "user_1",
+                "primary_email_address_id": "email_1",
+                "email_addresses": [{ "id": "email_1", "email_address": "a@example.com" }],
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let clerk = ClerkClient::new(server.uri(), Some("sk_test"), None, Duration::from_secs(60))
+            .expect("clerk client");
+
+        for _ in 0..2 {
+            let email = clerk.get_primary_email("user_1").await.expect("email");
+            assert_eq!(email.as_deref(), Some("a@example.com"));
+        }
+        server.verify().await;
+    }
 }
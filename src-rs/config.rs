@@ -1,33 +1,145 @@
 use std::{env, path::PathBuf};
 
+use ipnet::IpNet;
+
+use crate::{
+    features::Feature,
+    ghostscript::InkcovSampling,
+    plans::{DowngradePolicy, PlanId},
+};
+
+// Used when TRUST_PROXY is on but TRUSTED_PROXIES is unset: loopback and
+// private ranges cover the usual same-host or in-cluster reverse proxy.
+const DEFAULT_TRUSTED_PROXIES: &str =
+    "127.0.0.0/8,::1/128,10.0.0.0/8,172.16.0.0/12,192.168.0.0/16,fc00::/7";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AlreadyGrayscaleAction {
+    Convert,
+    Annotate,
+    Skip,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConcurrencyOverflow {
+    Reject,
+    Queue,
+}
+
+// Fixed identity injected by the session auth middleware instead of verifying
+// a Clerk token. Local development only.
+#[derive(Clone, Debug)]
+pub struct DevAuthUser {
+    pub clerk_id: String,
+    pub email: String,
+}
+
 #[derive(Clone, Debug)]
 pub struct Config {
     pub port: u16,
-    pub trust_proxy: bool,
+    pub trusted_proxies: Vec<IpNet>,
+    pub trusted_proxy_hops: usize,
+    pub rate_limit_ipv4_prefix: u8,
+    pub rate_limit_ipv6_prefix: u8,
     pub tls_key_path: Option<PathBuf>,
     pub tls_cert_path: Option<PathBuf>,
     pub convex_url: String,
+    pub convex_auth_token: Option<String>,
     pub clerk_secret_key: Option<String>,
+    pub clerk_webhook_secret: Option<String>,
     pub clerk_issuer: Option<String>,
+    pub clerk_jwt_leeway_secs: u64,
+    pub clerk_token_cache_size: usize,
+    pub jwks_max_keys: usize,
+    pub clerk_issuer_domains: Vec<String>,
+    pub user_sync_interval_secs: u64,
+    pub clerk_email_cache_ttl_secs: u64,
     pub clerk_api_base: String,
     pub stripe_secret_key: Option<String>,
     pub stripe_webhook_secret: Option<String>,
+    pub stripe_max_retries: u32,
+    pub stripe_retry_base_ms: u64,
+    pub stripe_retry_max_ms: u64,
+    pub stripe_circuit_failure_threshold: usize,
+    pub stripe_circuit_cooldown_secs: u64,
     pub frontend_url: Option<String>,
+    pub work_dir: PathBuf,
     pub ghostscript_concurrency: usize,
+    pub ghostscript_max_bitmap: Option<u64>,
+    pub inkcov_sampling: InkcovSampling,
+    pub inkcov_chunk_pages: u32,
+    pub grayscale_split_threshold_pages: u32,
+    pub grayscale_split_chunks: u32,
+    pub preflight_min_bleed_mm: f64,
+    pub ocr_units_per_page: i64,
+    pub ghostscript_buffer_space: Option<u64>,
+    pub require_ghostscript: Option<bool>,
     pub log_ghostscript_timings: bool,
     pub log_task_queue_timings: bool,
     pub log_processing_timings: bool,
+    pub allow_debug_timings: bool,
     pub grayscale_production_force_black_text: bool,
     pub grayscale_production_force_black_vector: bool,
     pub grayscale_production_black_threshold_l: Option<f64>,
     pub grayscale_production_black_threshold_c: Option<f64>,
-    pub stripe_price_id_starter: Option<String>,
-    pub stripe_price_id_pro: Option<String>,
-    pub stripe_price_id_business: Option<String>,
-    pub stripe_price_id_enterprise: Option<String>,
+    pub already_grayscale_action: AlreadyGrayscaleAction,
+    pub admin_token: Option<String>,
+    pub max_concurrent_requests: usize,
+    pub ghostscript_max_queue_depth: usize,
+    pub concurrency_overflow: ConcurrencyOverflow,
+    pub request_deadline_secs: u64,
+    pub process_request_deadline_secs: u64,
+    pub ocr_request_deadline_secs: u64,
+    pub watermark_free_plan: bool,
+    pub result_retention_secs: u64,
+    pub reservation_ttl_secs: u64,
+    pub reservation_cleanup_enabled: bool,
+    pub reservation_cleanup_interval_secs: u64,
+    pub reservation_cleanup_lookback_secs: u64,
+    pub tus_uploads_enabled: bool,
+    pub tus_upload_ttl_secs: u64,
+    pub tus_max_uploads_per_user: usize,
+    pub max_output_bytes: Option<u64>,
+    pub output_size_multiplier: u64,
+    pub watermark_text: String,
+    pub stripe_price_ids_starter: Vec<String>,
+    pub stripe_price_ids_pro: Vec<String>,
+    pub stripe_price_ids_business: Vec<String>,
+    pub stripe_price_ids_enterprise: Vec<String>,
+    pub stripe_metered_price_id: Option<String>,
+    pub stripe_metered_plans: Vec<PlanId>,
+    pub overage_report_interval_secs: u64,
+    pub downgrade_over_quota: DowngradePolicy,
+    pub dev_auth_bypass: Option<DevAuthUser>,
+    pub disabled_features: Vec<Feature>,
+    pub ghostscript_warmup: bool,
+    pub clerk_session_cookie: Option<String>,
+    pub clerk_session_cookie_origins: Vec<String>,
+    pub stripe_verify_prices: bool,
+    pub health_cache_secs: u64,
+    pub convex_warmup_connections: usize,
+    pub convex_required_at_startup: bool,
 }
 
 impl Config {
+    pub fn frontend_link(&self, path: &str) -> Option<String> {
+        self.frontend_url
+            .as_ref()
+            .map(|base| format!("{}/{}", base, path.trim_start_matches('/')))
+    }
+
+    pub fn max_output_bytes_for(&self, upload_limit_bytes: usize) -> u64 {
+        self.max_output_bytes.unwrap_or_else(|| {
+            (upload_limit_bytes as u64).saturating_mul(self.output_size_multiplier)
+        })
+    }
+
+    pub fn is_trusted_proxy(&self, address: std::net::IpAddr) -> bool {
+        self.trusted_proxies
+            .iter()
+            .any(|range| range.contains(&address))
+    }
+
     pub fn from_env() -> anyhow::Result<Self> {
         let port = parse_u16(env::var("PORT").ok(), 9001);
 
@@ -38,11 +150,46 @@ impl Config {
             }
             Err(_) => true,
         };
+        let trusted_proxies = if trust_proxy {
+            parse_trusted_proxies(
+                &env::var("TRUSTED_PROXIES")
+                    .unwrap_or_else(|_| DEFAULT_TRUSTED_PROXIES.to_string()),
+            )?
+        } else {
+            Vec::new()
+        };
 
         let convex_url = env::var("CONVEX_URL")
             .map_err(|_| anyhow::anyhow!("CONVEX_URL environment variable is not set"))?;
         let convex_url = normalize_convex_url(&convex_url);
 
+        let dev_auth_bypass = if parse_bool(env::var("DEV_AUTH_BYPASS").ok(), false) {
+            // Read here rather than trusting the caller so a NODE_ENV set in
+            // .env is honoured too.
+            let is_production = env::var("NODE_ENV")
+                .map(|value| value.trim().eq_ignore_ascii_case("production"))
+                .unwrap_or(false);
+            if is_production {
+                return Err(anyhow::anyhow!(
+                    "DEV_AUTH_BYPASS cannot be enabled when NODE_ENV=production"
+                ));
+            }
+            Some(DevAuthUser {
+                clerk_id: env::var("DEV_AUTH_CLERK_ID")
+                    .ok()
+                    .map(|value| value.trim().to_string())
+                    .filter(|value| !value.is_empty())
+                    .unwrap_or_else(|| "user_dev".to_string()),
+                email: env::var("DEV_AUTH_EMAIL")
+                    .ok()
+                    .map(|value| value.trim().to_string())
+                    .filter(|value| !value.is_empty())
+                    .unwrap_or_else(|| "dev@localhost".to_string()),
+            })
+        } else {
+            None
+        };
+
         let ghostscript_concurrency = parse_usize(
             env::var("GHOSTSCRIPT_CONCURRENCY")
                 .ok()
@@ -50,20 +197,111 @@ impl Config {
             default_ghostscript_concurrency(),
         );
 
+        let frontend_url = normalize_frontend_url(env::var("FRONTEND_URL").ok())?;
+        let mut clerk_session_cookie_origins = parse_string_list("CLERK_SESSION_COOKIE_ORIGINS")
+            .iter()
+            .map(|raw| parse_origin(raw))
+            .collect::<anyhow::Result<Vec<_>>>()
+            .map_err(|error| anyhow::anyhow!("CLERK_SESSION_COOKIE_ORIGINS: {}", error))?;
+        if let Some(frontend_url) = &frontend_url {
+            clerk_session_cookie_origins.push(parse_origin(frontend_url)?);
+        }
+
         Ok(Self {
             port,
-            trust_proxy,
+            trusted_proxies,
+            trusted_proxy_hops: parse_usize(env::var("TRUSTED_PROXY_HOPS").ok(), 1),
+            rate_limit_ipv4_prefix: parse_prefix_len(
+                env::var("RATE_LIMIT_IPV4_PREFIX").ok(),
+                32,
+                32,
+            ),
+            rate_limit_ipv6_prefix: parse_prefix_len(
+                env::var("RATE_LIMIT_IPV6_PREFIX").ok(),
+                64,
+                128,
+            ),
             tls_key_path: env::var("TLS_KEY_PATH").ok().map(PathBuf::from),
             tls_cert_path: env::var("TLS_CERT_PATH").ok().map(PathBuf::from),
             convex_url,
+            convex_auth_token: env::var("CONVEX_AUTH_TOKEN")
+                .ok()
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty()),
             clerk_secret_key: env::var("CLERK_SECRET_KEY").ok(),
+            clerk_webhook_secret: env::var("CLERK_WEBHOOK_SECRET").ok(),
             clerk_issuer: env::var("CLERK_ISSUER").ok(),
+            clerk_jwt_leeway_secs: env::var("CLERK_JWT_LEEWAY")
+                .ok()
+                .and_then(|value| value.trim().parse::<u64>().ok())
+                .map(|value| value.min(300))
+                .unwrap_or(10),
+            clerk_token_cache_size: env::var("CLERK_TOKEN_CACHE_SIZE")
+                .ok()
+                .and_then(|value| value.trim().parse::<usize>().ok())
+                .unwrap_or(1024),
+            jwks_max_keys: parse_usize(env::var("JWKS_MAX_KEYS").ok(), 20),
+            // Empty by default: shared hosts like `*.clerk.accounts.dev` let
+            // anyone mint tokens from their own instance.
+            clerk_issuer_domains: parse_string_list("CLERK_ISSUER_DOMAINS")
+                .into_iter()
+                .map(|domain| domain.to_ascii_lowercase())
+                .collect(),
+            user_sync_interval_secs: parse_u64(env::var("USER_SYNC_INTERVAL_SECS").ok(), 900),
+            clerk_email_cache_ttl_secs: parse_u64(env::var("CLERK_EMAIL_CACHE_TTL_SECS").ok(), 300),
             clerk_api_base: env::var("CLERK_API_BASE")
                 .unwrap_or_else(|_| "https://api.clerk.com/v1".to_string()),
             stripe_secret_key: env::var("STRIPE_SECRET_KEY").ok(),
             stripe_webhook_secret: env::var("STRIPE_WEBHOOK_SECRET").ok(),
-            frontend_url: env::var("FRONTEND_URL").ok(),
+            stripe_max_retries: env::var("STRIPE_MAX_RETRIES")
+                .ok()
+                .and_then(|value| value.trim().parse::<u32>().ok())
+                .unwrap_or(2),
+            stripe_retry_base_ms: parse_u64(env::var("STRIPE_RETRY_BASE_MS").ok(), 250),
+            stripe_retry_max_ms: parse_u64(env::var("STRIPE_RETRY_MAX_MS").ok(), 5_000),
+            stripe_circuit_failure_threshold: parse_usize(
+                env::var("STRIPE_CIRCUIT_FAILURE_THRESHOLD").ok(),
+                5,
+            ),
+            stripe_circuit_cooldown_secs: parse_u64(
+                env::var("STRIPE_CIRCUIT_COOLDOWN_SECS").ok(),
+                30,
+            ),
+            frontend_url,
+            work_dir: env::var("WORK_DIR")
+                .ok()
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty())
+                .map(PathBuf::from)
+                .unwrap_or_else(env::temp_dir),
             ghostscript_concurrency,
+            ghostscript_max_bitmap: parse_positive_u64("GHOSTSCRIPT_MAX_BITMAP")?,
+            inkcov_sampling: match env::var("INKCOV_SAMPLE") {
+                Ok(value) => InkcovSampling::parse(&value)
+                    .map_err(|message| anyhow::anyhow!("INKCOV_SAMPLE: {}", message))?,
+                Err(_) => InkcovSampling::All,
+            },
+            inkcov_chunk_pages: env::var("INKCOV_CHUNK_PAGES")
+                .ok()
+                .and_then(|value| value.trim().parse::<u32>().ok())
+                .unwrap_or(0),
+            grayscale_split_threshold_pages: env::var("GRAYSCALE_SPLIT_THRESHOLD_PAGES")
+                .ok()
+                .and_then(|value| value.trim().parse::<u32>().ok())
+                .unwrap_or(0),
+            grayscale_split_chunks: env::var("GRAYSCALE_SPLIT_CHUNKS")
+                .ok()
+                .and_then(|value| value.trim().parse::<u32>().ok())
+                .filter(|value| *value >= 2)
+                .unwrap_or(4),
+            preflight_min_bleed_mm: parse_f64(env::var("PREFLIGHT_MIN_BLEED_MM").ok())
+                .filter(|value| value.is_finite() && *value >= 0.0)
+                .unwrap_or(3.0),
+            ocr_units_per_page: parse_u64(env::var("OCR_UNITS_PER_PAGE").ok(), 5) as i64,
+            ghostscript_buffer_space: parse_positive_u64("GHOSTSCRIPT_BUFFER_SPACE")?,
+            require_ghostscript: env::var("REQUIRE_GHOSTSCRIPT")
+                .ok()
+                .map(|value| parse_bool(Some(value), true)),
             log_ghostscript_timings: env::var("LOG_GHOSTSCRIPT_TIMINGS")
                 .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
                 .unwrap_or(false),
@@ -73,6 +311,7 @@ impl Config {
             log_processing_timings: env::var("LOG_PROCESSING_TIMINGS")
                 .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
                 .unwrap_or(false),
+            allow_debug_timings: parse_bool(env::var("ALLOW_DEBUG_TIMINGS").ok(), false),
             grayscale_production_force_black_text: parse_bool(
                 env::var("GRAYSCALE_PRODUCTION_FORCE_BLACK_TEXT").ok(),
                 true,
@@ -87,10 +326,115 @@ impl Config {
             grayscale_production_black_threshold_c: parse_f64(
                 env::var("GRAYSCALE_PRODUCTION_BLACK_THRESHOLD_C").ok(),
             ),
-            stripe_price_id_starter: env::var("STRIPE_PRICE_ID_STARTER").ok(),
-            stripe_price_id_pro: env::var("STRIPE_PRICE_ID_PRO").ok(),
-            stripe_price_id_business: env::var("STRIPE_PRICE_ID_BUSINESS").ok(),
-            stripe_price_id_enterprise: env::var("STRIPE_PRICE_ID_ENTERPRISE").ok(),
+            reservation_ttl_secs: parse_u64(env::var("RESERVATION_TTL_SECS").ok(), 10 * 60),
+            reservation_cleanup_enabled: parse_bool(
+                env::var("RESERVATION_CLEANUP_ENABLED").ok(),
+                false,
+            ),
+            reservation_cleanup_interval_secs: parse_u64(
+                env::var("RESERVATION_CLEANUP_INTERVAL_SECS").ok(),
+                5 * 60,
+            )
+            .max(1),
+            reservation_cleanup_lookback_secs: parse_u64(
+                env::var("RESERVATION_CLEANUP_LOOKBACK_SECS").ok(),
+                24 * 60 * 60,
+            ),
+            result_retention_secs: env::var("RESULT_RETENTION_SECS")
+                .ok()
+                .and_then(|value| value.parse::<u64>().ok())
+                .filter(|value| *value > 0)
+                .unwrap_or(60 * 60),
+            tus_uploads_enabled: parse_bool(env::var("TUS_UPLOADS_ENABLED").ok(), false),
+            tus_upload_ttl_secs: parse_u64(env::var("TUS_UPLOAD_TTL_SECS").ok(), 24 * 60 * 60),
+            tus_max_uploads_per_user: parse_usize(env::var("TUS_MAX_UPLOADS_PER_USER").ok(), 5),
+            max_output_bytes: env::var("MAX_OUTPUT_BYTES")
+                .ok()
+                .and_then(|value| value.trim().parse::<u64>().ok())
+                .filter(|value| *value > 0),
+            output_size_multiplier: parse_u64(env::var("OUTPUT_SIZE_MULTIPLIER").ok(), 5),
+            already_grayscale_action: match env::var("GRAYSCALE_ALREADY_GRAY_ACTION")
+                .map(|value| value.trim().to_ascii_lowercase())
+                .as_deref()
+            {
+                Ok("annotate") => AlreadyGrayscaleAction::Annotate,
+                Ok("skip") => AlreadyGrayscaleAction::Skip,
+                _ => AlreadyGrayscaleAction::Convert,
+            },
+            admin_token: env::var("ADMIN_TOKEN")
+                .ok()
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty()),
+            max_concurrent_requests: parse_usize(env::var("MAX_CONCURRENT_REQUESTS").ok(), 1024),
+            ghostscript_max_queue_depth: env::var("GHOSTSCRIPT_MAX_QUEUE_DEPTH")
+                .ok()
+                .and_then(|value| value.trim().parse::<usize>().ok())
+                .unwrap_or(32),
+            concurrency_overflow: match env::var("CONCURRENCY_OVERFLOW")
+                .map(|value| value.trim().to_ascii_lowercase())
+                .as_deref()
+            {
+                Ok("queue") => ConcurrencyOverflow::Queue,
+                _ => ConcurrencyOverflow::Reject,
+            },
+            request_deadline_secs: env::var("REQUEST_DEADLINE_SECS")
+                .ok()
+                .and_then(|value| value.trim().parse::<u64>().ok())
+                .unwrap_or(30),
+            process_request_deadline_secs: env::var("PROCESS_REQUEST_DEADLINE_SECS")
+                .ok()
+                .and_then(|value| value.trim().parse::<u64>().ok())
+                .unwrap_or(180),
+            // Leaves room for the upload, page count and queueing on top of
+            // the ocrmypdf run itself.
+            ocr_request_deadline_secs: parse_u64(
+                env::var("OCR_REQUEST_DEADLINE_SECS").ok(),
+                crate::ocr::command_timeout().as_secs() + 120,
+            ),
+            watermark_free_plan: parse_bool(env::var("WATERMARK_FREE_PLAN").ok(), true),
+            watermark_text: env::var("WATERMARK_TEXT")
+                .ok()
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty())
+                .unwrap_or_else(|| {
+                    "Converted with GhostServer \u{2014} upgrade to remove".to_string()
+                }),
+            stripe_price_ids_starter: parse_string_list("STRIPE_PRICE_ID_STARTER"),
+            stripe_price_ids_pro: parse_string_list("STRIPE_PRICE_ID_PRO"),
+            stripe_price_ids_business: parse_string_list("STRIPE_PRICE_ID_BUSINESS"),
+            stripe_price_ids_enterprise: parse_string_list("STRIPE_PRICE_ID_ENTERPRISE"),
+            stripe_metered_price_id: env::var("STRIPE_METERED_PRICE_ID")
+                .ok()
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty()),
+            stripe_metered_plans: parse_plan_list("STRIPE_METERED_PLANS")?,
+            overage_report_interval_secs: env::var("OVERAGE_REPORT_INTERVAL_SECS")
+                .ok()
+                .and_then(|value| value.trim().parse::<u64>().ok())
+                .unwrap_or(0),
+            downgrade_over_quota: match env::var("DOWNGRADE_OVER_QUOTA") {
+                Ok(value) => DowngradePolicy::parse(&value)
+                    .map_err(|message| anyhow::anyhow!("DOWNGRADE_OVER_QUOTA: {}", message))?,
+                Err(_) => DowngradePolicy::Block,
+            },
+            dev_auth_bypass,
+            disabled_features: parse_feature_list("DISABLED_FEATURES")?,
+            ghostscript_warmup: parse_bool(env::var("GHOSTSCRIPT_WARMUP").ok(), false),
+            clerk_session_cookie: env::var("CLERK_SESSION_COOKIE")
+                .ok()
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty()),
+            clerk_session_cookie_origins,
+            stripe_verify_prices: parse_bool(env::var("STRIPE_VERIFY_PRICES").ok(), false),
+            health_cache_secs: env::var("HEALTH_CACHE_SECS")
+                .ok()
+                .and_then(|value| value.trim().parse::<u64>().ok())
+                .unwrap_or(5),
+            convex_warmup_connections: parse_usize(env::var("CONVEX_WARMUP_CONNECTIONS").ok(), 1),
+            convex_required_at_startup: parse_bool(
+                env::var("CONVEX_REQUIRED_AT_STARTUP").ok(),
+                false,
+            ),
         })
     }
 }
@@ -109,6 +453,35 @@ fn parse_usize(value: Option<String>, fallback: usize) -> usize {
         .unwrap_or(fallback)
 }
 
+fn parse_u64(value: Option<String>, fallback: u64) -> u64 {
+    value
+        .and_then(|v| v.parse::<u64>().ok())
+        .filter(|v| *v > 0)
+        .unwrap_or(fallback)
+}
+
+// Unset means "leave Ghostscript's default"; anything else must be a
+// positive integer so a typo can't silently disable a safety limit.
+fn parse_positive_u64(name: &str) -> anyhow::Result<Option<u64>> {
+    match env::var(name) {
+        Ok(value) if !value.trim().is_empty() => value
+            .trim()
+            .parse::<u64>()
+            .ok()
+            .filter(|parsed| *parsed > 0)
+            .map(Some)
+            .ok_or_else(|| anyhow::anyhow!("{} must be a positive integer", name)),
+        _ => Ok(None),
+    }
+}
+
+fn parse_prefix_len(value: Option<String>, fallback: u8, max: u8) -> u8 {
+    value
+        .and_then(|v| v.trim().parse::<u8>().ok())
+        .filter(|v| (1..=max).contains(v))
+        .unwrap_or(fallback)
+}
+
 fn parse_bool(value: Option<String>, fallback: bool) -> bool {
     value
         .map(|raw| {
@@ -118,6 +491,47 @@ fn parse_bool(value: Option<String>, fallback: bool) -> bool {
         .unwrap_or(fallback)
 }
 
+// Comma-separated values with blanks dropped.
+fn parse_string_list(name: &str) -> Vec<String> {
+    split_list(&env::var(name).unwrap_or_default())
+}
+
+fn split_list(raw: &str) -> Vec<String> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(ToString::to_string)
+        .collect()
+}
+
+// Comma-separated plan ids; an unknown name is a startup error rather than a
+// plan that silently never gets billed.
+fn parse_plan_list(name: &str) -> anyhow::Result<Vec<PlanId>> {
+    let raw = env::var(name).unwrap_or_default();
+    raw.split(',')
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(|value| {
+            PlanId::ALL
+                .into_iter()
+                .find(|plan_id| plan_id.as_str().eq_ignore_ascii_case(value))
+                .ok_or_else(|| anyhow::anyhow!("{}: unknown plan `{}`", name, value))
+        })
+        .collect()
+}
+
+fn parse_feature_list(name: &str) -> anyhow::Result<Vec<Feature>> {
+    let raw = env::var(name).unwrap_or_default();
+    raw.split(',')
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(|value| {
+            Feature::parse(value)
+                .ok_or_else(|| anyhow::anyhow!("{}: unknown feature `{}`", name, value))
+        })
+        .collect()
+}
+
 fn parse_f64(value: Option<String>) -> Option<f64> {
     value.and_then(|v| v.parse::<f64>().ok())
 }
@@ -128,6 +542,21 @@ fn default_ghostscript_concurrency() -> usize {
         .unwrap_or(3)
 }
 
+fn parse_trusted_proxies(raw: &str) -> anyhow::Result<Vec<IpNet>> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(|value| {
+            value
+                .parse::<IpNet>()
+                .or_else(|_| value.parse::<std::net::IpAddr>().map(IpNet::from))
+                .map_err(|_| {
+                    anyhow::anyhow!("TRUSTED_PROXIES contains an invalid range: {}", value)
+                })
+        })
+        .collect()
+}
+
 fn normalize_convex_url(raw: &str) -> String {
     let trimmed = raw.trim();
     if let Some(stripped) = trimmed.strip_prefix("wss://") {
@@ -138,3 +567,64 @@ fn normalize_convex_url(raw: &str) -> String {
     }
     trimmed.to_string()
 }
+
+// Reduces a URL to the `scheme://host[:port]` form browsers send in `Origin`.
+fn parse_origin(raw: &str) -> anyhow::Result<String> {
+    let parsed = reqwest::Url::parse(raw)
+        .map_err(|error| anyhow::anyhow!("`{}` is not a valid URL: {}", raw, error))?;
+    let origin = parsed.origin();
+    if !origin.is_tuple() {
+        return Err(anyhow::anyhow!("`{}` has no origin", raw));
+    }
+    Ok(origin.ascii_serialization())
+}
+
+fn normalize_frontend_url(raw: Option<String>) -> anyhow::Result<Option<String>> {
+    let raw = match raw.map(|value| value.trim().to_string()) {
+        Some(value) if !value.is_empty() => value,
+        _ => return Ok(None),
+    };
+
+    let parsed = reqwest::Url::parse(&raw)
+        .map_err(|error| anyhow::anyhow!("FRONTEND_URL is not a valid URL: {}", error))?;
+    if !matches!(parsed.scheme(), "http" | "https") || parsed.host_str().is_none() {
+        return Err(anyhow::anyhow!(
+            "FRONTEND_URL must be an absolute http(s) URL with a host"
+        ));
+    }
+
+    Ok(Some(parsed.as_str().trim_end_matches('/').to_string()))
+}
+
+#[cfg(test)]
+impl Config {
+    // Built once from the environment with only CONVEX_URL guaranteed; tests
+    // override the fields they depend on.
+    pub fn for_tests() -> Self {
+        static CONFIG: std::sync::OnceLock<Config> = std::sync::OnceLock::new();
+        CONFIG
+            .get_or_init(|| {
+                if env::var("CONVEX_URL").is_err() {
+                    env::set_var("CONVEX_URL", "http://127.0.0.1:3210");
+                }
+                Config::from_env().expect("test config")
+            })
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_list_accepts_single_and_comma_separated_values() {
+        assert_eq!(split_list("price_new"), vec!["price_new"]);
+        assert_eq!(
+            split_list(" price_new , price_old,,price_older ,"),
+            vec!["price_new", "price_old", "price_older"]
+        );
+        assert!(split_list("").is_empty());
+        assert!(split_list(" , ").is_empty());
+    }
+}
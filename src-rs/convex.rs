@@ -1,7 +1,8 @@
-use anyhow::{anyhow, Context};
-use reqwest::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
+use anyhow::Context;
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_TYPE};
 use serde::de::DeserializeOwned;
 use serde_json::{json, Value};
+use thiserror::Error;
 
 #[derive(Clone)]
 pub struct ConvexClient {
@@ -10,15 +11,91 @@ pub struct ConvexClient {
 }
 
 const CONVEX_CLIENT_HEADER: &str = "npm-1.26.2";
+const MAX_ERROR_ARGS_CHARS: usize = 512;
+
+// Null object fields are stripped from args by default because Convex
+// `v.optional` validators reject an explicit null. Turn pruning off for calls
+// whose function accepts `v.null()` to clear a field.
+#[derive(Debug, Clone, Copy)]
+pub struct CallOptions {
+    pub prune_nulls: bool,
+}
+
+impl Default for CallOptions {
+    fn default() -> Self {
+        Self { prune_nulls: true }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ConvexError {
+    #[error("Convex {kind} {path} failed: {message} (args={args})")]
+    FunctionError {
+        kind: &'static str,
+        path: String,
+        message: String,
+        args: String,
+    },
+    #[error("Convex {kind} request failed for {path} (base_url={base_url}, args={args})")]
+    Transport {
+        kind: &'static str,
+        path: String,
+        base_url: String,
+        args: String,
+        #[source]
+        source: reqwest::Error,
+    },
+    #[error("Convex {kind} HTTP error {status} for {path}: {body} (args={args})")]
+    Http {
+        kind: &'static str,
+        path: String,
+        status: reqwest::StatusCode,
+        body: String,
+        args: String,
+    },
+    #[error("Invalid Convex {kind} response for {path}: {body} (args={args})")]
+    InvalidResponse {
+        kind: &'static str,
+        path: String,
+        body: String,
+        args: String,
+    },
+    #[error("failed to decode Convex {kind} result for {path}")]
+    Decode {
+        kind: &'static str,
+        path: String,
+        #[source]
+        source: serde_json::Error,
+    },
+}
+
+impl ConvexError {
+    pub fn is_function_error(&self) -> bool {
+        matches!(self, ConvexError::FunctionError { .. })
+    }
+
+    pub fn function_message(&self) -> Option<&str> {
+        match self {
+            ConvexError::FunctionError { message, .. } => Some(message.as_str()),
+            _ => None,
+        }
+    }
+}
 
 impl ConvexClient {
-    pub fn new(base_url: String) -> anyhow::Result<Self> {
+    pub fn new(base_url: String, auth_token: Option<&str>) -> anyhow::Result<Self> {
         let mut headers = HeaderMap::new();
         headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
         headers.insert(
             "Convex-Client",
             HeaderValue::from_static(CONVEX_CLIENT_HEADER),
         );
+        if let Some(token) = auth_token {
+            let mut value = HeaderValue::from_str(&format!("Bearer {}", token))
+                .context("invalid CONVEX_AUTH_TOKEN for header")?;
+            value.set_sensitive(true);
+            headers.insert(AUTHORIZATION, value);
+        }
 
         let http = reqwest::Client::builder()
             .default_headers(headers)
@@ -28,30 +105,60 @@ impl ConvexClient {
         Ok(Self { base_url, http })
     }
 
-    pub async fn query<T: DeserializeOwned>(&self, path: &str, args: Value) -> anyhow::Result<T> {
-        let value = self.call("query", path, args).await?;
-        serde_json::from_value(value)
-            .with_context(|| format!("failed to decode Convex query result for {path}"))
+    pub async fn query<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        args: Value,
+    ) -> Result<T, ConvexError> {
+        let value = self
+            .call_with_options("query", path, args, CallOptions::default())
+            .await?;
+        decode_value("query", path, value)
     }
 
-    pub async fn query_value(&self, path: &str, args: Value) -> anyhow::Result<Value> {
-        self.call("query", path, args).await
+    pub async fn query_value(&self, path: &str, args: Value) -> Result<Value, ConvexError> {
+        self.call_with_options("query", path, args, CallOptions::default())
+            .await
     }
 
-    pub async fn action<T: DeserializeOwned>(&self, path: &str, args: Value) -> anyhow::Result<T> {
-        let value = self.call("action", path, args).await?;
-        serde_json::from_value(value)
-            .with_context(|| format!("failed to decode Convex action result for {path}"))
+    pub async fn action<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        args: Value,
+    ) -> Result<T, ConvexError> {
+        let value = self
+            .call_with_options("action", path, args, CallOptions::default())
+            .await?;
+        decode_value("action", path, value)
     }
 
-    pub async fn action_value(&self, path: &str, args: Value) -> anyhow::Result<Value> {
-        self.call("action", path, args).await
+    pub async fn action_value(&self, path: &str, args: Value) -> Result<Value, ConvexError> {
+        self.call_with_options("action", path, args, CallOptions::default())
+            .await
     }
 
-    async fn call(&self, kind: &str, path: &str, args: Value) -> anyhow::Result<Value> {
+    pub async fn action_value_with_options(
+        &self,
+        path: &str,
+        args: Value,
+        options: CallOptions,
+    ) -> Result<Value, ConvexError> {
+        self.call_with_options("action", path, args, options).await
+    }
+
+    async fn call_with_options(
+        &self,
+        kind: &'static str,
+        path: &str,
+        args: Value,
+        options: CallOptions,
+    ) -> Result<Value, ConvexError> {
         let endpoint = format!("{}/api/{}", self.base_url.trim_end_matches('/'), kind);
         let mut args = args;
-        prune_null_object_fields(&mut args);
+        if options.prune_nulls {
+            prune_null_object_fields(&mut args);
+        }
+        let args_summary = redacted_args(&args);
         let body = json!({
             "path": path,
             "format": "convex_encoded_json",
@@ -64,29 +171,45 @@ impl ConvexClient {
             .json(&body)
             .send()
             .await
-            .with_context(|| {
-                format!(
-                    "Convex {} request failed for {} (base_url={})",
-                    kind, path, self.base_url
-                )
+            .map_err(|source| ConvexError::Transport {
+                kind,
+                path: path.to_string(),
+                base_url: self.base_url.clone(),
+                args: args_summary.clone(),
+                source,
             })?;
 
         let status = response.status();
-        let response_body: Value = response
-            .json()
+        let response_text = response
+            .text()
             .await
-            .with_context(|| format!("failed to parse Convex {} response for {}", kind, path))?;
+            .map_err(|source| ConvexError::Transport {
+                kind,
+                path: path.to_string(),
+                base_url: self.base_url.clone(),
+                args: args_summary.clone(),
+                source,
+            })?;
 
+        // 560 is Convex's "function error" status; the body still carries the error details.
         if !status.is_success() && status.as_u16() != 560 {
-            return Err(anyhow!(
-                "Convex {} HTTP error {} for {}: {}",
+            return Err(ConvexError::Http {
                 kind,
+                path: path.to_string(),
                 status,
-                path,
-                response_body
-            ));
+                body: response_text,
+                args: args_summary,
+            });
         }
 
+        let response_body: Value =
+            serde_json::from_str(&response_text).map_err(|_| ConvexError::InvalidResponse {
+                kind,
+                path: path.to_string(),
+                body: response_text.clone(),
+                args: args_summary.clone(),
+            })?;
+
         match response_body.get("status").and_then(Value::as_str) {
             Some("success") => Ok(response_body.get("value").cloned().unwrap_or(Value::Null)),
             Some("error") => {
@@ -94,18 +217,74 @@ impl ConvexClient {
                     .get("errorMessage")
                     .and_then(Value::as_str)
                     .unwrap_or("Convex function error");
-                Err(anyhow!("Convex {} {} failed: {}", kind, path, message))
+                Err(ConvexError::FunctionError {
+                    kind,
+                    path: path.to_string(),
+                    message: message.to_string(),
+                    args: args_summary,
+                })
             }
-            _ => Err(anyhow!(
-                "Invalid Convex {} response for {}: {}",
+            _ => Err(ConvexError::InvalidResponse {
                 kind,
-                path,
-                response_body
-            )),
+                path: path.to_string(),
+                body: response_body.to_string(),
+                args: args_summary,
+            }),
         }
     }
 }
 
+fn decode_value<T: DeserializeOwned>(
+    kind: &'static str,
+    path: &str,
+    value: Value,
+) -> Result<T, ConvexError> {
+    serde_json::from_value(value).map_err(|source| ConvexError::Decode {
+        kind,
+        path: path.to_string(),
+        source,
+    })
+}
+
+// Args as they appear in errors: values under key/token/secret-like field
+// names are replaced, and the whole thing is capped so one large payload
+// can't flood the logs.
+fn redacted_args(args: &Value) -> String {
+    let mut args = args.clone();
+    redact_secret_fields(&mut args);
+    let rendered = args.to_string();
+    if rendered.chars().count() > MAX_ERROR_ARGS_CHARS {
+        let truncated: String = rendered.chars().take(MAX_ERROR_ARGS_CHARS).collect();
+        format!("{truncated}...")
+    } else {
+        rendered
+    }
+}
+
+fn redact_secret_fields(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, child) in map.iter_mut() {
+                let key = key.to_ascii_lowercase();
+                if ["key", "token", "secret"]
+                    .iter()
+                    .any(|suffix| key.ends_with(suffix))
+                {
+                    *child = Value::String("[redacted]".to_string());
+                } else {
+                    redact_secret_fields(child);
+                }
+            }
+        }
+        Value::Array(values) => {
+            for child in values {
+                redact_secret_fields(child);
+            }
+        }
+        _ => {}
+    }
+}
+
 fn prune_null_object_fields(value: &mut Value) {
     match value {
         Value::Object(map) => {
@@ -0,0 +1,710 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{
+    convex::{CallOptions, ConvexClient, ConvexError},
+    middleware::ConvexUser,
+    serde_convex::{de_i64_from_number, de_opt_i64_from_number},
+    subscription::Subscription,
+};
+
+// Typed wrappers for every Convex function the server calls, so function
+// paths and argument shapes live in one place instead of in each handler.
+#[derive(Clone)]
+pub struct ConvexApi {
+    client: ConvexClient,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ConvexUserForStripe {
+    #[serde(rename = "clerkId")]
+    pub clerk_id: String,
+    pub email: String,
+    #[serde(rename = "stripeCustomerId")]
+    pub stripe_customer_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConvexGeneratedApiKey {
+    pub key: String,
+    pub preview: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConvexApiKeyRecord {
+    #[serde(rename = "_id")]
+    pub id: String,
+    #[serde(default)]
+    pub name: Option<String>,
+    pub preview: String,
+    #[serde(rename = "createdAt")]
+    #[serde(deserialize_with = "de_i64_from_number")]
+    pub created_at: i64,
+    #[serde(rename = "lastUsedAt")]
+    #[serde(default, deserialize_with = "de_opt_i64_from_number")]
+    pub last_used_at: Option<i64>,
+    #[serde(rename = "expiresAt")]
+    #[serde(default, deserialize_with = "de_opt_i64_from_number")]
+    pub expires_at: Option<i64>,
+    #[serde(default)]
+    pub scopes: Option<Vec<String>>,
+}
+
+#[derive(Debug, Default)]
+pub struct NewApiKey {
+    pub scopes: Option<Vec<String>>,
+    pub expires_at: Option<i64>,
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConvexUsageRecord {
+    pub date: String,
+    #[serde(deserialize_with = "de_i64_from_number")]
+    pub count: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConvexUsageReservationRecord {
+    pub date: String,
+    pub status: String,
+    #[serde(deserialize_with = "de_i64_from_number")]
+    pub units: i64,
+    #[serde(rename = "expiresAt")]
+    #[serde(deserialize_with = "de_i64_from_number")]
+    pub expires_at: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConvexUsageReportRow {
+    #[serde(rename = "clerkId")]
+    pub clerk_id: String,
+    #[serde(deserialize_with = "de_i64_from_number")]
+    pub units: i64,
+    #[serde(
+        rename = "reportedOverage",
+        default,
+        deserialize_with = "de_i64_from_number"
+    )]
+    pub reported_overage: i64,
+    pub subscription: Option<Subscription>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConvexUsageReportPage {
+    pub rows: Vec<ConvexUsageReportRow>,
+    #[serde(rename = "isDone")]
+    pub is_done: bool,
+    #[serde(rename = "continueCursor")]
+    pub continue_cursor: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReserveResult {
+    pub allowed: bool,
+    #[serde(rename = "reservationId")]
+    pub reservation_id: Option<String>,
+    #[serde(rename = "expiresAt")]
+    #[serde(default, deserialize_with = "de_opt_i64_from_number")]
+    pub expires_at: Option<i64>,
+    #[serde(rename = "nextPendingExpiresAt")]
+    #[serde(default, deserialize_with = "de_opt_i64_from_number")]
+    pub next_pending_expires_at: Option<i64>,
+    #[serde(rename = "totalThisMonth")]
+    #[serde(deserialize_with = "de_i64_from_number")]
+    pub total_this_month: i64,
+    #[serde(rename = "pendingUnits")]
+    #[serde(default, deserialize_with = "de_opt_i64_from_number")]
+    pub pending_units: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CommitReservationResult {
+    pub committed: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReleaseReservationResult {
+    pub released: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConvexExpiredReservation {
+    #[serde(rename = "reservationId")]
+    pub reservation_id: String,
+    #[serde(rename = "clerkId")]
+    pub clerk_id: String,
+    #[serde(deserialize_with = "de_i64_from_number")]
+    pub units: i64,
+}
+
+#[derive(Debug)]
+pub struct NotificationWrite<'a> {
+    pub kind: &'a str,
+    pub stripe_object_id: &'a str,
+    pub due_at: Option<i64>,
+    pub action_url: Option<&'a str>,
+}
+
+#[derive(Debug)]
+pub struct SubscriptionWrite<'a> {
+    pub plan: &'a str,
+    pub status: &'a str,
+    pub stripe_subscription_id: Option<&'a str>,
+    pub stripe_price_id: Option<&'a str>,
+    // `None` leaves endsAt untouched; `Some(None)` clears it on update.
+    pub ends_at: Option<Option<i64>>,
+    // Same convention as `ends_at`.
+    pub metered_item_id: Option<Option<&'a str>>,
+}
+
+impl ConvexApi {
+    pub fn new(client: ConvexClient) -> Self {
+        Self { client }
+    }
+
+    pub async fn health(&self) -> Result<String, ConvexError> {
+        self.client.query("health:get", json!({})).await
+    }
+
+    pub async fn sync_user(&self, clerk_id: &str, email: &str) -> Result<(), ConvexError> {
+        self.client
+            .action_value("users:sync", json!({ "clerkId": clerk_id, "email": email }))
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn remove_user(&self, clerk_id: &str) -> Result<(), ConvexError> {
+        self.client
+            .action_value("users:remove", json!({ "clerkId": clerk_id }))
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn get_user_for_stripe(
+        &self,
+        clerk_id: &str,
+    ) -> Result<Option<ConvexUserForStripe>, ConvexError> {
+        self.client
+            .action("users:getUserForStripe", json!({ "clerkId": clerk_id }))
+            .await
+    }
+
+    pub async fn set_stripe_customer_id(
+        &self,
+        clerk_id: &str,
+        stripe_customer_id: &str,
+    ) -> Result<(), ConvexError> {
+        self.client
+            .action_value(
+                "users:setStripeCustomerId",
+                json!({
+                    "clerkId": clerk_id,
+                    "stripeCustomerId": stripe_customer_id,
+                }),
+            )
+            .await
+            .map(|_| ())
+    }
+
+    // Returns `None` for unknown keys.
+    pub async fn authenticate_api_key(&self, key: &str) -> Result<Option<ConvexUser>, ConvexError> {
+        self.client
+            .action("apiKeys:authenticateAndTrackUsage", json!({ "key": key }))
+            .await
+    }
+
+    pub async fn generate_api_key(
+        &self,
+        clerk_id: &str,
+        new_key: &NewApiKey,
+    ) -> Result<ConvexGeneratedApiKey, ConvexError> {
+        self.client
+            .action(
+                "apiKeys:generate",
+                json!({
+                    "userId": clerk_id,
+                    "scopes": new_key.scopes,
+                    "expiresAt": new_key.expires_at,
+                    "name": new_key.name,
+                }),
+            )
+            .await
+    }
+
+    pub async fn list_api_keys(
+        &self,
+        clerk_id: &str,
+    ) -> Result<Vec<ConvexApiKeyRecord>, ConvexError> {
+        self.client
+            .query("apiKeys:list", json!({ "userId": clerk_id }))
+            .await
+    }
+
+    pub async fn delete_api_key(
+        &self,
+        clerk_id: &str,
+        api_key_id: &str,
+    ) -> Result<(), ConvexError> {
+        self.client
+            .action_value(
+                "apiKeys:deleteApiKey",
+                json!({
+                    "clerkId": clerk_id,
+                    "apiKeyId": api_key_id,
+                }),
+            )
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn get_subscription(
+        &self,
+        clerk_id: &str,
+    ) -> Result<Option<Subscription>, ConvexError> {
+        self.client
+            .query("subscriptions:get", json!({ "userId": clerk_id }))
+            .await
+    }
+
+    // The raw record, for endpoints that pass it straight through to clients.
+    pub async fn get_subscription_value(&self, clerk_id: &str) -> Result<Value, ConvexError> {
+        self.client
+            .query_value("subscriptions:get", json!({ "userId": clerk_id }))
+            .await
+    }
+
+    pub async fn save_subscription(
+        &self,
+        clerk_id: &str,
+        exists: bool,
+        write: &SubscriptionWrite<'_>,
+    ) -> Result<(), ConvexError> {
+        let path = if exists {
+            "subscriptions:updateSubscription"
+        } else {
+            "subscriptions:createSubscription"
+        };
+
+        let mut args = json!({
+            "userId": clerk_id,
+            "plan": write.plan,
+            "status": write.status,
+        });
+        if let Some(id) = write.stripe_subscription_id {
+            args["stripeSubscriptionId"] = json!(id);
+        }
+        if let Some(id) = write.stripe_price_id {
+            args["stripePriceId"] = json!(id);
+        }
+        match write.ends_at {
+            Some(Some(at)) => args["endsAt"] = json!(at),
+            // createSubscription has nothing to clear and rejects null.
+            Some(None) if exists => args["endsAt"] = Value::Null,
+            _ => {}
+        }
+        match write.metered_item_id {
+            Some(Some(id)) => args["stripeMeteredItemId"] = json!(id),
+            Some(None) if exists => args["stripeMeteredItemId"] = Value::Null,
+            _ => {}
+        }
+
+        self.client
+            .action_value_with_options(path, args, CallOptions { prune_nulls: false })
+            .await
+            .map(|_| ())
+    }
+
+    // Idempotent per user, kind and Stripe object, so webhook retries are safe.
+    pub async fn record_notification(
+        &self,
+        clerk_id: &str,
+        notification: &NotificationWrite<'_>,
+    ) -> Result<(), ConvexError> {
+        self.client
+            .action_value(
+                "notifications:record",
+                json!({
+                    "clerkId": clerk_id,
+                    "kind": notification.kind,
+                    "stripeObjectId": notification.stripe_object_id,
+                    "dueAt": notification.due_at,
+                    "actionUrl": notification.action_url,
+                }),
+            )
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn get_usage_records(
+        &self,
+        clerk_id: &str,
+    ) -> Result<Vec<ConvexUsageRecord>, ConvexError> {
+        self.client
+            .query("usage:getUsageData", json!({ "userId": clerk_id }))
+            .await
+    }
+
+    pub async fn get_usage_reservations(
+        &self,
+        clerk_id: &str,
+    ) -> Result<Vec<ConvexUsageReservationRecord>, ConvexError> {
+        self.client
+            .query("usage:getUsageReservations", json!({ "userId": clerk_id }))
+            .await
+    }
+
+    // `month` is YYYY-MM; pass the previous page's `continue_cursor` to go on.
+    pub async fn get_usage_report_page(
+        &self,
+        month: &str,
+        cursor: Option<&str>,
+        page_size: usize,
+    ) -> Result<ConvexUsageReportPage, ConvexError> {
+        self.client
+            .query(
+                "usage:getMonthlyUsageReport",
+                json!({
+                    "month": month,
+                    "cursor": cursor,
+                    "numItems": page_size,
+                }),
+            )
+            .await
+    }
+
+    pub async fn record_reported_overage(
+        &self,
+        clerk_id: &str,
+        month: &str,
+        units: i64,
+    ) -> Result<(), ConvexError> {
+        self.client
+            .action_value(
+                "usage:recordReportedOverageForClerkUser",
+                json!({
+                    "clerkId": clerk_id,
+                    "month": month,
+                    "units": units,
+                }),
+            )
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn reserve_units(
+        &self,
+        clerk_id: &str,
+        units: i64,
+        monthly_quota: Option<i64>,
+        ttl_ms: i64,
+    ) -> Result<ReserveResult, ConvexError> {
+        self.client
+            .action(
+                "usage:reserveForClerkUser",
+                json!({
+                    "clerkId": clerk_id,
+                    "units": units,
+                    "monthlyQuota": monthly_quota,
+                    "ttlMs": ttl_ms,
+                }),
+            )
+            .await
+    }
+
+    pub async fn commit_reservation(
+        &self,
+        clerk_id: &str,
+        reservation_id: &str,
+    ) -> Result<CommitReservationResult, ConvexError> {
+        self.client
+            .action(
+                "usage:commitReservationForClerkUser",
+                json!({
+                    "clerkId": clerk_id,
+                    "reservationId": reservation_id,
+                }),
+            )
+            .await
+    }
+
+    pub async fn release_reservation(
+        &self,
+        clerk_id: &str,
+        reservation_id: &str,
+    ) -> Result<ReleaseReservationResult, ConvexError> {
+        self.client
+            .action(
+                "usage:releaseReservationForClerkUser",
+                json!({
+                    "clerkId": clerk_id,
+                    "reservationId": reservation_id,
+                }),
+            )
+            .await
+    }
+
+    // Bounds are ms timestamps.
+    pub async fn get_expired_pending_reservations(
+        &self,
+        expired_after: i64,
+        expired_before: i64,
+        limit: usize,
+    ) -> Result<Vec<ConvexExpiredReservation>, ConvexError> {
+        self.client
+            .query(
+                "usage:getExpiredPendingReservations",
+                json!({
+                    "expiredAfter": expired_after,
+                    "expiredBefore": expired_before,
+                    "limit": limit,
+                }),
+            )
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use wiremock::{
+        matchers::{body_partial_json, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::*;
+
+    fn api_for(base_url: String) -> ConvexApi {
+        ConvexApi::new(ConvexClient::new(base_url, None).expect("client"))
+    }
+
+    fn success(value: Value) -> ResponseTemplate {
+        ResponseTemplate::new(200).set_body_json(json!({ "status": "success", "value": value }))
+    }
+
+    async fn received_args(server: &MockServer) -> Vec<Value> {
+        server
+            .received_requests()
+            .await
+            .expect("request recording")
+            .iter()
+            .map(|request| {
+                let body: Value = serde_json::from_slice(&request.body).expect("json body");
+                assert_eq!(body["format"], "convex_encoded_json");
+                body["args"][0].clone()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn reserve_commit_release_round_trip() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/action"))
+            .and(body_partial_json(
+                json!({ "path": "usage:reserveForClerkUser" }),
+            ))
+            .respond_with(success(json!({
+                "allowed": true,
+                "reservationId": "res_1",
+                "expiresAt": 1_700_000_060_000.0,
+                "totalThisMonth": 12.0,
+                "pendingUnits": 3.0,
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/api/action"))
+            .and(body_partial_json(
+                json!({ "path": "usage:commitReservationForClerkUser" }),
+            ))
+            .respond_with(success(json!({ "committed": true })))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/api/action"))
+            .and(body_partial_json(
+                json!({ "path": "usage:releaseReservationForClerkUser" }),
+            ))
+            .respond_with(success(json!({ "released": false })))
+            .mount(&server)
+            .await;
+        let api = api_for(server.uri());
+
+        let reserved = api
+            .reserve_units("user_1", 3, Some(100), 60_000)
+            .await
+            .expect("reserve");
+        assert!(reserved.allowed);
+        assert_eq!(reserved.reservation_id.as_deref(), Some("res_1"));
+        assert_eq!(reserved.total_this_month, 12);
+        assert_eq!(reserved.pending_units, Some(3));
+        assert_eq!(reserved.next_pending_expires_at, None);
+
+        let committed = api
+            .commit_reservation("user_1", "res_1")
+            .await
+            .expect("commit");
+        assert!(committed.committed);
+        let released = api
+            .release_reservation("user_1", "res_1")
+            .await
+            .expect("release");
+        assert!(!released.released);
+
+        let args = received_args(&server).await;
+        assert_eq!(
+            args[0],
+            json!({ "clerkId": "user_1", "units": 3, "monthlyQuota": 100, "ttlMs": 60_000 })
+        );
+        assert_eq!(
+            args[1],
+            json!({ "clerkId": "user_1", "reservationId": "res_1" })
+        );
+    }
+
+    #[tokio::test]
+    async fn usage_records_decode_float_counts() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/query"))
+            .respond_with(success(json!([
+                { "date": "2026-10-01", "count": 4.0 },
+                { "date": "2026-10-02", "count": 7 },
+            ])))
+            .mount(&server)
+            .await;
+
+        let records = api_for(server.uri())
+            .get_usage_records("user_1")
+            .await
+            .expect("usage records");
+        let total: i64 = records.iter().map(|record| record.count).sum();
+        assert_eq!(records.len(), 2);
+        assert_eq!(total, 11);
+    }
+
+    #[tokio::test]
+    async fn function_error_is_distinct_from_transport_error() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/action"))
+            .respond_with(ResponseTemplate::new(560).set_body_json(json!({
+                "status": "error",
+                "errorMessage": "Quota exceeded",
+            })))
+            .mount(&server)
+            .await;
+
+        let error = api_for(server.uri())
+            .reserve_units("user_1", 1, None, 1_000)
+            .await
+            .expect_err("function error");
+        assert!(error.is_function_error());
+        assert_eq!(error.function_message(), Some("Quota exceeded"));
+
+        // Bind and drop a listener so the port is known to be closed.
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").expect("bind");
+        let address = closed.local_addr().expect("address");
+        drop(closed);
+        let error = api_for(format!("http://{}", address))
+            .reserve_units("user_1", 1, None, 1_000)
+            .await
+            .expect_err("transport error");
+        assert!(!error.is_function_error());
+        assert!(matches!(error, ConvexError::Transport { .. }));
+    }
+
+    #[tokio::test]
+    async fn http_error_and_malformed_body() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/query"))
+            .and(body_partial_json(json!({ "path": "health:get" })))
+            .respond_with(ResponseTemplate::new(502).set_body_string("bad gateway"))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/api/query"))
+            .and(body_partial_json(json!({ "path": "usage:getUsageData" })))
+            .respond_with(ResponseTemplate::new(200).set_body_string("not json"))
+            .mount(&server)
+            .await;
+        let api = api_for(server.uri());
+
+        let error = api.health().await.expect_err("http error");
+        assert!(matches!(error, ConvexError::Http { status, .. } if status.as_u16() == 502));
+        let error = api.get_usage_records("user_1").await.expect_err("invalid");
+        assert!(matches!(error, ConvexError::InvalidResponse { .. }));
+    }
+
+    #[tokio::test]
+    async fn nulls_are_pruned_unless_the_call_opts_out() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/action"))
+            .respond_with(success(Value::Null))
+            .mount(&server)
+            .await;
+        let api = api_for(server.uri());
+
+        api.reserve_units("user_1", 2, None, 1_000)
+            .await
+            .expect_err("null result does not decode");
+        api.record_notification(
+            "user_1",
+            &NotificationWrite {
+                kind: "trial_will_end",
+                stripe_object_id: "sub_1",
+                due_at: None,
+                action_url: None,
+            },
+        )
+        .await
+        .expect("notification");
+        let write = SubscriptionWrite {
+            plan: "pro",
+            status: "active",
+            stripe_subscription_id: Some("sub_1"),
+            stripe_price_id: None,
+            ends_at: Some(None),
+            metered_item_id: Some(None),
+        };
+        api.save_subscription("user_1", true, &write)
+            .await
+            .expect("update");
+        api.save_subscription("user_1", false, &write)
+            .await
+            .expect("create");
+
+        let args = received_args(&server).await;
+        assert_eq!(
+            args[0],
+            json!({ "clerkId": "user_1", "units": 2, "ttlMs": 1_000 })
+        );
+        assert_eq!(
+            args[1],
+            json!({ "clerkId": "user_1", "kind": "trial_will_end", "stripeObjectId": "sub_1" })
+        );
+        assert_eq!(
+            args[2],
+            json!({
+                "userId": "user_1",
+                "plan": "pro",
+                "status": "active",
+                "stripeSubscriptionId": "sub_1",
+                "endsAt": null,
+                "stripeMeteredItemId": null,
+            })
+        );
+        assert_eq!(
+            args[3],
+            json!({
+                "userId": "user_1",
+                "plan": "pro",
+                "status": "active",
+                "stripeSubscriptionId": "sub_1",
+            })
+        );
+    }
+}
@@ -0,0 +1,86 @@
+use std::collections::{BTreeMap, HashSet};
+
+use parking_lot::RwLock;
+
+// Processing operations that can be switched off during an incident without a
+// redeploy. Names match the API key scopes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Feature {
+    Analyze,
+    Grayscale,
+    ExtractPages,
+    Sanitize,
+    Ocr,
+}
+
+impl Feature {
+    pub const ALL: [Feature; 5] = [
+        Feature::Analyze,
+        Feature::Grayscale,
+        Feature::ExtractPages,
+        Feature::Sanitize,
+        Feature::Ocr,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Analyze => "analyze",
+            Self::Grayscale => "grayscale",
+            Self::ExtractPages => "extract_pages",
+            Self::Sanitize => "sanitize",
+            Self::Ocr => "ocr",
+        }
+    }
+
+    pub fn parse(raw: &str) -> Option<Self> {
+        let normalized = raw.trim().to_ascii_lowercase();
+        Self::ALL
+            .into_iter()
+            .find(|feature| feature.as_str() == normalized)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct FeatureFlags {
+    disabled: RwLock<HashSet<Feature>>,
+}
+
+impl FeatureFlags {
+    pub fn new(disabled: &[Feature]) -> Self {
+        Self {
+            disabled: RwLock::new(disabled.iter().copied().collect()),
+        }
+    }
+
+    pub fn is_enabled(&self, feature: Feature) -> bool {
+        !self.disabled.read().contains(&feature)
+    }
+
+    // Returns whether the flag actually changed.
+    pub fn set(&self, feature: Feature, enabled: bool) -> bool {
+        let changed = {
+            let mut disabled = self.disabled.write();
+            if enabled {
+                disabled.remove(&feature)
+            } else {
+                disabled.insert(feature)
+            }
+        };
+        if changed {
+            tracing::warn!(
+                feature = feature.as_str(),
+                enabled,
+                "feature flag changed at runtime"
+            );
+        }
+        changed
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<&'static str, bool> {
+        let disabled = self.disabled.read();
+        Feature::ALL
+            .into_iter()
+            .map(|feature| (feature.as_str(), !disabled.contains(&feature)))
+            .collect()
+    }
+}
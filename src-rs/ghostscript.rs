@@ -1,16 +1,37 @@
 use std::{
     path::Path,
     process::Stdio,
-    sync::atomic::{AtomicBool, Ordering},
-    time::Duration,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
+    time::{Duration, Instant},
 };
 
 use anyhow::{anyhow, Context};
+use parking_lot::Mutex;
 use regex::Regex;
 use serde::Serialize;
-use tokio::{process::Command, time::timeout};
+use tokio::{process::Command, sync::Semaphore, task::JoinSet, time::timeout};
+use utoipa::ToSchema;
+use uuid::Uuid;
 
-static HAS_LOGGED_PDFINFO_FALLBACK: AtomicBool = AtomicBool::new(false);
+use crate::{
+    preflight::{self, PreflightWarning},
+    upload::work_dir,
+};
+
+const PDFINFO_FALLBACK_LOG_INTERVAL: Duration = Duration::from_secs(60);
+static LAST_PDFINFO_FALLBACK_LOG: Mutex<Option<Instant>> = Mutex::new(None);
+static SUPPRESSED_PDFINFO_FALLBACKS: AtomicU64 = AtomicU64::new(0);
+static PDFINFO_PAGE_COUNT_ENABLED: once_cell::sync::Lazy<bool> = once_cell::sync::Lazy::new(|| {
+    !matches!(
+        std::env::var("PDFINFO_PAGE_COUNT")
+            .map(|value| value.trim().to_ascii_lowercase())
+            .as_deref(),
+        Ok("false" | "0" | "no" | "off")
+    )
+});
 static GHOSTSCRIPT_COMMAND_TIMEOUT: once_cell::sync::Lazy<Duration> =
     once_cell::sync::Lazy::new(|| {
         let timeout_ms = std::env::var("GHOSTSCRIPT_COMMAND_TIMEOUT_MS")
@@ -21,7 +42,179 @@ static GHOSTSCRIPT_COMMAND_TIMEOUT: once_cell::sync::Lazy<Duration> =
         Duration::from_millis(timeout_ms)
     });
 
-#[derive(Debug, Clone, Serialize)]
+// Extra `-d` params prepended to every `gs` invocation; set once at startup.
+static GHOSTSCRIPT_LIMIT_ARGS: OnceLock<Vec<String>> = OnceLock::new();
+
+pub fn configure_resource_limits(max_bitmap: Option<u64>, buffer_space: Option<u64>) {
+    let mut args = Vec::new();
+    if let Some(value) = max_bitmap {
+        args.push(format!("-dMaxBitmap={}", value));
+    }
+    if let Some(value) = buffer_space {
+        args.push(format!("-dBufferSpace={}", value));
+    }
+    if !args.is_empty() {
+        tracing::info!(args = %args.join(" "), "Ghostscript resource limits enabled");
+    }
+    let _ = GHOSTSCRIPT_LIMIT_ARGS.set(args);
+}
+
+struct InkcovChunking {
+    chunk_pages: i64,
+    // The shared Ghostscript semaphore; chunks beyond the first only run on
+    // permits that happen to be free.
+    permits: Arc<Semaphore>,
+}
+
+// Unset (or a chunk size of 0) keeps inkcov a single pass over the document.
+static INKCOV_CHUNKING: OnceLock<InkcovChunking> = OnceLock::new();
+
+pub fn configure_inkcov_chunking(chunk_pages: u32, permits: Arc<Semaphore>) {
+    if chunk_pages == 0 {
+        return;
+    }
+    tracing::info!(chunk_pages, "inkcov chunking enabled");
+    let _ = INKCOV_CHUNKING.set(InkcovChunking {
+        chunk_pages: i64::from(chunk_pages),
+        permits,
+    });
+}
+
+struct GrayscaleSplitting {
+    threshold_pages: i64,
+    chunks: i64,
+    // Shared with inkcov chunking; see `run_page_ranges`.
+    permits: Arc<Semaphore>,
+}
+
+// Unset (or a threshold of 0) keeps grayscale conversion a single `gs` pass.
+static GRAYSCALE_SPLITTING: OnceLock<GrayscaleSplitting> = OnceLock::new();
+
+pub fn configure_grayscale_splitting(threshold_pages: u32, chunks: u32, permits: Arc<Semaphore>) {
+    if threshold_pages == 0 || chunks < 2 {
+        return;
+    }
+    tracing::info!(
+        threshold_pages,
+        chunks,
+        "grayscale page-range splitting enabled"
+    );
+    let _ = GRAYSCALE_SPLITTING.set(GrayscaleSplitting {
+        threshold_pages: i64::from(threshold_pages),
+        chunks: i64::from(chunks),
+        permits,
+    });
+}
+
+// Failures caused by the uploaded file rather than by us; handlers report these
+// as 422 with `code()` instead of a 500.
+#[derive(Debug, thiserror::Error)]
+pub enum PdfInputError {
+    #[error("This PDF is password protected. Remove the password and upload it again.")]
+    PasswordRequired,
+    #[error("This file is not a valid PDF.")]
+    Invalid,
+    #[error("This PDF is damaged and could not be repaired. Re-export it and try again.")]
+    Corrupt,
+}
+
+impl PdfInputError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            PdfInputError::PasswordRequired => "pdf_password_required",
+            PdfInputError::Invalid => "invalid_pdf",
+            PdfInputError::Corrupt => "corrupt_pdf",
+        }
+    }
+}
+
+const PASSWORD_SIGNATURES: &[&str] = &[
+    "requires a password",
+    "incorrect password",
+    "password did not work",
+    "invalid password",
+];
+const INVALID_PDF_SIGNATURES: &[&str] = &[
+    "not a pdf file",
+    "may not be a pdf file",
+    "error: /undefined in",
+];
+const CORRUPT_PDF_SIGNATURES: &[&str] = &[
+    "unable to repair",
+    "could not be repaired",
+    "file has been damaged",
+    "couldn't find trailer",
+    "couldn't read xref",
+    "invalid xref",
+    "error: /syntaxerror",
+];
+
+// Maps Ghostscript/pdfinfo failure output to a client-facing input error.
+// Password problems win over the generic signatures they often come with.
+pub fn classify_error(output: &str) -> Option<PdfInputError> {
+    classify_error_for_script(output, None)
+}
+
+// `script` is the PostScript we passed with `-c`. An `/undefined` naming one
+// of its own operators is our problem (say, a Ghostscript build without
+// `Trailer`), not a bad upload.
+fn classify_error_for_script(output: &str, script: Option<&str>) -> Option<PdfInputError> {
+    if let (Some(name), Some(script)) = (undefined_name(output), script) {
+        let is_ours = script
+            .split(|ch: char| ch.is_whitespace() || "{}[]()<>/".contains(ch))
+            .any(|token| token.eq_ignore_ascii_case(name));
+        if is_ours && !output.to_ascii_lowercase().contains("password") {
+            return None;
+        }
+    }
+
+    let output = output.to_ascii_lowercase();
+    let matches = |signatures: &[&str]| {
+        signatures
+            .iter()
+            .any(|signature| output.contains(signature))
+    };
+
+    if matches(PASSWORD_SIGNATURES) {
+        Some(PdfInputError::PasswordRequired)
+    } else if matches(INVALID_PDF_SIGNATURES) {
+        Some(PdfInputError::Invalid)
+    } else if matches(CORRUPT_PDF_SIGNATURES) {
+        Some(PdfInputError::Corrupt)
+    } else {
+        None
+    }
+}
+
+// The name in `Error: /undefined in NAME`, without the `--op--` dashes.
+fn undefined_name(output: &str) -> Option<&str> {
+    let start = output.to_ascii_lowercase().find("error: /undefined in ")? + 21;
+    output
+        .get(start..)?
+        .split_whitespace()
+        .next()
+        .map(|name| name.trim_matches('-'))
+        .filter(|name| !name.is_empty())
+}
+
+// Everything passed after `-c`, for `classify_error_for_script`.
+fn postscript_args(args: &[String]) -> Option<String> {
+    let script = args
+        .windows(2)
+        .filter(|pair| pair[0] == "-c")
+        .map(|pair| pair[1].as_str())
+        .collect::<Vec<_>>()
+        .join(" ");
+    (!script.is_empty()).then_some(script)
+}
+
+pub const BLACK_THRESHOLD_L_RANGE: (f64, f64) = (0.0, 100.0);
+pub const BLACK_THRESHOLD_C_RANGE: (f64, f64) = (0.0, 128.0);
+// Ghostscript's own defaults for -dBlackThresholdL / -dBlackThresholdC.
+pub const DEFAULT_BLACK_THRESHOLD_L: f64 = 50.0;
+pub const DEFAULT_BLACK_THRESHOLD_C: f64 = 0.0;
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
 pub struct ColorProfile {
     pub page: i64,
     pub c: f64,
@@ -32,17 +225,117 @@ pub struct ColorProfile {
     pub ink_type: String,
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ColorProfilePercent {
+    pub page: i64,
+    pub c: f64,
+    pub m: f64,
+    pub y: f64,
+    pub k: f64,
+    #[serde(rename = "totalInk")]
+    pub total_ink: f64,
+    #[serde(rename = "isColor")]
+    pub is_color: bool,
+    #[serde(rename = "type")]
+    pub ink_type: String,
+}
+
+impl ColorProfile {
+    pub fn to_percentages(&self, color_epsilon: f64) -> ColorProfilePercent {
+        let percent = |value: f64| (value * 100.0 * 1000.0).round() / 1000.0;
+        ColorProfilePercent {
+            page: self.page,
+            c: percent(self.c),
+            m: percent(self.m),
+            y: percent(self.y),
+            k: percent(self.k),
+            total_ink: percent(self.c + self.m + self.y + self.k),
+            is_color: self.c > color_epsilon || self.m > color_epsilon || self.y > color_epsilon,
+            ink_type: self.ink_type.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, ToSchema)]
+pub struct PdfMetadata {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub subject: Option<String>,
+    pub keywords: Option<String>,
+    pub creator: Option<String>,
+    pub producer: Option<String>,
+    #[serde(rename = "creationDate")]
+    pub creation_date: Option<String>,
+    #[serde(rename = "modDate")]
+    pub mod_date: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
 pub struct PdfAnalysis {
     pub file_name: String,
     pub page_count: i64,
     pub has_formfields: bool,
     #[serde(rename = "colorProfiles")]
     pub color_profiles: Vec<ColorProfile>,
+    // True when `color_profiles` covers only a sample of the pages.
+    pub sampled: bool,
+    pub metadata: PdfMetadata,
+    pub warnings: Vec<PreflightWarning>,
+}
+
+// Long documents can be profiled on a subset of pages when only an overall
+// color verdict is needed. `All` keeps the exact per-page report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InkcovSampling {
+    #[default]
+    All,
+    EveryNth(u32),
+    FirstPages(u32),
+}
+
+impl InkcovSampling {
+    // Accepts `all`, `every:N` or `first:K` with N, K >= 1.
+    pub fn parse(raw: &str) -> Result<Self, &'static str> {
+        const INVALID: &str = "Invalid sample. Use \"all\", \"every:N\" or \"first:K\".";
+        let normalized = raw.trim().to_ascii_lowercase();
+        if normalized.is_empty() || normalized == "all" {
+            return Ok(Self::All);
+        }
+        let (kind, value) = normalized.split_once(':').ok_or(INVALID)?;
+        let value = value
+            .trim()
+            .parse::<u32>()
+            .ok()
+            .filter(|value| *value > 0)
+            .ok_or(INVALID)?;
+        match kind.trim() {
+            "every" => Ok(Self::EveryNth(value)),
+            "first" => Ok(Self::FirstPages(value)),
+            _ => Err(INVALID),
+        }
+    }
+
+    // `None` when the sample would cover every page anyway.
+    fn pages(self, page_count: i64) -> Option<Vec<i64>> {
+        let pages = match self {
+            Self::All => return None,
+            Self::EveryNth(step) => (1..=page_count).step_by(step as usize).collect::<Vec<_>>(),
+            Self::FirstPages(count) => (1..=page_count.min(i64::from(count))).collect(),
+        };
+        (pages.len() < page_count.max(0) as usize).then_some(pages)
+    }
 }
 
 pub async fn run_command(program: &str, args: &[String]) -> anyhow::Result<(String, String)> {
+    let limit_args = match program {
+        "gs" => GHOSTSCRIPT_LIMIT_ARGS
+            .get()
+            .map(Vec::as_slice)
+            .unwrap_or_default(),
+        _ => &[],
+    };
     let child = Command::new(program)
+        .args(limit_args)
         .args(args)
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
@@ -76,15 +369,23 @@ pub async fn run_command(program: &str, args: &[String]) -> anyhow::Result<(Stri
             message.to_string()
         };
 
-        return Err(anyhow!(reason));
+        let script = postscript_args(args);
+        return Err(
+            match classify_error_for_script(&reason, script.as_deref()) {
+                Some(input_error) => anyhow::Error::new(input_error).context(reason),
+                None => anyhow!(reason),
+            },
+        );
     }
 
     Ok((stdout, stderr))
 }
 
 pub async fn get_pdf_page_count(file_path: &Path) -> anyhow::Result<i64> {
-    if let Some(count) = try_get_pdf_page_count_with_pdfinfo(file_path).await? {
-        return Ok(count);
+    if *PDFINFO_PAGE_COUNT_ENABLED {
+        if let Some(count) = try_get_pdf_page_count_with_pdfinfo(file_path).await? {
+            return Ok(count);
+        }
     }
 
     let file_path_str = file_path.to_string_lossy().to_string();
@@ -107,85 +408,833 @@ pub async fn get_pdf_page_count(file_path: &Path) -> anyhow::Result<i64> {
         stdout.trim()
     };
 
-    let page_count = raw
-        .parse::<i64>()
-        .map_err(|_| anyhow!("Invalid page count reported by Ghostscript."))?;
+    let script = postscript_args(&args);
+    let page_count = raw.parse::<i64>().map_err(|_| {
+        match classify_error_for_script(raw, script.as_deref()) {
+            Some(input_error) => anyhow::Error::new(input_error).context(raw.to_string()),
+            None => anyhow!("Invalid page count reported by Ghostscript."),
+        }
+    })?;
+
+    if page_count <= 0 {
+        return Err(anyhow!("Invalid page count reported by Ghostscript."));
+    }
+
+    Ok(page_count)
+}
+
+pub async fn analyze_pdf(
+    file_path: &Path,
+    page_count_override: Option<i64>,
+    sampling: InkcovSampling,
+) -> anyhow::Result<PdfAnalysis> {
+    let page_count = match page_count_override {
+        Some(value) => value,
+        None => get_pdf_page_count(file_path).await?,
+    };
+
+    let (color_profiles, sampled) = match sampling.pages(page_count) {
+        Some(pages) => (get_sampled_color_profiles(file_path, &pages).await?, true),
+        None => (get_color_profiles(file_path, page_count).await?, false),
+    };
+
+    let has_formfields = detect_form_fields(file_path).await;
+
+    let metadata = get_pdf_metadata(file_path).await;
+
+    let file_name = file_path
+        .file_name()
+        .map(|value| value.to_string_lossy().to_string())
+        .unwrap_or_else(|| "document.pdf".to_string());
+
+    let mut analysis = PdfAnalysis {
+        file_name,
+        page_count,
+        has_formfields,
+        color_profiles,
+        sampled,
+        metadata,
+        warnings: Vec::new(),
+    };
+    analysis.warnings = preflight::collect_warnings(file_path, &analysis).await;
+
+    Ok(analysis)
+}
+
+const FORM_SCAN_CHUNK_BYTES: usize = 64 * 1024;
+const WIDGET_MARKER: &[u8] = b"/Subtype /Widget";
+const ANNOTS_MARKER: &[u8] = b"/Annots";
+const OBJECT_STREAM_MARKER: &[u8] = b"/Type /ObjStm";
+const STREAM_KEYWORD: &[u8] = b"stream";
+const ENDSTREAM_KEYWORD: &[u8] = b"endstream";
+
+// Avoid a second Ghostscript pass here. Some PDFs can hang on dDumpAnnots.
+// The byte scan only looks outside stream bodies; pdfinfo's AcroForm report
+// then vetoes stray markers and covers widgets hidden in object streams.
+pub async fn detect_form_fields(file_path: &Path) -> bool {
+    let scan = match scan_for_marker(file_path, WIDGET_MARKER).await {
+        Ok(value) => value,
+        Err(error) => {
+            tracing::warn!(error = %error, "failed to read PDF for form-field detection");
+            return false;
+        }
+    };
+
+    match pdfinfo_has_acroform(file_path).await {
+        Some(false) => false,
+        Some(true) => scan.found || scan.saw_object_stream,
+        None => scan.found,
+    }
+}
+
+// Whether any page has an `/Annots` array (links, comments, printer's marks).
+// `None` when the file can't be read, or when nothing was found but
+// compressed object streams could be hiding the page dictionaries.
+pub async fn detect_annotations(file_path: &Path) -> Option<bool> {
+    match scan_for_marker(file_path, ANNOTS_MARKER).await {
+        Ok(scan) if scan.found => Some(true),
+        Ok(scan) if scan.saw_object_stream => None,
+        Ok(_) => Some(false),
+        Err(error) => {
+            tracing::warn!(error = %error, "failed to read PDF for annotation detection");
+            None
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct MarkerScan {
+    found: bool,
+    saw_object_stream: bool,
+}
+
+// Reads in fixed chunks, carrying a short tail between reads so markers split
+// across a chunk boundary are still seen.
+async fn scan_for_marker(file_path: &Path, marker: &[u8]) -> std::io::Result<MarkerScan> {
+    use tokio::io::AsyncReadExt;
+
+    let overlap = marker.len().max(OBJECT_STREAM_MARKER.len());
+    let mut file = tokio::fs::File::open(file_path).await?;
+    let mut buffer = Vec::with_capacity(FORM_SCAN_CHUNK_BYTES + overlap);
+    let mut chunk = vec![0u8; FORM_SCAN_CHUNK_BYTES];
+    let mut scan = MarkerScan::default();
+    let mut in_stream = false;
+
+    loop {
+        let read = file.read(&mut chunk).await?;
+        if read == 0 {
+            return Ok(scan);
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+
+        let mut pos = 0;
+        loop {
+            if in_stream {
+                match find_bytes(&buffer[pos..], ENDSTREAM_KEYWORD) {
+                    Some(index) => {
+                        pos += index + ENDSTREAM_KEYWORD.len();
+                        in_stream = false;
+                    }
+                    None => break,
+                }
+                continue;
+            }
+
+            let window = &buffer[pos..];
+            let stream = find_stream_start(window);
+            let limit = match stream {
+                Some(StreamStart::Body(offset)) | Some(StreamStart::Incomplete(offset)) => offset,
+                None => window.len(),
+            };
+            if !scan.saw_object_stream {
+                scan.saw_object_stream =
+                    find_bytes(&window[..limit], OBJECT_STREAM_MARKER).is_some();
+            }
+            if find_bytes(&window[..limit], marker).is_some() {
+                scan.found = true;
+                return Ok(scan);
+            }
+            match stream {
+                Some(StreamStart::Body(offset)) => {
+                    pos += offset;
+                    in_stream = true;
+                }
+                Some(StreamStart::Incomplete(_)) | None => break,
+            }
+        }
+
+        let keep_from = pos.max(buffer.len().saturating_sub(overlap));
+        buffer.drain(..keep_from);
+    }
+}
+
+enum StreamStart {
+    // Offset of the first byte of the stream body.
+    Body(usize),
+    // Offset of a `stream` keyword whose end-of-line is not buffered yet.
+    Incomplete(usize),
+}
+
+fn find_stream_start(haystack: &[u8]) -> Option<StreamStart> {
+    let mut offset = 0;
+    while let Some(index) = find_bytes(&haystack[offset..], STREAM_KEYWORD) {
+        let start = offset + index;
+        let end = start + STREAM_KEYWORD.len();
+        let is_endstream = start > 0 && haystack[start - 1] == b'd';
+        match haystack.get(end) {
+            Some(b'\r') | Some(b'\n') if !is_endstream => return Some(StreamStart::Body(end + 1)),
+            None => return Some(StreamStart::Incomplete(start)),
+            _ => offset = end,
+        }
+    }
+    None
+}
+
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+async fn pdfinfo_has_acroform(file_path: &Path) -> Option<bool> {
+    let stdout = run_pdfinfo(file_path, &[]).await.ok()?;
+    stdout.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        (key.trim() == "Form").then(|| value.trim() != "none")
+    })
+}
+
+// One page with a line of Helvetica, so font setup gets primed too.
+const WARMUP_PDF: &[u8] = include_bytes!("assets/warmup.pdf");
+
+// Runs the two devices real requests use on a tiny PDF, so the first request
+// after boot doesn't pay for cold caches and font scanning.
+pub async fn warmup() -> anyhow::Result<Duration> {
+    let stem = work_dir().join(format!("ghost-warmup-{}", Uuid::new_v4()));
+    let input_path = stem.with_extension("pdf");
+    let output_path = stem.with_extension("out.pdf");
+    tokio::fs::write(&input_path, WARMUP_PDF)
+        .await
+        .context("failed to write warmup PDF")?;
+
+    let started_at = Instant::now();
+    let result = async {
+        run_inkcov(&input_path, &[]).await?;
+        let args = vec![
+            "-q".to_string(),
+            "-dSAFER".to_string(),
+            "-dBATCH".to_string(),
+            "-dNOPAUSE".to_string(),
+            "-sDEVICE=pdfwrite".to_string(),
+            format!("-sOutputFile={}", output_path.to_string_lossy()),
+            input_path.to_string_lossy().to_string(),
+        ];
+        run_command("gs", &args).await.map(|_| ())
+    }
+    .await;
+
+    let _ = tokio::fs::remove_file(&input_path).await;
+    let _ = tokio::fs::remove_file(&output_path).await;
+    result.map(|_| started_at.elapsed())
+}
+
+async fn run_inkcov(file_path: &Path, page_args: &[String]) -> anyhow::Result<String> {
+    let mut inkcov_args = vec![
+        "-q".to_string(),
+        "-o".to_string(),
+        "-".to_string(),
+        "-dSAFER".to_string(),
+        "-dBATCH".to_string(),
+        "-dNOPAUSE".to_string(),
+        "-sDEVICE=inkcov".to_string(),
+    ];
+    inkcov_args.extend_from_slice(page_args);
+    inkcov_args.push(file_path.to_string_lossy().to_string());
+
+    let (inkcov_stdout, inkcov_stderr) = run_command("gs", &inkcov_args).await?;
+    Ok(if inkcov_stderr.trim().is_empty() {
+        inkcov_stdout
+    } else if inkcov_stdout.trim().is_empty() {
+        inkcov_stderr
+    } else {
+        format!("{}\n{}", inkcov_stdout, inkcov_stderr)
+    })
+}
+
+// Profiles only `pages` (1-based, ascending) and labels each result with its
+// real page number.
+pub async fn get_sampled_color_profiles(
+    file_path: &Path,
+    pages: &[i64],
+) -> anyhow::Result<Vec<ColorProfile>> {
+    let page_list = pages
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(",");
+    let inkcov_output = run_inkcov(file_path, &[format!("-sPageList={}", page_list)]).await?;
+
+    let expected = pages.len() as i64;
+    let mut color_profiles = parse_inkcov_profiles(&inkcov_output, expected);
+    if color_profiles.len() != pages.len() {
+        tracing::warn!(
+            expected,
+            parsed = color_profiles.len(),
+            "sampled inkcov output did not contain one profile per page; normalizing parsed data"
+        );
+        color_profiles = normalize_profiles(color_profiles, expected);
+    }
+    for (profile, page) in color_profiles.iter_mut().zip(pages) {
+        profile.page = *page;
+    }
+
+    Ok(color_profiles)
+}
+
+pub async fn get_color_profiles(
+    file_path: &Path,
+    page_count: i64,
+) -> anyhow::Result<Vec<ColorProfile>> {
+    if let Some(chunking) = INKCOV_CHUNKING
+        .get()
+        .filter(|chunking| page_count > chunking.chunk_pages)
+    {
+        return get_chunked_color_profiles(file_path, page_count, chunking).await;
+    }
+
+    let inkcov_output = run_inkcov(file_path, &[]).await?;
+
+    let mut color_profiles = parse_inkcov_profiles(&inkcov_output, page_count);
+    if color_profiles.len() != page_count as usize {
+        let sample = inkcov_output.chars().take(600).collect::<String>();
+        tracing::warn!(
+            expected = page_count,
+            parsed = color_profiles.len(),
+            sample = %sample,
+            "inkcov output did not contain one profile per page; normalizing parsed data"
+        );
+        color_profiles = normalize_profiles(color_profiles, page_count);
+    }
+
+    Ok(color_profiles)
+}
+
+async fn get_chunked_color_profiles(
+    file_path: &Path,
+    page_count: i64,
+    chunking: &InkcovChunking,
+) -> anyhow::Result<Vec<ColorProfile>> {
+    let ranges = page_ranges(page_count, chunking.chunk_pages);
+    let chunks = run_page_ranges("inkcov", &ranges, &chunking.permits, |_, first, last| {
+        let file_path = file_path.to_path_buf();
+        async move { get_page_range_color_profiles(&file_path, first, last).await }
+    })
+    .await?;
+
+    Ok(chunks.into_iter().flatten().collect())
+}
+
+fn page_ranges(page_count: i64, chunk_pages: i64) -> Vec<(i64, i64)> {
+    let chunk_pages = chunk_pages.max(1);
+    (1..=page_count)
+        .step_by(chunk_pages as usize)
+        .map(|first| (first, (first + chunk_pages - 1).min(page_count)))
+        .collect()
+}
+
+// Runs `task` once per `-dFirstPage`/`-dLastPage` range and returns the
+// results in range order. The caller's permit covers one range at a time;
+// extra ranges only run alongside it on permits taken with `try_acquire`, so
+// this never waits on the semaphore the caller already holds. Dropping the
+// future aborts the JoinSet and kills the remaining `gs` children.
+async fn run_page_ranges<T, F, Fut>(
+    label: &'static str,
+    ranges: &[(i64, i64)],
+    permits: &Arc<Semaphore>,
+    task: F,
+) -> anyhow::Result<Vec<T>>
+where
+    T: Send + 'static,
+    F: Fn(usize, i64, i64) -> Fut,
+    Fut: std::future::Future<Output = anyhow::Result<T>> + Send + 'static,
+{
+    let mut extra_permits = Vec::new();
+    while extra_permits.len() + 1 < ranges.len() {
+        match permits.clone().try_acquire_owned() {
+            Ok(permit) => extra_permits.push(permit),
+            Err(_) => break,
+        }
+    }
+    let workers = extra_permits.len() + 1;
+    tracing::debug!(
+        label,
+        chunks = ranges.len(),
+        workers,
+        "running page-range chunks"
+    );
+
+    let mut results: Vec<Option<T>> = std::iter::repeat_with(|| None).take(ranges.len()).collect();
+    let mut pending = ranges.iter().copied().enumerate();
+    let mut running = JoinSet::new();
+    let spawn_chunk = |running: &mut JoinSet<_>, (index, (first, last)): (usize, (i64, i64))| {
+        let future = task(index, first, last);
+        running.spawn(async move { (index, future.await) });
+    };
+    for chunk in pending.by_ref().take(workers) {
+        spawn_chunk(&mut running, chunk);
+    }
+    while let Some(joined) = running.join_next().await {
+        let (index, result) = joined.with_context(|| format!("{label} chunk task failed"))?;
+        results[index] = Some(result?);
+        if let Some(chunk) = pending.next() {
+            spawn_chunk(&mut running, chunk);
+        }
+    }
+    drop(extra_permits);
+
+    Ok(results.into_iter().flatten().collect())
+}
+
+async fn get_page_range_color_profiles(
+    file_path: &Path,
+    first: i64,
+    last: i64,
+) -> anyhow::Result<Vec<ColorProfile>> {
+    let inkcov_output = run_inkcov(
+        file_path,
+        &[
+            format!("-dFirstPage={}", first),
+            format!("-dLastPage={}", last),
+        ],
+    )
+    .await?;
+
+    Ok(page_range_color_profiles(&inkcov_output, first, last))
+}
+
+// Parses one chunk's inkcov output and renumbers it to the document's pages.
+fn page_range_color_profiles(inkcov_output: &str, first: i64, last: i64) -> Vec<ColorProfile> {
+    let expected = last - first + 1;
+    let mut color_profiles = parse_inkcov_profiles(inkcov_output, expected);
+    if color_profiles.len() != expected as usize {
+        tracing::warn!(
+            first,
+            last,
+            parsed = color_profiles.len(),
+            "inkcov chunk did not contain one profile per page; normalizing parsed data"
+        );
+        color_profiles = normalize_profiles(color_profiles, expected);
+    }
+    for profile in &mut color_profiles {
+        profile.page += first - 1;
+    }
+
+    color_profiles
+}
+
+pub async fn get_pdf_metadata(file_path: &Path) -> PdfMetadata {
+    match run_pdfinfo(file_path, &["-rawdates"]).await {
+        Ok(stdout) => return parse_pdf_info_fields(&stdout),
+        Err(reason) => log_pdfinfo_fallback(&reason),
+    }
+
+    let file_path_str = file_path.to_string_lossy().to_string();
+    let args = vec![
+        "-q".to_string(),
+        "-dNODISPLAY".to_string(),
+        "-dSAFER".to_string(),
+        format!("--permit-file-read={}", file_path_str),
+        "-c".to_string(),
+        format!(
+            "({}) (r) file runpdfbegin Trailer /Info knownoget {{ {{ exch =only (: ) print = }} forall }} if quit",
+            file_path_str
+        ),
+    ];
+
+    match run_command("gs", &args).await {
+        Ok((stdout, _stderr)) => parse_pdf_info_fields(&stdout),
+        Err(error) => {
+            tracing::warn!(error = %error, "failed to read PDF metadata with Ghostscript");
+            PdfMetadata::default()
+        }
+    }
+}
+
+pub async fn convert_pdf_to_grayscale_file(
+    input_path: &Path,
+    output_path: &Path,
+    options: &PdfwriteOptions,
+) -> anyhow::Result<()> {
+    convert_grayscale(input_path, output_path, options, Vec::new()).await
+}
+
+// `-dCompatibilityLevel` values pdfwrite can target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PdfVersion {
+    V1_3,
+    V1_4,
+    V1_5,
+    V1_6,
+    V1_7,
+}
+
+impl PdfVersion {
+    pub fn parse(raw: &str) -> Result<Self, &'static str> {
+        match raw.trim() {
+            "1.3" => Ok(Self::V1_3),
+            "1.4" => Ok(Self::V1_4),
+            "1.5" => Ok(Self::V1_5),
+            "1.6" => Ok(Self::V1_6),
+            "1.7" => Ok(Self::V1_7),
+            _ => Err("pdfVersion must be one of 1.3, 1.4, 1.5, 1.6 or 1.7."),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::V1_3 => "1.3",
+            Self::V1_4 => "1.4",
+            Self::V1_5 => "1.5",
+            Self::V1_6 => "1.6",
+            Self::V1_7 => "1.7",
+        }
+    }
+}
+
+// Settings for the final pdfwrite output, applied to every pass that writes
+// the file the user downloads.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PdfwriteOptions {
+    pub preserve_annotations: bool,
+    // `None` leaves Ghostscript's default.
+    pub pdf_version: Option<PdfVersion>,
+    // The input's page count when the caller already has it, so splitting
+    // doesn't spend another `gs` pass counting.
+    pub input_page_count: Option<i64>,
+}
+
+impl PdfwriteOptions {
+    fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        // Spelled out rather than left to Ghostscript's defaults, which have
+        // changed between interpreter versions.
+        if self.preserve_annotations {
+            args.push("-dPreserveAnnots=true".to_string());
+            args.push("-dShowAnnots=true".to_string());
+        }
+        if let Some(version) = self.pdf_version {
+            args.push(format!("-dCompatibilityLevel={}", version.as_str()));
+        }
+        args
+    }
+}
+
+pub async fn convert_pdf_to_grayscale_with_black_controls(
+    input_path: &Path,
+    output_path: &Path,
+    force_black_text: bool,
+    force_black_vector: bool,
+    black_threshold_l: Option<f64>,
+    black_threshold_c: Option<f64>,
+    options: &PdfwriteOptions,
+) -> anyhow::Result<()> {
+    let mut args = Vec::new();
+
+    validate_black_controls(
+        force_black_text,
+        force_black_vector,
+        black_threshold_l,
+        black_threshold_c,
+    )?;
+
+    if force_black_text {
+        args.push("-dBlackText".to_string());
+    }
+    if force_black_vector {
+        args.push("-dBlackVector".to_string());
+    }
+    if force_black_text || force_black_vector {
+        let threshold_l = clamp_threshold(
+            black_threshold_l.unwrap_or(DEFAULT_BLACK_THRESHOLD_L),
+            BLACK_THRESHOLD_L_RANGE,
+        );
+        let threshold_c = clamp_threshold(
+            black_threshold_c.unwrap_or(DEFAULT_BLACK_THRESHOLD_C),
+            BLACK_THRESHOLD_C_RANGE,
+        );
+        args.push(format!("-dBlackThresholdL={}", threshold_l));
+        args.push(format!("-dBlackThresholdC={}", threshold_c));
+    }
+
+    convert_grayscale(input_path, output_path, options, args).await
+}
+
+fn grayscale_args(
+    input_path: &Path,
+    output_path: &Path,
+    extra_args: &[String],
+    page_range: Option<(i64, i64)>,
+) -> Vec<String> {
+    let mut args = vec![
+        "-q".to_string(),
+        "-dNOPAUSE".to_string(),
+        "-dBATCH".to_string(),
+        "-dSAFER".to_string(),
+        "-sDEVICE=pdfwrite".to_string(),
+        "-sColorConversionStrategy=Gray".to_string(),
+        "-dProcessColorModel=/DeviceGray".to_string(),
+    ];
+    args.extend_from_slice(extra_args);
+    if let Some((first, last)) = page_range {
+        args.push(format!("-dFirstPage={}", first));
+        args.push(format!("-dLastPage={}", last));
+    }
+    args.push(format!("-sOutputFile={}", output_path.to_string_lossy()));
+    args.push(input_path.to_string_lossy().to_string());
+    args
+}
+
+async fn convert_grayscale(
+    input_path: &Path,
+    output_path: &Path,
+    options: &PdfwriteOptions,
+    black_args: Vec<String>,
+) -> anyhow::Result<()> {
+    let mut extra_args = options.args();
+    extra_args.extend(black_args);
+
+    if let Some(splitting) = GRAYSCALE_SPLITTING.get() {
+        // An unreadable page count falls through to the single pass, which
+        // reports the real problem. Merging chunks drops the outline, so
+        // documents with bookmarks are never split.
+        let page_count = match options.input_page_count {
+            Some(page_count) => Some(page_count),
+            None => get_pdf_page_count(input_path).await.ok(),
+        };
+        if let Some(page_count) = page_count {
+            if page_count > splitting.threshold_pages
+                && pdf_has_outlines(input_path).await == Some(false)
+            {
+                return convert_grayscale_split(
+                    input_path,
+                    output_path,
+                    options,
+                    extra_args,
+                    page_count,
+                    splitting,
+                )
+                .await;
+            }
+        }
+    }
+
+    let args = grayscale_args(input_path, output_path, &extra_args, None);
+    run_command("gs", &args).await.map(|_| ())
+}
+
+// Converts page ranges in parallel and joins them with one more pdfwrite
+// pass. The black-control flags apply per chunk, so the result matches a
+// single pass page for page.
+async fn convert_grayscale_split(
+    input_path: &Path,
+    output_path: &Path,
+    options: &PdfwriteOptions,
+    extra_args: Vec<String>,
+    page_count: i64,
+    splitting: &GrayscaleSplitting,
+) -> anyhow::Result<()> {
+    let chunk_pages = (page_count + splitting.chunks - 1) / splitting.chunks;
+    let ranges = page_ranges(page_count, chunk_pages);
+    let chunk_paths = (0..ranges.len())
+        .map(|index| {
+            crate::upload::track_temp_path(
+                output_path.with_extension(format!("part{}.pdf", index + 1)),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let result = async {
+        run_page_ranges(
+            "grayscale",
+            &ranges,
+            &splitting.permits,
+            |index, first, last| {
+                let args = grayscale_args(
+                    input_path,
+                    &chunk_paths[index],
+                    &extra_args,
+                    Some((first, last)),
+                );
+                async move { run_command("gs", &args).await.map(|_| ()) }
+            },
+        )
+        .await?;
+
+        let mut merge_args = vec![
+            "-q".to_string(),
+            "-dNOPAUSE".to_string(),
+            "-dBATCH".to_string(),
+            "-dSAFER".to_string(),
+            "-sDEVICE=pdfwrite".to_string(),
+            "-sColorConversionStrategy=Gray".to_string(),
+            "-dProcessColorModel=/DeviceGray".to_string(),
+        ];
+        merge_args.extend(options.args());
+        merge_args.push(format!("-sOutputFile={}", output_path.to_string_lossy()));
+        merge_args.extend(
+            chunk_paths
+                .iter()
+                .map(|path| path.to_string_lossy().to_string()),
+        );
+        run_command("gs", &merge_args)
+            .await
+            .context("failed to merge grayscale chunks")?;
+
+        let merged_pages = get_pdf_page_count(output_path)
+            .await
+            .context("failed to count pages in merged grayscale output")?;
+        if merged_pages != page_count {
+            return Err(anyhow!(
+                "merged grayscale output has {} pages, expected {}",
+                merged_pages,
+                page_count
+            ));
+        }
+        Ok(())
+    }
+    .await;
+
+    for path in &chunk_paths {
+        let _ = tokio::fs::remove_file(path).await;
+    }
+    result
+}
+
+// Whether the catalog has an outline with at least one entry; `None` when
+// Ghostscript can't tell. pdfwrite carries outlines over on a single pass, so
+// this is for checking that a conversion didn't lose them.
+pub async fn pdf_has_outlines(file_path: &Path) -> Option<bool> {
+    let file_path_str = file_path.to_string_lossy().to_string();
+    let args = vec![
+        "-q".to_string(),
+        "-dNODISPLAY".to_string(),
+        "-dSAFER".to_string(),
+        format!("--permit-file-read={}", file_path_str),
+        "-c".to_string(),
+        format!(
+            "({}) (r) file runpdfbegin Trailer /Root knownoget {{ /Outlines knownoget {{ /First known }} {{ false }} ifelse }} {{ false }} ifelse = quit",
+            file_path_str
+        ),
+    ];
+
+    match run_command("gs", &args).await {
+        Ok((stdout, _stderr)) => match stdout.trim() {
+            "true" => Some(true),
+            "false" => Some(false),
+            _ => None,
+        },
+        Err(error) => {
+            tracing::warn!(error = %error, "failed to read PDF outline with Ghostscript");
+            None
+        }
+    }
+}
+
+pub async fn apply_watermark(
+    input_path: &Path,
+    output_path: &Path,
+    text: &str,
+    options: &PdfwriteOptions,
+) -> anyhow::Result<()> {
+    let stamp = format!(
+        "<< /EndPage {{ exch pop 0 eq {{ gsave /Helvetica findfont 8 scalefont setfont 0.5 setgray 36 18 moveto ({}) show grestore true }} {{ false }} ifelse }} bind >> setpagedevice",
+        escape_postscript_string(text)
+    );
+    let mut args = vec![
+        "-q".to_string(),
+        "-dNOPAUSE".to_string(),
+        "-dBATCH".to_string(),
+        "-dSAFER".to_string(),
+        "-sDEVICE=pdfwrite".to_string(),
+    ];
+    args.extend(options.args());
+    args.extend([
+        format!("-sOutputFile={}", output_path.to_string_lossy()),
+        "-c".to_string(),
+        stamp,
+        "-f".to_string(),
+        input_path.to_string_lossy().to_string(),
+    ]);
+
+    run_command("gs", &args).await.map(|_| ())
+}
+
+fn escape_postscript_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for ch in value.chars() {
+        match ch {
+            '\\' | '(' | ')' => {
+                escaped.push('\\');
+                escaped.push(ch);
+            }
+            '\u{2013}' | '\u{2014}' => escaped.push('-'),
+            ch if ch.is_ascii() && !ch.is_ascii_control() => escaped.push(ch),
+            _ => escaped.push('?'),
+        }
+    }
+    escaped
+}
+
+pub fn validate_black_controls(
+    force_black_text: bool,
+    force_black_vector: bool,
+    black_threshold_l: Option<f64>,
+    black_threshold_c: Option<f64>,
+) -> anyhow::Result<()> {
+    for value in [black_threshold_l, black_threshold_c].into_iter().flatten() {
+        if !value.is_finite() {
+            return Err(anyhow!("Black thresholds must be finite numbers."));
+        }
+    }
 
-    if page_count <= 0 {
-        return Err(anyhow!("Invalid page count reported by Ghostscript."));
+    if !force_black_text
+        && !force_black_vector
+        && (black_threshold_l.is_some() || black_threshold_c.is_some())
+    {
+        return Err(anyhow!(
+            "Black thresholds have no effect unless forceBlackText or forceBlackVector is enabled."
+        ));
     }
 
-    Ok(page_count)
+    Ok(())
 }
 
-pub async fn analyze_pdf(
-    file_path: &Path,
-    page_count_override: Option<i64>,
-) -> anyhow::Result<PdfAnalysis> {
-    let file_path_str = file_path.to_string_lossy().to_string();
-
-    let page_count = match page_count_override {
-        Some(value) => value,
-        None => get_pdf_page_count(file_path).await?,
-    };
+fn clamp_threshold(value: f64, (min, max): (f64, f64)) -> f64 {
+    value.clamp(min, max)
+}
 
-    let inkcov_args = vec![
+pub async fn extract_pages(
+    input_path: &Path,
+    output_path: &Path,
+    first_page: i64,
+    last_page: i64,
+) -> anyhow::Result<()> {
+    let args = vec![
         "-q".to_string(),
-        "-o".to_string(),
-        "-".to_string(),
-        "-dSAFER".to_string(),
-        "-dBATCH".to_string(),
         "-dNOPAUSE".to_string(),
-        "-sDEVICE=inkcov".to_string(),
-        file_path_str.clone(),
+        "-dBATCH".to_string(),
+        "-dSAFER".to_string(),
+        "-sDEVICE=pdfwrite".to_string(),
+        format!("-dFirstPage={}", first_page),
+        format!("-dLastPage={}", last_page),
+        format!("-sOutputFile={}", output_path.to_string_lossy()),
+        input_path.to_string_lossy().to_string(),
     ];
-    let (inkcov_stdout, inkcov_stderr) = run_command("gs", &inkcov_args).await?;
-    let inkcov_output = if inkcov_stderr.trim().is_empty() {
-        inkcov_stdout
-    } else if inkcov_stdout.trim().is_empty() {
-        inkcov_stderr
-    } else {
-        format!("{}\n{}", inkcov_stdout, inkcov_stderr)
-    };
-
-    let mut color_profiles = parse_inkcov_profiles(&inkcov_output, page_count);
-    if color_profiles.len() != page_count as usize {
-        let sample = inkcov_output.chars().take(600).collect::<String>();
-        tracing::warn!(
-            expected = page_count,
-            parsed = color_profiles.len(),
-            sample = %sample,
-            "inkcov output did not contain one profile per page; normalizing parsed data"
-        );
-        color_profiles = normalize_profiles(color_profiles, page_count);
-    }
-
-    // Avoid a second Ghostscript pass here. Some PDFs can hang on dDumpAnnots.
-    // A raw byte scan is fast and works for our current form-field signal.
-    let has_formfields = match tokio::fs::read(file_path).await {
-        Ok(bytes) => bytes
-            .windows(15)
-            .any(|window| window == b"/Subtype /Widget"),
-        Err(error) => {
-            tracing::warn!(error = %error, "failed to read PDF for form-field detection");
-            false
-        }
-    };
-
-    let file_name = file_path
-        .file_name()
-        .map(|value| value.to_string_lossy().to_string())
-        .unwrap_or_else(|| "document.pdf".to_string());
 
-    Ok(PdfAnalysis {
-        file_name,
-        page_count,
-        has_formfields,
-        color_profiles,
-    })
+    run_command("gs", &args).await.map(|_| ())
 }
 
-pub async fn convert_pdf_to_grayscale_file(
+pub async fn convert_postscript_to_pdf(
     input_path: &Path,
     output_path: &Path,
 ) -> anyhow::Result<()> {
@@ -194,9 +1243,8 @@ pub async fn convert_pdf_to_grayscale_file(
         "-dNOPAUSE".to_string(),
         "-dBATCH".to_string(),
         "-dSAFER".to_string(),
+        "-dEPSCrop".to_string(),
         "-sDEVICE=pdfwrite".to_string(),
-        "-sColorConversionStrategy=Gray".to_string(),
-        "-dProcessColorModel=/DeviceGray".to_string(),
         format!("-sOutputFile={}", output_path.to_string_lossy()),
         input_path.to_string_lossy().to_string(),
     ];
@@ -204,13 +1252,36 @@ pub async fn convert_pdf_to_grayscale_file(
     run_command("gs", &args).await.map(|_| ())
 }
 
-pub async fn convert_pdf_to_grayscale_with_black_controls(
+// Renders form field appearances into page content and drops the widgets.
+pub async fn flatten_form_fields(input_path: &Path, output_path: &Path) -> anyhow::Result<()> {
+    let args = vec![
+        "-q".to_string(),
+        "-dNOPAUSE".to_string(),
+        "-dBATCH".to_string(),
+        "-dSAFER".to_string(),
+        "-sDEVICE=pdfwrite".to_string(),
+        "-dShowAcroForm=true".to_string(),
+        "-dShowAnnots=true".to_string(),
+        "-dPreserveAnnots=false".to_string(),
+        format!("-sOutputFile={}", output_path.to_string_lossy()),
+        input_path.to_string_lossy().to_string(),
+    ];
+
+    run_command("gs", &args).await.map(|_| ())
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct SanitizeOptions {
+    pub strip_javascript: bool,
+    pub strip_metadata: bool,
+}
+
+// pdfwrite never re-emits document-level JavaScript; dropping annotations
+// also removes widget/link actions (JavaScript, Launch) that would survive.
+pub async fn sanitize_pdf(
     input_path: &Path,
     output_path: &Path,
-    force_black_text: bool,
-    force_black_vector: bool,
-    black_threshold_l: Option<f64>,
-    black_threshold_c: Option<f64>,
+    options: SanitizeOptions,
 ) -> anyhow::Result<()> {
     let mut args = vec![
         "-q".to_string(),
@@ -218,29 +1289,40 @@ pub async fn convert_pdf_to_grayscale_with_black_controls(
         "-dBATCH".to_string(),
         "-dSAFER".to_string(),
         "-sDEVICE=pdfwrite".to_string(),
-        "-sColorConversionStrategy=Gray".to_string(),
-        "-dProcessColorModel=/DeviceGray".to_string(),
     ];
-
-    if force_black_text {
-        args.push("-dBlackText".to_string());
-    }
-    if force_black_vector {
-        args.push("-dBlackVector".to_string());
-    }
-    if let Some(value) = black_threshold_l {
-        args.push(format!("-dBlackThresholdL={}", value));
+    if options.strip_javascript {
+        args.push("-dPreserveAnnots=false".to_string());
     }
-    if let Some(value) = black_threshold_c {
-        args.push(format!("-dBlackThresholdC={}", value));
+    if options.strip_metadata {
+        args.push("-dOmitInfoDate".to_string());
+        args.push("-dOmitID".to_string());
+        args.push("-dOmitXMP".to_string());
     }
-
     args.push(format!("-sOutputFile={}", output_path.to_string_lossy()));
     args.push(input_path.to_string_lossy().to_string());
+    if options.strip_metadata {
+        args.push("-c".to_string());
+        args.push(
+            "[ /Title () /Author () /Subject () /Keywords () /Creator () /DOCINFO pdfmark"
+                .to_string(),
+        );
+    }
 
     run_command("gs", &args).await.map(|_| ())
 }
 
+pub async fn contains_javascript(file_path: &Path) -> bool {
+    match tokio::fs::read(file_path).await {
+        Ok(bytes) => bytes
+            .windows(b"/JavaScript".len())
+            .any(|window| window == b"/JavaScript"),
+        Err(error) => {
+            tracing::warn!(error = %error, "failed to read PDF for JavaScript detection");
+            false
+        }
+    }
+}
+
 pub fn sanitize_base_name(value: &str) -> String {
     static NON_SAFE_RE: once_cell::sync::Lazy<Regex> =
         once_cell::sync::Lazy::new(|| Regex::new(r"[^a-zA-Z0-9_-]+").expect("valid regex"));
@@ -258,28 +1340,19 @@ pub fn sanitize_base_name(value: &str) -> String {
 }
 
 async fn try_get_pdf_page_count_with_pdfinfo(file_path: &Path) -> anyhow::Result<Option<i64>> {
-    let args = vec![file_path.to_string_lossy().to_string()];
-    let output = Command::new("pdfinfo").args(args).output().await;
-    let output = match output {
-        Ok(output) => output,
-        Err(error) => {
-            log_pdfinfo_fallback(&format!("spawn failed: {}", error));
+    let stdout = match run_pdfinfo(file_path, &[]).await {
+        Ok(stdout) => stdout,
+        // Ghostscript often repairs what pdfinfo calls invalid or corrupt, so
+        // only a password problem is final here.
+        Err(reason) => {
+            if let Some(input_error @ PdfInputError::PasswordRequired) = classify_error(&reason) {
+                return Err(anyhow::Error::new(input_error).context(reason));
+            }
+            log_pdfinfo_fallback(&reason);
             return Ok(None);
         }
     };
 
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
-        let reason = if stderr.trim().is_empty() {
-            format!("exit={}", output.status)
-        } else {
-            stderr.trim().to_string()
-        };
-        log_pdfinfo_fallback(&reason);
-        return Ok(None);
-    }
-
-    let stdout = String::from_utf8_lossy(&output.stdout);
     let pages_regex = Regex::new(r"(?m)^\s*Pages:\s+(\d+)\s*$").expect("valid regex");
     let captures = match pages_regex.captures(&stdout) {
         Some(captures) => captures,
@@ -304,13 +1377,204 @@ async fn try_get_pdf_page_count_with_pdfinfo(file_path: &Path) -> anyhow::Result
     Ok(Some(page_count))
 }
 
+async fn run_pdfinfo(file_path: &Path, extra_args: &[&str]) -> Result<String, String> {
+    let mut args = extra_args
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+    args.push(file_path.to_string_lossy().to_string());
+
+    let output = Command::new("pdfinfo")
+        .args(args)
+        .output()
+        .await
+        .map_err(|error| format!("spawn failed: {}", error))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        return Err(if stderr.trim().is_empty() {
+            format!("exit={}", output.status)
+        } else {
+            stderr.trim().to_string()
+        });
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PdfBox {
+    pub x0: f64,
+    pub y0: f64,
+    pub x1: f64,
+    pub y1: f64,
+}
+
+// Page boxes in points as reported by `pdfinfo -box`. pdfinfo fills in
+// missing boxes from their defaults (CropBox, then MediaBox), so an absent
+// TrimBox shows up as one equal to the MediaBox.
+#[derive(Debug, Clone)]
+pub struct PageBoxes {
+    pub page: i64,
+    pub media_box: PdfBox,
+    pub bleed_box: PdfBox,
+    pub trim_box: PdfBox,
+}
+
+pub async fn get_page_boxes(file_path: &Path, page_count: i64) -> Result<Vec<PageBoxes>, String> {
+    let last_page = page_count.to_string();
+    let output = run_pdfinfo(file_path, &["-box", "-f", "1", "-l", &last_page]).await?;
+    Ok(parse_page_boxes(&output))
+}
+
+fn parse_page_boxes(output: &str) -> Vec<PageBoxes> {
+    // [MediaBox, BleedBox, TrimBox] per page.
+    let mut pages: Vec<(i64, [Option<PdfBox>; 3])> = Vec::new();
+    for line in output.lines() {
+        let Some(rest) = line.strip_prefix("Page") else {
+            continue;
+        };
+        let mut tokens = rest.split_whitespace();
+        let (Some(page), Some(label)) = (tokens.next(), tokens.next()) else {
+            continue;
+        };
+        let Ok(page) = page.parse::<i64>() else {
+            continue;
+        };
+        let coords = tokens
+            .filter_map(|token| token.parse::<f64>().ok())
+            .collect::<Vec<_>>();
+        let [x0, y0, x1, y1] = coords[..] else {
+            continue;
+        };
+        let pdf_box = PdfBox {
+            x0: x0.min(x1),
+            y0: y0.min(y1),
+            x1: x0.max(x1),
+            y1: y0.max(y1),
+        };
+
+        if pages.last().map(|entry| entry.0) != Some(page) {
+            pages.push((page, [None; 3]));
+        }
+        let boxes = &mut pages.last_mut().expect("entry pushed above").1;
+        match label {
+            "MediaBox:" => boxes[0] = Some(pdf_box),
+            "BleedBox:" => boxes[1] = Some(pdf_box),
+            "TrimBox:" => boxes[2] = Some(pdf_box),
+            _ => {}
+        }
+    }
+
+    pages
+        .into_iter()
+        .filter_map(|(page, [media_box, bleed_box, trim_box])| {
+            let media_box = media_box?;
+            Some(PageBoxes {
+                page,
+                media_box,
+                bleed_box: bleed_box.unwrap_or(media_box),
+                trim_box: trim_box.unwrap_or(media_box),
+            })
+        })
+        .collect()
+}
+
+fn parse_pdf_info_fields(output: &str) -> PdfMetadata {
+    let mut metadata = PdfMetadata::default();
+    for line in output.lines() {
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        if value.is_empty() || value == "--nostringval--" {
+            continue;
+        }
+        let value = Some(value.to_string());
+        match key.trim() {
+            "Title" => metadata.title = value,
+            "Author" => metadata.author = value,
+            "Subject" => metadata.subject = value,
+            "Keywords" => metadata.keywords = value,
+            "Creator" => metadata.creator = value,
+            "Producer" => metadata.producer = value,
+            "CreationDate" => metadata.creation_date = value.as_deref().and_then(parse_pdf_date),
+            "ModDate" => metadata.mod_date = value.as_deref().and_then(parse_pdf_date),
+            _ => {}
+        }
+    }
+    metadata
+}
+
+fn parse_pdf_date(raw: &str) -> Option<String> {
+    let raw = raw.trim();
+    let raw = raw.strip_prefix("D:").unwrap_or(raw);
+    let digits_len = raw.chars().take_while(char::is_ascii_digit).count();
+    if digits_len < 4 {
+        return None;
+    }
+    let (digits, zone) = raw.split_at(digits_len);
+
+    let field = |start: usize, len: usize, fallback: u32| -> Option<u32> {
+        match digits.get(start..start + len) {
+            Some(value) => value.parse::<u32>().ok(),
+            None => Some(fallback),
+        }
+    };
+    let year = digits.get(0..4)?.parse::<i32>().ok()?;
+    let month = field(4, 2, 1)?;
+    let day = field(6, 2, 1)?;
+    let hour = field(8, 2, 0)?;
+    let minute = field(10, 2, 0)?;
+    let second = field(12, 2, 0)?;
+
+    let zone = zone.trim();
+    let offset_seconds = match zone.chars().next() {
+        Some(sign @ ('+' | '-')) => {
+            let zone_digits = zone[1..]
+                .chars()
+                .filter(char::is_ascii_digit)
+                .collect::<String>();
+            let hours = zone_digits.get(0..2).and_then(|v| v.parse::<i32>().ok())?;
+            let minutes = zone_digits
+                .get(2..4)
+                .and_then(|v| v.parse::<i32>().ok())
+                .unwrap_or(0);
+            let seconds = hours * 3600 + minutes * 60;
+            if sign == '-' {
+                -seconds
+            } else {
+                seconds
+            }
+        }
+        _ => 0,
+    };
+
+    let offset = chrono::FixedOffset::east_opt(offset_seconds)?;
+    let naive =
+        chrono::NaiveDate::from_ymd_opt(year, month, day)?.and_hms_opt(hour, minute, second)?;
+    naive
+        .and_local_timezone(offset)
+        .single()
+        .map(|value| value.to_rfc3339())
+}
+
+// At most one line per interval so a persistently broken pdfinfo stays
+// visible without flooding the logs.
 fn log_pdfinfo_fallback(reason: &str) {
-    if HAS_LOGGED_PDFINFO_FALLBACK.swap(true, Ordering::SeqCst) {
-        return;
+    {
+        let mut last = LAST_PDFINFO_FALLBACK_LOG.lock();
+        if last.is_some_and(|at| at.elapsed() < PDFINFO_FALLBACK_LOG_INTERVAL) {
+            SUPPRESSED_PDFINFO_FALLBACKS.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        *last = Some(Instant::now());
     }
-    tracing::info!(
+    let suppressed = SUPPRESSED_PDFINFO_FALLBACKS.swap(0, Ordering::Relaxed);
+    tracing::warn!(
         reason = reason,
-        "pdfinfo page-count fast path unavailable; falling back to Ghostscript page counting"
+        suppressed,
+        "pdfinfo fast path unavailable; falling back to Ghostscript"
     );
 }
 
@@ -396,3 +1660,387 @@ fn normalize_profiles(mut profiles: Vec<ColorProfile>, page_count: i64) -> Vec<C
 
     profiles
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inkcov_line(page: i64) -> String {
+        let c = if page % 3 == 0 { 0.25 } else { 0.0 };
+        format!(" {:.5}  0.00000  0.00000  0.{:05} CMYK OK", c, page)
+    }
+
+    fn profile_key(profile: &ColorProfile) -> (i64, String) {
+        (
+            profile.page,
+            format!(
+                "{} {} {} {} {}",
+                profile.c, profile.m, profile.y, profile.k, profile.ink_type
+            ),
+        )
+    }
+
+    #[test]
+    fn classify_error_fixtures() {
+        let cases: &[(&str, Option<&str>)] = &[
+            (
+                "   **** Error: This file requires a password for access.\n",
+                Some("pdf_password_required"),
+            ),
+            (
+                "Command Line Error: Incorrect password",
+                Some("pdf_password_required"),
+            ),
+            (
+                "   **** Error: Password did not work.\n   **** Cannot decrypt PDF file.\n\
+                 Error: /undefined in --runpdfbegin--",
+                Some("pdf_password_required"),
+            ),
+            (
+                "Syntax Warning: May not be a PDF file (continuing anyway)",
+                Some("invalid_pdf"),
+            ),
+            ("Error: May not be a PDF file", Some("invalid_pdf")),
+            (
+                "Error: /undefined in PK\nOperand stack:\n\nExecution stack:",
+                Some("invalid_pdf"),
+            ),
+            (
+                "Syntax Error: Couldn't find trailer dictionary",
+                Some("corrupt_pdf"),
+            ),
+            (
+                "Syntax Error: Couldn't read xref table",
+                Some("corrupt_pdf"),
+            ),
+            (
+                "   **** Error:  An error occurred while reading an XREF table.\n\
+                 **** The file has been damaged.  This may have been caused",
+                Some("corrupt_pdf"),
+            ),
+            ("Error: /syntaxerror in --token--", Some("corrupt_pdf")),
+            (
+                "   **** Warning: Unable to repair PDF file",
+                Some("corrupt_pdf"),
+            ),
+            ("gs failed with status exit status: 1", None),
+            ("Error: /VMerror in --runpdf--", None),
+            ("", None),
+        ];
+
+        for (output, expected) in cases {
+            let code = classify_error(output).map(|error| error.code());
+            assert_eq!(code, *expected, "{output:?}");
+        }
+    }
+
+    #[test]
+    fn undefined_in_our_own_postscript_is_not_an_input_error() {
+        let outline_probe = "(/tmp/in.pdf) (r) file runpdfbegin Trailer /Root knownoget \
+                             { /Outlines knownoget { /First known } { false } ifelse } \
+                             { false } ifelse = quit";
+        let output = "Error: /undefined in Trailer\nOperand stack:\n   --nostringval--";
+        assert!(classify_error_for_script(output, Some(outline_probe)).is_none());
+        assert!(classify_error_for_script(
+            "Error: /undefined in --runpdfbegin--",
+            Some(outline_probe)
+        )
+        .is_none());
+
+        // Names that aren't in our script still come from the file.
+        assert_eq!(
+            classify_error_for_script("Error: /undefined in PK", Some(outline_probe))
+                .map(|error| error.code()),
+            Some("invalid_pdf")
+        );
+        assert_eq!(
+            classify_error_for_script(output, None).map(|error| error.code()),
+            Some("invalid_pdf")
+        );
+    }
+
+    #[test]
+    fn postscript_args_collects_every_dash_c_argument() {
+        let args = ["-q", "-c", "mark /A 1", "-f", "in.pdf", "-c", "quit"].map(String::from);
+        assert_eq!(postscript_args(&args).as_deref(), Some("mark /A 1 quit"));
+        assert_eq!(
+            postscript_args(&["-q".to_string(), "in.pdf".to_string()]),
+            None
+        );
+        assert_eq!(
+            undefined_name("Error: /undefined in --pdfpagecount--"),
+            Some("pdfpagecount")
+        );
+        assert_eq!(undefined_name("Error: /typecheck in --put--"), None);
+    }
+
+    #[test]
+    fn parse_pdf_date_fixtures() {
+        let cases = [
+            ("D:20231105143000Z", Some("2023-11-05T14:30:00+00:00")),
+            ("D:20231105143000+05'30'", Some("2023-11-05T14:30:00+05:30")),
+            ("D:20231105143000+05'30", Some("2023-11-05T14:30:00+05:30")),
+            ("D:20231105143000-08'00'", Some("2023-11-05T14:30:00-08:00")),
+            ("D:20231105143000-08", Some("2023-11-05T14:30:00-08:00")),
+            ("20231105143000", Some("2023-11-05T14:30:00+00:00")),
+            (" D:2023 ", Some("2023-01-01T00:00:00+00:00")),
+            ("D:202311", Some("2023-11-01T00:00:00+00:00")),
+            ("D:2023110514", Some("2023-11-05T14:00:00+00:00")),
+            ("D:20231305", None),
+            ("D:20230230", None),
+            ("D:20231105250000", None),
+            ("D:202", None),
+            ("D:", None),
+            ("yesterday", None),
+        ];
+
+        for (raw, expected) in cases {
+            assert_eq!(parse_pdf_date(raw).as_deref(), expected, "{raw:?}");
+        }
+    }
+
+    #[test]
+    fn parse_pdf_info_fields_reads_pdfinfo_and_ghostscript_output() {
+        let pdfinfo = "Title:          Report: Q3\n\
+                       Author:         Jane\n\
+                       Creator:        \n\
+                       Producer:       --nostringval--\n\
+                       CreationDate:   D:20231105143000Z\n\
+                       ModDate:        not a date\n\
+                       Pages:          4\n";
+        let metadata = parse_pdf_info_fields(pdfinfo);
+        assert_eq!(metadata.title.as_deref(), Some("Report: Q3"));
+        assert_eq!(metadata.author.as_deref(), Some("Jane"));
+        assert_eq!(metadata.creator, None);
+        assert_eq!(metadata.producer, None);
+        assert_eq!(
+            metadata.creation_date.as_deref(),
+            Some("2023-11-05T14:30:00+00:00")
+        );
+        assert_eq!(metadata.mod_date, None);
+    }
+
+    #[test]
+    fn clamp_threshold_keeps_values_in_range() {
+        assert_eq!(clamp_threshold(-5.0, BLACK_THRESHOLD_L_RANGE), 0.0);
+        assert_eq!(clamp_threshold(42.5, BLACK_THRESHOLD_L_RANGE), 42.5);
+        assert_eq!(clamp_threshold(100.0, BLACK_THRESHOLD_L_RANGE), 100.0);
+        assert_eq!(clamp_threshold(250.0, BLACK_THRESHOLD_L_RANGE), 100.0);
+        assert_eq!(clamp_threshold(0.0, BLACK_THRESHOLD_C_RANGE), 0.0);
+        assert_eq!(clamp_threshold(129.0, BLACK_THRESHOLD_C_RANGE), 128.0);
+        assert_eq!(
+            clamp_threshold(f64::INFINITY, BLACK_THRESHOLD_C_RANGE),
+            128.0
+        );
+    }
+
+    #[test]
+    fn validate_black_controls_cases() {
+        assert!(validate_black_controls(false, false, None, None).is_ok());
+        assert!(validate_black_controls(true, false, None, None).is_ok());
+        assert!(validate_black_controls(false, true, Some(60.0), Some(10.0)).is_ok());
+        assert!(validate_black_controls(true, true, Some(0.0), Some(128.0)).is_ok());
+
+        let error =
+            validate_black_controls(false, false, Some(60.0), None).expect_err("no forcing");
+        assert!(error.to_string().contains("no effect"));
+        assert!(validate_black_controls(false, false, None, Some(1.0)).is_err());
+
+        for value in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            let error =
+                validate_black_controls(true, false, Some(value), None).expect_err("finite");
+            assert!(error.to_string().contains("finite"));
+            assert!(validate_black_controls(false, true, None, Some(value)).is_err());
+        }
+    }
+
+    #[test]
+    fn page_ranges_cover_every_page_once() {
+        assert_eq!(page_ranges(10, 5), vec![(1, 5), (6, 10)]);
+        assert_eq!(page_ranges(11, 5), vec![(1, 5), (6, 10), (11, 11)]);
+        assert_eq!(page_ranges(7, 3), vec![(1, 3), (4, 6), (7, 7)]);
+        assert_eq!(page_ranges(3, 10), vec![(1, 3)]);
+        assert_eq!(page_ranges(1, 1), vec![(1, 1)]);
+        assert_eq!(page_ranges(0, 4), Vec::<(i64, i64)>::new());
+        assert_eq!(page_ranges(3, 0), vec![(1, 1), (2, 2), (3, 3)]);
+
+        for (page_count, chunk_pages) in [(1, 1), (9, 2), (100, 7), (101, 10), (64, 8)] {
+            let ranges = page_ranges(page_count, chunk_pages);
+            let pages: Vec<i64> = ranges
+                .iter()
+                .flat_map(|&(first, last)| first..=last)
+                .collect();
+            assert_eq!(pages, (1..=page_count).collect::<Vec<_>>());
+            assert!(ranges
+                .iter()
+                .all(|&(first, last)| last - first < chunk_pages));
+        }
+    }
+
+    #[test]
+    fn chunked_profiles_match_single_pass() {
+        for (page_count, chunk_pages) in [(10, 5), (11, 5), (13, 4), (2, 1)] {
+            let full_output = (1..=page_count)
+                .map(inkcov_line)
+                .collect::<Vec<_>>()
+                .join("\n");
+            let single_pass = parse_inkcov_profiles(&full_output, page_count);
+
+            let chunked: Vec<ColorProfile> = page_ranges(page_count, chunk_pages)
+                .into_iter()
+                .flat_map(|(first, last)| {
+                    let output = (first..=last)
+                        .map(inkcov_line)
+                        .collect::<Vec<_>>()
+                        .join("\n");
+                    page_range_color_profiles(&output, first, last)
+                })
+                .collect();
+
+            assert_eq!(
+                chunked.iter().map(profile_key).collect::<Vec<_>>(),
+                single_pass.iter().map(profile_key).collect::<Vec<_>>(),
+                "{page_count} pages in chunks of {chunk_pages}"
+            );
+        }
+    }
+
+    #[test]
+    fn short_last_chunk_is_padded_to_its_own_pages() {
+        let profiles = page_range_color_profiles(&inkcov_line(11), 11, 12);
+        let pages: Vec<i64> = profiles.iter().map(|profile| profile.page).collect();
+        assert_eq!(pages, vec![11, 12]);
+        assert_eq!(profiles[1].k, 0.0);
+        assert!(profiles[1].ink_type.is_empty());
+    }
+
+    #[tokio::test]
+    async fn run_page_ranges_keeps_range_order() {
+        let ranges = page_ranges(11, 3);
+        let permits = Arc::new(Semaphore::new(2));
+        let results = run_page_ranges("test", &ranges, &permits, |index, first, last| async move {
+            // Earlier ranges finish last.
+            tokio::time::sleep(Duration::from_millis(5 * (4 - index as u64))).await;
+            Ok((first, last))
+        })
+        .await
+        .expect("ranges");
+
+        assert_eq!(results, ranges);
+        assert_eq!(permits.available_permits(), 2);
+    }
+
+    #[tokio::test]
+    async fn run_page_ranges_fails_when_any_range_fails() {
+        let ranges = page_ranges(6, 2);
+        let permits = Arc::new(Semaphore::new(4));
+        let result = run_page_ranges("test", &ranges, &permits, |_, first, _| async move {
+            if first == 3 {
+                anyhow::bail!("chunk failed");
+            }
+            Ok(first)
+        })
+        .await;
+
+        assert!(result.is_err());
+    }
+
+    async fn scan_bytes(bytes: &[u8], marker: &[u8]) -> MarkerScan {
+        let path = std::env::temp_dir().join(format!("ghost-scan-{}.pdf", Uuid::new_v4()));
+        tokio::fs::write(&path, bytes).await.expect("write");
+        let scan = scan_for_marker(&path, marker).await.expect("scan");
+        let _ = tokio::fs::remove_file(&path).await;
+        scan
+    }
+
+    #[tokio::test]
+    async fn scan_for_marker_ignores_markers_inside_compressed_streams() {
+        // Stream body padded past the read chunk so the marker and the
+        // `endstream` arrive in later reads than the `stream` keyword.
+        let mut body = vec![0x9c_u8; FORM_SCAN_CHUNK_BYTES + 100];
+        body.extend_from_slice(b"/Annots [5 0 R] /Subtype /Widget");
+        body.extend_from_slice(&[0x78; 64]);
+        let mut pdf =
+            b"%PDF-1.5\n4 0 obj\n<< /Type /ObjStm /N 1 /First 4 /Filter /FlateDecode >>\nstream\n"
+                .to_vec();
+        pdf.extend_from_slice(&body);
+        pdf.extend_from_slice(b"\nendstream\nendobj\n%%EOF\n");
+
+        let scan = scan_bytes(&pdf, ANNOTS_MARKER).await;
+        assert!(!scan.found);
+        assert!(scan.saw_object_stream);
+        assert!(!scan_bytes(&pdf, WIDGET_MARKER).await.found);
+
+        // The same marker after the stream ends is a real hit.
+        pdf.extend_from_slice(b"5 0 obj\n<< /Type /Page /Annots [6 0 R] >>\nendobj\n");
+        assert!(scan_bytes(&pdf, ANNOTS_MARKER).await.found);
+    }
+
+    // These run the real interpreter, so they pass trivially where `gs` isn't
+    // installed.
+    async fn gs_available() -> bool {
+        let available = run_command("gs", &["--version".to_string()]).await.is_ok();
+        if !available {
+            eprintln!("gs not found; skipping");
+        }
+        available
+    }
+
+    fn fixture_path(name: &str, bytes: &[u8]) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("ghost-{}-{}.pdf", name, Uuid::new_v4()));
+        std::fs::write(&path, bytes).expect("fixture");
+        path
+    }
+
+    #[tokio::test]
+    async fn grayscale_conversion_keeps_bookmarks() {
+        if !gs_available().await {
+            return;
+        }
+        let input = fixture_path("bookmarks", include_bytes!("assets/bookmarks.pdf"));
+        let output = input.with_extension("out.pdf");
+
+        assert_eq!(pdf_has_outlines(&input).await, Some(true));
+        convert_pdf_to_grayscale_file(&input, &output, &PdfwriteOptions::default())
+            .await
+            .expect("conversion");
+        let kept = pdf_has_outlines(&output).await;
+
+        let _ = std::fs::remove_file(&input);
+        let _ = std::fs::remove_file(&output);
+        assert_eq!(kept, Some(true));
+    }
+
+    // Page 1 is filled with a near-black RGB gray, page 2 with a light one.
+    #[tokio::test]
+    async fn black_controls_push_near_black_to_pure_k() {
+        if !gs_available().await {
+            return;
+        }
+        let input = fixture_path("black", include_bytes!("assets/black_controls.pdf"));
+        let output = input.with_extension("out.pdf");
+
+        convert_pdf_to_grayscale_with_black_controls(
+            &input,
+            &output,
+            false,
+            true,
+            Some(50.0),
+            Some(10.0),
+            &PdfwriteOptions::default(),
+        )
+        .await
+        .expect("conversion");
+        let profiles = get_color_profiles(&output, 2).await;
+
+        let _ = std::fs::remove_file(&input);
+        let _ = std::fs::remove_file(&output);
+        let profiles = profiles.expect("inkcov");
+        assert!(profiles[0].k > 0.99, "near-black k = {}", profiles[0].k);
+        assert!(
+            (0.1..0.3).contains(&profiles[1].k),
+            "light gray k = {}",
+            profiles[1].k
+        );
+    }
+}
@@ -1,43 +1,147 @@
-use std::{path::Path, time::Instant};
+use std::{collections::HashMap, path::Path, time::Instant};
 
+use anyhow::Context;
 use axum::{
-    body::Bytes,
-    extract::{Extension, Json, Multipart, Path as AxumPath, State},
+    body::{Body, Bytes},
+    extract::{Extension, Json, Multipart, Path as AxumPath, Query, State},
     http::{
-        header::{CONTENT_DISPOSITION, CONTENT_TYPE},
-        HeaderMap, HeaderValue, StatusCode,
+        header::{
+            ACCEPT, ACCEPT_RANGES, CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_LENGTH,
+            CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_NONE_MATCH, LOCATION, RANGE, RETRY_AFTER,
+        },
+        HeaderMap, HeaderValue, Method, StatusCode, Uri,
     },
     response::{IntoResponse, Response},
 };
+use base64::{engine::general_purpose::STANDARD, Engine};
 use chrono::Utc;
+use futures_util::StreamExt;
 use serde::{Deserialize, Serialize};
-use serde_json::json;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio_util::io::ReaderStream;
+use utoipa::{IntoParams, ToSchema};
 use uuid::Uuid;
 
 use crate::{
+    clerk::{ClerkDeletedObject, ClerkUser, ClerkWebhookEvent},
+    config::{AlreadyGrayscaleAction, Config},
+    convex_api::{
+        ConvexUsageRecord, ConvexUsageReportRow, ConvexUserForStripe, NewApiKey, NotificationWrite,
+        SubscriptionWrite,
+    },
+    features::Feature,
     ghostscript::{
-        analyze_pdf, convert_pdf_to_grayscale_file, convert_pdf_to_grayscale_with_black_controls,
-        get_pdf_page_count, sanitize_base_name,
+        analyze_pdf, apply_watermark, contains_javascript, convert_pdf_to_grayscale_file,
+        convert_pdf_to_grayscale_with_black_controls, convert_postscript_to_pdf,
+        detect_annotations, detect_form_fields, extract_pages as extract_pdf_pages,
+        flatten_form_fields, get_color_profiles, get_pdf_page_count, pdf_has_outlines,
+        sanitize_base_name, sanitize_pdf, validate_black_controls, InkcovSampling, PdfAnalysis,
+        PdfInputError, PdfVersion, PdfwriteOptions, SanitizeOptions, BLACK_THRESHOLD_C_RANGE,
+        BLACK_THRESHOLD_L_RANGE,
     },
+    jobs::JobStatus,
+    messages::MessageCode,
+    middleware::{AuthenticatedUser, ConvexUser, API_KEY_SCOPES},
     mupdf::convert_pdf_to_grayscale_with_mupdf,
-    middleware::{AuthenticatedUser, ConvexUser},
-    plans::{is_subscription_active, plan_definition, resolve_plan_id, PlanId},
+    ocr::{ocr_pdf, validate_language as validate_ocr_language},
+    openapi::{
+        AnalyzeForm, ErrorBody, ExtractPagesForm, GrayscaleForm, OcrForm, PdfFile, SanitizeForm,
+    },
+    plans::{is_subscription_active, plan_definition, DowngradePolicy, PlanId},
     quota::{
         commit_reservation_for_clerk_user, release_reservation_for_clerk_user,
-        reserve_units_for_clerk_user, QuotaReservation,
+        reserve_units_for_clerk_user, QuotaReservation, ReservationGuard,
     },
-    serde_convex::de_i64_from_number,
     state::AppState,
-    stripe_api::{StripeEvent, StripeInvoice, StripeSubscription},
-    upload::{remove_file_if_exists, save_pdf_from_multipart, save_pdf_with_mode_from_multipart, UploadError},
+    stripe_api::{
+        ProrationBehavior, StripeEvent, StripeInvoice, StripeSubscription, StripeSubscriptionItem,
+    },
+    subscription::{effective_plan, Subscription},
+    tus::{TusError, TUS_VERSION},
+    upload::{
+        allowed_upload_extensions, detect_upload_kind, new_temp_upload_path, remove_file_if_exists,
+        sanitize_original_name, save_pdf_from_multipart, save_pdf_from_url,
+        save_pdf_with_mode_from_multipart, track_temp_path, work_dir, UploadError, UploadKind,
+        UploadedFile, UploadedPdfRequest,
+    },
 };
 
+const COLOR_COVERAGE_EPSILON: f64 = 0.0001;
+const PREFLIGHT_UPLOAD_LIMIT_BYTES: usize = 5 * 1024 * 1024;
+const PROCESSING_UPLOAD_LIMIT_BYTES: usize = 20 * 1024 * 1024;
+// Multipart framing plus the text fields sent alongside the file.
+const MULTIPART_OVERHEAD_BYTES: usize = 1024 * 1024;
+pub const PREFLIGHT_BODY_LIMIT_BYTES: usize =
+    PREFLIGHT_UPLOAD_LIMIT_BYTES + MULTIPART_OVERHEAD_BYTES;
+pub const PROCESSING_BODY_LIMIT_BYTES: usize =
+    PROCESSING_UPLOAD_LIMIT_BYTES + MULTIPART_OVERHEAD_BYTES;
+pub const JSON_BODY_LIMIT_BYTES: usize = 64 * 1024;
+// Webhook bodies are buffered whole before the signature check.
+pub const WEBHOOK_BODY_LIMIT_BYTES: usize = 256 * 1024;
+const ALREADY_GRAYSCALE_UNITS: i64 = 1;
+
+#[derive(Debug, thiserror::Error)]
+#[error("output is {actual} bytes, above the {limit} byte limit")]
+struct OutputTooLarge {
+    actual: u64,
+    limit: u64,
+}
+
+#[derive(Debug, Default, Deserialize, ToSchema)]
+pub struct GenerateApiKeyRequest {
+    pub scopes: Option<Vec<String>>,
+    #[serde(rename = "expiresInDays")]
+    pub expires_in_days: Option<u32>,
+    #[serde(alias = "label")]
+    pub name: Option<String>,
+}
+
+const MAX_API_KEY_LIFETIME_DAYS: u32 = 3650;
+const MAX_API_KEY_NAME_CHARS: usize = 64;
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ApiKeySummary {
+    id: String,
+    name: Option<String>,
+    preview: String,
+    created_at: i64,
+    last_used_at: Option<i64>,
+    expires_at: Option<i64>,
+    expired: bool,
+    scopes: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LogLevelRequest {
+    pub filter: String,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct DeleteApiKeyPath {
     pub id: String,
 }
 
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct GrayscaleQuery {
+    #[serde(rename = "async")]
+    pub run_async: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UploadPath {
+    pub upload_id: String,
+}
+
 #[derive(Debug, Deserialize)]
+pub struct ResultPath {
+    pub job_id: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct CreateCheckoutRequest {
     #[serde(rename = "priceId")]
     pub price_id: Option<String>,
@@ -47,48 +151,61 @@ pub struct CreateCheckoutRequest {
     pub cancel_url: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
-pub struct SyncStripeSessionRequest {
-    #[serde(rename = "sessionId")]
-    pub session_id: Option<String>,
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct PreflightUrlRequest {
+    pub url: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
-struct ConvexSubscription {
-    pub plan: Option<String>,
-    pub status: Option<String>,
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AnalysisQuery {
+    pub format: Option<String>,
+    pub sample: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
-struct ConvexUsageRecord {
-    pub date: String,
-    #[serde(deserialize_with = "de_i64_from_number")]
-    pub count: i64,
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct UsageQuery {
+    pub format: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
-struct ConvexUsageReservationRecord {
-    pub date: String,
-    pub status: String,
-    #[serde(deserialize_with = "de_i64_from_number")]
-    pub units: i64,
-    #[serde(rename = "expiresAt")]
-    #[serde(deserialize_with = "de_i64_from_number")]
-    pub expires_at: i64,
-}
-
-#[derive(Debug, Deserialize, Clone)]
-struct ConvexUserForStripe {
-    #[serde(rename = "clerkId")]
-    pub clerk_id: String,
-    pub email: String,
-    #[serde(rename = "stripeCustomerId")]
-    pub stripe_customer_id: Option<String>,
+pub struct UsageReportQuery {
+    pub month: Option<String>,
+    pub format: Option<String>,
+    pub cursor: Option<String>,
+    pub limit: Option<usize>,
 }
 
-#[derive(Debug, Serialize)]
-struct QuotaExceededBody {
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ChangePlanRequest {
+    #[serde(rename = "priceId")]
+    pub price_id: Option<String>,
+    #[serde(rename = "prorationBehavior", default)]
+    pub proration_behavior: ProrationBehavior,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct SyncStripeSessionRequest {
+    #[serde(rename = "sessionId")]
+    pub session_id: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub(crate) struct PlanSummary {
+    id: &'static str,
+    name: &'static str,
+    #[serde(rename = "monthlyUnits")]
+    monthly_units: Option<i64>,
+    /// The price offered for checkout first, then legacy prices.
+    #[serde(rename = "stripePriceIds")]
+    stripe_price_ids: Vec<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub(crate) struct QuotaExceededBody {
     error: &'static str,
+    code: &'static str,
     plan: String,
     #[serde(rename = "monthlyQuota")]
     monthly_quota: Option<i64>,
@@ -98,6 +215,48 @@ struct QuotaExceededBody {
     pending_units: i64,
     #[serde(rename = "unitsRequested")]
     units_requested: i64,
+    // When the next pending reservation lapses and frees its units.
+    #[serde(rename = "pendingUnitsReleaseAt")]
+    pending_units_release_at: Option<i64>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub(crate) struct PlanListBody {
+    plans: Vec<PlanSummary>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct UsageSummaryBody {
+    plan: &'static str,
+    total_units: i64,
+    units_this_month: i64,
+    pending_units: i64,
+    monthly_quota: Option<i64>,
+    remaining_units: Option<i64>,
+}
+
+impl From<&UsageSummary> for UsageSummaryBody {
+    fn from(summary: &UsageSummary) -> Self {
+        Self {
+            plan: summary.plan_id.as_str(),
+            total_units: summary.total_units,
+            units_this_month: summary.units_this_month,
+            pending_units: summary.pending_units,
+            monthly_quota: summary.monthly_quota,
+            remaining_units: summary.remaining_units,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ProfileBody {
+    clerk_id: String,
+    email: Option<String>,
+    plan: &'static str,
+    subscription_status: String,
+    usage: UsageSummaryBody,
 }
 
 pub async fn health(State(state): State<AppState>) -> Response {
@@ -122,7 +281,25 @@ pub async fn health(State(state): State<AppState>) -> Response {
             ),
         };
 
-    match state.convex.query::<String>("health:get", json!({})).await {
+    let stale_issuers = state
+        .auth
+        .jwks_health()
+        .into_iter()
+        .filter(|issuer| issuer.stale)
+        .map(|issuer| issuer.issuer)
+        .collect::<Vec<_>>();
+
+    match state.convex_health().await {
+        // Tokens signed with a rotated key can't be verified until a refresh
+        // succeeds, so this instance shouldn't take traffic.
+        Ok(_) if !stale_issuers.is_empty() => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!(
+                "JWKS refresh is failing and the cached keys are stale for: {}",
+                stale_issuers.join(", ")
+            ),
+        )
+            .into_response(),
         Ok(convex_health) => {
             let suffix = ghostscript_error
                 .map(|value| format!(" (Error: {})", value))
@@ -136,8 +313,7 @@ pub async fn health(State(state): State<AppState>) -> Response {
             )
                 .into_response()
         }
-        Err(error) => {
-            tracing::error!(error = %error, "failed to connect to Convex");
+        Err(_) => {
             let suffix = ghostscript_error
                 .map(|value| format!(" (Error: {})", value))
                 .unwrap_or_default();
@@ -153,26 +329,320 @@ pub async fn health(State(state): State<AppState>) -> Response {
     }
 }
 
-pub async fn conversion_placeholder() -> Response {
-    (StatusCode::OK, "conversion").into_response()
+// Per-issuer JWKS fetch state, for telling a failing key refresh apart from
+// bad tokens when auth errors spike.
+pub async fn health_jwks(State(state): State<AppState>) -> Response {
+    let issuers = state.auth.jwks_health();
+    let status = if issuers.iter().any(|issuer| issuer.stale) {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::OK
+    };
+    (status, Json(json!({ "issuers": issuers }))).into_response()
 }
 
-pub async fn not_found() -> Response {
-    (StatusCode::NOT_FOUND, "Not Found").into_response()
+// Liveness only: no Convex or Ghostscript checks, so restarts aren't
+// triggered by a dependency outage.
+pub async fn health_live() -> Response {
+    (StatusCode::OK, "OK").into_response()
 }
 
-pub async fn test_document(State(state): State<AppState>, multipart: Multipart) -> Response {
-    let uploaded = match save_pdf_from_multipart(multipart, 5 * 1024 * 1024).await {
-        Ok(file) => file,
-        Err(error) => return upload_error_to_response(error),
+#[utoipa::path(
+    get,
+    path = "/process/conversion",
+    tag = "process",
+    summary = "Modes, engines and limits available to the caller",
+    security(("clerk" = [])),
+    responses((status = 200, description = "Capabilities for the caller's plan"))
+)]
+pub async fn conversion_capabilities(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthenticatedUser>,
+    headers: HeaderMap,
+) -> Response {
+    let summary = match load_usage_summary(&state, &user.clerk_id).await {
+        Ok(summary) => summary,
+        Err(error) => {
+            tracing::error!(error = ?error, "failed to load plan for conversion capabilities");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "Error loading conversion capabilities" })),
+            )
+                .into_response();
+        }
+    };
+    let mupdf_available = crate::tools::mupdf_recolor_available().await;
+    let ocr_available = crate::tools::ocr_available().await;
+
+    etag_json_response(
+        &headers,
+        "private, no-cache",
+        &json!({
+            "modes": [GrayscaleMode::Preview.as_str(), GrayscaleMode::Production.as_str()],
+            "engines": [
+                { "id": "ghostscript", "available": true },
+                { "id": "mupdf", "available": mupdf_available },
+            ],
+            "inputFormats": allowed_upload_extensions(),
+            "outputFormats": ["pdf"],
+            "ocr": {
+                "available": ocr_available,
+                "unitsPerPage": state.config.ocr_units_per_page,
+            },
+            "limits": {
+                "plan": summary.plan_id.as_str(),
+                "monthlyUnits": summary.monthly_quota,
+                "remainingUnits": summary.remaining_units,
+                "maxUploadBytes": PROCESSING_UPLOAD_LIMIT_BYTES,
+                "maxOutputBytes": state.config.max_output_bytes_for(PROCESSING_UPLOAD_LIMIT_BYTES),
+                "watermark": state.config.watermark_free_plan && summary.plan_id == PlanId::Free,
+            },
+        }),
+    )
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/plans",
+    tag = "account",
+    summary = "Plans with their quotas and Stripe price ids",
+    responses((status = 200, description = "Plans", body = PlanListBody))
+)]
+pub async fn list_plans(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    let plans = PlanId::ALL
+        .iter()
+        .map(|plan_id| PlanSummary {
+            id: plan_id.as_str(),
+            name: plan_id.display_name(),
+            monthly_units: plan_definition(*plan_id).monthly_units,
+            stripe_price_ids: state.price_map.price_ids_for_plan(*plan_id),
+        })
+        .collect::<Vec<_>>();
+
+    etag_json_response(&headers, "public, no-cache", &json!(PlanListBody { plans }))
+}
+
+pub async fn debug_queue(State(state): State<AppState>) -> Response {
+    let concurrency = state.config.ghostscript_concurrency;
+    let available = state.ghostscript_semaphore.available_permits();
+    let recent = state.recent_ghostscript_timings();
+
+    let count = recent.len() as u128;
+    let stats = (count > 0).then(|| {
+        json!({
+            "count": count,
+            "avgWaitMs": recent.iter().map(|timing| timing.wait_ms).sum::<u128>() / count,
+            "maxWaitMs": recent.iter().map(|timing| timing.wait_ms).max(),
+            "avgRunMs": recent.iter().map(|timing| timing.run_ms).sum::<u128>() / count,
+            "maxRunMs": recent.iter().map(|timing| timing.run_ms).max(),
+            "failed": recent.iter().filter(|timing| !timing.ok).count(),
+        })
+    });
+
+    (
+        StatusCode::OK,
+        Json(json!({
+            "ghostscript": {
+                "concurrency": concurrency,
+                "available": available,
+                "running": concurrency.saturating_sub(available),
+                "waiting": state.ghostscript_queue_depth(),
+                "maxQueueDepth": state.config.ghostscript_max_queue_depth,
+            },
+            "stats": stats,
+            "recent": recent,
+        })),
+    )
+        .into_response()
+}
+
+pub async fn set_log_level(
+    State(state): State<AppState>,
+    Json(body): Json<LogLevelRequest>,
+) -> Response {
+    let filter = match tracing_subscriber::EnvFilter::try_new(body.filter.trim()) {
+        Ok(filter) => filter,
+        Err(error) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "error": format!("Invalid log filter: {}", error) })),
+            )
+                .into_response()
+        }
+    };
+    let current = filter.to_string();
+
+    let previous = state
+        .log_filter
+        .with_current(|filter| filter.to_string())
+        .ok();
+    if let Err(error) = state.log_filter.reload(filter) {
+        tracing::error!(error = %error, "failed to reload log filter");
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": "Failed to update log filter" })),
+        )
+            .into_response();
+    }
+
+    tracing::warn!(previous = ?previous, current = %current, "log filter changed at runtime");
+    (
+        StatusCode::OK,
+        Json(json!({ "previous": previous, "current": current })),
+    )
+        .into_response()
+}
+
+pub async fn warmup_ghostscript(State(state): State<AppState>) -> Response {
+    match state.warmup_ghostscript().await {
+        Ok(duration) => Json(json!({ "durationMs": duration.as_millis() as u64 })).into_response(),
+        Err(error) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": format!("Ghostscript warmup failed: {}", error) })),
+        )
+            .into_response(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(transparent)]
+pub struct FeatureFlagsRequest(pub std::collections::HashMap<String, bool>);
+
+pub async fn get_feature_flags(State(state): State<AppState>) -> Response {
+    Json(json!({ "features": state.features.snapshot() })).into_response()
+}
+
+// Partial update: `{ "grayscale": false }` only touches grayscale.
+pub async fn set_feature_flags(
+    State(state): State<AppState>,
+    Json(body): Json<FeatureFlagsRequest>,
+) -> Response {
+    let mut updates = Vec::with_capacity(body.0.len());
+    for (name, enabled) in body.0 {
+        match Feature::parse(&name) {
+            Some(feature) => updates.push((feature, enabled)),
+            None => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({ "error": format!("Unknown feature: {}", name) })),
+                )
+                    .into_response()
+            }
+        }
+    }
+    for (feature, enabled) in updates {
+        state.features.set(feature, enabled);
+    }
+    Json(json!({ "features": state.features.snapshot() })).into_response()
+}
+
+fn feature_disabled_response(state: &AppState, feature: Feature) -> Option<Response> {
+    if state.features.is_enabled(feature) {
+        return None;
+    }
+    Some(
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({
+                "error": "This operation is temporarily disabled.",
+                "code": MessageCode::FeatureDisabled.code(),
+                "feature": feature.as_str(),
+            })),
+        )
+            .into_response(),
+    )
+}
+
+pub async fn not_found(method: Method, uri: Uri) -> Response {
+    route_error_response(
+        StatusCode::NOT_FOUND,
+        "not_found",
+        "No route for",
+        method,
+        uri,
+    )
+}
+
+// axum keeps the `Allow` header on responses from this fallback.
+pub async fn method_not_allowed(method: Method, uri: Uri) -> Response {
+    route_error_response(
+        StatusCode::METHOD_NOT_ALLOWED,
+        "method_not_allowed",
+        "Method not allowed for",
+        method,
+        uri,
+    )
+}
+
+// API clients get a JSON body they can parse; everything else keeps the
+// plain-text response browsers and probes have always seen.
+fn route_error_response(
+    status: StatusCode,
+    code: &str,
+    message: &str,
+    method: Method,
+    uri: Uri,
+) -> Response {
+    let path = uri.path();
+    if path != "/api" && !path.starts_with("/api/") {
+        return (status, status.canonical_reason().unwrap_or_default()).into_response();
+    }
+
+    (
+        status,
+        Json(json!({
+            "error": {
+                "code": code,
+                "message": format!("{} {} {}", message, method, path),
+                "method": method.as_str(),
+                "path": path,
+            }
+        })),
+    )
+        .into_response()
+}
+
+#[utoipa::path(
+    post,
+    path = "/process/preflight-test",
+    tag = "process",
+    summary = "Analyze a PDF without an account (rate limited)",
+    params(AnalysisQuery),
+    request_body(content = AnalyzeForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "Analysis", body = PdfAnalysis),
+        (status = 400, description = "Invalid upload or options", body = ErrorBody),
+        (status = 422, description = "Password-protected, invalid or corrupt PDF", body = ErrorBody),
+        (status = 429, description = "Rate limited", body = ErrorBody),
+    )
+)]
+pub async fn test_document(
+    State(state): State<AppState>,
+    Query(query): Query<AnalysisQuery>,
+    multipart: Multipart,
+) -> Response {
+    if let Some(response) = feature_disabled_response(&state, Feature::Analyze) {
+        return response;
+    }
+    let options = match AnalysisOptions::from_query(&query, &state.config) {
+        Ok(value) => value,
+        Err((message, code)) => return bad_request(message, code),
     };
+    let mut uploaded =
+        match save_pdf_from_multipart(multipart, PREFLIGHT_UPLOAD_LIMIT_BYTES, None).await {
+            Ok(file) => file,
+            Err(error) => return upload_error_to_response(error),
+        };
+    if let Err(response) = ensure_pdf_input(&state, uploaded.kind, &mut uploaded.temp_path).await {
+        return response;
+    }
 
     let temp_path = uploaded.temp_path.clone();
     let original_name = uploaded.original_name.clone();
 
     let result = state
         .run_ghostscript_job("preflight-test", || async {
-            let mut analysis = analyze_pdf(&temp_path, None).await?;
+            let mut analysis = analyze_pdf(&temp_path, None, options.sampling).await?;
             analysis.file_name = original_name;
             Ok(analysis)
         })
@@ -181,31 +651,124 @@ pub async fn test_document(State(state): State<AppState>, multipart: Multipart)
     remove_file_if_exists(&temp_path).await;
 
     match result {
-        Ok(analysis) => Json(analysis).into_response(),
+        Ok(analysis) => analysis_response(&analysis, options.format),
         Err(error) => {
             tracing::error!(error = %error, "failed to analyze PDF");
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(json!({ "error": error.to_string() })),
-            )
-                .into_response()
+            processing_error_response(&error)
         }
     }
 }
 
+#[utoipa::path(
+    post,
+    path = "/process/preflight",
+    tag = "process",
+    summary = "Analyze an uploaded PDF",
+    security(("clerk" = [])),
+    params(AnalysisQuery),
+    request_body(content = AnalyzeForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "Analysis", body = PdfAnalysis),
+        (status = 400, description = "Invalid upload or options", body = ErrorBody),
+        (status = 402, description = "Monthly quota exceeded", body = QuotaExceededBody),
+        (status = 422, description = "Password-protected, invalid or corrupt PDF", body = ErrorBody),
+    )
+)]
 pub async fn preflight_document(
     State(state): State<AppState>,
     Extension(user): Extension<AuthenticatedUser>,
+    Query(query): Query<AnalysisQuery>,
     multipart: Multipart,
 ) -> Response {
-    preflight_for_clerk_user(state, &user.clerk_id, multipart, 5 * 1024 * 1024).await
+    if let Some(response) = feature_disabled_response(&state, Feature::Analyze) {
+        return response;
+    }
+    let options = match AnalysisOptions::from_query(&query, &state.config) {
+        Ok(value) => value,
+        Err((message, code)) => return bad_request(message, code),
+    };
+    preflight_for_clerk_user(
+        state,
+        &user.clerk_id,
+        multipart,
+        PREFLIGHT_UPLOAD_LIMIT_BYTES,
+        options,
+    )
+    .await
+}
+
+#[utoipa::path(
+    post,
+    path = "/process/preflight-url",
+    tag = "process",
+    summary = "Download a PDF from a public https URL and analyze it",
+    security(("clerk" = [])),
+    params(AnalysisQuery),
+    request_body = PreflightUrlRequest,
+    responses(
+        (status = 200, description = "Analysis", body = PdfAnalysis),
+        (status = 400, description = "Invalid URL or download failed", body = ErrorBody),
+        (status = 402, description = "Monthly quota exceeded", body = QuotaExceededBody),
+        (status = 422, description = "Password-protected, invalid or corrupt PDF", body = ErrorBody),
+    )
+)]
+pub async fn preflight_document_from_url(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthenticatedUser>,
+    Query(query): Query<AnalysisQuery>,
+    Json(body): Json<PreflightUrlRequest>,
+) -> Response {
+    if let Some(response) = feature_disabled_response(&state, Feature::Analyze) {
+        return response;
+    }
+    let options = match AnalysisOptions::from_query(&query, &state.config) {
+        Ok(value) => value,
+        Err((message, code)) => return bad_request(message, code),
+    };
+    let url = match body.url.filter(|value| !value.trim().is_empty()) {
+        Some(value) => value,
+        None => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "error": "Missing required parameter: url" })),
+            )
+                .into_response()
+        }
+    };
+
+    let uploaded =
+        match save_pdf_from_url(&state.fetch_http, &url, PREFLIGHT_UPLOAD_LIMIT_BYTES).await {
+            Ok(file) => file,
+            Err(error) => return upload_error_to_response(error),
+        };
+
+    preflight_uploaded_for_clerk_user(state, &user.clerk_id, uploaded, options).await
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/process/analyze",
+    tag = "api",
+    summary = "Analyze an uploaded PDF",
+    security(("api_key" = [])),
+    params(AnalysisQuery),
+    request_body(content = AnalyzeForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "Analysis", body = PdfAnalysis),
+        (status = 400, description = "Invalid upload or options", body = ErrorBody),
+        (status = 402, description = "Monthly quota exceeded", body = QuotaExceededBody),
+        (status = 422, description = "Password-protected, invalid or corrupt PDF", body = ErrorBody),
+    )
+)]
 pub async fn process_document_api(
     State(state): State<AppState>,
     Extension(convex_user): Extension<ConvexUser>,
+    Query(query): Query<AnalysisQuery>,
     multipart: Multipart,
 ) -> Response {
+    if let Some(response) = feature_disabled_response(&state, Feature::Analyze) {
+        return response;
+    }
     let clerk_id = match convex_user.clerk_id {
         Some(value) if !value.trim().is_empty() => value,
         _ => {
@@ -217,22 +780,66 @@ pub async fn process_document_api(
         }
     };
 
-    preflight_for_clerk_user(state, &clerk_id, multipart, 20 * 1024 * 1024).await
+    let options = match AnalysisOptions::from_query(&query, &state.config) {
+        Ok(value) => value,
+        Err((message, code)) => return bad_request(message, code),
+    };
+    preflight_for_clerk_user(state, &clerk_id, multipart, 20 * 1024 * 1024, options).await
 }
 
+#[utoipa::path(
+    post,
+    path = "/process/grayscale",
+    tag = "process",
+    summary = "Convert a PDF to grayscale",
+    security(("clerk" = [])),
+    request_body(content = GrayscaleForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "Converted PDF", body = PdfFile, content_type = "application/pdf"),
+        (status = 400, description = "Invalid upload or options", body = ErrorBody),
+        (status = 402, description = "Monthly quota exceeded", body = QuotaExceededBody),
+        (status = 422, description = "Password-protected, invalid or corrupt PDF", body = ErrorBody),
+    )
+)]
 pub async fn convert_document_to_grayscale(
     State(state): State<AppState>,
     Extension(user): Extension<AuthenticatedUser>,
+    headers: HeaderMap,
     multipart: Multipart,
 ) -> Response {
-    grayscale_for_clerk_user(state, &user.clerk_id, multipart).await
+    if let Some(response) = feature_disabled_response(&state, Feature::Grayscale) {
+        return response;
+    }
+    let debug_timings = wants_debug_timings(&state, &headers);
+    grayscale_for_clerk_user(state, &user.clerk_id, multipart, debug_timings, false).await
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/process/grayscale",
+    tag = "api",
+    summary = "Convert a PDF to grayscale",
+    security(("api_key" = [])),
+    params(GrayscaleQuery),
+    request_body(content = GrayscaleForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "Converted PDF", body = PdfFile, content_type = "application/pdf"),
+        (status = 202, description = "Queued with `async=true`; poll `/api/process/jobs/{jobId}`"),
+        (status = 400, description = "Invalid upload or options", body = ErrorBody),
+        (status = 402, description = "Monthly quota exceeded", body = QuotaExceededBody),
+        (status = 422, description = "Password-protected, invalid or corrupt PDF", body = ErrorBody),
+    )
+)]
 pub async fn convert_document_to_grayscale_api(
     State(state): State<AppState>,
     Extension(convex_user): Extension<ConvexUser>,
+    Query(query): Query<GrayscaleQuery>,
+    headers: HeaderMap,
     multipart: Multipart,
 ) -> Response {
+    if let Some(response) = feature_disabled_response(&state, Feature::Grayscale) {
+        return response;
+    }
     let clerk_id = match convex_user.clerk_id {
         Some(value) if !value.trim().is_empty() => value,
         _ => {
@@ -244,937 +851,3572 @@ pub async fn convert_document_to_grayscale_api(
         }
     };
 
-    grayscale_for_clerk_user(state, &clerk_id, multipart).await
+    let run_async = match parse_bool_field(query.run_async.as_deref(), "async") {
+        Ok(value) => value,
+        Err(message) => return bad_request(message, MessageCode::InvalidOption),
+    };
+    let debug_timings = wants_debug_timings(&state, &headers);
+    grayscale_for_clerk_user(state, &clerk_id, multipart, debug_timings, run_async).await
 }
 
-pub async fn generate_api_key(
+#[utoipa::path(
+    post,
+    path = "/process/extract-pages",
+    tag = "process",
+    summary = "Extract a page range into a new PDF",
+    security(("clerk" = [])),
+    request_body(content = ExtractPagesForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "Extracted pages", body = PdfFile, content_type = "application/pdf"),
+        (status = 400, description = "Invalid upload or options", body = ErrorBody),
+        (status = 402, description = "Monthly quota exceeded", body = QuotaExceededBody),
+        (status = 422, description = "Password-protected, invalid or corrupt PDF", body = ErrorBody),
+    )
+)]
+pub async fn extract_pages(
     State(state): State<AppState>,
     Extension(user): Extension<AuthenticatedUser>,
+    multipart: Multipart,
 ) -> Response {
-    match state
-        .convex
-        .action_value("apiKeys:generate", json!({ "userId": &user.clerk_id }))
-        .await
-    {
-        Ok(api_key) => (StatusCode::CREATED, Json(json!({ "apiKey": api_key }))).into_response(),
-        Err(error) => {
-            tracing::error!(error = %error, "failed to generate API key");
-            (
+    if let Some(response) = feature_disabled_response(&state, Feature::ExtractPages) {
+        return response;
+    }
+    extract_pages_for_clerk_user(state, &user.clerk_id, multipart).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/process/extract-pages",
+    tag = "api",
+    summary = "Extract a page range into a new PDF",
+    security(("api_key" = [])),
+    request_body(content = ExtractPagesForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "Extracted pages", body = PdfFile, content_type = "application/pdf"),
+        (status = 400, description = "Invalid upload or options", body = ErrorBody),
+        (status = 402, description = "Monthly quota exceeded", body = QuotaExceededBody),
+        (status = 422, description = "Password-protected, invalid or corrupt PDF", body = ErrorBody),
+    )
+)]
+pub async fn extract_pages_api(
+    State(state): State<AppState>,
+    Extension(convex_user): Extension<ConvexUser>,
+    multipart: Multipart,
+) -> Response {
+    if let Some(response) = feature_disabled_response(&state, Feature::ExtractPages) {
+        return response;
+    }
+    let clerk_id = match convex_user.clerk_id {
+        Some(value) if !value.trim().is_empty() => value,
+        _ => {
+            return (
                 StatusCode::INTERNAL_SERVER_ERROR,
-                "Error generating API key",
+                "Authenticated user missing Clerk ID.",
             )
                 .into_response()
         }
-    }
+    };
+
+    extract_pages_for_clerk_user(state, &clerk_id, multipart).await
 }
 
-pub async fn list_api_keys(
+#[utoipa::path(
+    post,
+    path = "/process/sanitize",
+    tag = "process",
+    summary = "Flatten forms and strip JavaScript or metadata",
+    security(("clerk" = [])),
+    request_body(content = SanitizeForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "Sanitized PDF", body = PdfFile, content_type = "application/pdf"),
+        (status = 400, description = "Invalid upload or options", body = ErrorBody),
+        (status = 402, description = "Monthly quota exceeded", body = QuotaExceededBody),
+        (status = 422, description = "Password-protected, invalid or corrupt PDF", body = ErrorBody),
+    )
+)]
+pub async fn sanitize_document(
     State(state): State<AppState>,
     Extension(user): Extension<AuthenticatedUser>,
+    multipart: Multipart,
 ) -> Response {
-    match state
-        .convex
-        .query_value("apiKeys:list", json!({ "userId": &user.clerk_id }))
-        .await
-    {
-        Ok(keys) => (StatusCode::OK, Json(keys)).into_response(),
-        Err(error) => {
-            tracing::error!(error = %error, "failed to list API keys");
-            (StatusCode::INTERNAL_SERVER_ERROR, "Error listing API keys").into_response()
-        }
+    if let Some(response) = feature_disabled_response(&state, Feature::Sanitize) {
+        return response;
     }
+    sanitize_for_clerk_user(state, &user.clerk_id, multipart).await
 }
 
-pub async fn delete_api_key(
+#[utoipa::path(
+    post,
+    path = "/api/process/sanitize",
+    tag = "api",
+    summary = "Flatten forms and strip JavaScript or metadata",
+    security(("api_key" = [])),
+    request_body(content = SanitizeForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "Sanitized PDF", body = PdfFile, content_type = "application/pdf"),
+        (status = 400, description = "Invalid upload or options", body = ErrorBody),
+        (status = 402, description = "Monthly quota exceeded", body = QuotaExceededBody),
+        (status = 422, description = "Password-protected, invalid or corrupt PDF", body = ErrorBody),
+    )
+)]
+pub async fn sanitize_document_api(
     State(state): State<AppState>,
-    Extension(user): Extension<AuthenticatedUser>,
-    AxumPath(path): AxumPath<DeleteApiKeyPath>,
+    Extension(convex_user): Extension<ConvexUser>,
+    multipart: Multipart,
 ) -> Response {
-    if path.id.trim().is_empty() {
-        return (StatusCode::BAD_REQUEST, "Missing API key ID.").into_response();
+    if let Some(response) = feature_disabled_response(&state, Feature::Sanitize) {
+        return response;
     }
-
-    match state
-        .convex
-        .action_value(
-            "apiKeys:deleteApiKey",
-            json!({
-                "clerkId": &user.clerk_id,
-                "apiKeyId": path.id,
-            }),
-        )
-        .await
-    {
-        Ok(_) => (
-            StatusCode::OK,
-            Json(json!({ "message": "API key deleted successfully." })),
-        )
-            .into_response(),
-        Err(error) => {
-            tracing::error!(error = %error, "failed to delete API key");
-            (StatusCode::INTERNAL_SERVER_ERROR, "Error deleting API key.").into_response()
+    let clerk_id = match convex_user.clerk_id {
+        Some(value) if !value.trim().is_empty() => value,
+        _ => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Authenticated user missing Clerk ID.",
+            )
+                .into_response()
         }
-    }
+    };
+
+    sanitize_for_clerk_user(state, &clerk_id, multipart).await
 }
 
-pub async fn get_subscription(
+#[utoipa::path(
+    post,
+    path = "/process/ocr",
+    tag = "process",
+    summary = "Add a searchable text layer with OCR",
+    security(("clerk" = [])),
+    request_body(content = OcrForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "PDF with a text layer", body = PdfFile, content_type = "application/pdf"),
+        (status = 400, description = "Invalid upload or options", body = ErrorBody),
+        (status = 402, description = "Monthly quota exceeded", body = QuotaExceededBody),
+        (status = 422, description = "Password-protected, invalid or corrupt PDF", body = ErrorBody),
+        (status = 503, description = "OCR is not installed on this server", body = ErrorBody),
+    )
+)]
+pub async fn ocr_document(
     State(state): State<AppState>,
     Extension(user): Extension<AuthenticatedUser>,
+    multipart: Multipart,
 ) -> Response {
-    let subscription = state
-        .convex
-        .query_value("subscriptions:get", json!({ "userId": &user.clerk_id }))
-        .await;
-
-    match subscription {
-        Ok(value) => {
-            if value.is_null() {
-                (
-                    StatusCode::OK,
-                    Json(json!({ "plan": "free", "status": "inactive" })),
-                )
-                    .into_response()
-            } else {
-                (StatusCode::OK, Json(value)).into_response()
-            }
-        }
-        Err(error) => {
-            tracing::error!(error = %error, "failed to fetch subscription");
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Error fetching subscription",
-            )
-                .into_response()
-        }
+    if let Some(response) = feature_disabled_response(&state, Feature::Ocr) {
+        return response;
     }
+    ocr_for_clerk_user(state, &user.clerk_id, multipart).await
 }
 
-pub async fn get_usage(
+#[utoipa::path(
+    post,
+    path = "/api/process/ocr",
+    tag = "api",
+    summary = "Add a searchable text layer with OCR",
+    security(("api_key" = [])),
+    request_body(content = OcrForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "PDF with a text layer", body = PdfFile, content_type = "application/pdf"),
+        (status = 400, description = "Invalid upload or options", body = ErrorBody),
+        (status = 402, description = "Monthly quota exceeded", body = QuotaExceededBody),
+        (status = 422, description = "Password-protected, invalid or corrupt PDF", body = ErrorBody),
+        (status = 503, description = "OCR is not installed on this server", body = ErrorBody),
+    )
+)]
+pub async fn ocr_document_api(
     State(state): State<AppState>,
-    Extension(user): Extension<AuthenticatedUser>,
+    Extension(convex_user): Extension<ConvexUser>,
+    multipart: Multipart,
 ) -> Response {
-    let usage_records: Vec<ConvexUsageRecord> = match state
-        .convex
-        .query("usage:getUsageData", json!({ "userId": &user.clerk_id }))
-        .await
-    {
-        Ok(records) => records,
-        Err(error) => {
-            tracing::error!(error = %error, "failed to fetch usage records");
+    if let Some(response) = feature_disabled_response(&state, Feature::Ocr) {
+        return response;
+    }
+    let clerk_id = match convex_user.clerk_id {
+        Some(value) if !value.trim().is_empty() => value,
+        _ => {
             return (
                 StatusCode::INTERNAL_SERVER_ERROR,
-                "Error fetching usage data",
+                "Authenticated user missing Clerk ID.",
             )
-                .into_response();
+                .into_response()
         }
     };
 
-    let reservation_records: Vec<ConvexUsageReservationRecord> = match state
-        .convex
-        .query(
-            "usage:getUsageReservations",
-            json!({ "userId": &user.clerk_id }),
-        )
-        .await
-    {
-        Ok(records) => records,
-        Err(error) => {
-            tracing::error!(error = %error, "failed to fetch usage reservations");
+    ocr_for_clerk_user(state, &clerk_id, multipart).await
+}
+
+pub async fn tus_create_upload(
+    State(state): State<AppState>,
+    Extension(convex_user): Extension<ConvexUser>,
+    headers: HeaderMap,
+) -> Response {
+    let clerk_id = match convex_user.clerk_id {
+        Some(value) if !value.trim().is_empty() => value,
+        _ => {
             return (
                 StatusCode::INTERNAL_SERVER_ERROR,
-                "Error fetching usage data",
+                "Authenticated user missing Clerk ID.",
             )
-                .into_response();
+                .into_response()
         }
     };
+    if let Some(response) = tus_version_mismatch(&headers) {
+        return response;
+    }
 
-    let current_month = Utc::now().format("%Y-%m").to_string();
+    let length = match header_u64(&headers, "upload-length") {
+        Some(value) => value,
+        None => return tus_error(StatusCode::BAD_REQUEST, "Upload-Length header is required."),
+    };
+    if length == 0 {
+        return tus_error(StatusCode::BAD_REQUEST, "Upload-Length must be positive.");
+    }
+    if length > PROCESSING_UPLOAD_LIMIT_BYTES as u64 {
+        return tus_error(StatusCode::PAYLOAD_TOO_LARGE, "File exceeds upload limit");
+    }
 
-    let mut total_units = 0i64;
-    let mut units_this_month = 0i64;
-    for record in usage_records {
-        total_units += record.count;
-        if record.date.starts_with(&current_month) {
-            units_this_month += record.count;
-        }
+    let metadata = parse_tus_metadata(
+        headers
+            .get("upload-metadata")
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default(),
+    );
+    let file_name = sanitize_original_name(
+        metadata
+            .get("filename")
+            .map(String::as_str)
+            .unwrap_or_default(),
+    );
+    let kind = match detect_upload_kind(metadata.get("filetype").map(String::as_str), &file_name) {
+        Ok(value) => value,
+        Err(error) => return upload_error_to_response(error),
+    };
+
+    if !state.uploads.has_capacity(&clerk_id) {
+        return tus_error(
+            StatusCode::TOO_MANY_REQUESTS,
+            &TusError::TooManyUploads.to_string(),
+        );
     }
 
-    let now = Utc::now().timestamp_millis();
-    let mut pending_units = 0i64;
-    for reservation in reservation_records {
-        if reservation.status == "pending"
-            && reservation.date.starts_with(&current_month)
-            && reservation.expires_at > now
+    let temp_path = new_temp_upload_path(kind);
+    if let Err(error) = tokio::fs::File::create(&temp_path).await {
+        tracing::error!(error = %error, "failed to create resumable upload file");
+        return upload_error_to_response(UploadError::IoError);
+    }
+
+    let upload_id =
+        match state
+            .uploads
+            .create(&clerk_id, temp_path.clone(), kind, file_name, length)
         {
-            pending_units += reservation.units;
-        }
+            Ok(value) => value,
+            Err(error) => {
+                remove_file_if_exists(&temp_path).await;
+                return tus_error(StatusCode::TOO_MANY_REQUESTS, &error.to_string());
+            }
+        };
+    let mut response = tus_response(StatusCode::CREATED);
+    if let Ok(value) = HeaderValue::from_str(&format!("/api/uploads/{}", upload_id)) {
+        response.headers_mut().insert(LOCATION, value);
     }
+    response
+}
 
-    let subscription: Option<ConvexSubscription> = match state
-        .convex
-        .query("subscriptions:get", json!({ "userId": &user.clerk_id }))
-        .await
-    {
+pub async fn tus_upload_status(
+    State(state): State<AppState>,
+    Extension(convex_user): Extension<ConvexUser>,
+    AxumPath(path): AxumPath<UploadPath>,
+) -> Response {
+    let clerk_id = convex_user.clerk_id.unwrap_or_default();
+    let status = Uuid::parse_str(&path.upload_id)
+        .ok()
+        .and_then(|upload_id| state.uploads.status(&upload_id, &clerk_id));
+    let (offset, length) = match status {
+        Some(value) => value,
+        None => return tus_response(StatusCode::NOT_FOUND),
+    };
+
+    let mut response = tus_response(StatusCode::OK);
+    let headers = response.headers_mut();
+    headers.insert("upload-offset", HeaderValue::from(offset));
+    headers.insert("upload-length", HeaderValue::from(length));
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    response
+}
+
+pub async fn tus_append_chunk(
+    State(state): State<AppState>,
+    Extension(convex_user): Extension<ConvexUser>,
+    AxumPath(path): AxumPath<UploadPath>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let clerk_id = convex_user.clerk_id.unwrap_or_default();
+    if let Some(response) = tus_version_mismatch(&headers) {
+        return response;
+    }
+    let is_offset_stream = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.eq_ignore_ascii_case("application/offset+octet-stream"));
+    if !is_offset_stream {
+        return tus_error(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Content-Type must be application/offset+octet-stream.",
+        );
+    }
+    let offset = match header_u64(&headers, "upload-offset") {
+        Some(value) => value,
+        None => return tus_error(StatusCode::BAD_REQUEST, "Upload-Offset header is required."),
+    };
+    let upload_id = match Uuid::parse_str(&path.upload_id) {
         Ok(value) => value,
-        Err(error) => {
-            tracing::error!(error = %error, "failed to fetch subscription for usage");
+        Err(_) => return tus_response(StatusCode::NOT_FOUND),
+    };
+
+    let patch = match state.uploads.begin_patch(&upload_id, &clerk_id, offset) {
+        Ok(value) => value,
+        Err(TusError::NotFound) => return tus_response(StatusCode::NOT_FOUND),
+        Err(error) => return tus_error(StatusCode::CONFLICT, &error.to_string()),
+    };
+
+    if body.len() as u64 > patch.remaining {
+        state.uploads.finish_patch(&upload_id, 0);
+        return tus_error(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "Chunk exceeds Upload-Length.",
+        );
+    }
+
+    // Written at the recorded offset rather than appended, and cut back to it
+    // on failure, so bytes from a partial write never shift later chunks.
+    let write_result = async {
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .open(&patch.path)
+            .await?;
+        let result = async {
+            file.seek(std::io::SeekFrom::Start(patch.offset)).await?;
+            file.write_all(&body).await?;
+            file.flush().await
+        }
+        .await;
+        if result.is_err() {
+            if let Err(error) = file.set_len(patch.offset).await {
+                tracing::warn!(error = %error, "failed to truncate resumable upload after a failed write");
+            }
+        }
+        result
+    }
+    .await;
+    let written = if write_result.is_ok() {
+        body.len() as u64
+    } else {
+        0
+    };
+    let new_offset = state.uploads.finish_patch(&upload_id, written);
+
+    if let Err(error) = write_result {
+        tracing::error!(error = %error, "failed to append resumable upload chunk");
+        return upload_error_to_response(UploadError::IoError);
+    }
+
+    let mut response = tus_response(StatusCode::NO_CONTENT);
+    if let Some(new_offset) = new_offset {
+        response
+            .headers_mut()
+            .insert("upload-offset", HeaderValue::from(new_offset));
+    }
+    response
+}
+
+#[utoipa::path(
+    get,
+    path = "/process/result/{job_id}",
+    tag = "process",
+    summary = "Download a retained result",
+    security(("clerk" = [])),
+    params(("job_id" = String, Path, description = "Result id from the `X-Job-Id` header or a job's `downloadUrl`")),
+    responses(
+        (status = 200, description = "Result PDF; supports `Range`", body = PdfFile, content_type = "application/pdf"),
+        (status = 404, description = "Result not found or expired", body = ErrorBody),
+    )
+)]
+pub async fn get_result(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthenticatedUser>,
+    AxumPath(path): AxumPath<ResultPath>,
+    headers: HeaderMap,
+) -> Response {
+    result_for_clerk_user(&state, &user.clerk_id, &path.job_id, &headers).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/process/result/{job_id}",
+    tag = "api",
+    summary = "Download a retained result",
+    security(("api_key" = [])),
+    params(("job_id" = String, Path, description = "Result id from the `X-Job-Id` header or a job's `downloadUrl`")),
+    responses(
+        (status = 200, description = "Result PDF; supports `Range`", body = PdfFile, content_type = "application/pdf"),
+        (status = 404, description = "Result not found or expired", body = ErrorBody),
+    )
+)]
+pub async fn get_result_api(
+    State(state): State<AppState>,
+    Extension(convex_user): Extension<ConvexUser>,
+    AxumPath(path): AxumPath<ResultPath>,
+    headers: HeaderMap,
+) -> Response {
+    let clerk_id = match convex_user.clerk_id {
+        Some(value) if !value.trim().is_empty() => value,
+        _ => {
             return (
                 StatusCode::INTERNAL_SERVER_ERROR,
-                "Error fetching usage data",
+                "Authenticated user missing Clerk ID.",
             )
-                .into_response();
+                .into_response()
         }
     };
 
-    let plan_id = match subscription {
-        Some(subscription) if is_subscription_active(subscription.status.as_deref()) => {
-            resolve_plan_id(subscription.plan.as_deref())
+    result_for_clerk_user(&state, &clerk_id, &path.job_id, &headers).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/process/jobs/{job_id}",
+    tag = "api",
+    summary = "Status of an async grayscale job",
+    security(("api_key" = [])),
+    params(("job_id" = String, Path)),
+    responses(
+        (status = 200, description = "`jobId`, `status`, `error`, `warnings` and `downloadUrl` once finished"),
+        (status = 404, description = "Job not found or expired", body = ErrorBody),
+    )
+)]
+pub async fn get_job_api(
+    State(state): State<AppState>,
+    Extension(convex_user): Extension<ConvexUser>,
+    AxumPath(path): AxumPath<ResultPath>,
+) -> Response {
+    let clerk_id = match convex_user.clerk_id {
+        Some(value) if !value.trim().is_empty() => value,
+        _ => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Authenticated user missing Clerk ID.",
+            )
+                .into_response()
         }
-        _ => PlanId::Free,
     };
 
-    let monthly_quota = plan_definition(plan_id).monthly_units;
-    let remaining_units =
-        monthly_quota.map(|quota| (quota - units_this_month - pending_units).max(0));
+    let job = Uuid::parse_str(path.job_id.trim())
+        .ok()
+        .and_then(|job_id| state.jobs.get(&job_id, &clerk_id).map(|job| (job_id, job)));
+    let (job_id, job) = match job {
+        Some(value) => value,
+        None => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(json!({ "error": "Job not found or expired." })),
+            )
+                .into_response()
+        }
+    };
 
+    let download_url = job
+        .result_id
+        .map(|result_id| format!("/api/process/result/{}", result_id));
     (
         StatusCode::OK,
         Json(json!({
-            "plan": plan_id.as_str(),
-            "totalUnits": total_units,
-            "unitsThisMonth": units_this_month,
-            "pendingUnits": pending_units,
-            "monthlyQuota": monthly_quota,
-            "remainingUnits": remaining_units,
+            "jobId": job_id,
+            "status": job.status,
+            "error": job.error,
+            "warnings": job.warnings,
+            "downloadUrl": download_url,
         })),
     )
         .into_response()
 }
 
-pub async fn create_checkout_session(
-    State(state): State<AppState>,
-    Extension(user): Extension<AuthenticatedUser>,
-    Json(body): Json<CreateCheckoutRequest>,
+async fn result_for_clerk_user(
+    state: &AppState,
+    clerk_id: &str,
+    job_id: &str,
+    headers: &HeaderMap,
 ) -> Response {
-    let price_id = match body.price_id.filter(|value| !value.trim().is_empty()) {
+    let stored = Uuid::parse_str(job_id.trim())
+        .ok()
+        .and_then(|job_id| state.results.get(&job_id, clerk_id));
+    let stored = match stored {
         Some(value) => value,
         None => {
             return (
-                StatusCode::BAD_REQUEST,
-                "Missing required parameters: priceId, successUrl, cancelUrl",
+                StatusCode::NOT_FOUND,
+                Json(json!({ "error": "Result not found or expired." })),
             )
-                .into_response();
+                .into_response()
         }
     };
-    let success_url = match body.success_url.filter(|value| !value.trim().is_empty()) {
-        Some(value) => value,
-        None => {
+
+    match pdf_file_response(&stored.path, &stored.file_name, headers).await {
+        Ok(response) => response,
+        Err(error) => {
+            tracing::error!(error = %error, "failed to read retained result");
+            (
+                StatusCode::NOT_FOUND,
+                Json(json!({ "error": "Result not found or expired." })),
+            )
+                .into_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/keys",
+    tag = "account",
+    summary = "Create an API key; the full key is only returned here",
+    security(("clerk" = [])),
+    request_body = GenerateApiKeyRequest,
+    responses(
+        (status = 201, description = "The new key and its metadata"),
+        (status = 400, description = "Invalid scopes, lifetime or name", body = ErrorBody),
+    )
+)]
+pub async fn generate_api_key(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthenticatedUser>,
+    body: Option<Json<GenerateApiKeyRequest>>,
+) -> Response {
+    let Json(body) = body.unwrap_or_default();
+
+    let mut new_key = NewApiKey::default();
+    if let Some(scopes) = body.scopes {
+        if scopes.is_empty() {
             return (
                 StatusCode::BAD_REQUEST,
-                "Missing required parameters: priceId, successUrl, cancelUrl",
+                Json(json!({ "error": "scopes must not be empty; omit it for full access." })),
             )
                 .into_response();
         }
-    };
-    let cancel_url = match body.cancel_url.filter(|value| !value.trim().is_empty()) {
-        Some(value) => value,
-        None => {
+        if let Some(unknown) = scopes
+            .iter()
+            .find(|scope| !API_KEY_SCOPES.contains(&scope.as_str()))
+        {
             return (
                 StatusCode::BAD_REQUEST,
-                "Missing required parameters: priceId, successUrl, cancelUrl",
+                Json(json!({
+                    "error": format!("Unknown scope `{}`.", unknown),
+                    "allowedScopes": API_KEY_SCOPES,
+                })),
             )
                 .into_response();
         }
-    };
 
-    if state
-        .price_map
-        .get_plan_for_price_id(Some(price_id.as_str()))
-        .is_none()
-    {
-        return (
-            StatusCode::BAD_REQUEST,
-            "Unknown or unsupported Stripe price ID.",
-        )
-            .into_response();
+        let mut scopes = scopes;
+        scopes.sort();
+        scopes.dedup();
+        new_key.scopes = Some(scopes);
     }
 
-    let user_for_stripe: Option<ConvexUserForStripe> = match state
-        .convex
-        .action(
-            "users:getUserForStripe",
-            json!({ "clerkId": &user.clerk_id }),
-        )
-        .await
-    {
-        Ok(value) => value,
-        Err(error) => {
-            tracing::error!(error = %error, "failed to load user for Stripe checkout");
+    let mut expires_at = None;
+    if let Some(days) = body.expires_in_days {
+        if days == 0 || days > MAX_API_KEY_LIFETIME_DAYS {
             return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Error creating checkout session",
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "error": format!(
+                        "expiresInDays must be between 1 and {}.",
+                        MAX_API_KEY_LIFETIME_DAYS
+                    ),
+                })),
             )
                 .into_response();
         }
-    };
+        let at = (Utc::now() + chrono::Duration::days(i64::from(days))).timestamp_millis();
+        expires_at = Some(at);
+    }
 
-    let mut user_for_stripe = match user_for_stripe {
-        Some(value) => value,
-        None => {
-            return (StatusCode::NOT_FOUND, "User not found in Convex database.").into_response()
-        }
-    };
-
-    let stripe_customer_id = if let Some(customer_id) = user_for_stripe.stripe_customer_id.clone() {
-        customer_id
-    } else {
-        let customer = match state
-            .stripe
-            .create_customer(&user_for_stripe.email, &user_for_stripe.clerk_id)
-            .await
-        {
-            Ok(customer) => customer,
-            Err(error) => {
-                tracing::error!(error = %error, "failed to create Stripe customer");
-                return (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    "Error creating checkout session",
-                )
-                    .into_response();
-            }
-        };
-
-        if let Err(error) = state
-            .convex
-            .action_value(
-                "users:setStripeCustomerId",
-                json!({
-                    "clerkId": &user_for_stripe.clerk_id,
-                    "stripeCustomerId": &customer.id,
-                }),
-            )
-            .await
-        {
-            tracing::error!(error = %error, "failed to persist Stripe customer id");
+    let name = body
+        .name
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty());
+    if let Some(name) = &name {
+        if name.chars().count() > MAX_API_KEY_NAME_CHARS {
             return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Error creating checkout session",
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "error": format!("name must be at most {} characters.", MAX_API_KEY_NAME_CHARS),
+                })),
             )
                 .into_response();
         }
+    }
+    new_key.expires_at = expires_at;
+    new_key.name = name.clone();
 
-        user_for_stripe.stripe_customer_id = Some(customer.id.clone());
-        customer.id
-    };
-
-    let session = match state
-        .stripe
-        .create_checkout_session(&stripe_customer_id, &price_id, &success_url, &cancel_url)
+    match state
+        .convex_api
+        .generate_api_key(&user.clerk_id, &new_key)
         .await
     {
-        Ok(session) => session,
+        // The only response that ever carries the full key.
+        Ok(generated) => (
+            StatusCode::CREATED,
+            Json(json!({
+                "apiKey": generated.key,
+                "preview": generated.preview,
+                "name": name,
+                "expiresAt": expires_at,
+            })),
+        )
+            .into_response(),
+        Err(error) if error.is_function_error() => {
+            tracing::warn!(error = %error, "API key generation rejected by Convex");
+            (
+                StatusCode::NOT_FOUND,
+                error
+                    .function_message()
+                    .unwrap_or("User not found")
+                    .to_string(),
+            )
+                .into_response()
+        }
         Err(error) => {
-            tracing::error!(error = %error, "failed to create Stripe checkout session");
-            return (
+            tracing::error!(error = %error, "failed to generate API key");
+            (
                 StatusCode::INTERNAL_SERVER_ERROR,
-                "Error creating checkout session",
+                "Error generating API key",
             )
-                .into_response();
+                .into_response()
         }
-    };
-
-    match session.url {
-        Some(url) => (StatusCode::OK, Json(json!({ "url": url }))).into_response(),
-        None => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "Error creating Stripe checkout session.",
-        )
-            .into_response(),
     }
 }
 
-pub async fn sync_stripe_session(
+#[utoipa::path(
+    get,
+    path = "/api/keys",
+    tag = "account",
+    summary = "List API keys",
+    security(("clerk" = [])),
+    responses((status = 200, description = "Keys, masked", body = Vec<ApiKeySummary>))
+)]
+pub async fn list_api_keys(
     State(state): State<AppState>,
     Extension(user): Extension<AuthenticatedUser>,
-    Json(body): Json<SyncStripeSessionRequest>,
 ) -> Response {
-    let session_id = match body.session_id.filter(|value| !value.trim().is_empty()) {
-        Some(value) => value,
-        None => return (StatusCode::BAD_REQUEST, "Missing sessionId").into_response(),
-    };
-
-    let session = match state.stripe.retrieve_checkout_session(&session_id).await {
-        Ok(session) => session,
+    match state.convex_api.list_api_keys(&user.clerk_id).await {
+        Ok(keys) => {
+            let now = Utc::now().timestamp_millis();
+            let keys = keys
+                .into_iter()
+                .map(|key| ApiKeySummary {
+                    expired: key.expires_at.is_some_and(|at| at <= now),
+                    id: key.id,
+                    name: key.name,
+                    preview: key.preview,
+                    created_at: key.created_at,
+                    last_used_at: key.last_used_at,
+                    expires_at: key.expires_at,
+                    scopes: key.scopes,
+                })
+                .collect::<Vec<_>>();
+            (StatusCode::OK, Json(keys)).into_response()
+        }
         Err(error) => {
-            tracing::error!(error = %error, "failed to retrieve Stripe checkout session");
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Error syncing Stripe session",
-            )
-                .into_response();
+            tracing::error!(error = %error, "failed to list API keys");
+            (StatusCode::INTERNAL_SERVER_ERROR, "Error listing API keys").into_response()
         }
-    };
-
-    if session.status.as_deref() != Some("complete") {
-        return (StatusCode::BAD_REQUEST, "Checkout session not complete.").into_response();
     }
+}
 
-    let subscription_id = session.subscription.map(|value| value.id());
-    let price_id = session
-        .line_items
-        .as_ref()
-        .and_then(|line_items| line_items.data.first())
-        .and_then(|item| item.price.as_ref())
-        .and_then(|price| price.id.clone());
+#[utoipa::path(
+    delete,
+    path = "/api/keys/{id}",
+    tag = "account",
+    summary = "Delete an API key",
+    security(("clerk" = [])),
+    params(("id" = String, Path)),
+    responses(
+        (status = 200, description = "Deleted"),
+        (status = 404, description = "Key not found"),
+    )
+)]
+pub async fn delete_api_key(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthenticatedUser>,
+    AxumPath(path): AxumPath<DeleteApiKeyPath>,
+) -> Response {
+    if path.id.trim().is_empty() {
+        return (StatusCode::BAD_REQUEST, "Missing API key ID.").into_response();
+    }
 
-    let (subscription_id, price_id) = match (subscription_id, price_id) {
-        (Some(subscription_id), Some(price_id)) => (subscription_id, price_id),
-        _ => {
-            return (
-                StatusCode::BAD_REQUEST,
-                "Could not find subscription or price ID in session.",
+    match state
+        .convex_api
+        .delete_api_key(&user.clerk_id, &path.id)
+        .await
+    {
+        Ok(_) => (
+            StatusCode::OK,
+            Json(json!({ "message": "API key deleted successfully." })),
+        )
+            .into_response(),
+        Err(error) if error.is_function_error() => {
+            tracing::warn!(error = %error, "API key deletion rejected by Convex");
+            (
+                StatusCode::NOT_FOUND,
+                error
+                    .function_message()
+                    .unwrap_or("API Key not found or does not belong to user.")
+                    .to_string(),
             )
                 .into_response()
         }
-    };
+        Err(error) => {
+            tracing::error!(error = %error, "failed to delete API key");
+            (StatusCode::INTERNAL_SERVER_ERROR, "Error deleting API key.").into_response()
+        }
+    }
+}
 
-    let plan_id = match state
-        .price_map
-        .get_plan_for_price_id(Some(price_id.as_str()))
-    {
-        Some(plan_id) => plan_id,
-        None => {
-            return (
-                StatusCode::BAD_REQUEST,
-                "Unknown or unsupported Stripe price ID.",
+#[utoipa::path(
+    get,
+    path = "/api/subscription",
+    tag = "billing",
+    summary = "The caller's subscription, or the free plan",
+    security(("clerk" = [])),
+    responses((status = 200, description = "Subscription record"))
+)]
+pub async fn get_subscription(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthenticatedUser>,
+) -> Response {
+    let subscription = state
+        .convex_api
+        .get_subscription_value(&user.clerk_id)
+        .await;
+
+    match subscription {
+        Ok(value) => {
+            if value.is_null() {
+                (
+                    StatusCode::OK,
+                    Json(json!({ "plan": "free", "status": "inactive" })),
+                )
+                    .into_response()
+            } else {
+                (StatusCode::OK, Json(value)).into_response()
+            }
+        }
+        Err(error) => {
+            tracing::error!(error = %error, "failed to fetch subscription");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Error fetching subscription",
             )
                 .into_response()
         }
-    };
+    }
+}
 
-    let user_exists: Option<ConvexUserForStripe> = match state
-        .convex
-        .action(
-            "users:getUserForStripe",
-            json!({ "clerkId": &user.clerk_id }),
-        )
-        .await
-    {
-        Ok(value) => value,
+#[utoipa::path(
+    get,
+    path = "/api/usage",
+    tag = "account",
+    summary = "Units used this month and the remaining quota",
+    security(("clerk" = [])),
+    params(UsageQuery),
+    responses((status = 200, description = "Usage; daily CSV with `format=csv` or `Accept: text/csv`", body = UsageSummaryBody))
+)]
+pub async fn get_usage(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthenticatedUser>,
+    Query(query): Query<UsageQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let summary = match load_usage_summary(&state, &user.clerk_id).await {
+        Ok(summary) => summary,
         Err(error) => {
-            tracing::error!(error = %error, "failed to fetch user for Stripe sync");
+            tracing::error!(error = ?error, "failed to fetch usage data");
             return (
                 StatusCode::INTERNAL_SERVER_ERROR,
-                "Error syncing Stripe session",
+                "Error fetching usage data",
             )
                 .into_response();
         }
     };
 
-    if user_exists.is_none() {
-        return (StatusCode::NOT_FOUND, "User not found.").into_response();
+    if wants_csv(query.format.as_deref(), &headers) {
+        return usage_csv_response(&summary);
     }
 
-    let existing_subscription: Option<ConvexSubscription> = match state
-        .convex
-        .query("subscriptions:get", json!({ "userId": &user.clerk_id }))
-        .await
-    {
-        Ok(value) => value,
+    (StatusCode::OK, Json(UsageSummaryBody::from(&summary))).into_response()
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/me",
+    tag = "account",
+    summary = "The caller's email, plan, subscription status and usage",
+    security(("clerk" = [])),
+    responses((status = 200, description = "Profile", body = ProfileBody))
+)]
+pub async fn get_profile(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthenticatedUser>,
+) -> Response {
+    let (email, summary) = tokio::join!(
+        load_primary_email(&state, &user.clerk_id),
+        load_usage_summary(&state, &user.clerk_id)
+    );
+
+    let summary = match summary {
+        Ok(summary) => summary,
         Err(error) => {
-            tracing::error!(error = %error, "failed to fetch existing subscription");
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Error syncing Stripe session",
-            )
-                .into_response();
+            tracing::error!(error = ?error, "failed to fetch profile usage data");
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Error fetching profile").into_response();
         }
     };
 
-    let action_name = if existing_subscription.is_some() {
-        "subscriptions:updateSubscription"
-    } else {
-        "subscriptions:createSubscription"
-    };
-
-    if let Err(error) = state
-        .convex
-        .action_value(
-            action_name,
-            json!({
-                "userId": &user.clerk_id,
-                "plan": plan_id.as_str(),
-                "status": "active",
-                "stripeSubscriptionId": subscription_id,
-                "stripePriceId": price_id,
-            }),
-        )
-        .await
-    {
-        tracing::error!(error = %error, "failed to sync subscription in Convex");
-        return (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "Error syncing Stripe session",
-        )
-            .into_response();
-    }
+    let subscription_status = summary
+        .subscription
+        .as_ref()
+        .and_then(|subscription| subscription.status.clone())
+        .unwrap_or_else(|| "inactive".to_string());
 
     (
         StatusCode::OK,
-        Json(json!({ "message": "Subscription synced successfully." })),
+        Json(ProfileBody {
+            clerk_id: user.clerk_id,
+            email,
+            plan: summary.plan_id.as_str(),
+            subscription_status,
+            usage: UsageSummaryBody::from(&summary),
+        }),
     )
         .into_response()
 }
 
-pub async fn create_customer_portal_session(
-    State(state): State<AppState>,
-    Extension(user): Extension<AuthenticatedUser>,
-) -> Response {
-    let user_for_stripe: Option<ConvexUserForStripe> = match state
-        .convex
-        .action(
-            "users:getUserForStripe",
-            json!({ "clerkId": &user.clerk_id }),
-        )
-        .await
-    {
-        Ok(value) => value,
+// Clerk is the source of truth; the copy synced into Convex covers a Clerk
+// outage or a user without a primary address.
+async fn load_primary_email(state: &AppState, clerk_id: &str) -> Option<String> {
+    match state.clerk.get_primary_email(clerk_id).await {
+        Ok(Some(email)) => return Some(email),
+        Ok(None) => {}
         Err(error) => {
-            tracing::error!(error = %error, "failed to load user for portal session");
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Error creating customer portal session",
-            )
-                .into_response();
+            tracing::warn!(error = %error, user_id = %clerk_id, "failed to load Clerk user for profile");
         }
-    };
+    }
 
-    let user_for_stripe = match user_for_stripe {
-        Some(user) => user,
-        None => {
-            return (
-                StatusCode::BAD_REQUEST,
-                "User or Stripe Customer ID not found.",
-            )
-                .into_response()
+    match state.convex_api.get_user_for_stripe(clerk_id).await {
+        Ok(user) => user
+            .map(|user| user.email)
+            .filter(|email| !email.is_empty()),
+        Err(error) => {
+            tracing::warn!(error = %error, user_id = %clerk_id, "failed to load Convex user for profile");
+            None
         }
-    };
+    }
+}
 
-    let stripe_customer_id = match user_for_stripe.stripe_customer_id {
-        Some(value) => value,
-        None => {
-            return (
-                StatusCode::BAD_REQUEST,
-                "User or Stripe Customer ID not found.",
-            )
-                .into_response()
-        }
-    };
+struct UsageSummary {
+    plan_id: PlanId,
+    subscription: Option<Subscription>,
+    records: Vec<ConvexUsageRecord>,
+    total_units: i64,
+    units_this_month: i64,
+    pending_units: i64,
+    monthly_quota: Option<i64>,
+    remaining_units: Option<i64>,
+}
 
-    let return_url = format!(
-        "{}/dashboard",
-        state
-            .config
-            .frontend_url
-            .clone()
-            .unwrap_or_else(|| "".to_string())
-            .trim_end_matches('/')
-    );
+async fn load_usage_summary(state: &AppState, clerk_id: &str) -> anyhow::Result<UsageSummary> {
+    let mut records = state
+        .convex_api
+        .get_usage_records(clerk_id)
+        .await
+        .context("failed to fetch usage records")?;
 
-    let session = match state
-        .stripe
-        .create_billing_portal_session(&stripe_customer_id, &return_url)
+    let reservation_records = state
+        .convex_api
+        .get_usage_reservations(clerk_id)
         .await
-    {
-        Ok(session) => session,
-        Err(error) => {
-            tracing::error!(error = %error, "failed to create Stripe portal session");
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Error creating customer portal session",
-            )
-                .into_response();
+        .context("failed to fetch usage reservations")?;
+
+    let current_month = Utc::now().format("%Y-%m").to_string();
+
+    let mut total_units = 0i64;
+    let mut units_this_month = 0i64;
+    for record in &records {
+        total_units += record.count;
+        if record.date.starts_with(&current_month) {
+            units_this_month += record.count;
         }
-    };
+    }
 
-    match session.url {
-        Some(url) => (StatusCode::OK, Json(json!({ "url": url }))).into_response(),
-        None => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "Error creating Stripe customer portal session.",
-        )
-            .into_response(),
+    let now = Utc::now().timestamp_millis();
+    let mut pending_units = 0i64;
+    for reservation in reservation_records {
+        if reservation.status == "pending"
+            && reservation.date.starts_with(&current_month)
+            && reservation.expires_at > now
+        {
+            pending_units += reservation.units;
+        }
+    }
+
+    let subscription = state
+        .convex_api
+        .get_subscription(clerk_id)
+        .await
+        .context("failed to fetch subscription for usage")?;
+
+    let plan_id = effective_plan(subscription.as_ref());
+
+    let monthly_quota = plan_definition(plan_id).monthly_units;
+    let remaining_units =
+        monthly_quota.map(|quota| (quota - units_this_month - pending_units).max(0));
+
+    records.sort_by(|left, right| left.date.cmp(&right.date));
+
+    Ok(UsageSummary {
+        plan_id,
+        subscription,
+        records,
+        total_units,
+        units_this_month,
+        pending_units,
+        monthly_quota,
+        remaining_units,
+    })
+}
+
+fn wants_csv(format: Option<&str>, headers: &HeaderMap) -> bool {
+    if let Some(format) = format.map(|value| value.trim().to_ascii_lowercase()) {
+        return format == "csv";
     }
+
+    headers
+        .get(ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| {
+            value
+                .split(',')
+                .any(|entry| entry.split(';').next().unwrap_or_default().trim() == "text/csv")
+        })
+        .unwrap_or(false)
 }
 
-pub async fn handle_stripe_webhook(
+fn usage_csv_response(summary: &UsageSummary) -> Response {
+    let mut body = String::from("date,units,plan\r\n");
+    for record in &summary.records {
+        body.push_str(&csv_field(&record.date));
+        body.push(',');
+        body.push_str(&record.count.to_string());
+        body.push(',');
+        body.push_str(&csv_field(summary.plan_id.as_str()));
+        body.push_str("\r\n");
+    }
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/csv; charset=utf-8"),
+    );
+    let file_name = format!("usage-{}.csv", Utc::now().format("%Y-%m-%d"));
+    if let Ok(content_disposition) =
+        HeaderValue::from_str(&format!("attachment; filename=\"{}\"", file_name))
+    {
+        headers.insert(CONTENT_DISPOSITION, content_disposition);
+    }
+
+    (StatusCode::OK, headers, body).into_response()
+}
+
+const USAGE_REPORT_DEFAULT_PAGE_SIZE: usize = 100;
+const USAGE_REPORT_MAX_PAGE_SIZE: usize = 500;
+
+// JSON is paginated with `cursor`/`nextCursor`; CSV streams every page.
+pub async fn usage_report(
     State(state): State<AppState>,
+    Query(query): Query<UsageReportQuery>,
     headers: HeaderMap,
-    body: Bytes,
 ) -> Response {
-    let signature = match headers
-        .get("stripe-signature")
-        .and_then(|value| value.to_str().ok())
-    {
-        Some(value) => value,
-        None => return (StatusCode::BAD_REQUEST, "Missing Stripe signature.").into_response(),
+    let month = match parse_report_month(query.month.as_deref()) {
+        Ok(value) => value,
+        Err(message) => return bad_request(message, MessageCode::InvalidOption),
     };
+    let page_size = query
+        .limit
+        .unwrap_or(USAGE_REPORT_DEFAULT_PAGE_SIZE)
+        .clamp(1, USAGE_REPORT_MAX_PAGE_SIZE);
 
-    if let Err(error) = state.stripe.verify_webhook_signature(signature, &body) {
-        tracing::error!(error = %error, "Stripe webhook signature verification failed");
-        let message = error.to_string();
-        if message.contains("STRIPE_WEBHOOK_SECRET") {
-            return (StatusCode::INTERNAL_SERVER_ERROR, "Webhook not configured.").into_response();
+    if wants_csv(query.format.as_deref(), &headers) {
+        return usage_report_csv_response(state, month, page_size);
+    }
+
+    let page = match state
+        .convex_api
+        .get_usage_report_page(&month, query.cursor.as_deref(), page_size)
+        .await
+    {
+        Ok(page) => page,
+        Err(error) => {
+            tracing::error!(error = %error, month = %month, "failed to fetch usage report");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "Error fetching usage report" })),
+            )
+                .into_response();
         }
-        return (StatusCode::BAD_REQUEST, "Invalid signature.").into_response();
+    };
+
+    let rows = page
+        .rows
+        .into_iter()
+        .map(UsageReportRow::from_convex)
+        .collect::<Vec<_>>();
+    Json(json!({
+        "month": month,
+        "rows": rows,
+        "nextCursor": (!page.is_done).then_some(page.continue_cursor),
+    }))
+    .into_response()
+}
+
+// For cron: `month` defaults to the current one; pass the previous month right
+// after rollover to send its final total.
+pub async fn report_overage(
+    State(state): State<AppState>,
+    Query(query): Query<UsageReportQuery>,
+) -> Response {
+    let month = match parse_report_month(query.month.as_deref()) {
+        Ok(value) => value,
+        Err(message) => return bad_request(message, MessageCode::InvalidOption),
+    };
+    if state.config.stripe_metered_plans.is_empty() {
+        return (
+            StatusCode::CONFLICT,
+            Json(json!({ "error": "No plans are configured for metered billing." })),
+        )
+            .into_response();
     }
 
-    let event: StripeEvent = match serde_json::from_slice(&body) {
-        Ok(event) => event,
+    match crate::billing::report_overage(&state, &month).await {
+        Ok(summary) => Json(summary).into_response(),
         Err(error) => {
-            tracing::error!(error = %error, "invalid Stripe webhook payload");
-            return (StatusCode::BAD_REQUEST, "Invalid signature.").into_response();
+            tracing::error!(error = ?error, month = %month, "overage report failed");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "Error reporting overage" })),
+            )
+                .into_response()
         }
+    }
+}
+
+fn parse_report_month(raw: Option<&str>) -> Result<String, &'static str> {
+    let Some(raw) = raw.map(str::trim).filter(|value| !value.is_empty()) else {
+        return Ok(Utc::now().format("%Y-%m").to_string());
     };
+    chrono::NaiveDate::parse_from_str(&format!("{}-01", raw), "%Y-%m-%d")
+        .map(|date| date.format("%Y-%m").to_string())
+        .map_err(|_| "month must be YYYY-MM")
+}
 
-    let result = match event.event_type.as_str() {
-        "customer.subscription.created"
-        | "customer.subscription.updated"
-        | "customer.subscription.deleted" => {
-            let subscription: StripeSubscription = match serde_json::from_value(event.data.object) {
-                Ok(value) => value,
-                Err(error) => {
-                    tracing::error!(error = %error, "failed to decode subscription object");
-                    return (StatusCode::INTERNAL_SERVER_ERROR, "Webhook handler failed.")
-                        .into_response();
-                }
-            };
-            sync_subscription_from_stripe(&state, subscription).await
+// Plans come from each user's current subscription, not the one they had
+// during `month`.
+#[derive(Debug, Serialize)]
+struct UsageReportRow {
+    #[serde(rename = "clerkId")]
+    clerk_id: String,
+    plan: &'static str,
+    units: i64,
+    #[serde(rename = "monthlyQuota")]
+    monthly_quota: Option<i64>,
+    overage: i64,
+}
+
+impl UsageReportRow {
+    fn from_convex(row: ConvexUsageReportRow) -> Self {
+        let plan_id = effective_plan(row.subscription.as_ref());
+        let monthly_quota = plan_definition(plan_id).monthly_units;
+        Self {
+            clerk_id: row.clerk_id,
+            plan: plan_id.as_str(),
+            units: row.units,
+            monthly_quota,
+            overage: monthly_quota.map_or(0, |quota| (row.units - quota).max(0)),
         }
-        "invoice.payment_failed" | "invoice.payment_succeeded" => {
-            let invoice: StripeInvoice = match serde_json::from_value(event.data.object) {
-                Ok(value) => value,
+    }
+
+    fn csv_line(&self) -> String {
+        format!(
+            "{},{},{},{},{}\r\n",
+            csv_field(&self.clerk_id),
+            csv_field(self.plan),
+            self.units,
+            self.monthly_quota
+                .map(|quota| quota.to_string())
+                .unwrap_or_default(),
+            self.overage
+        )
+    }
+}
+
+// A Convex failure midway aborts the body so the download shows as failed
+// instead of silently truncated.
+fn usage_report_csv_response(state: AppState, month: String, page_size: usize) -> Response {
+    let file_name = format!("usage-report-{}.csv", month);
+    let header_line = Bytes::from_static(b"clerk_id,plan,units,monthly_quota,overage\r\n");
+    let pages = futures_util::stream::unfold(
+        Some((state, month, None::<String>)),
+        move |cursor_state| async move {
+            let (state, month, cursor) = cursor_state?;
+            match state
+                .convex_api
+                .get_usage_report_page(&month, cursor.as_deref(), page_size)
+                .await
+            {
+                Ok(page) => {
+                    let chunk = page
+                        .rows
+                        .into_iter()
+                        .map(|row| UsageReportRow::from_convex(row).csv_line())
+                        .collect::<String>();
+                    let next =
+                        (!page.is_done).then_some((state, month, Some(page.continue_cursor)));
+                    Some((Ok(Bytes::from(chunk)), next))
+                }
                 Err(error) => {
-                    tracing::error!(error = %error, "failed to decode invoice object");
-                    return (StatusCode::INTERNAL_SERVER_ERROR, "Webhook handler failed.")
-                        .into_response();
+                    tracing::error!(error = %error, month = %month, "usage report stream failed");
+                    Some((Err(std::io::Error::other(error.to_string())), None))
                 }
-            };
+            }
+        },
+    );
+    let body = futures_util::stream::once(async move { Ok(header_line) }).chain(pages);
 
-            if let Some(subscription_ref) = invoice.subscription {
-                let subscription_id = subscription_ref.id();
-                match state.stripe.retrieve_subscription(&subscription_id).await {
-                    Ok(subscription) => sync_subscription_from_stripe(&state, subscription).await,
-                    Err(error) => Err(error),
-                }
-            } else {
-                Ok(())
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/csv; charset=utf-8"),
+    );
+    if let Ok(content_disposition) =
+        HeaderValue::from_str(&format!("attachment; filename=\"{}\"", file_name))
+    {
+        headers.insert(CONTENT_DISPOSITION, content_disposition);
+    }
+
+    (StatusCode::OK, headers, Body::from_stream(body)).into_response()
+}
+
+fn csv_field(value: &str) -> String {
+    // Neutralize spreadsheet formula injection before applying RFC 4180 quoting.
+    let value = if value.starts_with(['=', '+', '-', '@']) {
+        format!("'{}", value)
+    } else {
+        value.to_string()
+    };
+
+    if value.contains([',', '"', '\r', '\n']) || value.starts_with('\'') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/stripe/create-checkout-session",
+    tag = "billing",
+    summary = "Start a Stripe Checkout session for a plan price",
+    security(("clerk" = [])),
+    request_body = CreateCheckoutRequest,
+    responses(
+        (status = 200, description = "`url` of the checkout page"),
+        (status = 400, description = "Missing parameters or unknown price"),
+    )
+)]
+pub async fn create_checkout_session(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthenticatedUser>,
+    Json(body): Json<CreateCheckoutRequest>,
+) -> Response {
+    let price_id = match body.price_id.filter(|value| !value.trim().is_empty()) {
+        Some(value) => value,
+        None => {
+            return (
+                StatusCode::BAD_REQUEST,
+                "Missing required parameters: priceId, successUrl, cancelUrl",
+            )
+                .into_response();
+        }
+    };
+    let success_url = match body.success_url.filter(|value| !value.trim().is_empty()) {
+        Some(value) => value,
+        None => {
+            return (
+                StatusCode::BAD_REQUEST,
+                "Missing required parameters: priceId, successUrl, cancelUrl",
+            )
+                .into_response();
+        }
+    };
+    let cancel_url = match body.cancel_url.filter(|value| !value.trim().is_empty()) {
+        Some(value) => value,
+        None => {
+            return (
+                StatusCode::BAD_REQUEST,
+                "Missing required parameters: priceId, successUrl, cancelUrl",
+            )
+                .into_response();
+        }
+    };
+
+    if state
+        .price_map
+        .get_offered_plan_for_price_id(&price_id)
+        .is_none()
+    {
+        return (
+            StatusCode::BAD_REQUEST,
+            "Unknown or unsupported Stripe price ID.",
+        )
+            .into_response();
+    }
+
+    let user_for_stripe: Option<ConvexUserForStripe> =
+        match state.convex_api.get_user_for_stripe(&user.clerk_id).await {
+            Ok(value) => value,
+            Err(error) => {
+                tracing::error!(error = %error, "failed to load user for Stripe checkout");
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Error creating checkout session",
+                )
+                    .into_response();
             }
+        };
+
+    let user_for_stripe = match user_for_stripe {
+        Some(value) => value,
+        None => {
+            return (StatusCode::NOT_FOUND, "User not found in Convex database.").into_response()
         }
-        _ => Ok(()),
     };
 
-    match result {
-        Ok(_) => (StatusCode::OK, Json(json!({ "received": true }))).into_response(),
+    let stripe_customer_id = match user_for_stripe.stripe_customer_id.clone() {
+        Some(customer_id) => customer_id,
+        None => match ensure_stripe_customer(&state, &user_for_stripe).await {
+            Ok(customer_id) => customer_id,
+            Err(error) => {
+                tracing::error!(error = %error, "failed to create Stripe customer");
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Error creating checkout session",
+                )
+                    .into_response();
+            }
+        },
+    };
+
+    let session = match state
+        .stripe
+        .create_checkout_session(&stripe_customer_id, &price_id, &success_url, &cancel_url)
+        .await
+    {
+        Ok(session) => session,
         Err(error) => {
-            tracing::error!(error = %error, "Stripe webhook handling failed");
-            (StatusCode::INTERNAL_SERVER_ERROR, "Webhook handler failed.").into_response()
+            tracing::error!(error = %error, "failed to create Stripe checkout session");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Error creating checkout session",
+            )
+                .into_response();
         }
+    };
+
+    match session.url {
+        Some(url) => (StatusCode::OK, Json(json!({ "url": url }))).into_response(),
+        None => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Error creating Stripe checkout session.",
+        )
+            .into_response(),
     }
 }
 
-async fn sync_subscription_from_stripe(
+#[utoipa::path(
+    post,
+    path = "/api/stripe/sync-session",
+    tag = "billing",
+    summary = "Store the subscription from a completed checkout",
+    security(("clerk" = [])),
+    request_body = SyncStripeSessionRequest,
+    responses(
+        (status = 200, description = "Synced"),
+        (status = 400, description = "Session incomplete or unknown price"),
+    )
+)]
+pub async fn sync_stripe_session(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthenticatedUser>,
+    Json(body): Json<SyncStripeSessionRequest>,
+) -> Response {
+    let session_id = match body.session_id.filter(|value| !value.trim().is_empty()) {
+        Some(value) => value,
+        None => return (StatusCode::BAD_REQUEST, "Missing sessionId").into_response(),
+    };
+
+    let session = match state.stripe.retrieve_checkout_session(&session_id).await {
+        Ok(session) => session,
+        Err(error) => {
+            tracing::error!(error = %error, "failed to retrieve Stripe checkout session");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Error syncing Stripe session",
+            )
+                .into_response();
+        }
+    };
+
+    if session.status.as_deref() != Some("complete") {
+        return (StatusCode::BAD_REQUEST, "Checkout session not complete.").into_response();
+    }
+
+    let subscription_id = session.subscription.map(|value| value.id());
+    let price_id = session
+        .line_items
+        .as_ref()
+        .and_then(|line_items| line_items.data.first())
+        .and_then(|item| item.price.as_ref())
+        .and_then(|price| price.id.clone());
+
+    let (subscription_id, price_id) = match (subscription_id, price_id) {
+        (Some(subscription_id), Some(price_id)) => (subscription_id, price_id),
+        _ => {
+            return (
+                StatusCode::BAD_REQUEST,
+                "Could not find subscription or price ID in session.",
+            )
+                .into_response()
+        }
+    };
+
+    let plan_id = match state
+        .price_map
+        .get_plan_for_price_id(Some(price_id.as_str()))
+    {
+        Some(plan_id) => plan_id,
+        None => {
+            return (
+                StatusCode::BAD_REQUEST,
+                "Unknown or unsupported Stripe price ID.",
+            )
+                .into_response()
+        }
+    };
+
+    let user_exists: Option<ConvexUserForStripe> =
+        match state.convex_api.get_user_for_stripe(&user.clerk_id).await {
+            Ok(value) => value,
+            Err(error) => {
+                tracing::error!(error = %error, "failed to fetch user for Stripe sync");
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Error syncing Stripe session",
+                )
+                    .into_response();
+            }
+        };
+
+    if user_exists.is_none() {
+        return (StatusCode::NOT_FOUND, "User not found.").into_response();
+    }
+
+    let existing_subscription = match state.convex_api.get_subscription(&user.clerk_id).await {
+        Ok(value) => value,
+        Err(error) => {
+            tracing::error!(error = %error, "failed to fetch existing subscription");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Error syncing Stripe session",
+            )
+                .into_response();
+        }
+    };
+
+    let write = SubscriptionWrite {
+        plan: plan_id.as_str(),
+        status: "active",
+        stripe_subscription_id: Some(&subscription_id),
+        stripe_price_id: Some(&price_id),
+        ends_at: None,
+        metered_item_id: None,
+    };
+    if let Err(error) = state
+        .convex_api
+        .save_subscription(&user.clerk_id, existing_subscription.is_some(), &write)
+        .await
+    {
+        tracing::error!(error = %error, "failed to sync subscription in Convex");
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Error syncing Stripe session",
+        )
+            .into_response();
+    }
+
+    (
+        StatusCode::OK,
+        Json(json!({ "message": "Subscription synced successfully." })),
+    )
+        .into_response()
+}
+
+// Without an active Stripe subscription there is nothing to update, so the
+// client is told to start a checkout for the price instead.
+#[utoipa::path(
+    post,
+    path = "/api/subscription/change",
+    tag = "billing",
+    summary = "Switch the active subscription to another price",
+    security(("clerk" = [])),
+    request_body = ChangePlanRequest,
+    responses(
+        (status = 200, description = "`changed`, or `checkoutRequired` when there is no active subscription"),
+        (status = 400, description = "Unknown price or already on it"),
+        (status = 409, description = "Usage already exceeds the target plan's quota", body = ErrorBody),
+    )
+)]
+pub async fn change_subscription_plan(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthenticatedUser>,
+    Json(body): Json<ChangePlanRequest>,
+) -> Response {
+    let price_id = match body.price_id.filter(|value| !value.trim().is_empty()) {
+        Some(value) => value,
+        None => return (StatusCode::BAD_REQUEST, "Missing priceId").into_response(),
+    };
+    let target_plan = match state.price_map.get_offered_plan_for_price_id(&price_id) {
+        Some(plan_id) => plan_id,
+        None => {
+            return (
+                StatusCode::BAD_REQUEST,
+                "Unknown or unsupported Stripe price ID.",
+            )
+                .into_response()
+        }
+    };
+
+    let existing_subscription = match state.convex_api.get_subscription(&user.clerk_id).await {
+        Ok(value) => value,
+        Err(error) => {
+            tracing::error!(error = %error, "failed to fetch subscription for plan change");
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Error changing plan").into_response();
+        }
+    };
+    let stripe_subscription_id = match existing_subscription
+        .as_ref()
+        .filter(|subscription| subscription.is_active())
+        .and_then(|subscription| subscription.stripe_subscription_id.clone())
+    {
+        Some(value) => value,
+        None => {
+            return (
+                StatusCode::OK,
+                Json(json!({
+                    "changed": false,
+                    "checkoutRequired": true,
+                    "priceId": price_id,
+                })),
+            )
+                .into_response()
+        }
+    };
+    if existing_subscription
+        .as_ref()
+        .and_then(|subscription| subscription.stripe_price_id.as_deref())
+        == Some(price_id.as_str())
+    {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "The subscription already uses this price." })),
+        )
+            .into_response();
+    }
+
+    let current_plan = effective_plan(existing_subscription.as_ref());
+    let over_quota =
+        match downgrade_overage(&state, &user.clerk_id, current_plan, target_plan).await {
+            Ok(value) => value,
+            Err(error) => {
+                tracing::error!(error = ?error, "failed to load usage for plan change");
+                return (StatusCode::INTERNAL_SERVER_ERROR, "Error changing plan").into_response();
+            }
+        };
+    if let Some(details) = &over_quota {
+        if state.config.downgrade_over_quota == DowngradePolicy::Block {
+            let mut body = details.clone();
+            body["error"] = json!("This month's usage already exceeds the new plan's quota.");
+            body["code"] = json!("downgrade_over_quota");
+            return (StatusCode::CONFLICT, Json(body)).into_response();
+        }
+    }
+
+    let stripe_subscription = match state
+        .stripe
+        .retrieve_subscription(&stripe_subscription_id)
+        .await
+    {
+        Ok(value) => value,
+        Err(error) => {
+            tracing::error!(error = %error, "failed to retrieve Stripe subscription for plan change");
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Error changing plan").into_response();
+        }
+    };
+    let item_id = match plan_subscription_item(&state, &stripe_subscription)
+        .and_then(|item| item.id.clone())
+    {
+        Some(value) => value,
+        None => {
+            tracing::error!(subscription_id = %stripe_subscription_id, "Stripe subscription has no plan item");
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Error changing plan").into_response();
+        }
+    };
+
+    let updated = match state
+        .stripe
+        .update_subscription_price(
+            &stripe_subscription_id,
+            &item_id,
+            &price_id,
+            body.proration_behavior,
+        )
+        .await
+    {
+        Ok(value) => value,
+        Err(error) => {
+            tracing::error!(error = %error, "failed to update Stripe subscription price");
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Error changing plan").into_response();
+        }
+    };
+    let status = updated.status.clone();
+
+    // The webhook will sync this too; doing it now means the response and the
+    // next quota check already see the new plan.
+    if let Err(error) = sync_subscription_from_stripe(&state, updated).await {
+        tracing::error!(error = %error, "failed to sync subscription after plan change");
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Error changing plan").into_response();
+    }
+
+    (
+        StatusCode::OK,
+        Json(json!({
+            "changed": true,
+            "checkoutRequired": false,
+            "plan": target_plan.as_str(),
+            "status": status,
+            "overQuota": over_quota,
+        })),
+    )
+        .into_response()
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/subscription/resync",
+    tag = "billing",
+    summary = "Re-read the caller's subscription from Stripe",
+    description = "Repairs a subscription that drifted after missed webhooks. Users without a Stripe customer or any Stripe subscription are reset to free.",
+    security(("clerk" = [])),
+    responses((status = 200, description = "The subscription as now stored"))
+)]
+pub async fn resync_subscription(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthenticatedUser>,
+) -> Response {
+    resync_subscription_response(&state, &user.clerk_id).await
+}
+
+// Support's version of the above, for any user by Clerk id.
+pub async fn admin_resync_subscription(
+    State(state): State<AppState>,
+    AxumPath(clerk_id): AxumPath<String>,
+) -> Response {
+    resync_subscription_response(&state, &clerk_id).await
+}
+
+async fn resync_subscription_response(state: &AppState, clerk_id: &str) -> Response {
+    match resync_subscription_for_user(state, clerk_id).await {
+        Ok(subscription) => {
+            let (plan, status) = match &subscription {
+                Some(subscription) => (
+                    subscription.effective_plan().as_str(),
+                    subscription.status.as_deref().unwrap_or("inactive"),
+                ),
+                None => (PlanId::Free.as_str(), "inactive"),
+            };
+            (
+                StatusCode::OK,
+                Json(json!({
+                    "plan": plan,
+                    "status": status,
+                    "stripeSubscriptionId": subscription
+                        .as_ref()
+                        .and_then(|subscription| subscription.stripe_subscription_id.as_deref()),
+                })),
+            )
+                .into_response()
+        }
+        Err(error) => {
+            tracing::error!(error = ?error, user_id = %clerk_id, "failed to resync subscription");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Error resyncing subscription",
+            )
+                .into_response()
+        }
+    }
+}
+
+// Prefers an active or trialing subscription, then the newest one so a
+// past_due or canceled status is recorded as Stripe has it.
+async fn resync_subscription_for_user(
     state: &AppState,
-    subscription: StripeSubscription,
-) -> anyhow::Result<()> {
-    let customer_id = subscription.customer.id();
+    clerk_id: &str,
+) -> anyhow::Result<Option<Subscription>> {
+    let customer_id = state
+        .convex_api
+        .get_user_for_stripe(clerk_id)
+        .await
+        .context("failed to load user for resync")?
+        .and_then(|user| user.stripe_customer_id);
 
-    let clerk_id = get_clerk_id_for_customer(state, &customer_id).await?;
-    let clerk_id = match clerk_id {
+    let mut subscriptions = match &customer_id {
+        Some(customer_id) => state
+            .stripe
+            .list_customer_subscriptions(customer_id)
+            .await
+            .context("failed to list Stripe subscriptions")?,
+        None => Vec::new(),
+    };
+
+    let chosen = subscriptions
+        .iter()
+        .position(|subscription| is_subscription_active(Some(&subscription.status)))
+        .or((!subscriptions.is_empty()).then_some(0))
+        .map(|index| subscriptions.swap_remove(index));
+
+    match chosen {
+        Some(subscription) => {
+            tracing::info!(
+                user_id = %clerk_id,
+                subscription_id = %subscription.id,
+                status = %subscription.status,
+                "resyncing subscription from Stripe"
+            );
+            sync_subscription_for_user(state, clerk_id, subscription).await?;
+        }
+        None => {
+            let existing = state.convex_api.get_subscription(clerk_id).await?;
+            if existing.is_some() {
+                tracing::info!(
+                    user_id = %clerk_id,
+                    customer_id = ?customer_id,
+                    "no Stripe subscription found; resetting to free"
+                );
+                let write = SubscriptionWrite {
+                    plan: PlanId::Free.as_str(),
+                    status: "canceled",
+                    stripe_subscription_id: None,
+                    stripe_price_id: None,
+                    ends_at: Some(None),
+                    metered_item_id: Some(None),
+                };
+                state
+                    .convex_api
+                    .save_subscription(clerk_id, true, &write)
+                    .await?;
+            }
+        }
+    }
+
+    Ok(state.convex_api.get_subscription(clerk_id).await?)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/stripe/create-customer-portal-session",
+    tag = "billing",
+    summary = "Open the Stripe customer portal",
+    security(("clerk" = [])),
+    responses((status = 200, description = "`url` of the portal session"))
+)]
+pub async fn create_customer_portal_session(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthenticatedUser>,
+) -> Response {
+    let user_for_stripe: Option<ConvexUserForStripe> =
+        match state.convex_api.get_user_for_stripe(&user.clerk_id).await {
+            Ok(value) => value,
+            Err(error) => {
+                tracing::error!(error = %error, "failed to load user for portal session");
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Error creating customer portal session",
+                )
+                    .into_response();
+            }
+        };
+
+    let user_for_stripe = match user_for_stripe {
+        Some(user) => user,
+        None => {
+            return (
+                StatusCode::BAD_REQUEST,
+                "User or Stripe Customer ID not found.",
+            )
+                .into_response()
+        }
+    };
+
+    let stripe_customer_id = match user_for_stripe.stripe_customer_id {
         Some(value) => value,
         None => {
-            tracing::warn!(customer_id = %customer_id, "Stripe webhook: missing clerkId metadata for customer");
-            return Ok(());
+            return (
+                StatusCode::BAD_REQUEST,
+                "User or Stripe Customer ID not found.",
+            )
+                .into_response()
+        }
+    };
+
+    let return_url = match state.config.frontend_link("dashboard") {
+        Some(value) => value,
+        None => {
+            tracing::error!("FRONTEND_URL is not configured; cannot build portal return URL");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Error creating customer portal session",
+            )
+                .into_response();
+        }
+    };
+
+    let session = match state
+        .stripe
+        .create_billing_portal_session(&stripe_customer_id, &return_url)
+        .await
+    {
+        Ok(session) => session,
+        Err(error) => {
+            tracing::error!(error = %error, "failed to create Stripe portal session");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Error creating customer portal session",
+            )
+                .into_response();
+        }
+    };
+
+    match session.url {
+        Some(url) => (StatusCode::OK, Json(json!({ "url": url }))).into_response(),
+        None => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Error creating Stripe customer portal session.",
+        )
+            .into_response(),
+    }
+}
+
+pub async fn handle_stripe_webhook(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let signature = match headers
+        .get("stripe-signature")
+        .and_then(|value| value.to_str().ok())
+    {
+        Some(value) => value,
+        None => return (StatusCode::BAD_REQUEST, "Missing Stripe signature.").into_response(),
+    };
+
+    if let Err(error) = state.stripe.verify_webhook_signature(signature, &body) {
+        tracing::error!(error = %error, "Stripe webhook signature verification failed");
+        let message = error.to_string();
+        if message.contains("STRIPE_WEBHOOK_SECRET") {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Webhook not configured.").into_response();
+        }
+        if message.contains("replay") {
+            return (StatusCode::BAD_REQUEST, "Webhook replay rejected.").into_response();
+        }
+        return (StatusCode::BAD_REQUEST, "Invalid signature.").into_response();
+    }
+
+    let event: StripeEvent = match serde_json::from_slice(&body) {
+        Ok(event) => event,
+        Err(error) => {
+            tracing::error!(error = %error, "invalid Stripe webhook payload");
+            return (StatusCode::BAD_REQUEST, "Invalid signature.").into_response();
+        }
+    };
+
+    let result = match event.event_type.as_str() {
+        "customer.subscription.created"
+        | "customer.subscription.updated"
+        | "customer.subscription.deleted" => {
+            let subscription: StripeSubscription = match serde_json::from_value(event.data.object) {
+                Ok(value) => value,
+                Err(error) => {
+                    tracing::error!(error = %error, "failed to decode subscription object");
+                    return (StatusCode::INTERNAL_SERVER_ERROR, "Webhook handler failed.")
+                        .into_response();
+                }
+            };
+            sync_subscription_from_stripe(&state, subscription).await
+        }
+        "customer.subscription.trial_will_end" => {
+            let subscription: StripeSubscription = match serde_json::from_value(event.data.object) {
+                Ok(value) => value,
+                Err(error) => {
+                    tracing::error!(error = %error, "failed to decode subscription object");
+                    return (StatusCode::INTERNAL_SERVER_ERROR, "Webhook handler failed.")
+                        .into_response();
+                }
+            };
+            let notification = NotificationWrite {
+                kind: "trial_will_end",
+                stripe_object_id: &subscription.id,
+                due_at: subscription.trial_end.map(|seconds| seconds * 1000),
+                action_url: None,
+            };
+            notify_stripe_customer(&state, &subscription.customer.id(), &notification).await
+        }
+        "invoice.payment_action_required" => {
+            let invoice: StripeInvoice = match serde_json::from_value(event.data.object) {
+                Ok(value) => value,
+                Err(error) => {
+                    tracing::error!(error = %error, "failed to decode invoice object");
+                    return (StatusCode::INTERNAL_SERVER_ERROR, "Webhook handler failed.")
+                        .into_response();
+                }
+            };
+            match (invoice.id.as_deref(), invoice.customer.as_ref()) {
+                (Some(invoice_id), Some(customer)) => {
+                    // The hosted invoice page is where the customer completes
+                    // the SCA challenge.
+                    let notification = NotificationWrite {
+                        kind: "payment_action_required",
+                        stripe_object_id: invoice_id,
+                        due_at: None,
+                        action_url: invoice.hosted_invoice_url.as_deref(),
+                    };
+                    notify_stripe_customer(&state, &customer.id(), &notification).await
+                }
+                _ => {
+                    tracing::warn!(
+                        "Stripe webhook: payment_action_required invoice without id or customer"
+                    );
+                    Ok(())
+                }
+            }
+        }
+        "invoice.payment_failed" | "invoice.payment_succeeded" => {
+            let invoice: StripeInvoice = match serde_json::from_value(event.data.object) {
+                Ok(value) => value,
+                Err(error) => {
+                    tracing::error!(error = %error, "failed to decode invoice object");
+                    return (StatusCode::INTERNAL_SERVER_ERROR, "Webhook handler failed.")
+                        .into_response();
+                }
+            };
+
+            if let Some(subscription_ref) = invoice.subscription {
+                let subscription_id = subscription_ref.id();
+                match state.stripe.retrieve_subscription(&subscription_id).await {
+                    Ok(subscription) => sync_subscription_from_stripe(&state, subscription).await,
+                    Err(error) => Err(error),
+                }
+            } else {
+                Ok(())
+            }
+        }
+        _ => Ok(()),
+    };
+
+    match result {
+        Ok(_) => (StatusCode::OK, Json(json!({ "received": true }))).into_response(),
+        Err(error) => {
+            tracing::error!(error = %error, "Stripe webhook handling failed");
+            (StatusCode::INTERNAL_SERVER_ERROR, "Webhook handler failed.").into_response()
+        }
+    }
+}
+
+pub async fn handle_clerk_webhook(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let svix_header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+    let (message_id, timestamp, signature) = match (
+        svix_header("svix-id"),
+        svix_header("svix-timestamp"),
+        svix_header("svix-signature"),
+    ) {
+        (Some(id), Some(timestamp), Some(signature)) => (id, timestamp, signature),
+        _ => return (StatusCode::BAD_REQUEST, "Missing Svix signature.").into_response(),
+    };
+
+    if let Err(error) = state
+        .clerk
+        .verify_webhook_signature(message_id, timestamp, signature, &body)
+    {
+        tracing::error!(error = %error, "Clerk webhook signature verification failed");
+        let message = error.to_string();
+        if message.contains("CLERK_WEBHOOK_SECRET") {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Webhook not configured.").into_response();
+        }
+        return (StatusCode::BAD_REQUEST, "Invalid signature.").into_response();
+    }
+
+    let event: ClerkWebhookEvent = match serde_json::from_slice(&body) {
+        Ok(event) => event,
+        Err(error) => {
+            tracing::error!(error = %error, "invalid Clerk webhook payload");
+            return (StatusCode::BAD_REQUEST, "Invalid payload.").into_response();
+        }
+    };
+
+    let result = match event.event_type.as_str() {
+        "user.created" | "user.updated" => match serde_json::from_value::<ClerkUser>(event.data) {
+            Ok(user) => sync_user_from_clerk(&state, user).await,
+            Err(error) => Err(anyhow::Error::new(error).context("failed to decode Clerk user")),
+        },
+        "user.deleted" => match serde_json::from_value::<ClerkDeletedObject>(event.data) {
+            Ok(ClerkDeletedObject { id: Some(clerk_id) }) => {
+                state.clerk.forget_primary_email(&clerk_id);
+                state.forget_user_sync(&clerk_id);
+                state
+                    .convex_api
+                    .remove_user(&clerk_id)
+                    .await
+                    .map_err(anyhow::Error::from)
+            }
+            Ok(_) => Ok(()),
+            Err(error) => Err(anyhow::Error::new(error).context("failed to decode deleted user")),
+        },
+        _ => Ok(()),
+    };
+
+    match result {
+        Ok(_) => (StatusCode::OK, Json(json!({ "received": true }))).into_response(),
+        Err(error) => {
+            tracing::error!(error = %error, "Clerk webhook handling failed");
+            (StatusCode::INTERNAL_SERVER_ERROR, "Webhook handler failed.").into_response()
+        }
+    }
+}
+
+async fn sync_user_from_clerk(state: &AppState, user: ClerkUser) -> anyhow::Result<()> {
+    let clerk_id = user.id.clone();
+    let email = user.primary_email();
+    state.clerk.cache_primary_email(&clerk_id, email.clone());
+
+    match email {
+        Some(email) => {
+            state.convex_api.sync_user(&clerk_id, &email).await?;
+        }
+        None => {
+            tracing::warn!(user_id = %clerk_id, "Clerk webhook: user has no primary email");
+        }
+    }
+    Ok(())
+}
+
+async fn sync_subscription_from_stripe(
+    state: &AppState,
+    subscription: StripeSubscription,
+) -> anyhow::Result<()> {
+    let customer_id = subscription.customer.id();
+
+    let clerk_id = get_clerk_id_for_customer(state, &customer_id).await?;
+    let clerk_id = match clerk_id {
+        Some(value) => value,
+        None => {
+            tracing::warn!(customer_id = %customer_id, "Stripe webhook: missing clerkId metadata for customer");
+            return Ok(());
+        }
+    };
+
+    sync_subscription_for_user(state, &clerk_id, subscription).await
+}
+
+async fn sync_subscription_for_user(
+    state: &AppState,
+    clerk_id: &str,
+    subscription: StripeSubscription,
+) -> anyhow::Result<()> {
+    let metered_item_id = subscription
+        .items
+        .data
+        .iter()
+        .find(|item| is_metered_item(state, item))
+        .and_then(|item| item.id.clone());
+    let price_id = plan_subscription_item(state, &subscription)
+        .and_then(|item| item.price.as_ref())
+        .and_then(|price| price.id.clone());
+
+    let existing_subscription = state.convex_api.get_subscription(clerk_id).await?;
+
+    let plan_from_price = state.price_map.get_plan_for_price_id(price_id.as_deref());
+    let plan_id = match (plan_from_price, existing_subscription.as_ref()) {
+        (Some(plan_id), _) => Some(plan_id),
+        (None, Some(subscription)) => Some(subscription.plan_id()),
+        (None, None) => None,
+    };
+
+    let plan_id = match plan_id {
+        Some(value) => value,
+        None => {
+            tracing::warn!(price_id = ?price_id, "Stripe webhook: unable to resolve plan for price");
+            return Ok(());
+        }
+    };
+
+    let ends_at = subscription
+        .current_period_end
+        .map(|seconds| seconds * 1000);
+
+    // A missing period end is written as an explicit clear so a stale value
+    // from an earlier period doesn't linger.
+    let write = SubscriptionWrite {
+        plan: plan_id.as_str(),
+        status: &subscription.status,
+        stripe_subscription_id: Some(&subscription.id),
+        stripe_price_id: price_id.as_deref(),
+        ends_at: Some(ends_at),
+        metered_item_id: Some(metered_item_id.as_deref()),
+    };
+    state
+        .convex_api
+        .save_subscription(clerk_id, existing_subscription.is_some(), &write)
+        .await?;
+
+    Ok(())
+}
+
+async fn notify_stripe_customer(
+    state: &AppState,
+    customer_id: &str,
+    notification: &NotificationWrite<'_>,
+) -> anyhow::Result<()> {
+    let clerk_id = match get_clerk_id_for_customer(state, customer_id).await? {
+        Some(value) => value,
+        None => {
+            tracing::warn!(customer_id = %customer_id, kind = notification.kind, "Stripe webhook: missing clerkId metadata for customer");
+            return Ok(());
+        }
+    };
+
+    state
+        .convex_api
+        .record_notification(&clerk_id, notification)
+        .await?;
+    Ok(())
+}
+
+// Committed plus pending units count, so jobs still running can't slip a
+// downgrade past the check. `None` for upgrades or when usage still fits.
+async fn downgrade_overage(
+    state: &AppState,
+    clerk_id: &str,
+    current_plan: PlanId,
+    target_plan: PlanId,
+) -> anyhow::Result<Option<Value>> {
+    let Some(target_quota) = plan_definition(target_plan).monthly_units else {
+        return Ok(None);
+    };
+    let is_downgrade = plan_definition(current_plan)
+        .monthly_units
+        .is_none_or(|quota| quota > target_quota);
+    if !is_downgrade {
+        return Ok(None);
+    }
+
+    let summary = load_usage_summary(state, clerk_id).await?;
+    let used = summary.units_this_month + summary.pending_units;
+    if used <= target_quota {
+        return Ok(None);
+    }
+    Ok(Some(json!({
+        "currentPlan": current_plan.as_str(),
+        "targetPlan": target_plan.as_str(),
+        "unitsThisMonth": summary.units_this_month,
+        "pendingUnits": summary.pending_units,
+        "targetMonthlyQuota": target_quota,
+        "overage": used - target_quota,
+    })))
+}
+
+// The overage item is billed alongside the plan item; it never decides the
+// plan.
+fn is_metered_item(state: &AppState, item: &StripeSubscriptionItem) -> bool {
+    let metered_price_id = state.config.stripe_metered_price_id.as_deref();
+    metered_price_id.is_some()
+        && item.price.as_ref().and_then(|price| price.id.as_deref()) == metered_price_id
+}
+
+fn plan_subscription_item<'a>(
+    state: &AppState,
+    subscription: &'a StripeSubscription,
+) -> Option<&'a StripeSubscriptionItem> {
+    subscription
+        .items
+        .data
+        .iter()
+        .find(|item| !is_metered_item(state, item))
+}
+
+// Re-reads the user under the per-user lock: a concurrent checkout may have
+// created and stored the customer while we were waiting.
+async fn ensure_stripe_customer(
+    state: &AppState,
+    user: &ConvexUserForStripe,
+) -> anyhow::Result<String> {
+    let _guard = state.lock_customer_creation(&user.clerk_id).await;
+
+    let latest: Option<ConvexUserForStripe> = state
+        .convex_api
+        .get_user_for_stripe(&user.clerk_id)
+        .await
+        .context("failed to reload user for Stripe customer creation")?;
+    if let Some(customer_id) = latest.and_then(|value| value.stripe_customer_id) {
+        return Ok(customer_id);
+    }
+
+    let customer = state
+        .stripe
+        .create_customer(&user.email, &user.clerk_id)
+        .await?;
+
+    state
+        .convex_api
+        .set_stripe_customer_id(&user.clerk_id, &customer.id)
+        .await
+        .context("failed to persist Stripe customer id")?;
+
+    Ok(customer.id)
+}
+
+async fn get_clerk_id_for_customer(
+    state: &AppState,
+    customer_id: &str,
+) -> anyhow::Result<Option<String>> {
+    let customer = state.stripe.retrieve_customer(customer_id).await?;
+    if customer.deleted {
+        return Ok(None);
+    }
+    Ok(customer.metadata.get("clerkId").cloned())
+}
+
+async fn preflight_for_clerk_user(
+    state: AppState,
+    clerk_id: &str,
+    multipart: Multipart,
+    max_upload_size_bytes: usize,
+    options: AnalysisOptions,
+) -> Response {
+    let mut uploaded = match save_pdf_from_multipart(
+        multipart,
+        max_upload_size_bytes,
+        Some((&state.uploads, clerk_id)),
+    )
+    .await
+    {
+        Ok(file) => file,
+        Err(error) => return upload_error_to_response(error),
+    };
+    if let Err(response) = ensure_pdf_input(&state, uploaded.kind, &mut uploaded.temp_path).await {
+        return response;
+    }
+
+    preflight_uploaded_for_clerk_user(state, clerk_id, uploaded, options).await
+}
+
+async fn preflight_uploaded_for_clerk_user(
+    state: AppState,
+    clerk_id: &str,
+    uploaded: UploadedFile,
+    options: AnalysisOptions,
+) -> Response {
+    maybe_log_upload_throughput(
+        state.config.log_processing_timings,
+        uploaded.size_bytes,
+        uploaded.upload_ms,
+    );
+
+    let temp_path = uploaded.temp_path.clone();
+    let original_name = uploaded.original_name.clone();
+    let clerk_id = clerk_id.to_string();
+
+    let result = state
+        .run_ghostscript_job("preflight", || {
+            reserve_and_analyze(&state, &clerk_id, &temp_path, options.sampling)
+        })
+        .await;
+
+    remove_file_if_exists(&temp_path).await;
+
+    match result {
+        Ok(PreflightOutcome::Analysis {
+            mut analysis,
+            reservation,
+            units,
+        }) => {
+            analysis.file_name = original_name;
+            with_quota_headers(
+                analysis_response(&analysis, options.format),
+                &reservation,
+                units,
+            )
+        }
+        Ok(PreflightOutcome::QuotaExceeded { reservation, units }) => {
+            quota_exceeded_response(reservation, units)
+        }
+        Err(error) => {
+            tracing::error!(error = ?error, "preflight failed");
+            processing_error_response(&error)
+        }
+    }
+}
+
+// Full inkcov analysis billed at two units per page: reserved up front,
+// committed once the analysis succeeds and released if it fails.
+async fn reserve_and_analyze(
+    state: &AppState,
+    clerk_id: &str,
+    temp_path: &Path,
+    sampling: InkcovSampling,
+) -> anyhow::Result<PreflightOutcome> {
+    let page_count = get_pdf_page_count(temp_path).await?;
+    let units = page_count * 2;
+    let reservation = reserve_units_for_clerk_user(
+        &state.convex_api,
+        clerk_id,
+        units,
+        state.config.reservation_ttl_secs,
+    )
+    .await?;
+    if !reservation.allowed {
+        return Ok(PreflightOutcome::QuotaExceeded { reservation, units });
+    }
+
+    let reservation_id = reservation
+        .reservation_id
+        .clone()
+        .ok_or_else(|| anyhow::anyhow!("Failed to create usage reservation."))?;
+
+    match analyze_pdf(temp_path, Some(page_count), sampling).await {
+        Ok(analysis) => {
+            let commit_result =
+                commit_reservation_for_clerk_user(&state.convex_api, clerk_id, &reservation_id)
+                    .await?;
+            if !commit_result.committed {
+                tracing::warn!("Usage reservation commit failed");
+            }
+
+            Ok(PreflightOutcome::Analysis {
+                analysis: Box::new(analysis),
+                reservation,
+                units,
+            })
+        }
+        Err(error) => {
+            let _ =
+                release_reservation_for_clerk_user(&state.convex_api, clerk_id, &reservation_id)
+                    .await;
+            Err(error)
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+enum ProfileFormat {
+    Raw,
+    Percent,
+}
+
+impl ProfileFormat {
+    fn parse(raw: Option<&str>) -> Result<Self, &'static str> {
+        let normalized = raw
+            .map(|value| value.trim().to_ascii_lowercase())
+            .unwrap_or_default();
+        match normalized.as_str() {
+            "" | "raw" => Ok(Self::Raw),
+            "percent" => Ok(Self::Percent),
+            _ => Err("Invalid format. Use \"raw\" or \"percent\"."),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct AnalysisOptions {
+    format: ProfileFormat,
+    sampling: InkcovSampling,
+}
+
+impl AnalysisOptions {
+    // `sample` overrides the INKCOV_SAMPLE default for this request.
+    fn from_query(
+        query: &AnalysisQuery,
+        config: &Config,
+    ) -> Result<Self, (&'static str, MessageCode)> {
+        let format = ProfileFormat::parse(query.format.as_deref())
+            .map_err(|message| (message, MessageCode::InvalidProfile))?;
+        let sampling = match query.sample.as_deref() {
+            Some(raw) => InkcovSampling::parse(raw)
+                .map_err(|message| (message, MessageCode::InvalidSample))?,
+            None => config.inkcov_sampling,
+        };
+        Ok(Self { format, sampling })
+    }
+}
+
+// Option errors carry a code so `localize_errors` can translate them.
+fn bad_request(message: impl std::fmt::Display, code: MessageCode) -> Response {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(json!({ "error": message.to_string(), "code": code.code() })),
+    )
+        .into_response()
+}
+
+fn processing_error_response(error: &anyhow::Error) -> Response {
+    if let Some(input_error) = error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<PdfInputError>())
+    {
+        return (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(json!({ "error": input_error.to_string(), "code": input_error.code() })),
+        )
+            .into_response();
+    }
+
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!({ "error": error.to_string() })),
+    )
+        .into_response()
+}
+
+fn analysis_response(analysis: &PdfAnalysis, format: ProfileFormat) -> Response {
+    match format {
+        ProfileFormat::Raw => Json(analysis).into_response(),
+        ProfileFormat::Percent => {
+            let mut body = json!(analysis);
+            body["colorProfiles"] = json!(analysis
+                .color_profiles
+                .iter()
+                .map(|profile| profile.to_percentages(COLOR_COVERAGE_EPSILON))
+                .collect::<Vec<_>>());
+            Json(body).into_response()
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+enum GrayscaleMode {
+    Preview,
+    Production,
+}
+
+impl GrayscaleMode {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Preview => "preview",
+            Self::Production => "production",
+        }
+    }
+
+    fn parse(raw: Option<&str>) -> Result<Self, &'static str> {
+        let normalized = raw
+            .map(|value| value.trim().to_ascii_lowercase())
+            .unwrap_or_default();
+        if normalized.is_empty() || normalized == "preview" {
+            return Ok(Self::Preview);
+        }
+        if normalized == "production" {
+            return Ok(Self::Production);
+        }
+        Err("Invalid mode. Use \"preview\" or \"production\".")
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+enum GrayscaleEngine {
+    Ghostscript,
+    Mupdf,
+}
+
+impl GrayscaleEngine {
+    fn parse(raw: Option<&str>) -> Result<Self, &'static str> {
+        let normalized = raw
+            .map(|value| value.trim().to_ascii_lowercase())
+            .unwrap_or_default();
+        if normalized.is_empty() || normalized == "ghostscript" || normalized == "gs" {
+            return Ok(Self::Ghostscript);
+        }
+        if normalized == "mupdf" || normalized == "mutool" {
+            return Ok(Self::Mupdf);
+        }
+        Err("Invalid engine. Use \"ghostscript\" or \"mupdf\".")
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct BlackControls {
+    force_black_text: bool,
+    force_black_vector: bool,
+    black_threshold_l: Option<f64>,
+    black_threshold_c: Option<f64>,
+}
+
+impl BlackControls {
+    // Request fields take precedence; anything omitted falls back to the
+    // GRAYSCALE_PRODUCTION_* config defaults.
+    fn from_request(
+        state: &AppState,
+        uploaded: &UploadedPdfRequest,
+    ) -> Result<Self, (String, MessageCode)> {
+        let config = &state.config;
+        let invalid_option = |message| (message, MessageCode::InvalidOption);
+        let invalid_threshold = |message| (message, MessageCode::InvalidBlackThreshold);
+        let force_black_text = match uploaded.force_black_text.as_deref() {
+            Some(raw) => parse_bool_field(Some(raw), "forceBlackText").map_err(invalid_option)?,
+            None => config.grayscale_production_force_black_text,
+        };
+        let force_black_vector = match uploaded.force_black_vector.as_deref() {
+            Some(raw) => parse_bool_field(Some(raw), "forceBlackVector").map_err(invalid_option)?,
+            None => config.grayscale_production_force_black_vector,
+        };
+        let forcing = force_black_text || force_black_vector;
+        // Config thresholds only matter while forcing is on, so drop them rather
+        // than rejecting a request that merely turned both flags off.
+        let black_threshold_l = match uploaded.black_threshold_l.as_deref() {
+            Some(raw) => Some(
+                parse_ranged_f64(raw, "blackThresholdL", BLACK_THRESHOLD_L_RANGE)
+                    .map_err(invalid_threshold)?,
+            ),
+            None => config
+                .grayscale_production_black_threshold_l
+                .filter(|_| forcing),
+        };
+        let black_threshold_c = match uploaded.black_threshold_c.as_deref() {
+            Some(raw) => Some(
+                parse_ranged_f64(raw, "blackThresholdC", BLACK_THRESHOLD_C_RANGE)
+                    .map_err(invalid_threshold)?,
+            ),
+            None => config
+                .grayscale_production_black_threshold_c
+                .filter(|_| forcing),
+        };
+        validate_black_controls(
+            force_black_text,
+            force_black_vector,
+            black_threshold_l,
+            black_threshold_c,
+        )
+        .map_err(|error| invalid_threshold(error.to_string()))?;
+
+        Ok(Self {
+            force_black_text,
+            force_black_vector,
+            black_threshold_l,
+            black_threshold_c,
+        })
+    }
+}
+
+fn parse_ranged_f64(raw: &str, name: &str, (min, max): (f64, f64)) -> Result<f64, String> {
+    raw.trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|value| value.is_finite() && *value >= min && *value <= max)
+        .ok_or_else(|| format!("{} must be a number between {} and {}.", name, min, max))
+}
+
+async fn grayscale_for_clerk_user(
+    state: AppState,
+    clerk_id: &str,
+    multipart: Multipart,
+    debug_timings: bool,
+    run_async: bool,
+) -> Response {
+    let total_started = Instant::now();
+    let mut timings = ProcessingTimings::new(
+        "grayscale",
+        state.config.log_processing_timings,
+        debug_timings,
+    );
+
+    let upload_started = Instant::now();
+    let upload_limit_bytes = PROCESSING_UPLOAD_LIMIT_BYTES;
+    let mut uploaded = match save_pdf_with_mode_from_multipart(
+        multipart,
+        upload_limit_bytes,
+        &state.uploads,
+        clerk_id,
+    )
+    .await
+    {
+        Ok(file) => file,
+        Err(error) => return upload_error_to_response(error),
+    };
+    timings.record("upload", upload_started);
+    if let Err(response) = ensure_pdf_input(&state, uploaded.kind, &mut uploaded.temp_path).await {
+        return response;
+    }
+    maybe_log_upload_throughput(
+        state.config.log_processing_timings,
+        uploaded.size_bytes,
+        uploaded.upload_ms,
+    );
+
+    let temp_path = uploaded.temp_path.clone();
+    let original_name = uploaded.original_name.clone();
+    let mode = match GrayscaleMode::parse(uploaded.mode.as_deref()) {
+        Ok(value) => value,
+        Err(message) => {
+            remove_file_if_exists(&temp_path).await;
+            return bad_request(message, MessageCode::InvalidOption);
+        }
+    };
+    let engine = match GrayscaleEngine::parse(uploaded.engine.as_deref()) {
+        Ok(value) => value,
+        Err(message) => {
+            remove_file_if_exists(&temp_path).await;
+            return bad_request(message, MessageCode::InvalidOption);
+        }
+    };
+    let dry_run = match parse_bool_field(uploaded.dry_run.as_deref(), "dryRun") {
+        Ok(value) => value,
+        Err(message) => {
+            remove_file_if_exists(&temp_path).await;
+            return bad_request(message, MessageCode::InvalidOption);
+        }
+    };
+    let retain = match parse_bool_field(uploaded.retain.as_deref(), "retain") {
+        Ok(value) => value,
+        Err(message) => {
+            remove_file_if_exists(&temp_path).await;
+            return bad_request(message, MessageCode::InvalidOption);
+        }
+    };
+    let flatten = match parse_bool_field(uploaded.flatten.as_deref(), "flatten") {
+        Ok(value) => value,
+        Err(message) => {
+            remove_file_if_exists(&temp_path).await;
+            return bad_request(message, MessageCode::InvalidOption);
+        }
+    };
+    let preserve_bookmarks =
+        match parse_bool_field(uploaded.preserve_bookmarks.as_deref(), "preserveBookmarks") {
+            Ok(value) => value,
+            Err(message) => {
+                remove_file_if_exists(&temp_path).await;
+                return bad_request(message, MessageCode::InvalidOption);
+            }
+        };
+    let preserve_annotations = match uploaded.preserve_annotations.as_deref() {
+        None => matches!(mode, GrayscaleMode::Production),
+        raw => match parse_bool_field(raw, "preserveAnnotations") {
+            Ok(value) => value,
+            Err(message) => {
+                remove_file_if_exists(&temp_path).await;
+                return bad_request(message, MessageCode::InvalidOption);
+            }
+        },
+    };
+    let pdf_version = match uploaded.pdf_version.as_deref().map(str::trim) {
+        None | Some("") => None,
+        Some(raw) => match PdfVersion::parse(raw) {
+            Ok(value) => Some(value),
+            Err(message) => {
+                remove_file_if_exists(&temp_path).await;
+                return bad_request(message, MessageCode::InvalidPdfVersion);
+            }
+        },
+    };
+    if pdf_version.is_some() && matches!(engine, GrayscaleEngine::Mupdf) {
+        remove_file_if_exists(&temp_path).await;
+        return bad_request(
+            "pdfVersion requires the ghostscript engine.",
+            MessageCode::InvalidPdfVersion,
+        );
+    }
+    tracing::info!(mode = ?mode, engine = ?engine, dry_run, retain, "grayscale conversion request");
+    let black_controls = match BlackControls::from_request(&state, &uploaded) {
+        Ok(value) => value,
+        Err((message, code)) => {
+            remove_file_if_exists(&temp_path).await;
+            return bad_request(message, code);
+        }
+    };
+    let BlackControls {
+        force_black_text,
+        force_black_vector,
+        ..
+    } = black_controls;
+
+    // Billed like preflight, which does the same inkcov pass.
+    if dry_run {
+        let result = state
+            .run_ghostscript_job("grayscale-dry-run", || {
+                reserve_and_analyze(&state, clerk_id, &temp_path, InkcovSampling::All)
+            })
+            .await;
+        remove_file_if_exists(&temp_path).await;
+
+        return match result {
+            Ok(PreflightOutcome::Analysis {
+                analysis,
+                reservation,
+                units,
+            }) => with_quota_headers(
+                (
+                    StatusCode::OK,
+                    Json(grayscale_dry_run_report(
+                        &original_name,
+                        mode,
+                        force_black_text,
+                        force_black_vector,
+                        &analysis,
+                    )),
+                )
+                    .into_response(),
+                &reservation,
+                units,
+            ),
+            Ok(PreflightOutcome::QuotaExceeded { reservation, units }) => {
+                quota_exceeded_response(reservation, units)
+            }
+            Err(error) => {
+                tracing::error!(error = %error, "grayscale dry run failed");
+                processing_error_response(&error)
+            }
+        };
+    }
+
+    let base_name = sanitize_base_name(
+        Path::new(&original_name)
+            .file_stem()
+            .and_then(|value| value.to_str())
+            .unwrap_or("document"),
+    );
+    let output_name = format!("{}-grayscale.pdf", base_name);
+    let output_path =
+        track_temp_path(work_dir().join(format!("{}-{}-grayscale.pdf", base_name, Uuid::new_v4())));
+
+    let clerk_id = clerk_id.to_string();
+
+    let page_count_started = Instant::now();
+    let page_count = match state
+        .run_ghostscript_job("grayscale-page-count", || async {
+            get_pdf_page_count(&temp_path).await
+        })
+        .await
+    {
+        Ok(value) => value,
+        Err(error) => {
+            tracing::error!(error = %error, "failed to get page count for grayscale");
+            remove_file_if_exists(&temp_path).await;
+            remove_file_if_exists(&output_path).await;
+            return processing_error_response(&error);
+        }
+    };
+
+    maybe_log_ghostscript_timing(
+        state.config.log_ghostscript_timings,
+        "page-count",
+        page_count_started,
+    );
+    timings.record("page-count", page_count_started);
+
+    // Cheap inkcov-only pass; skipping it is the default so existing
+    // documents are never charged an extra Ghostscript run.
+    let already_grayscale = match state.config.already_grayscale_action {
+        AlreadyGrayscaleAction::Convert => false,
+        AlreadyGrayscaleAction::Annotate | AlreadyGrayscaleAction::Skip => {
+            let check_started = Instant::now();
+            let profiles = state
+                .run_ghostscript_job("grayscale-color-check", || async {
+                    get_color_profiles(&temp_path, page_count).await
+                })
+                .await;
+            timings.record("color-check", check_started);
+            match profiles {
+                Ok(profiles) => profiles.iter().all(|profile| {
+                    profile.c <= COLOR_COVERAGE_EPSILON
+                        && profile.m <= COLOR_COVERAGE_EPSILON
+                        && profile.y <= COLOR_COVERAGE_EPSILON
+                }),
+                Err(error) => {
+                    tracing::warn!(error = %error, "color pre-check failed; converting anyway");
+                    false
+                }
+            }
+        }
+    };
+    // Returning the input as-is can't honour a requested PDF version.
+    let passthrough = already_grayscale
+        && state.config.already_grayscale_action == AlreadyGrayscaleAction::Skip
+        && pdf_version.is_none();
+
+    let units = if passthrough {
+        ALREADY_GRAYSCALE_UNITS
+    } else {
+        page_count
+    };
+    let reserve_started = Instant::now();
+    let reservation = match reserve_units_for_clerk_user(
+        &state.convex_api,
+        &clerk_id,
+        units,
+        state.config.reservation_ttl_secs,
+    )
+    .await
+    {
+        Ok(value) => value,
+        Err(error) => {
+            tracing::error!(error = ?error, "failed to reserve quota for grayscale");
+            remove_file_if_exists(&temp_path).await;
+            remove_file_if_exists(&output_path).await;
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "Failed to reserve usage quota." })),
+            )
+                .into_response();
+        }
+    };
+    timings.record("reserve", reserve_started);
+
+    if !reservation.allowed {
+        remove_file_if_exists(&temp_path).await;
+        remove_file_if_exists(&output_path).await;
+        return quota_exceeded_response(reservation, units);
+    }
+
+    let reservation_id = match reservation.reservation_id.clone() {
+        Some(value) => value,
+        None => {
+            remove_file_if_exists(&temp_path).await;
+            remove_file_if_exists(&output_path).await;
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "Failed to create usage reservation." })),
+            )
+                .into_response();
+        }
+    };
+
+    let conversion = GrayscaleConversion {
+        temp_path: temp_path.clone(),
+        output_path: output_path.clone(),
+        mode,
+        engine,
+        black_controls,
+        flatten,
+        preserve_bookmarks,
+        preserve_annotations,
+        pdf_version,
+        page_count,
+        passthrough,
+        watermark: (state.config.watermark_free_plan && reservation.plan_id == PlanId::Free)
+            .then(|| state.config.watermark_text.clone()),
+        upload_limit_bytes,
+    };
+
+    if run_async {
+        let job_id = state.jobs.create(&clerk_id);
+        tokio::spawn(run_grayscale_job(
+            state.clone(),
+            job_id,
+            clerk_id,
+            reservation_id,
+            conversion,
+            output_name,
+        ));
+
+        let mut response = (
+            StatusCode::ACCEPTED,
+            Json(json!({
+                "jobId": job_id,
+                "status": JobStatus::Queued,
+                "alreadyGrayscale": already_grayscale,
+                "statusUrl": format!("/api/process/jobs/{}", job_id),
+                "reservationExpiresAt": reservation.expires_at,
+            })),
+        )
+            .into_response();
+        if let Ok(value) = HeaderValue::from_str(&job_id.to_string()) {
+            response.headers_mut().insert("x-job-id", value);
+        }
+        return with_quota_headers(response, &reservation, units);
+    }
+
+    let warnings = match run_grayscale_conversion(&state, &conversion, &mut timings, || {}).await {
+        Ok(value) => value,
+        Err(error) => {
+            let _ =
+                release_reservation_for_clerk_user(&state.convex_api, &clerk_id, &reservation_id)
+                    .await;
+            tracing::error!(error = %error, "grayscale conversion failed");
+            remove_file_if_exists(&temp_path).await;
+            remove_file_if_exists(&output_path).await;
+            if error.downcast_ref::<OutputTooLarge>().is_some() {
+                return output_too_large_response();
+            }
+            return processing_error_response(&error);
+        }
+    };
+
+    let commit_started = Instant::now();
+    match commit_reservation_for_clerk_user(&state.convex_api, &clerk_id, &reservation_id).await {
+        Ok(result) => {
+            if !result.committed {
+                tracing::warn!("Usage reservation commit failed");
+            }
+        }
+        Err(error) => {
+            tracing::warn!(error = %error, "failed to commit reservation");
+        }
+    }
+    timings.record("commit", commit_started);
+
+    let read_started = Instant::now();
+    let pdf_bytes = match tokio::fs::read(&output_path).await {
+        Ok(bytes) => bytes,
+        Err(error) => {
+            tracing::error!(error = %error, "failed to read grayscale output");
+            remove_file_if_exists(&temp_path).await;
+            remove_file_if_exists(&output_path).await;
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "Failed to send grayscale PDF" })),
+            )
+                .into_response();
+        }
+    };
+    timings.record("read", read_started);
+
+    remove_file_if_exists(&temp_path).await;
+
+    let job_id = if retain {
+        match state
+            .results
+            .retain(&clerk_id, &output_path, &output_name)
+            .await
+        {
+            Ok(job_id) => Some(job_id),
+            Err(error) => {
+                tracing::warn!(error = %error, "failed to retain grayscale result");
+                remove_file_if_exists(&output_path).await;
+                None
+            }
+        }
+    } else {
+        remove_file_if_exists(&output_path).await;
+        None
+    };
+
+    timings.record("total", total_started);
+
+    let mut response = timings.apply(with_quota_headers(
+        pdf_attachment_response(&output_name, pdf_bytes),
+        &reservation,
+        units,
+    ));
+    if already_grayscale {
+        response
+            .headers_mut()
+            .insert("x-already-grayscale", HeaderValue::from_static("true"));
+    }
+    if !warnings.is_empty() {
+        if let Ok(value) = HeaderValue::from_str(&warnings.join("; ")) {
+            response
+                .headers_mut()
+                .insert("x-processing-warnings", value);
+        }
+    }
+    if let Some(job_id) = job_id {
+        if let Ok(value) = HeaderValue::from_str(&job_id.to_string()) {
+            response.headers_mut().insert("x-job-id", value);
+        }
+        response.headers_mut().insert(
+            "x-job-expires-in",
+            HeaderValue::from(state.results.ttl().as_secs()),
+        );
+    }
+    response
+}
+
+struct GrayscaleConversion {
+    temp_path: std::path::PathBuf,
+    output_path: std::path::PathBuf,
+    mode: GrayscaleMode,
+    engine: GrayscaleEngine,
+    black_controls: BlackControls,
+    flatten: bool,
+    // Check the outline before and after and warn if it was dropped.
+    preserve_bookmarks: bool,
+    // Same, for links and other annotations; also passed on to pdfwrite.
+    preserve_annotations: bool,
+    pdf_version: Option<PdfVersion>,
+    page_count: i64,
+    // Input is already grayscale: copy it through instead of converting.
+    passthrough: bool,
+    watermark: Option<String>,
+    upload_limit_bytes: usize,
+}
+
+const FLATTEN_INCOMPLETE_WARNING: &str = "Form fields remain after flattening.";
+const BOOKMARKS_LOST_WARNING: &str = "Bookmarks were lost during conversion.";
+const ANNOTATIONS_LOST_WARNING: &str = "Links or annotations were lost during conversion.";
+
+async fn run_grayscale_conversion(
+    state: &AppState,
+    conversion: &GrayscaleConversion,
+    timings: &mut ProcessingTimings,
+    on_started: impl FnOnce(),
+) -> anyhow::Result<Vec<&'static str>> {
+    let GrayscaleConversion {
+        temp_path,
+        output_path,
+        mode,
+        engine,
+        black_controls,
+        flatten,
+        preserve_bookmarks,
+        preserve_annotations,
+        pdf_version,
+        page_count,
+        passthrough,
+        watermark,
+        upload_limit_bytes,
+    } = conversion;
+    let preserve_annotations = *preserve_annotations;
+    let pdfwrite = PdfwriteOptions {
+        preserve_annotations,
+        pdf_version: *pdf_version,
+        input_page_count: Some(*page_count),
+    };
+    let mut warnings = Vec::new();
+    let (mode, engine) = (*mode, *engine);
+    let BlackControls {
+        force_black_text,
+        force_black_vector,
+        black_threshold_l,
+        black_threshold_c,
+    } = *black_controls;
+
+    let conversion_started = Instant::now();
+    let mut input_has_outlines = None;
+    let mut input_has_annotations = None;
+    state
+        .run_ghostscript_job("grayscale-conversion", || async {
+            on_started();
+            if *flatten {
+                // Flatten in place so both engines convert the flattened copy.
+                let flattened_path = track_temp_path(temp_path.with_extension("flattened.pdf"));
+                if let Err(error) = flatten_form_fields(temp_path, &flattened_path).await {
+                    remove_file_if_exists(&flattened_path).await;
+                    return Err(error.context("form field flattening failed"));
+                }
+                tokio::fs::rename(&flattened_path, temp_path)
+                    .await
+                    .context("failed to replace upload with flattened copy")?;
+            }
+            // After flattening, which removes widget annotations on purpose.
+            if *preserve_bookmarks && !*passthrough {
+                input_has_outlines = pdf_has_outlines(temp_path).await;
+            }
+            if preserve_annotations && !*passthrough {
+                input_has_annotations = detect_annotations(temp_path).await;
+            }
+            if *passthrough {
+                return tokio::fs::copy(temp_path, output_path)
+                    .await
+                    .map(|_| ())
+                    .context("failed to copy already-grayscale input");
+            }
+            match engine {
+                GrayscaleEngine::Ghostscript => match mode {
+                    GrayscaleMode::Preview => {
+                        convert_pdf_to_grayscale_file(temp_path, output_path, &pdfwrite).await
+                    }
+                    GrayscaleMode::Production => {
+                        convert_pdf_to_grayscale_with_black_controls(
+                            temp_path,
+                            output_path,
+                            force_black_text,
+                            force_black_vector,
+                            black_threshold_l,
+                            black_threshold_c,
+                            &pdfwrite,
+                        )
+                        .await
+                    }
+                },
+                GrayscaleEngine::Mupdf => {
+                    match convert_pdf_to_grayscale_with_mupdf(temp_path, output_path).await {
+                        Ok(()) => Ok(()),
+                        Err(error) if is_mupdf_missing(&error) => {
+                            tracing::warn!(
+                                "mutool not available; falling back to ghostscript conversion"
+                            );
+                            match mode {
+                                GrayscaleMode::Preview => {
+                                    convert_pdf_to_grayscale_file(temp_path, output_path, &pdfwrite)
+                                        .await
+                                }
+                                GrayscaleMode::Production => {
+                                    convert_pdf_to_grayscale_with_black_controls(
+                                        temp_path,
+                                        output_path,
+                                        force_black_text,
+                                        force_black_vector,
+                                        black_threshold_l,
+                                        black_threshold_c,
+                                        &pdfwrite,
+                                    )
+                                    .await
+                                }
+                            }
+                        }
+                        Err(error) => Err(error),
+                    }
+                }
+            }
+        })
+        .await?;
+
+    maybe_log_ghostscript_timing(
+        state.config.log_ghostscript_timings,
+        "grayscale-conversion",
+        conversion_started,
+    );
+    timings.record("conversion", conversion_started);
+
+    if let Some(watermark_text) = watermark {
+        let watermark_started = Instant::now();
+        let watermarked_path = track_temp_path(output_path.with_extension("watermarked.pdf"));
+        let watermark_result = state
+            .run_ghostscript_job("grayscale-watermark", || async {
+                apply_watermark(output_path, &watermarked_path, watermark_text, &pdfwrite).await?;
+                tokio::fs::rename(&watermarked_path, output_path)
+                    .await
+                    .context("failed to replace grayscale output with watermarked copy")
+            })
+            .await;
+
+        if let Err(error) = watermark_result {
+            remove_file_if_exists(&watermarked_path).await;
+            return Err(error.context("grayscale watermark failed"));
+        }
+
+        timings.record("watermark", watermark_started);
+    }
+
+    ensure_output_within_limit(state, output_path, *upload_limit_bytes).await?;
+
+    if *flatten && detect_form_fields(output_path).await {
+        tracing::warn!("form fields still present after flattening");
+        warnings.push(FLATTEN_INCOMPLETE_WARNING);
+    }
+
+    if input_has_annotations == Some(true) && detect_annotations(output_path).await == Some(false) {
+        tracing::warn!("annotations lost during grayscale conversion");
+        warnings.push(ANNOTATIONS_LOST_WARNING);
+    }
+
+    if input_has_outlines == Some(true) {
+        let output_has_outlines = state
+            .run_ghostscript_job("grayscale-outline-check", || async {
+                Ok(pdf_has_outlines(output_path).await)
+            })
+            .await?;
+        if output_has_outlines == Some(false) {
+            tracing::warn!("bookmarks lost during grayscale conversion");
+            warnings.push(BOOKMARKS_LOST_WARNING);
+        }
+    }
+
+    Ok(warnings)
+}
+
+// Background half of `?async=true`: the upload is already saved and the quota
+// reserved, so this only converts, settles the reservation and parks the
+// output in the result store for download.
+async fn run_grayscale_job(
+    state: AppState,
+    job_id: Uuid,
+    clerk_id: String,
+    reservation_id: String,
+    conversion: GrayscaleConversion,
+    output_name: String,
+) {
+    let mut timings = ProcessingTimings::new(
+        "grayscale-async",
+        state.config.log_processing_timings,
+        false,
+    );
+    let jobs = state.jobs.clone();
+    let result = run_grayscale_conversion(&state, &conversion, &mut timings, || {
+        jobs.mark_running(&job_id)
+    })
+    .await;
+    remove_file_if_exists(&conversion.temp_path).await;
+
+    let warnings = match result {
+        Ok(warnings) => warnings.into_iter().map(ToString::to_string).collect(),
+        Err(error) => {
+            let _ =
+                release_reservation_for_clerk_user(&state.convex_api, &clerk_id, &reservation_id)
+                    .await;
+            tracing::error!(error = %error, job_id = %job_id, "async grayscale conversion failed");
+            remove_file_if_exists(&conversion.output_path).await;
+            state.jobs.mark_failed(&job_id, error.to_string());
+            return;
+        }
+    };
+
+    match commit_reservation_for_clerk_user(&state.convex_api, &clerk_id, &reservation_id).await {
+        Ok(result) => {
+            if !result.committed {
+                tracing::warn!("Usage reservation commit failed");
+            }
+        }
+        Err(error) => {
+            tracing::warn!(error = %error, "failed to commit reservation");
+        }
+    }
+
+    match state
+        .results
+        .retain(&clerk_id, &conversion.output_path, &output_name)
+        .await
+    {
+        Ok(result_id) => state.jobs.mark_done(&job_id, result_id, warnings),
+        Err(error) => {
+            tracing::error!(error = %error, job_id = %job_id, "failed to store async grayscale result");
+            remove_file_if_exists(&conversion.output_path).await;
+            state
+                .jobs
+                .mark_failed(&job_id, "Failed to store grayscale PDF");
+        }
+    }
+}
+
+async fn extract_pages_for_clerk_user(
+    state: AppState,
+    clerk_id: &str,
+    multipart: Multipart,
+) -> Response {
+    let upload_limit_bytes = PROCESSING_UPLOAD_LIMIT_BYTES;
+    let mut uploaded = match save_pdf_with_mode_from_multipart(
+        multipart,
+        upload_limit_bytes,
+        &state.uploads,
+        clerk_id,
+    )
+    .await
+    {
+        Ok(file) => file,
+        Err(error) => return upload_error_to_response(error),
+    };
+    if let Err(response) = ensure_pdf_input(&state, uploaded.kind, &mut uploaded.temp_path).await {
+        return response;
+    }
+    maybe_log_upload_throughput(
+        state.config.log_processing_timings,
+        uploaded.size_bytes,
+        uploaded.upload_ms,
+    );
+
+    let temp_path = uploaded.temp_path.clone();
+    let original_name = uploaded.original_name;
+
+    let page_count = match state
+        .run_ghostscript_job("extract-pages-page-count", || async {
+            get_pdf_page_count(&temp_path).await
+        })
+        .await
+    {
+        Ok(value) => value,
+        Err(error) => {
+            tracing::error!(error = %error, "failed to get page count for page extraction");
+            remove_file_if_exists(&temp_path).await;
+            return processing_error_response(&error);
+        }
+    };
+
+    let (first_page, last_page) = match parse_page_range(
+        uploaded.first_page.as_deref(),
+        uploaded.last_page.as_deref(),
+        page_count,
+    ) {
+        Ok(value) => value,
+        Err(message) => {
+            remove_file_if_exists(&temp_path).await;
+            return bad_request(message, MessageCode::InvalidOption);
+        }
+    };
+
+    let base_name = sanitize_base_name(
+        Path::new(&original_name)
+            .file_stem()
+            .and_then(|value| value.to_str())
+            .unwrap_or("document"),
+    );
+    let output_name = format!("{}-pages-{}-{}.pdf", base_name, first_page, last_page);
+    let output_path =
+        track_temp_path(work_dir().join(format!("{}-{}-pages.pdf", base_name, Uuid::new_v4())));
+
+    let clerk_id = clerk_id.to_string();
+    let units = last_page - first_page + 1;
+    let reservation = match reserve_units_for_clerk_user(
+        &state.convex_api,
+        &clerk_id,
+        units,
+        state.config.reservation_ttl_secs,
+    )
+    .await
+    {
+        Ok(value) => value,
+        Err(error) => {
+            tracing::error!(error = ?error, "failed to reserve quota for page extraction");
+            remove_file_if_exists(&temp_path).await;
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "Failed to reserve usage quota." })),
+            )
+                .into_response();
         }
     };
 
-    let price_id = subscription
-        .items
-        .data
-        .first()
-        .and_then(|item| item.price.as_ref())
-        .and_then(|price| price.id.clone());
-
-    let existing_subscription: Option<ConvexSubscription> = state
-        .convex
-        .query("subscriptions:get", json!({ "userId": &clerk_id }))
-        .await?;
-
-    let plan_from_price = state.price_map.get_plan_for_price_id(price_id.as_deref());
-    let plan_id = match (plan_from_price, existing_subscription.as_ref()) {
-        (Some(plan_id), _) => Some(plan_id),
-        (None, Some(subscription)) => Some(resolve_plan_id(subscription.plan.as_deref())),
-        (None, None) => None,
-    };
+    if !reservation.allowed {
+        remove_file_if_exists(&temp_path).await;
+        return quota_exceeded_response(reservation, units);
+    }
 
-    let plan_id = match plan_id {
+    let reservation_id = match reservation.reservation_id.clone() {
         Some(value) => value,
         None => {
-            tracing::warn!(price_id = ?price_id, "Stripe webhook: unable to resolve plan for price");
-            return Ok(());
+            remove_file_if_exists(&temp_path).await;
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "Failed to create usage reservation." })),
+            )
+                .into_response();
         }
     };
 
-    let ends_at = subscription
-        .current_period_end
-        .map(|seconds| seconds * 1000);
+    let extraction_result = state
+        .run_ghostscript_job("extract-pages", || async {
+            extract_pdf_pages(&temp_path, &output_path, first_page, last_page).await?;
+            ensure_output_within_limit(&state, &output_path, upload_limit_bytes).await
+        })
+        .await;
 
-    let action_name = if existing_subscription.is_some() {
-        "subscriptions:updateSubscription"
-    } else {
-        "subscriptions:createSubscription"
-    };
+    if let Err(error) = extraction_result {
+        let _ =
+            release_reservation_for_clerk_user(&state.convex_api, &clerk_id, &reservation_id).await;
+        tracing::error!(error = %error, "page extraction failed");
+        remove_file_if_exists(&temp_path).await;
+        remove_file_if_exists(&output_path).await;
+        if error.downcast_ref::<OutputTooLarge>().is_some() {
+            return output_too_large_response();
+        }
+        return processing_error_response(&error);
+    }
 
-    state
-        .convex
-        .action_value(
-            action_name,
-            json!({
-                "userId": &clerk_id,
-                "plan": plan_id.as_str(),
-                "status": subscription.status,
-                "stripeSubscriptionId": subscription.id,
-                "stripePriceId": price_id,
-                "endsAt": ends_at,
-            }),
-        )
-        .await?;
+    match commit_reservation_for_clerk_user(&state.convex_api, &clerk_id, &reservation_id).await {
+        Ok(result) => {
+            if !result.committed {
+                tracing::warn!("Usage reservation commit failed");
+            }
+        }
+        Err(error) => {
+            tracing::warn!(error = %error, "failed to commit reservation");
+        }
+    }
 
-    Ok(())
-}
+    let pdf_bytes = tokio::fs::read(&output_path).await;
+    remove_file_if_exists(&temp_path).await;
+    remove_file_if_exists(&output_path).await;
 
-async fn get_clerk_id_for_customer(
-    state: &AppState,
-    customer_id: &str,
-) -> anyhow::Result<Option<String>> {
-    let customer = state.stripe.retrieve_customer(customer_id).await?;
-    if customer.deleted {
-        return Ok(None);
+    match pdf_bytes {
+        Ok(bytes) => with_quota_headers(
+            pdf_attachment_response(&output_name, bytes),
+            &reservation,
+            units,
+        ),
+        Err(error) => {
+            tracing::error!(error = %error, "failed to read extracted pages output");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "Failed to send extracted PDF" })),
+            )
+                .into_response()
+        }
     }
-    Ok(customer.metadata.get("clerkId").cloned())
 }
 
-async fn preflight_for_clerk_user(
+async fn sanitize_for_clerk_user(
     state: AppState,
     clerk_id: &str,
     multipart: Multipart,
-    max_upload_size_bytes: usize,
 ) -> Response {
-    let uploaded = match save_pdf_from_multipart(multipart, max_upload_size_bytes).await {
+    let upload_limit_bytes = PROCESSING_UPLOAD_LIMIT_BYTES;
+    let mut uploaded = match save_pdf_with_mode_from_multipart(
+        multipart,
+        upload_limit_bytes,
+        &state.uploads,
+        clerk_id,
+    )
+    .await
+    {
         Ok(file) => file,
         Err(error) => return upload_error_to_response(error),
     };
+    if let Err(response) = ensure_pdf_input(&state, uploaded.kind, &mut uploaded.temp_path).await {
+        return response;
+    }
+    maybe_log_upload_throughput(
+        state.config.log_processing_timings,
+        uploaded.size_bytes,
+        uploaded.upload_ms,
+    );
 
     let temp_path = uploaded.temp_path.clone();
-    let original_name = uploaded.original_name.clone();
-    let clerk_id = clerk_id.to_string();
-
-    let result = state
-        .run_ghostscript_job("preflight", || async {
-            let page_count = get_pdf_page_count(&temp_path).await?;
-            let units = page_count * 2;
-            let reservation = reserve_units_for_clerk_user(&state.convex, &clerk_id, units).await?;
-            if !reservation.allowed {
-                return Ok(PreflightOutcome::QuotaExceeded { reservation, units });
-            }
-
-            let reservation_id = reservation
-                .reservation_id
-                .clone()
-                .ok_or_else(|| anyhow::anyhow!("Failed to create usage reservation."))?;
-
-            let mut analysis_result = analyze_pdf(&temp_path, Some(page_count)).await;
-            match analysis_result.as_mut() {
-                Ok(analysis) => {
-                    let commit_result = commit_reservation_for_clerk_user(
-                        &state.convex,
-                        &clerk_id,
-                        &reservation_id,
-                    )
-                    .await?;
-                    if !commit_result.committed {
-                        tracing::warn!("Usage reservation commit failed");
-                    }
+    let options = match (
+        parse_bool_field(uploaded.strip_javascript.as_deref(), "stripJavascript"),
+        parse_bool_field(uploaded.strip_metadata.as_deref(), "stripMetadata"),
+    ) {
+        (Ok(strip_javascript), Ok(strip_metadata)) => SanitizeOptions {
+            strip_javascript: uploaded.strip_javascript.is_none() || strip_javascript,
+            strip_metadata: uploaded.strip_metadata.is_none() || strip_metadata,
+        },
+        (Err(message), _) | (_, Err(message)) => {
+            remove_file_if_exists(&temp_path).await;
+            return bad_request(message, MessageCode::InvalidOption);
+        }
+    };
 
-                    analysis.file_name = original_name;
-                    Ok(PreflightOutcome::Analysis {
-                        analysis: analysis.clone(),
-                    })
-                }
-                Err(error) => {
-                    let _ = release_reservation_for_clerk_user(
-                        &state.convex,
-                        &clerk_id,
-                        &reservation_id,
-                    )
-                    .await;
-                    Err(anyhow::anyhow!(error.to_string()))
-                }
-            }
+    let page_count = match state
+        .run_ghostscript_job("sanitize-page-count", || async {
+            get_pdf_page_count(&temp_path).await
         })
-        .await;
+        .await
+    {
+        Ok(value) => value,
+        Err(error) => {
+            tracing::error!(error = %error, "failed to get page count for sanitize");
+            remove_file_if_exists(&temp_path).await;
+            return processing_error_response(&error);
+        }
+    };
 
-    remove_file_if_exists(&temp_path).await;
+    let had_javascript = contains_javascript(&temp_path).await;
 
-    match result {
-        Ok(PreflightOutcome::Analysis { analysis }) => Json(analysis).into_response(),
-        Ok(PreflightOutcome::QuotaExceeded { reservation, units }) => {
-            quota_exceeded_response(reservation, units)
-        }
+    let base_name = sanitize_base_name(
+        Path::new(&uploaded.original_name)
+            .file_stem()
+            .and_then(|value| value.to_str())
+            .unwrap_or("document"),
+    );
+    let output_name = format!("{}-sanitized.pdf", base_name);
+    let output_path =
+        track_temp_path(work_dir().join(format!("{}-{}-sanitized.pdf", base_name, Uuid::new_v4())));
+
+    let clerk_id = clerk_id.to_string();
+    let units = page_count;
+    let reservation = match reserve_units_for_clerk_user(
+        &state.convex_api,
+        &clerk_id,
+        units,
+        state.config.reservation_ttl_secs,
+    )
+    .await
+    {
+        Ok(value) => value,
         Err(error) => {
-            tracing::error!(error = ?error, "preflight failed");
-            (
+            tracing::error!(error = ?error, "failed to reserve quota for sanitize");
+            remove_file_if_exists(&temp_path).await;
+            return (
                 StatusCode::INTERNAL_SERVER_ERROR,
-                Json(json!({ "error": error.to_string() })),
+                Json(json!({ "error": "Failed to reserve usage quota." })),
             )
-                .into_response()
+                .into_response();
         }
+    };
+
+    if !reservation.allowed {
+        remove_file_if_exists(&temp_path).await;
+        return quota_exceeded_response(reservation, units);
     }
-}
 
-#[derive(Debug, Copy, Clone)]
-enum GrayscaleMode {
-    Preview,
-    Production,
-}
+    let reservation_id = match reservation.reservation_id.clone() {
+        Some(value) => value,
+        None => {
+            remove_file_if_exists(&temp_path).await;
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "Failed to create usage reservation." })),
+            )
+                .into_response();
+        }
+    };
 
-impl GrayscaleMode {
-    fn parse(raw: Option<&str>) -> Result<Self, &'static str> {
-        let normalized = raw
-            .map(|value| value.trim().to_ascii_lowercase())
-            .unwrap_or_default();
-        if normalized.is_empty() || normalized == "preview" {
-            return Ok(Self::Preview);
+    let sanitize_result = state
+        .run_ghostscript_job("sanitize", || async {
+            sanitize_pdf(&temp_path, &output_path, options).await?;
+            ensure_output_within_limit(&state, &output_path, upload_limit_bytes).await
+        })
+        .await;
+
+    if let Err(error) = sanitize_result {
+        let _ =
+            release_reservation_for_clerk_user(&state.convex_api, &clerk_id, &reservation_id).await;
+        tracing::error!(error = %error, "PDF sanitize failed");
+        remove_file_if_exists(&temp_path).await;
+        remove_file_if_exists(&output_path).await;
+        if error.downcast_ref::<OutputTooLarge>().is_some() {
+            return output_too_large_response();
         }
-        if normalized == "production" {
-            return Ok(Self::Production);
+        return processing_error_response(&error);
+    }
+
+    match commit_reservation_for_clerk_user(&state.convex_api, &clerk_id, &reservation_id).await {
+        Ok(result) => {
+            if !result.committed {
+                tracing::warn!("Usage reservation commit failed");
+            }
+        }
+        Err(error) => {
+            tracing::warn!(error = %error, "failed to commit reservation");
         }
-        Err("Invalid mode. Use \"preview\" or \"production\".")
     }
-}
 
-#[derive(Debug, Copy, Clone)]
-enum GrayscaleEngine {
-    Ghostscript,
-    Mupdf,
-}
+    let pdf_bytes = tokio::fs::read(&output_path).await;
+    remove_file_if_exists(&temp_path).await;
+    remove_file_if_exists(&output_path).await;
 
-impl GrayscaleEngine {
-    fn parse(raw: Option<&str>) -> Result<Self, &'static str> {
-        let normalized = raw
-            .map(|value| value.trim().to_ascii_lowercase())
-            .unwrap_or_default();
-        if normalized.is_empty() || normalized == "ghostscript" || normalized == "gs" {
-            return Ok(Self::Ghostscript);
+    match pdf_bytes {
+        Ok(bytes) => {
+            let mut response = with_quota_headers(
+                pdf_attachment_response(&output_name, bytes),
+                &reservation,
+                units,
+            );
+            response.headers_mut().insert(
+                "x-javascript-found",
+                HeaderValue::from_static(if had_javascript { "true" } else { "false" }),
+            );
+            response
         }
-        if normalized == "mupdf" || normalized == "mutool" {
-            return Ok(Self::Mupdf);
+        Err(error) => {
+            tracing::error!(error = %error, "failed to read sanitized output");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "Failed to send sanitized PDF" })),
+            )
+                .into_response()
         }
-        Err("Invalid engine. Use \"ghostscript\" or \"mupdf\".")
     }
 }
 
-async fn grayscale_for_clerk_user(
-    state: AppState,
-    clerk_id: &str,
-    multipart: Multipart,
-) -> Response {
-    let total_started = Instant::now();
+async fn ocr_for_clerk_user(state: AppState, clerk_id: &str, multipart: Multipart) -> Response {
+    if !crate::tools::ocr_available().await {
+        return ocr_unavailable_response();
+    }
 
-    let upload_started = Instant::now();
-    let uploaded = match save_pdf_with_mode_from_multipart(multipart, 20 * 1024 * 1024).await {
+    let upload_limit_bytes = PROCESSING_UPLOAD_LIMIT_BYTES;
+    let mut uploaded = match save_pdf_with_mode_from_multipart(
+        multipart,
+        upload_limit_bytes,
+        &state.uploads,
+        clerk_id,
+    )
+    .await
+    {
         Ok(file) => file,
         Err(error) => return upload_error_to_response(error),
     };
-    maybe_log_processing_timing(
+    if let Err(response) = ensure_pdf_input(&state, uploaded.kind, &mut uploaded.temp_path).await {
+        return response;
+    }
+    maybe_log_upload_throughput(
         state.config.log_processing_timings,
-        "grayscale-upload",
-        upload_started,
+        uploaded.size_bytes,
+        uploaded.upload_ms,
     );
 
     let temp_path = uploaded.temp_path.clone();
-    let original_name = uploaded.original_name;
-    let mode = match GrayscaleMode::parse(uploaded.mode.as_deref()) {
+    let language = match validate_ocr_language(uploaded.language.as_deref()) {
         Ok(value) => value,
         Err(message) => {
             remove_file_if_exists(&temp_path).await;
-            return (StatusCode::BAD_REQUEST, Json(json!({ "error": message }))).into_response();
+            return bad_request(message, MessageCode::InvalidLanguage);
         }
     };
-    let engine = match GrayscaleEngine::parse(uploaded.engine.as_deref()) {
-        Ok(value) => value,
-        Err(message) => {
-            remove_file_if_exists(&temp_path).await;
-            return (StatusCode::BAD_REQUEST, Json(json!({ "error": message }))).into_response();
-        }
-    };
-    tracing::info!(mode = ?mode, engine = ?engine, "grayscale conversion request");
-    let force_black_text = state.config.grayscale_production_force_black_text;
-    let force_black_vector = state.config.grayscale_production_force_black_vector;
-    let black_threshold_l = state.config.grayscale_production_black_threshold_l;
-    let black_threshold_c = state.config.grayscale_production_black_threshold_c;
-
-    let base_name = sanitize_base_name(
-        Path::new(&original_name)
-            .file_stem()
-            .and_then(|value| value.to_str())
-            .unwrap_or("document"),
-    );
-    let output_name = format!("{}-grayscale.pdf", base_name);
-    let output_path =
-        std::env::temp_dir().join(format!("{}-{}-grayscale.pdf", base_name, Uuid::new_v4()));
-
-    let clerk_id = clerk_id.to_string();
 
-    let page_count_started = Instant::now();
     let page_count = match state
-        .run_ghostscript_job("grayscale-page-count", || async {
+        .run_ghostscript_job("ocr-page-count", || async {
             get_pdf_page_count(&temp_path).await
         })
         .await
     {
         Ok(value) => value,
         Err(error) => {
-            tracing::error!(error = %error, "failed to get page count for grayscale");
+            tracing::error!(error = %error, "failed to get page count for OCR");
             remove_file_if_exists(&temp_path).await;
-            remove_file_if_exists(&output_path).await;
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(json!({ "error": error.to_string() })),
-            )
-                .into_response();
+            return processing_error_response(&error);
         }
     };
 
-    maybe_log_ghostscript_timing(
-        state.config.log_ghostscript_timings,
-        "page-count",
-        page_count_started,
-    );
-    maybe_log_processing_timing(
-        state.config.log_processing_timings,
-        "grayscale-page-count",
-        page_count_started,
+    let base_name = sanitize_base_name(
+        Path::new(&uploaded.original_name)
+            .file_stem()
+            .and_then(|value| value.to_str())
+            .unwrap_or("document"),
     );
+    let output_name = format!("{}-ocr.pdf", base_name);
+    let output_path =
+        track_temp_path(work_dir().join(format!("{}-{}-ocr.pdf", base_name, Uuid::new_v4())));
 
-    let units = page_count;
-    let reserve_started = Instant::now();
-    let reservation = match reserve_units_for_clerk_user(&state.convex, &clerk_id, units).await {
+    let clerk_id = clerk_id.to_string();
+    let units = page_count.saturating_mul(state.config.ocr_units_per_page);
+    let reservation = match reserve_units_for_clerk_user(
+        &state.convex_api,
+        &clerk_id,
+        units,
+        state.config.reservation_ttl_secs,
+    )
+    .await
+    {
         Ok(value) => value,
         Err(error) => {
-            tracing::error!(error = ?error, "failed to reserve quota for grayscale");
+            tracing::error!(error = ?error, "failed to reserve quota for OCR");
             remove_file_if_exists(&temp_path).await;
-            remove_file_if_exists(&output_path).await;
             return (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 Json(json!({ "error": "Failed to reserve usage quota." })),
@@ -1182,15 +4424,9 @@ async fn grayscale_for_clerk_user(
                 .into_response();
         }
     };
-    maybe_log_processing_timing(
-        state.config.log_processing_timings,
-        "grayscale-reserve",
-        reserve_started,
-    );
 
     if !reservation.allowed {
         remove_file_if_exists(&temp_path).await;
-        remove_file_if_exists(&output_path).await;
         return quota_exceeded_response(reservation, units);
     }
 
@@ -1198,7 +4434,6 @@ async fn grayscale_for_clerk_user(
         Some(value) => value,
         None => {
             remove_file_if_exists(&temp_path).await;
-            remove_file_if_exists(&output_path).await;
             return (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 Json(json!({ "error": "Failed to create usage reservation." })),
@@ -1207,82 +4442,30 @@ async fn grayscale_for_clerk_user(
         }
     };
 
-    let conversion_started = Instant::now();
-    let conversion_result = state
-        .run_ghostscript_job("grayscale-conversion", || async {
-            match engine {
-                GrayscaleEngine::Ghostscript => match mode {
-                    GrayscaleMode::Preview => {
-                        convert_pdf_to_grayscale_file(&temp_path, &output_path).await
-                    }
-                    GrayscaleMode::Production => {
-                        convert_pdf_to_grayscale_with_black_controls(
-                            &temp_path,
-                            &output_path,
-                            force_black_text,
-                            force_black_vector,
-                            black_threshold_l,
-                            black_threshold_c,
-                        )
-                        .await
-                    }
-                },
-                GrayscaleEngine::Mupdf => {
-                    match convert_pdf_to_grayscale_with_mupdf(&temp_path, &output_path).await {
-                        Ok(()) => Ok(()),
-                        Err(error) if is_mupdf_missing(&error) => {
-                            tracing::warn!(
-                                "mutool not available; falling back to ghostscript conversion"
-                            );
-                            match mode {
-                                GrayscaleMode::Preview => {
-                                    convert_pdf_to_grayscale_file(&temp_path, &output_path).await
-                                }
-                                GrayscaleMode::Production => {
-                                    convert_pdf_to_grayscale_with_black_controls(
-                                        &temp_path,
-                                        &output_path,
-                                        force_black_text,
-                                        force_black_vector,
-                                        black_threshold_l,
-                                        black_threshold_c,
-                                    )
-                                    .await
-                                }
-                            }
-                        }
-                        Err(error) => Err(error),
-                    }
-                }
-            }
+    let reservation_guard = ReservationGuard::new(&state.convex_api, &clerk_id, &reservation_id);
+    let ocr_result = state
+        .run_ghostscript_job("ocr", || async {
+            ocr_pdf(&temp_path, &output_path, &language).await?;
+            ensure_output_within_limit(&state, &output_path, upload_limit_bytes).await
         })
         .await;
 
-    if let Err(error) = conversion_result {
-        let _ = release_reservation_for_clerk_user(&state.convex, &clerk_id, &reservation_id).await;
-        tracing::error!(error = %error, "grayscale conversion failed");
+    if let Err(error) = ocr_result {
+        drop(reservation_guard);
+        tracing::error!(error = %error, "PDF OCR failed");
         remove_file_if_exists(&temp_path).await;
         remove_file_if_exists(&output_path).await;
-        return (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(json!({ "error": error.to_string() })),
-        )
-            .into_response();
+        if error.downcast_ref::<OutputTooLarge>().is_some() {
+            return output_too_large_response();
+        }
+        if is_ocrmypdf_missing(&error) {
+            return ocr_unavailable_response();
+        }
+        return processing_error_response(&error);
     }
 
-    maybe_log_ghostscript_timing(
-        state.config.log_ghostscript_timings,
-        "grayscale-conversion",
-        conversion_started,
-    );
-    maybe_log_processing_timing(
-        state.config.log_processing_timings,
-        "grayscale-conversion",
-        conversion_started,
-    );
-
-    let commit_started = Instant::now();
-    match commit_reservation_for_clerk_user(&state.convex, &clerk_id, &reservation_id).await {
+    reservation_guard.disarm();
+    match commit_reservation_for_clerk_user(&state.convex_api, &clerk_id, &reservation_id).await {
         Ok(result) => {
             if !result.committed {
                 tracing::warn!("Usage reservation commit failed");
@@ -1292,67 +4475,451 @@ async fn grayscale_for_clerk_user(
             tracing::warn!(error = %error, "failed to commit reservation");
         }
     }
-    maybe_log_processing_timing(
-        state.config.log_processing_timings,
-        "grayscale-commit",
-        commit_started,
-    );
 
-    let read_started = Instant::now();
-    let pdf_bytes = match tokio::fs::read(&output_path).await {
-        Ok(bytes) => bytes,
+    let pdf_bytes = tokio::fs::read(&output_path).await;
+    remove_file_if_exists(&temp_path).await;
+    remove_file_if_exists(&output_path).await;
+
+    match pdf_bytes {
+        Ok(bytes) => with_quota_headers(
+            pdf_attachment_response(&output_name, bytes),
+            &reservation,
+            units,
+        ),
         Err(error) => {
-            tracing::error!(error = %error, "failed to read grayscale output");
-            remove_file_if_exists(&temp_path).await;
-            remove_file_if_exists(&output_path).await;
-            return (
+            tracing::error!(error = %error, "failed to read OCR output");
+            (
                 StatusCode::INTERNAL_SERVER_ERROR,
-                Json(json!({ "error": "Failed to send grayscale PDF" })),
+                Json(json!({ "error": "Failed to send OCR PDF" })),
             )
-                .into_response();
+                .into_response()
+        }
+    }
+}
+
+fn ocr_unavailable_response() -> Response {
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        Json(json!({
+            "error": "OCR is not available on this server.",
+            "code": MessageCode::OcrUnavailable.code(),
+        })),
+    )
+        .into_response()
+}
+
+// Checked before any output is read into memory; oversized files are removed.
+// The cap scales with the upload limit the request was held to.
+async fn ensure_output_within_limit(
+    state: &AppState,
+    output_path: &Path,
+    upload_limit_bytes: usize,
+) -> anyhow::Result<()> {
+    let limit = state.config.max_output_bytes_for(upload_limit_bytes);
+    let actual = tokio::fs::metadata(output_path)
+        .await
+        .context("failed to stat conversion output")?
+        .len();
+    if actual > limit {
+        let _ = tokio::fs::remove_file(output_path).await;
+        return Err(OutputTooLarge { actual, limit }.into());
+    }
+    Ok(())
+}
+
+fn output_too_large_response() -> Response {
+    (
+        StatusCode::UNPROCESSABLE_ENTITY,
+        Json(json!({
+            "error": "The converted file exceeds the maximum output size.",
+            "code": MessageCode::OutputTooLarge.code(),
+        })),
+    )
+        .into_response()
+}
+
+fn tus_response(status: StatusCode) -> Response {
+    let mut response = status.into_response();
+    response
+        .headers_mut()
+        .insert("tus-resumable", HeaderValue::from_static(TUS_VERSION));
+    response
+}
+
+fn tus_error(status: StatusCode, message: &str) -> Response {
+    let mut response = (status, Json(json!({ "error": message }))).into_response();
+    response
+        .headers_mut()
+        .insert("tus-resumable", HeaderValue::from_static(TUS_VERSION));
+    response
+}
+
+fn tus_version_mismatch(headers: &HeaderMap) -> Option<Response> {
+    let version = headers
+        .get("tus-resumable")
+        .and_then(|value| value.to_str().ok());
+    if version == Some(TUS_VERSION) {
+        return None;
+    }
+
+    let mut response = tus_response(StatusCode::PRECONDITION_FAILED);
+    response
+        .headers_mut()
+        .insert("tus-version", HeaderValue::from_static(TUS_VERSION));
+    Some(response)
+}
+
+fn header_u64(headers: &HeaderMap, name: &str) -> Option<u64> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+}
+
+// Upload-Metadata is `key base64value` pairs separated by commas.
+fn parse_tus_metadata(raw: &str) -> HashMap<String, String> {
+    raw.split(',')
+        .filter_map(|pair| {
+            let mut parts = pair.trim().splitn(2, ' ');
+            let key = parts.next()?.trim();
+            let value = parts
+                .next()
+                .and_then(|value| STANDARD.decode(value.trim()).ok())
+                .and_then(|value| String::from_utf8(value).ok())
+                .unwrap_or_default();
+            (!key.is_empty()).then(|| (key.to_string(), value))
+        })
+        .collect()
+}
+
+fn parse_page_range(
+    first_page: Option<&str>,
+    last_page: Option<&str>,
+    page_count: i64,
+) -> Result<(i64, i64), String> {
+    let parse = |raw: Option<&str>, name: &str, fallback: i64| -> Result<i64, String> {
+        match raw {
+            None => Ok(fallback),
+            Some(value) => value
+                .parse::<i64>()
+                .ok()
+                .filter(|value| *value > 0)
+                .ok_or_else(|| format!("{} must be a positive integer.", name)),
         }
     };
-    maybe_log_processing_timing(
-        state.config.log_processing_timings,
-        "grayscale-read",
-        read_started,
-    );
 
-    remove_file_if_exists(&temp_path).await;
-    remove_file_if_exists(&output_path).await;
+    let first = parse(first_page, "firstPage", 1)?;
+    let last = parse(last_page, "lastPage", page_count)?;
+
+    if first > last {
+        return Err(format!(
+            "Invalid page range: firstPage ({}) is greater than lastPage ({}).",
+            first, last
+        ));
+    }
+    if last > page_count {
+        return Err(format!(
+            "Invalid page range: lastPage ({}) exceeds the document page count ({}).",
+            last, page_count
+        ));
+    }
+
+    Ok((first, last))
+}
 
+fn pdf_attachment_response(file_name: &str, bytes: Vec<u8>) -> Response {
     let mut headers = HeaderMap::new();
     headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/pdf"));
     if let Ok(content_disposition) = HeaderValue::from_str(&format!(
         "attachment; filename=\"{}\"",
-        sanitize_filename_for_header(&output_name)
+        sanitize_filename_for_header(file_name)
     )) {
         headers.insert(CONTENT_DISPOSITION, content_disposition);
     }
 
-    maybe_log_processing_timing(
-        state.config.log_processing_timings,
-        "grayscale-total",
-        total_started,
-    );
+    (StatusCode::OK, headers, bytes).into_response()
+}
+
+// For polled read-only endpoints: the ETag is a hash of the serialized body, so
+// an unchanged payload answers `If-None-Match` with an empty 304.
+fn etag_json_response(
+    request_headers: &HeaderMap,
+    cache_control: &'static str,
+    body: &serde_json::Value,
+) -> Response {
+    let bytes = match serde_json::to_vec(body) {
+        Ok(bytes) => bytes,
+        Err(error) => {
+            tracing::error!(error = %error, "failed to serialize response body");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let digest = Sha256::digest(&bytes);
+    let etag = format!("\"{}\"", hex::encode(&digest[..16]));
+
+    let not_modified = request_headers
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|candidate| candidate.trim())
+        .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag);
+
+    let mut headers = HeaderMap::new();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static(cache_control));
+    if let Ok(value) = HeaderValue::from_str(&etag) {
+        headers.insert(ETAG, value);
+    }
 
-    (StatusCode::OK, headers, pdf_bytes).into_response()
+    if not_modified {
+        return (StatusCode::NOT_MODIFIED, headers).into_response();
+    }
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    (StatusCode::OK, headers, bytes).into_response()
 }
 
-fn maybe_log_ghostscript_timing(enabled: bool, stage: &str, started_at: Instant) {
+// Streams a stored PDF, honoring a single `Range: bytes=` range. Multi-range
+// and malformed headers fall back to the full file, as RFC 9110 allows.
+async fn pdf_file_response(
+    path: &Path,
+    file_name: &str,
+    request_headers: &HeaderMap,
+) -> std::io::Result<Response> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let file_len = file.metadata().await?.len();
+
+    let range = request_headers
+        .get(RANGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| parse_byte_range(value, file_len));
+
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/pdf"));
+    headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    if let Ok(content_disposition) = HeaderValue::from_str(&format!(
+        "attachment; filename=\"{}\"",
+        sanitize_filename_for_header(file_name)
+    )) {
+        headers.insert(CONTENT_DISPOSITION, content_disposition);
+    }
+
+    let (status, start, len) = match range {
+        None => (StatusCode::OK, 0, file_len),
+        Some(ByteRange::Unsatisfiable) => {
+            if let Ok(value) = HeaderValue::from_str(&format!("bytes */{}", file_len)) {
+                headers.insert(CONTENT_RANGE, value);
+            }
+            return Ok((StatusCode::RANGE_NOT_SATISFIABLE, headers).into_response());
+        }
+        Some(ByteRange::Satisfiable { start, end }) => {
+            if let Ok(value) =
+                HeaderValue::from_str(&format!("bytes {}-{}/{}", start, end, file_len))
+            {
+                headers.insert(CONTENT_RANGE, value);
+            }
+            (StatusCode::PARTIAL_CONTENT, start, end - start + 1)
+        }
+    };
+
+    if start > 0 {
+        file.seek(std::io::SeekFrom::Start(start)).await?;
+    }
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(len));
+    let body = Body::from_stream(ReaderStream::new(file.take(len)));
+    Ok((status, headers, body).into_response())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum ByteRange {
+    Satisfiable { start: u64, end: u64 },
+    Unsatisfiable,
+}
+
+// `None` means "ignore the header and send everything".
+fn parse_byte_range(header: &str, file_len: u64) -> Option<ByteRange> {
+    let spec = header.trim().strip_prefix("bytes=")?.trim();
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+
+    let (start, end) = if start.is_empty() {
+        // Suffix range: the last N bytes.
+        let suffix = end.parse::<u64>().ok()?;
+        if suffix == 0 || file_len == 0 {
+            return Some(ByteRange::Unsatisfiable);
+        }
+        (file_len.saturating_sub(suffix), file_len - 1)
+    } else {
+        let start = start.parse::<u64>().ok()?;
+        let end = match end {
+            "" => file_len.saturating_sub(1),
+            value => value.parse::<u64>().ok()?.min(file_len.saturating_sub(1)),
+        };
+        if start >= file_len {
+            return Some(ByteRange::Unsatisfiable);
+        }
+        if end < start {
+            return None;
+        }
+        (start, end)
+    };
+
+    Some(ByteRange::Satisfiable { start, end })
+}
+
+// PostScript/EPS uploads are rewritten to PDF up front so analysis and
+// conversion only ever see PDF input.
+async fn ensure_pdf_input(
+    state: &AppState,
+    kind: UploadKind,
+    temp_path: &mut std::path::PathBuf,
+) -> Result<(), Response> {
+    if kind.is_pdf() {
+        return Ok(());
+    }
+
+    let pdf_path = track_temp_path(temp_path.with_extension("pdf"));
+    let result = state
+        .run_ghostscript_job("normalize-input", || async {
+            convert_postscript_to_pdf(temp_path, &pdf_path).await
+        })
+        .await;
+    remove_file_if_exists(temp_path).await;
+
+    match result {
+        Ok(()) => {
+            *temp_path = pdf_path;
+            Ok(())
+        }
+        Err(error) => {
+            tracing::warn!(error = %error, kind = ?kind, "failed to convert upload to PDF");
+            remove_file_if_exists(&pdf_path).await;
+            Err((
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Json(json!({ "error": "Failed to read uploaded PostScript file." })),
+            )
+                .into_response())
+        }
+    }
+}
+
+fn grayscale_dry_run_report(
+    original_name: &str,
+    mode: GrayscaleMode,
+    force_black_text: bool,
+    force_black_vector: bool,
+    analysis: &PdfAnalysis,
+) -> serde_json::Value {
+    let pages = analysis
+        .color_profiles
+        .iter()
+        .map(|profile| {
+            let color_coverage = profile.c + profile.m + profile.y;
+            json!({
+                "page": profile.page,
+                "hasColor": color_coverage > COLOR_COVERAGE_EPSILON,
+                "colorCoverage": color_coverage,
+                "blackCoverage": profile.k,
+            })
+        })
+        .collect::<Vec<_>>();
+    let color_pages = analysis
+        .color_profiles
+        .iter()
+        .filter(|profile| profile.c + profile.m + profile.y > COLOR_COVERAGE_EPSILON)
+        .count();
+    let production = matches!(mode, GrayscaleMode::Production);
+
+    json!({
+        "dryRun": true,
+        "fileName": original_name,
+        "mode": mode.as_str(),
+        "pageCount": analysis.page_count,
+        "pagesWithColor": color_pages,
+        "pagesUnchanged": analysis.color_profiles.len() - color_pages,
+        "forceBlackText": production && force_black_text,
+        "forceBlackVector": production && force_black_vector,
+        "pages": pages,
+    })
+}
+
+fn parse_bool_field(raw: Option<&str>, name: &str) -> Result<bool, String> {
+    match raw.map(|value| value.trim().to_ascii_lowercase()) {
+        None => Ok(false),
+        Some(value) => match value.as_str() {
+            "1" | "true" | "yes" | "on" => Ok(true),
+            "0" | "false" | "no" | "off" => Ok(false),
+            _ => Err(format!("{} must be a boolean.", name)),
+        },
+    }
+}
+
+fn maybe_log_upload_throughput(enabled: bool, size_bytes: usize, upload_ms: u128) {
     if !enabled {
         return;
     }
-    let duration_ms = Instant::now().duration_since(started_at).as_millis();
-    tracing::info!(stage = stage, duration_ms, "ghostscript timing");
+    let bytes_per_sec = (size_bytes as u128 * 1000) / upload_ms.max(1);
+    tracing::info!(size_bytes, upload_ms, bytes_per_sec, "upload throughput");
 }
 
-fn maybe_log_processing_timing(enabled: bool, stage: &str, started_at: Instant) {
+fn maybe_log_ghostscript_timing(enabled: bool, stage: &str, started_at: Instant) {
     if !enabled {
         return;
     }
     let duration_ms = Instant::now().duration_since(started_at).as_millis();
-    tracing::info!(stage = stage, duration_ms, "processing timing");
+    tracing::info!(stage = stage, duration_ms, "ghostscript timing");
+}
+
+struct ProcessingTimings {
+    operation: &'static str,
+    log_enabled: bool,
+    collect: bool,
+    stages: Vec<(&'static str, u128)>,
+}
+
+impl ProcessingTimings {
+    fn new(operation: &'static str, log_enabled: bool, collect: bool) -> Self {
+        Self {
+            operation,
+            log_enabled,
+            collect,
+            stages: Vec::new(),
+        }
+    }
+
+    fn record(&mut self, stage: &'static str, started_at: Instant) {
+        let duration_ms = Instant::now().duration_since(started_at).as_millis();
+        if self.log_enabled {
+            let stage = format!("{}-{}", self.operation, stage);
+            tracing::info!(stage = %stage, duration_ms, "processing timing");
+        }
+        if self.collect {
+            self.stages.push((stage, duration_ms));
+        }
+    }
+
+    fn apply(&self, mut response: Response) -> Response {
+        if !self.collect || self.stages.is_empty() {
+            return response;
+        }
+
+        let value = self
+            .stages
+            .iter()
+            .map(|(stage, duration_ms)| format!("{};dur={}", stage, duration_ms))
+            .collect::<Vec<_>>()
+            .join(", ");
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            response.headers_mut().insert("x-debug-timings", value);
+        }
+        response
+    }
+}
+
+fn wants_debug_timings(state: &AppState, headers: &HeaderMap) -> bool {
+    state.config.allow_debug_timings && headers.contains_key("x-debug-timings")
 }
 
 fn sanitize_filename_for_header(value: &str) -> String {
@@ -1372,52 +4939,205 @@ fn is_mupdf_missing(error: &anyhow::Error) -> bool {
     error.to_string().contains("mutool-not-found")
 }
 
+fn is_ocrmypdf_missing(error: &anyhow::Error) -> bool {
+    error.to_string().contains("ocrmypdf-not-found")
+}
+
 fn upload_error_to_response(error: UploadError) -> Response {
     match error {
         UploadError::MissingFile => (
             StatusCode::BAD_REQUEST,
-            Json(json!({ "error": "File not found" })),
+            Json(json!({ "error": "File not found", "code": MessageCode::FileNotFound.code() })),
         )
             .into_response(),
         UploadError::UnsupportedFileType => (
             StatusCode::BAD_REQUEST,
-            Json(json!({ "error": "Only PDF files are supported" })),
+            Json(json!({ "error": "Only PDF files are supported", "code": MessageCode::UnsupportedFileType.code() })),
         )
             .into_response(),
         UploadError::FileTooLarge => (
             StatusCode::BAD_REQUEST,
-            Json(json!({ "error": "File exceeds upload limit" })),
+            Json(json!({ "error": "File exceeds upload limit", "code": MessageCode::FileTooLarge.code() })),
+        )
+            .into_response(),
+        UploadError::InvalidUrl => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "Only public https URLs are supported", "code": MessageCode::InvalidUrl.code() })),
+        )
+            .into_response(),
+        UploadError::DownloadFailed => (
+            StatusCode::BAD_GATEWAY,
+            Json(json!({ "error": "Failed to download file", "code": MessageCode::DownloadFailed.code() })),
+        )
+            .into_response(),
+        UploadError::Stalled => (
+            StatusCode::REQUEST_TIMEOUT,
+            Json(json!({ "error": "Upload stalled", "code": MessageCode::UploadStalled.code() })),
+        )
+            .into_response(),
+        UploadError::UnknownUpload => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "Unknown or incomplete upload", "code": MessageCode::UnknownUpload.code() })),
+        )
+            .into_response(),
+        UploadError::InsufficientStorage => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({
+                "error": UploadError::InsufficientStorage.to_string(),
+                "code": MessageCode::InsufficientStorage.code(),
+            })),
+        )
+            .into_response(),
+        UploadError::FormTooLarge => (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            Json(json!({
+                "error": "Form has too many fields or a field is too long",
+                "code": MessageCode::FormTooLarge.code(),
+            })),
         )
             .into_response(),
         UploadError::MultipartError | UploadError::IoError => (
             StatusCode::INTERNAL_SERVER_ERROR,
-            Json(json!({ "error": "Failed to parse upload" })),
+            Json(json!({
+                "error": "Failed to parse upload",
+                "code": MessageCode::UploadFailed.code(),
+            })),
         )
             .into_response(),
     }
 }
 
+fn with_quota_headers(
+    mut response: Response,
+    reservation: &QuotaReservation,
+    units: i64,
+) -> Response {
+    let used = reservation.total_this_month + reservation.pending_units + units;
+    let (limit, remaining) = match reservation.monthly_quota {
+        Some(quota) => (quota.to_string(), (quota - used).max(0).to_string()),
+        None => ("unlimited".to_string(), "unlimited".to_string()),
+    };
+
+    let headers = response.headers_mut();
+    for (name, value) in [
+        ("x-quota-limit", limit),
+        ("x-quota-used", used.to_string()),
+        ("x-quota-remaining", remaining),
+    ] {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.insert(name, value);
+        }
+    }
+
+    response
+}
+
 fn quota_exceeded_response(reservation: QuotaReservation, units: i64) -> Response {
-    (
+    let pending_units_release_at = reservation
+        .next_pending_expires_at
+        .filter(|_| reservation.pending_units > 0);
+    // Only worth retrying if the request fits once pending units are freed.
+    let fits_after_release = reservation
+        .monthly_quota
+        .is_some_and(|quota| reservation.total_this_month + units <= quota);
+    let retry_after = pending_units_release_at
+        .filter(|_| fits_after_release)
+        .map(|release_at| {
+            let wait_ms = (release_at - Utc::now().timestamp_millis()).max(0);
+            ((wait_ms + 999) / 1000).max(1).to_string()
+        });
+
+    let mut response = (
         StatusCode::PAYMENT_REQUIRED,
         Json(QuotaExceededBody {
             error: "Monthly quota exceeded.",
+            code: MessageCode::QuotaExceeded.code(),
             plan: reservation.plan_id.as_str().to_string(),
             monthly_quota: reservation.monthly_quota,
             units_this_month: reservation.total_this_month,
             pending_units: reservation.pending_units,
             units_requested: units,
+            pending_units_release_at,
         }),
     )
-        .into_response()
+        .into_response();
+    if let Some(value) = retry_after.and_then(|value| HeaderValue::from_str(&value).ok()) {
+        response.headers_mut().insert(RETRY_AFTER, value);
+    }
+    response
 }
 
 enum PreflightOutcome {
     Analysis {
-        analysis: crate::ghostscript::PdfAnalysis,
+        analysis: Box<PdfAnalysis>,
+        reservation: QuotaReservation,
+        units: i64,
     },
     QuotaExceeded {
         reservation: QuotaReservation,
         units: i64,
     },
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(start: u64, end: u64) -> Option<ByteRange> {
+        Some(ByteRange::Satisfiable { start, end })
+    }
+
+    #[test]
+    fn parse_ranged_f64_rejects_out_of_range_thresholds() {
+        let range = BLACK_THRESHOLD_L_RANGE;
+        assert_eq!(parse_ranged_f64(" 50 ", "blackThresholdL", range), Ok(50.0));
+        assert_eq!(parse_ranged_f64("0", "blackThresholdL", range), Ok(0.0));
+        assert_eq!(parse_ranged_f64("100", "blackThresholdL", range), Ok(100.0));
+        for raw in ["-0.1", "100.01", "NaN", "inf", "", "fifty"] {
+            assert_eq!(
+                parse_ranged_f64(raw, "blackThresholdL", range),
+                Err("blackThresholdL must be a number between 0 and 100.".to_string()),
+                "{raw:?}"
+            );
+        }
+        assert!(parse_ranged_f64("128", "blackThresholdC", BLACK_THRESHOLD_C_RANGE).is_ok());
+        assert!(parse_ranged_f64("129", "blackThresholdC", BLACK_THRESHOLD_C_RANGE).is_err());
+    }
+
+    #[test]
+    fn parse_byte_range_cases() {
+        let cases = [
+            ("bytes=0-", 100, range(0, 99)),
+            ("bytes=0-0", 100, range(0, 0)),
+            ("bytes=10-19", 100, range(10, 19)),
+            (" bytes= 10 - 19 ", 100, range(10, 19)),
+            // End past EOF is clamped.
+            ("bytes=90-500", 100, range(90, 99)),
+            ("bytes=-10", 100, range(90, 99)),
+            // Suffix longer than the file means the whole file.
+            ("bytes=-500", 100, range(0, 99)),
+            ("bytes=-0", 100, Some(ByteRange::Unsatisfiable)),
+            ("bytes=100-", 100, Some(ByteRange::Unsatisfiable)),
+            ("bytes=150-200", 100, Some(ByteRange::Unsatisfiable)),
+            // Inverted, multi-range and malformed headers are ignored.
+            ("bytes=50-10", 100, None),
+            ("bytes=0-10,20-30", 100, None),
+            ("bytes=0-10, 20-", 100, None),
+            ("items=0-10", 100, None),
+            ("bytes=abc-10", 100, None),
+            ("bytes=10", 100, None),
+            ("bytes=-", 100, None),
+            // Nothing in an empty file can be satisfied.
+            ("bytes=0-", 0, Some(ByteRange::Unsatisfiable)),
+            ("bytes=-10", 0, Some(ByteRange::Unsatisfiable)),
+        ];
+
+        for (header, file_len, expected) in cases {
+            assert_eq!(
+                parse_byte_range(header, file_len),
+                expected,
+                "{header:?} of {file_len}"
+            );
+        }
+    }
+}
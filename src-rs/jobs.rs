@@ -0,0 +1,121 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+use serde::Serialize;
+use uuid::Uuid;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Done,
+    Failed,
+}
+
+impl JobStatus {
+    fn is_finished(self) -> bool {
+        matches!(self, JobStatus::Done | JobStatus::Failed)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct JobRecord {
+    pub owner: String,
+    pub status: JobStatus,
+    pub error: Option<String>,
+    pub result_id: Option<Uuid>,
+    // What a synchronous request would send in `X-Processing-Warnings`.
+    pub warnings: Vec<String>,
+    pub updated_at: Instant,
+}
+
+#[derive(Clone)]
+pub struct JobStore {
+    ttl: Duration,
+    entries: Arc<Mutex<HashMap<Uuid, JobRecord>>>,
+}
+
+impl JobStore {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn create(&self, owner: &str) -> Uuid {
+        let job_id = Uuid::new_v4();
+        self.entries.lock().insert(
+            job_id,
+            JobRecord {
+                owner: owner.to_string(),
+                status: JobStatus::Queued,
+                error: None,
+                result_id: None,
+                warnings: Vec::new(),
+                updated_at: Instant::now(),
+            },
+        );
+        job_id
+    }
+
+    pub fn mark_running(&self, job_id: &Uuid) {
+        self.update(job_id, |record| record.status = JobStatus::Running);
+    }
+
+    pub fn mark_done(&self, job_id: &Uuid, result_id: Uuid, warnings: Vec<String>) {
+        self.update(job_id, |record| {
+            record.status = JobStatus::Done;
+            record.result_id = Some(result_id);
+            record.warnings = warnings;
+        });
+    }
+
+    pub fn mark_failed(&self, job_id: &Uuid, error: impl Into<String>) {
+        let error = error.into();
+        self.update(job_id, |record| {
+            record.status = JobStatus::Failed;
+            record.error = Some(error);
+        });
+    }
+
+    pub fn get(&self, job_id: &Uuid, owner: &str) -> Option<JobRecord> {
+        self.entries
+            .lock()
+            .get(job_id)
+            .filter(|record| record.owner == owner)
+            .cloned()
+    }
+
+    // Only finished jobs expire; queued and running jobs stay visible until
+    // their background task reports back.
+    pub fn sweep_expired(&self) {
+        let now = Instant::now();
+        self.entries.lock().retain(|_, record| {
+            !record.status.is_finished() || now.duration_since(record.updated_at) < self.ttl
+        });
+    }
+
+    pub fn spawn_sweeper(&self, interval: Duration) {
+        let store = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                store.sweep_expired();
+            }
+        });
+    }
+
+    fn update(&self, job_id: &Uuid, apply: impl FnOnce(&mut JobRecord)) {
+        if let Some(record) = self.entries.lock().get_mut(job_id) {
+            apply(record);
+            record.updated_at = Instant::now();
+        }
+    }
+}
@@ -1,17 +1,30 @@
 mod auth;
+mod billing;
 mod clerk;
 mod config;
 mod convex;
+mod convex_api;
+mod features;
 mod ghostscript;
-mod mupdf;
 mod handlers;
+mod jobs;
+mod messages;
 mod middleware;
+mod mupdf;
+mod net_guard;
+mod ocr;
+mod openapi;
 mod plans;
+mod preflight;
 mod quota;
 mod rate_limit;
+mod results;
 mod serde_convex;
 mod state;
 mod stripe_api;
+mod subscription;
+mod tools;
+mod tus;
 mod upload;
 
 use std::{collections::HashSet, env, net::SocketAddr, path::PathBuf};
@@ -19,14 +32,13 @@ use std::{collections::HashSet, env, net::SocketAddr, path::PathBuf};
 use anyhow::Context;
 use axum::{
     extract::DefaultBodyLimit,
-    http::Method,
+    http::{HeaderName, Method},
     middleware as axum_middleware,
     routing::{delete, get, post},
     Router,
 };
 use axum_server::tls_rustls::RustlsConfig;
 use config::Config;
-use serde_json::json;
 use state::AppState;
 use tower_http::{
     cors::{Any, CorsLayer},
@@ -40,7 +52,7 @@ async fn main() -> anyhow::Result<()> {
         .map(|value| value.eq_ignore_ascii_case("production"))
         .unwrap_or(false);
     let loaded_env_files = load_env_files()?;
-    init_tracing();
+    let log_filter = init_tracing();
     if loaded_env_files.is_empty() {
         tracing::warn!("No .env or .env.local file found. Using process environment only.");
     } else {
@@ -66,23 +78,110 @@ async fn main() -> anyhow::Result<()> {
         );
     }
 
-    let convex = convex::ConvexClient::new(config.convex_url.clone())?;
-    if config.clerk_issuer.is_none() {
+    if config.frontend_url.is_none() {
+        if is_production {
+            return Err(anyhow::anyhow!(
+                "FRONTEND_URL environment variable is not set"
+            ));
+        }
+
+        tracing::warn!(
+            "FRONTEND_URL is not set. Stripe customer portal sessions will fail until it is provided."
+        );
+    }
+
+    if config.clerk_session_cookie.is_some() && config.clerk_session_cookie_origins.is_empty() {
+        tracing::warn!(
+            "CLERK_SESSION_COOKIE is set but neither FRONTEND_URL nor CLERK_SESSION_COOKIE_ORIGINS is. Every session cookie will be rejected."
+        );
+    }
+
+    if let Some(dev_user) = &config.dev_auth_bypass {
         tracing::warn!(
-            "CLERK_ISSUER is not set. JWT verification will accept any valid Clerk issuer."
+            clerk_id = %dev_user.clerk_id,
+            "DEV_AUTH_BYPASS is ON: session routes skip token verification and act as this user. Never expose this server."
         );
     }
 
-    let auth = auth::AuthService::new(config.clerk_issuer.clone())?;
+    if config.convex_auth_token.is_none() {
+        tracing::warn!(
+            "CONVEX_AUTH_TOKEN is not set. Convex requests will be sent without authentication."
+        );
+    }
+
+    let convex = convex::ConvexClient::new(
+        config.convex_url.clone(),
+        config.convex_auth_token.as_deref(),
+    )?;
+    if config.clerk_issuer.is_none() {
+        if config.clerk_issuer_domains.is_empty() {
+            if is_production {
+                return Err(anyhow::anyhow!(
+                    "CLERK_ISSUER or CLERK_ISSUER_DOMAINS must be set"
+                ));
+            }
+            tracing::warn!(
+                "Neither CLERK_ISSUER nor CLERK_ISSUER_DOMAINS is set. Every Clerk token will be rejected."
+            );
+        } else {
+            tracing::warn!(
+                allowed_domains = ?config.clerk_issuer_domains,
+                "CLERK_ISSUER is not set. JWT verification will accept any https issuer in CLERK_ISSUER_DOMAINS."
+            );
+        }
+    }
+
+    let auth = auth::AuthService::new(
+        config.clerk_issuer.clone(),
+        config.clerk_jwt_leeway_secs,
+        config.clerk_token_cache_size,
+        config.jwks_max_keys,
+        config.clerk_issuer_domains.clone(),
+    )?;
     let clerk = clerk::ClerkClient::new(
         config.clerk_api_base.clone(),
         config.clerk_secret_key.as_deref(),
+        config.clerk_webhook_secret.clone(),
+        std::time::Duration::from_secs(config.clerk_email_cache_ttl_secs),
     )?;
     let stripe = stripe_api::StripeApi::new(
         config.stripe_secret_key.clone(),
         config.stripe_webhook_secret.clone(),
+        stripe_api::StripeRetryPolicy::from_config(&config),
     )?;
 
+    let fetch_http = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .dns_resolver(net_guard::PublicOnlyResolver::shared())
+        .timeout(std::time::Duration::from_secs(60))
+        .build()
+        .context("failed to build URL fetch HTTP client")?;
+
+    upload::configure_work_dir(&config.work_dir).await?;
+    tracing::info!(path = %config.work_dir.display(), "Using work directory");
+
+    ghostscript::configure_resource_limits(
+        config.ghostscript_max_bitmap,
+        config.ghostscript_buffer_space,
+    );
+    preflight::configure_min_bleed(config.preflight_min_bleed_mm);
+
+    let dependencies = tools::verify_dependencies().await;
+    if let Some(problem) = dependencies.ghostscript_problem() {
+        if config.require_ghostscript.unwrap_or(is_production) {
+            return Err(anyhow::anyhow!(
+                "Ghostscript dependency check failed: {}",
+                problem
+            ));
+        }
+        tracing::warn!(problem = %problem, "Ghostscript dependency check failed; processing endpoints may not work");
+    }
+    if dependencies.pdfinfo_version.is_none() {
+        tracing::warn!(
+            "pdfinfo not found; page counts and metadata will use the slower Ghostscript fallback"
+        );
+    }
+
     match mupdf::ensure_mutool_recolor_support().await {
         Ok(()) => tracing::info!("mutool recolor support check passed"),
         Err(error) => {
@@ -98,21 +197,97 @@ async fn main() -> anyhow::Result<()> {
         }
     }
 
-    let state = AppState::new(config.clone(), convex, auth, clerk, stripe);
+    let state = AppState::new(
+        config.clone(),
+        convex,
+        auth,
+        clerk,
+        stripe,
+        fetch_http,
+        log_filter,
+    );
+    ghostscript::configure_inkcov_chunking(
+        config.inkcov_chunk_pages,
+        state.ghostscript_semaphore.clone(),
+    );
+    ghostscript::configure_grayscale_splitting(
+        config.grayscale_split_threshold_pages,
+        config.grayscale_split_chunks,
+        state.ghostscript_semaphore.clone(),
+    );
 
-    match state.convex.query::<String>("health:get", json!({})).await {
-        Ok(value) => {
-            tracing::info!(convex_health = %value, "Convex connectivity check passed");
+    // Several concurrent queries open that many pooled connections up front,
+    // so the first requests don't each pay for a TLS handshake.
+    let checks = futures_util::future::join_all(
+        (0..config.convex_warmup_connections).map(|_| state.convex_api.health()),
+    )
+    .await;
+    let failed = checks.iter().filter(|result| result.is_err()).count();
+    match checks.into_iter().find_map(Result::err) {
+        None => {
+            tracing::info!(
+                connections = config.convex_warmup_connections,
+                "Convex connectivity check passed"
+            );
         }
-        Err(error) => {
+        Some(error) => {
             tracing::error!(
                 error = ?error,
+                failed,
                 convex_url = %config.convex_url,
                 "Convex connectivity check failed. If using local Convex, run `bunx convex dev` and ensure CONVEX_URL matches that deployment."
             );
+            if config.convex_required_at_startup {
+                return Err(anyhow::anyhow!(
+                    "Convex is unreachable and CONVEX_REQUIRED_AT_STARTUP is set"
+                ));
+            }
         }
     }
 
+    if state.config.stripe_verify_prices {
+        let invalid = billing::verify_price_ids(&state).await;
+        if invalid == 0 {
+            tracing::info!("Stripe price check passed");
+        } else {
+            tracing::error!(
+                invalid,
+                "Stripe price check failed; checkouts using these prices will fail"
+            );
+        }
+    }
+
+    state
+        .results
+        .spawn_sweeper(std::time::Duration::from_secs(60));
+    state.jobs.spawn_sweeper(std::time::Duration::from_secs(60));
+    if state.config.tus_uploads_enabled {
+        state
+            .uploads
+            .spawn_sweeper(std::time::Duration::from_secs(60));
+    }
+    if state.config.ghostscript_warmup {
+        let state = state.clone();
+        tokio::spawn(async move {
+            let _ = state.warmup_ghostscript().await;
+        });
+    }
+    if state.config.reservation_cleanup_enabled {
+        quota::spawn_reservation_cleanup(
+            state.convex_api.clone(),
+            std::time::Duration::from_secs(state.config.reservation_cleanup_interval_secs),
+            std::time::Duration::from_secs(state.config.reservation_cleanup_lookback_secs),
+        );
+    }
+    if state.config.overage_report_interval_secs > 0
+        && !state.config.stripe_metered_plans.is_empty()
+    {
+        billing::spawn_overage_reporter(
+            state.clone(),
+            std::time::Duration::from_secs(state.config.overage_report_interval_secs),
+        );
+    }
+
     let app = build_router(state.clone());
 
     let addr = SocketAddr::from(([0, 0, 0, 0], config.port));
@@ -149,26 +324,42 @@ async fn main() -> anyhow::Result<()> {
 }
 
 fn build_router(state: AppState) -> Router {
-    let process_public_router = Router::new().route(
-        "/preflight-test",
-        post(handlers::test_document).route_layer(axum_middleware::from_fn_with_state(
-            state.clone(),
-            middleware::preflight_test_rate_limit,
-        )),
-    );
+    let process_public_router = Router::new()
+        .route(
+            "/preflight-test",
+            post(handlers::test_document).route_layer(axum_middleware::from_fn_with_state(
+                state.clone(),
+                middleware::preflight_test_rate_limit,
+            )),
+        )
+        .layer(DefaultBodyLimit::max(handlers::PREFLIGHT_BODY_LIMIT_BYTES));
 
     let process_private_router = Router::new()
         .route("/preflight", post(handlers::preflight_document))
+        .route(
+            "/preflight-url",
+            post(handlers::preflight_document_from_url),
+        )
         .route("/grayscale", post(handlers::convert_document_to_grayscale))
-        .route("/conversion", get(handlers::conversion_placeholder))
+        .route("/extract-pages", post(handlers::extract_pages))
+        .route("/sanitize", post(handlers::sanitize_document))
+        .route("/ocr", post(handlers::ocr_document))
+        .route("/result/{job_id}", get(handlers::get_result))
+        .route("/conversion", get(handlers::conversion_capabilities))
         .route_layer(axum_middleware::from_fn_with_state(
             state.clone(),
             middleware::require_auth_and_sync,
-        ));
+        ))
+        .layer(DefaultBodyLimit::max(handlers::PROCESSING_BODY_LIMIT_BYTES));
 
     let process_router = Router::new()
         .merge(process_public_router)
-        .merge(process_private_router);
+        .merge(process_private_router)
+        .route_layer(axum_middleware::from_fn_with_state(
+            state.clone(),
+            middleware::ghostscript_load_shed,
+        ))
+        .route_layer(axum_middleware::from_fn(middleware::disk_space_preflight));
 
     let api_key_router = Router::new()
         .route(
@@ -179,14 +370,18 @@ fn build_router(state: AppState) -> Router {
         .route_layer(axum_middleware::from_fn_with_state(
             state.clone(),
             middleware::require_auth_and_sync,
-        ));
+        ))
+        .layer(DefaultBodyLimit::max(handlers::JSON_BODY_LIMIT_BYTES));
 
     let subscription_router = Router::new()
         .route("/", get(handlers::get_subscription))
+        .route("/change", post(handlers::change_subscription_plan))
+        .route("/resync", post(handlers::resync_subscription))
         .route_layer(axum_middleware::from_fn_with_state(
             state.clone(),
             middleware::require_auth_and_sync,
-        ));
+        ))
+        .layer(DefaultBodyLimit::max(handlers::JSON_BODY_LIMIT_BYTES));
 
     let stripe_router = Router::new()
         .route(
@@ -198,6 +393,14 @@ fn build_router(state: AppState) -> Router {
             "/create-customer-portal-session",
             post(handlers::create_customer_portal_session),
         )
+        .route_layer(axum_middleware::from_fn_with_state(
+            state.clone(),
+            middleware::require_auth_and_sync,
+        ))
+        .layer(DefaultBodyLimit::max(handlers::JSON_BODY_LIMIT_BYTES));
+
+    let profile_router = Router::new()
+        .route("/", get(handlers::get_profile))
         .route_layer(axum_middleware::from_fn_with_state(
             state.clone(),
             middleware::require_auth_and_sync,
@@ -208,7 +411,8 @@ fn build_router(state: AppState) -> Router {
         .route_layer(axum_middleware::from_fn_with_state(
             state.clone(),
             middleware::require_auth,
-        ));
+        ))
+        .layer(DefaultBodyLimit::max(handlers::JSON_BODY_LIMIT_BYTES));
 
     let api_process_router = Router::new()
         .route("/analyze", post(handlers::process_document_api))
@@ -216,21 +420,51 @@ fn build_router(state: AppState) -> Router {
             "/grayscale",
             post(handlers::convert_document_to_grayscale_api),
         )
+        .route("/jobs/{job_id}", get(handlers::get_job_api))
+        .route("/extract-pages", post(handlers::extract_pages_api))
+        .route("/sanitize", post(handlers::sanitize_document_api))
+        .route("/ocr", post(handlers::ocr_document_api))
+        .route("/result/{job_id}", get(handlers::get_result_api))
         .route_layer(axum_middleware::from_fn_with_state(
             state.clone(),
             middleware::api_key_auth,
-        ));
+        ))
+        .route_layer(axum_middleware::from_fn_with_state(
+            state.clone(),
+            middleware::ghostscript_load_shed,
+        ))
+        .route_layer(axum_middleware::from_fn(middleware::disk_space_preflight))
+        .layer(DefaultBodyLimit::max(handlers::PROCESSING_BODY_LIMIT_BYTES));
 
-    let api_router = Router::new()
+    let mut api_router = Router::new()
         .nest("/keys", api_key_router)
         .nest("/subscription", subscription_router)
         .nest("/stripe", stripe_router)
         .nest("/usage", usage_router)
+        .nest("/me", profile_router)
         .nest("/process", api_process_router)
-        .route_layer(axum_middleware::from_fn_with_state(
-            state.clone(),
-            middleware::api_rate_limit,
-        ));
+        .route("/plans", get(handlers::list_plans));
+
+    if state.config.tus_uploads_enabled {
+        let upload_router = Router::new()
+            .route("/", post(handlers::tus_create_upload))
+            .route(
+                "/{upload_id}",
+                axum::routing::head(handlers::tus_upload_status).patch(handlers::tus_append_chunk),
+            )
+            .route_layer(axum_middleware::from_fn_with_state(
+                state.clone(),
+                middleware::api_key_auth,
+            ))
+            .route_layer(axum_middleware::from_fn(middleware::disk_space_preflight))
+            .layer(DefaultBodyLimit::max(handlers::PROCESSING_BODY_LIMIT_BYTES));
+        api_router = api_router.nest("/uploads", upload_router);
+    }
+
+    let api_router = api_router.route_layer(axum_middleware::from_fn_with_state(
+        state.clone(),
+        middleware::api_rate_limit,
+    ));
 
     let cors = CorsLayer::new()
         .allow_origin(Any)
@@ -238,19 +472,102 @@ fn build_router(state: AppState) -> Router {
             Method::GET,
             Method::POST,
             Method::PATCH,
+            Method::HEAD,
             Method::DELETE,
             Method::OPTIONS,
         ])
-        .allow_headers(Any);
+        .allow_headers(Any)
+        .expose_headers([
+            HeaderName::from_static("x-quota-limit"),
+            HeaderName::from_static("x-quota-used"),
+            HeaderName::from_static("x-quota-remaining"),
+            HeaderName::from_static("x-debug-timings"),
+            HeaderName::from_static("x-job-id"),
+            HeaderName::from_static("x-job-expires-in"),
+            HeaderName::from_static("x-javascript-found"),
+            HeaderName::from_static("x-processing-warnings"),
+            HeaderName::from_static("x-already-grayscale"),
+            HeaderName::from_static("location"),
+            HeaderName::from_static("tus-resumable"),
+            HeaderName::from_static("tus-version"),
+            HeaderName::from_static("tus-max-size"),
+            HeaderName::from_static("tus-extension"),
+            HeaderName::from_static("upload-offset"),
+            HeaderName::from_static("upload-length"),
+            HeaderName::from_static("www-authenticate"),
+            HeaderName::from_static("accept-ranges"),
+            HeaderName::from_static("content-range"),
+            HeaderName::from_static("etag"),
+            HeaderName::from_static("retry-after"),
+        ]);
+
+    let debug_router = Router::new()
+        .route("/queue", get(handlers::debug_queue))
+        .route_layer(axum_middleware::from_fn_with_state(
+            state.clone(),
+            middleware::require_admin,
+        ));
+
+    let admin_router = Router::new()
+        .route("/log-level", post(handlers::set_log_level))
+        .route("/warmup", post(handlers::warmup_ghostscript))
+        .route("/usage/report", get(handlers::usage_report))
+        .route("/billing/report-overage", post(handlers::report_overage))
+        .route(
+            "/subscriptions/{clerk_id}/resync",
+            post(handlers::admin_resync_subscription),
+        )
+        .route(
+            "/features",
+            get(handlers::get_feature_flags).post(handlers::set_feature_flags),
+        )
+        .route_layer(axum_middleware::from_fn_with_state(
+            state.clone(),
+            middleware::require_admin,
+        ))
+        .layer(DefaultBodyLimit::max(handlers::JSON_BODY_LIMIT_BYTES));
 
     Router::new()
-        .route("/api/stripe/webhook", post(handlers::handle_stripe_webhook))
-        .nest("/health", Router::new().route("/", get(handlers::health)))
+        .route(
+            "/api/stripe/webhook",
+            post(handlers::handle_stripe_webhook)
+                .layer(DefaultBodyLimit::max(handlers::WEBHOOK_BODY_LIMIT_BYTES)),
+        )
+        .route(
+            "/api/clerk/webhook",
+            post(handlers::handle_clerk_webhook)
+                .layer(DefaultBodyLimit::max(handlers::WEBHOOK_BODY_LIMIT_BYTES)),
+        )
+        .nest(
+            "/health",
+            Router::new()
+                .route("/", get(handlers::health))
+                .route("/live", get(handlers::health_live))
+                .route("/jwks", get(handlers::health_jwks)),
+        )
+        .route("/openapi.json", get(openapi::openapi_json))
         .nest("/process", process_router)
         .nest("/api", api_router)
+        .nest("/debug", debug_router)
+        .nest("/admin", admin_router)
         .fallback(handlers::not_found)
-        .with_state(state)
+        // Must come after the nests so it also covers nested routes; it also
+        // keeps route_layer auth from answering 401 for a wrong method.
+        .method_not_allowed_fallback(handlers::method_not_allowed)
+        .with_state(state.clone())
         .layer(DefaultBodyLimit::max(25 * 1024 * 1024))
+        // Inside CORS so rejections still carry CORS headers.
+        .layer(axum_middleware::from_fn_with_state(
+            state.clone(),
+            middleware::global_concurrency_limit,
+        ))
+        // Outside the concurrency limit so time spent queued counts too.
+        .layer(axum_middleware::from_fn_with_state(
+            state,
+            middleware::request_deadline,
+        ))
+        // Outermost below CORS so middleware rejections are translated too.
+        .layer(axum_middleware::from_fn(middleware::localize_errors))
         .layer(cors)
         .layer(TraceLayer::new_for_http())
 }
@@ -297,15 +614,33 @@ fn valid_tls_paths(config: &Config) -> Option<(String, String)> {
     }
 }
 
-fn init_tracing() {
+// Runs before Config is loaded, so LOG_FORMAT is read straight from the env.
+fn init_tracing() -> state::LogFilterHandle {
+    use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, Layer};
+
     let env_filter = tracing_subscriber::EnvFilter::try_from_default_env()
         .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info"));
-
-    tracing_subscriber::fmt()
-        .with_env_filter(env_filter)
-        .with_target(false)
-        .compact()
+    let (filter_layer, handle) = tracing_subscriber::reload::Layer::new(env_filter);
+
+    let format = env::var("LOG_FORMAT")
+        .map(|value| value.trim().to_ascii_lowercase())
+        .unwrap_or_default();
+    let fmt_layer = match format.as_str() {
+        "json" => fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .boxed(),
+        "pretty" => fmt::layer().pretty().boxed(),
+        _ => fmt::layer().with_target(false).compact().boxed(),
+    };
+
+    tracing_subscriber::registry()
+        .with(filter_layer)
+        .with(fmt_layer)
         .init();
+
+    handle
 }
 
 fn load_env_files() -> anyhow::Result<Vec<PathBuf>> {
@@ -347,3 +682,66 @@ fn load_env_files() -> anyhow::Result<Vec<PathBuf>> {
 
     Ok(loaded)
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{header, Request, StatusCode},
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn router() -> Router {
+        build_router(AppState::for_tests(Config::for_tests()))
+    }
+
+    #[tokio::test]
+    async fn wrong_method_gets_405_with_allow_before_auth() {
+        for uri in ["/process/grayscale", "/api/process/analyze"] {
+            let response = router()
+                .oneshot(Request::get(uri).body(Body::empty()).expect("request"))
+                .await
+                .expect("response");
+
+            assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED, "{uri}");
+            let allow = response
+                .headers()
+                .get(header::ALLOW)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default();
+            assert!(allow.contains("POST"), "{uri}: {allow:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn oversized_webhook_bodies_get_413() {
+        for uri in ["/api/stripe/webhook", "/api/clerk/webhook"] {
+            let status_for = |size: usize| async move {
+                router()
+                    .oneshot(
+                        Request::post(uri)
+                            .body(Body::from(vec![b'{'; size]))
+                            .expect("request"),
+                    )
+                    .await
+                    .expect("response")
+                    .status()
+            };
+
+            assert_eq!(
+                status_for(handlers::WEBHOOK_BODY_LIMIT_BYTES + 1).await,
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "{uri}"
+            );
+            // At the limit the body is read and the missing signature is
+            // what gets rejected.
+            assert_ne!(
+                status_for(handlers::WEBHOOK_BODY_LIMIT_BYTES).await,
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "{uri}"
+            );
+        }
+    }
+}
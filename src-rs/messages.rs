@@ -0,0 +1,253 @@
+// Translations for user-facing error messages, keyed by the stable `code`
+// field of JSON error bodies. Handlers keep writing the English text; the
+// `localize_errors` middleware swaps it for the client's language.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageCode {
+    FileNotFound,
+    UnsupportedFileType,
+    FileTooLarge,
+    InvalidUrl,
+    DownloadFailed,
+    UploadStalled,
+    UnknownUpload,
+    UploadFailed,
+    FormTooLarge,
+    InsufficientStorage,
+    PdfPasswordRequired,
+    InvalidPdf,
+    CorruptPdf,
+    OutputTooLarge,
+    OcrUnavailable,
+    FeatureDisabled,
+    QuotaExceeded,
+    ScopeNotPermitted,
+    ServerBusy,
+    QueueFull,
+    RequestTimeout,
+    InvalidOption,
+    InvalidBlackThreshold,
+    InvalidSample,
+    InvalidLanguage,
+    InvalidProfile,
+    InvalidPdfVersion,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Language {
+    #[default]
+    En,
+    De,
+    Fr,
+}
+
+impl Language {
+    pub fn tag(self) -> &'static str {
+        match self {
+            Self::En => "en",
+            Self::De => "de",
+            Self::Fr => "fr",
+        }
+    }
+
+    // Picks the highest-weighted supported language; anything else is English.
+    pub fn from_accept_language(header: &str) -> Self {
+        let mut best: Option<(f32, Self)> = None;
+        for entry in header.split(',') {
+            let mut parts = entry.split(';');
+            let tag = parts.next().unwrap_or_default().trim();
+            let weight = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .and_then(|value| value.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if weight <= 0.0 {
+                continue;
+            }
+            let primary = tag.split('-').next().unwrap_or_default();
+            let language = match primary.to_ascii_lowercase().as_str() {
+                "en" => Self::En,
+                "de" => Self::De,
+                "fr" => Self::Fr,
+                _ => continue,
+            };
+            if best.is_none_or(|(best_weight, _)| weight > best_weight) {
+                best = Some((weight, language));
+            }
+        }
+        best.map(|(_, language)| language).unwrap_or_default()
+    }
+}
+
+impl MessageCode {
+    pub fn code(self) -> &'static str {
+        match self {
+            Self::FileNotFound => "file_not_found",
+            Self::UnsupportedFileType => "unsupported_file_type",
+            Self::FileTooLarge => "file_too_large",
+            Self::InvalidUrl => "invalid_url",
+            Self::DownloadFailed => "download_failed",
+            Self::UploadStalled => "upload_stalled",
+            Self::UnknownUpload => "unknown_upload",
+            Self::UploadFailed => "upload_failed",
+            Self::FormTooLarge => "form_too_large",
+            Self::InsufficientStorage => "insufficient_storage",
+            Self::PdfPasswordRequired => "pdf_password_required",
+            Self::InvalidPdf => "invalid_pdf",
+            Self::CorruptPdf => "corrupt_pdf",
+            Self::OutputTooLarge => "output_too_large",
+            Self::OcrUnavailable => "ocr_unavailable",
+            Self::FeatureDisabled => "feature_disabled",
+            Self::QuotaExceeded => "quota_exceeded",
+            Self::ScopeNotPermitted => "scope_not_permitted",
+            Self::ServerBusy => "server_busy",
+            Self::QueueFull => "queue_full",
+            Self::RequestTimeout => "request_timeout",
+            Self::InvalidOption => "invalid_option",
+            Self::InvalidBlackThreshold => "invalid_black_threshold",
+            Self::InvalidSample => "invalid_sample",
+            Self::InvalidLanguage => "invalid_language",
+            Self::InvalidProfile => "invalid_profile",
+            Self::InvalidPdfVersion => "invalid_pdf_version",
+        }
+    }
+
+    pub fn from_code(code: &str) -> Option<Self> {
+        Some(match code {
+            "file_not_found" => Self::FileNotFound,
+            "unsupported_file_type" => Self::UnsupportedFileType,
+            "file_too_large" => Self::FileTooLarge,
+            "invalid_url" => Self::InvalidUrl,
+            "download_failed" => Self::DownloadFailed,
+            "upload_stalled" => Self::UploadStalled,
+            "unknown_upload" => Self::UnknownUpload,
+            "upload_failed" => Self::UploadFailed,
+            "form_too_large" => Self::FormTooLarge,
+            "insufficient_storage" => Self::InsufficientStorage,
+            "pdf_password_required" => Self::PdfPasswordRequired,
+            "invalid_pdf" => Self::InvalidPdf,
+            "corrupt_pdf" => Self::CorruptPdf,
+            "output_too_large" => Self::OutputTooLarge,
+            "ocr_unavailable" => Self::OcrUnavailable,
+            "feature_disabled" => Self::FeatureDisabled,
+            "quota_exceeded" => Self::QuotaExceeded,
+            "scope_not_permitted" => Self::ScopeNotPermitted,
+            "server_busy" => Self::ServerBusy,
+            "queue_full" => Self::QueueFull,
+            "request_timeout" => Self::RequestTimeout,
+            "invalid_option" => Self::InvalidOption,
+            "invalid_black_threshold" => Self::InvalidBlackThreshold,
+            "invalid_sample" => Self::InvalidSample,
+            "invalid_language" => Self::InvalidLanguage,
+            "invalid_profile" => Self::InvalidProfile,
+            "invalid_pdf_version" => Self::InvalidPdfVersion,
+            _ => return None,
+        })
+    }
+
+    // `None` for English: the handler's own text is the English original.
+    pub fn translation(self, language: Language) -> Option<&'static str> {
+        match language {
+            Language::En => None,
+            Language::De => Some(match self {
+                Self::FileNotFound => "Datei nicht gefunden",
+                Self::UnsupportedFileType => "Es werden nur PDF-Dateien unterstützt",
+                Self::FileTooLarge => "Die Datei überschreitet das Upload-Limit",
+                Self::InvalidUrl => "Es werden nur öffentliche https-URLs unterstützt",
+                Self::DownloadFailed => "Die Datei konnte nicht heruntergeladen werden",
+                Self::UploadStalled => "Der Upload ist ins Stocken geraten",
+                Self::UnknownUpload => "Unbekannter oder unvollständiger Upload",
+                Self::UploadFailed => "Der Upload konnte nicht verarbeitet werden",
+                Self::FormTooLarge => {
+                    "Das Formular hat zu viele Felder oder ein Feld ist zu lang"
+                }
+                Self::InsufficientStorage => {
+                    "Der Server hat vorübergehend keinen Speicherplatz. Bitte versuchen Sie es später erneut."
+                }
+                Self::PdfPasswordRequired => {
+                    "Diese PDF ist passwortgeschützt. Entfernen Sie das Passwort und laden Sie sie erneut hoch."
+                }
+                Self::InvalidPdf => "Diese Datei ist keine gültige PDF.",
+                Self::CorruptPdf => {
+                    "Diese PDF ist beschädigt und konnte nicht gelesen werden. Exportieren Sie sie erneut und laden Sie sie noch einmal hoch."
+                }
+                Self::OutputTooLarge => {
+                    "Die konvertierte Datei überschreitet die maximale Ausgabegröße."
+                }
+                Self::OcrUnavailable => "OCR ist auf diesem Server nicht verfügbar.",
+                Self::FeatureDisabled => "Diese Funktion ist vorübergehend deaktiviert.",
+                Self::QuotaExceeded => "Monatliches Kontingent überschritten.",
+                Self::ScopeNotPermitted => {
+                    "Dieser API-Schlüssel darf diese Operation nicht verwenden."
+                }
+                Self::ServerBusy => {
+                    "Der Server ist ausgelastet, bitte versuchen Sie es gleich erneut."
+                }
+                Self::QueueFull => {
+                    "Die Verarbeitungswarteschlange ist voll, bitte versuchen Sie es gleich erneut."
+                }
+                Self::RequestTimeout => "Die Anfrage hat zu lange gedauert.",
+                Self::InvalidOption => "Ein Parameter der Anfrage ist ungültig.",
+                Self::InvalidBlackThreshold => {
+                    "Ungültige Schwarzschwelle: blackThresholdL muss zwischen 0 und 100 und blackThresholdC zwischen 0 und 128 liegen, und forceBlackText oder forceBlackVector muss aktiv sein."
+                }
+                Self::InvalidSample => "Ungültiger Wert für sample.",
+                Self::InvalidLanguage => {
+                    "Ungültige OCR-Sprache. Verwenden Sie Tesseract-Codes wie eng oder eng+deu."
+                }
+                Self::InvalidProfile => "Ungültiges Profilformat.",
+                Self::InvalidPdfVersion => {
+                    "Ungültige PDF-Version: erlaubt sind 1.3 bis 1.7 mit der Ghostscript-Engine."
+                }
+            }),
+            Language::Fr => Some(match self {
+                Self::FileNotFound => "Fichier introuvable",
+                Self::UnsupportedFileType => "Seuls les fichiers PDF sont pris en charge",
+                Self::FileTooLarge => "Le fichier dépasse la limite de téléversement",
+                Self::InvalidUrl => "Seules les URL https publiques sont prises en charge",
+                Self::DownloadFailed => "Échec du téléchargement du fichier",
+                Self::UploadStalled => "Le téléversement est bloqué",
+                Self::UnknownUpload => "Téléversement inconnu ou incomplet",
+                Self::UploadFailed => "Impossible de traiter le téléversement",
+                Self::FormTooLarge => {
+                    "Le formulaire contient trop de champs ou un champ trop long"
+                }
+                Self::InsufficientStorage => {
+                    "Le serveur manque temporairement d'espace de stockage. Veuillez réessayer plus tard."
+                }
+                Self::PdfPasswordRequired => {
+                    "Ce PDF est protégé par un mot de passe. Supprimez le mot de passe et téléversez-le à nouveau."
+                }
+                Self::InvalidPdf => "Ce fichier n'est pas un PDF valide.",
+                Self::CorruptPdf => {
+                    "Ce PDF est endommagé et n'a pas pu être lu. Exportez-le à nouveau puis téléversez-le."
+                }
+                Self::OutputTooLarge => {
+                    "Le fichier converti dépasse la taille de sortie maximale."
+                }
+                Self::OcrUnavailable => "L'OCR n'est pas disponible sur ce serveur.",
+                Self::FeatureDisabled => "Cette opération est temporairement désactivée.",
+                Self::QuotaExceeded => "Quota mensuel dépassé.",
+                Self::ScopeNotPermitted => {
+                    "Cette clé API n'est pas autorisée à utiliser cette opération."
+                }
+                Self::ServerBusy => "Le serveur est occupé, veuillez réessayer dans un instant.",
+                Self::QueueFull => {
+                    "La file de traitement est pleine, veuillez réessayer dans un instant."
+                }
+                Self::RequestTimeout => "La requête a pris trop de temps.",
+                Self::InvalidOption => "Un paramètre de la requête est invalide.",
+                Self::InvalidBlackThreshold => {
+                    "Seuil de noir invalide : blackThresholdL doit être entre 0 et 100 et blackThresholdC entre 0 et 128, avec forceBlackText ou forceBlackVector activé."
+                }
+                Self::InvalidSample => "Valeur de sample invalide.",
+                Self::InvalidLanguage => {
+                    "Langue OCR invalide. Utilisez des codes Tesseract comme eng ou eng+deu."
+                }
+                Self::InvalidProfile => "Format de profil invalide.",
+                Self::InvalidPdfVersion => {
+                    "Version PDF invalide : de 1.3 à 1.7, avec le moteur Ghostscript uniquement."
+                }
+            }),
+        }
+    }
+}
@@ -1,17 +1,36 @@
-use std::net::SocketAddr;
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
 
 use axum::{
-    body::Body,
+    body::{Body, HttpBody},
     extract::connect_info::ConnectInfo,
-    extract::State,
-    http::{header::AUTHORIZATION, HeaderMap, Request, StatusCode},
+    extract::{OriginalUri, State},
+    http::{
+        header::{
+            ACCEPT_LANGUAGE, AUTHORIZATION, CONTENT_LANGUAGE, CONTENT_LENGTH, CONTENT_TYPE, COOKIE,
+            ORIGIN, RETRY_AFTER, VARY, WWW_AUTHENTICATE,
+        },
+        HeaderMap, HeaderValue, Method, Request, StatusCode,
+    },
     middleware::Next,
     response::{IntoResponse, Response},
+    Json,
 };
+use ipnet::IpNet;
 use serde::Deserialize;
 use serde_json::json;
+use subtle::ConstantTimeEq;
 
-use crate::state::AppState;
+use crate::{
+    auth::{AuthError, ClerkClaims},
+    config::{ConcurrencyOverflow, Config},
+    messages::{Language, MessageCode},
+    serde_convex::de_opt_i64_from_number,
+    state::{AppState, UserSyncDue},
+    upload::{ensure_disk_space, remove_tracked_temp_paths, scope_temp_paths, TrackedTempPaths},
+};
 
 #[derive(Debug, Clone)]
 pub struct AuthenticatedUser {
@@ -22,6 +41,153 @@ pub struct AuthenticatedUser {
 pub struct ConvexUser {
     #[serde(rename = "clerkId")]
     pub clerk_id: Option<String>,
+    // Keys created before scoping existed have no scopes and keep full access.
+    #[serde(default)]
+    pub scopes: Option<Vec<String>>,
+    #[serde(rename = "expiresAt")]
+    #[serde(default, deserialize_with = "de_opt_i64_from_number")]
+    pub expires_at: Option<i64>,
+}
+
+pub const API_KEY_SCOPES: &[&str] = &["analyze", "grayscale", "extract_pages", "sanitize", "ocr"];
+
+impl ConvexUser {
+    pub fn allows(&self, scope: &str) -> bool {
+        self.scopes
+            .as_ref()
+            .is_none_or(|scopes| scopes.iter().any(|granted| granted == scope))
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum ApiScope {
+    Required(&'static str),
+    Open,
+    // Scoped keys are refused on anything not listed, so a new operation
+    // route stays closed to them until it is given a scope here.
+    Unlisted,
+}
+
+// Matches the full request path. Job polling, result downloads and tus
+// uploads only touch the caller's own data, so any key may use them.
+fn required_api_scope(path: &str) -> ApiScope {
+    match path.trim_end_matches('/') {
+        "/api/process/analyze" => ApiScope::Required("analyze"),
+        "/api/process/grayscale" => ApiScope::Required("grayscale"),
+        "/api/process/extract-pages" => ApiScope::Required("extract_pages"),
+        "/api/process/sanitize" => ApiScope::Required("sanitize"),
+        "/api/process/ocr" => ApiScope::Required("ocr"),
+        "/api/uploads" => ApiScope::Open,
+        path if is_single_segment_under(path, "/api/process/jobs/")
+            || is_single_segment_under(path, "/api/process/result/")
+            || is_single_segment_under(path, "/api/uploads/") =>
+        {
+            ApiScope::Open
+        }
+        _ => ApiScope::Unlisted,
+    }
+}
+
+fn is_single_segment_under(path: &str, prefix: &str) -> bool {
+    path.strip_prefix(prefix)
+        .is_some_and(|rest| !rest.is_empty() && !rest.contains('/'))
+}
+
+async fn verify_request_token(
+    state: &AppState,
+    method: &Method,
+    headers: &HeaderMap,
+) -> Result<ClerkClaims, Response> {
+    // The Authorization header always wins; the session cookie is only read
+    // when the header is absent and CLERK_SESSION_COOKIE is set.
+    let result = match headers.get(AUTHORIZATION) {
+        Some(value) => match value.to_str() {
+            Ok(value) => state.auth.verify_bearer_token(value).await,
+            Err(_) => Err(AuthError::MalformedHeader),
+        },
+        None => match state
+            .config
+            .clerk_session_cookie
+            .as_deref()
+            .and_then(|name| session_cookie(headers, name))
+        {
+            Some(token) => verify_session_cookie(state, method, headers, &token).await,
+            None => Err(AuthError::MissingHeader),
+        },
+    };
+
+    result.map_err(|error| {
+        if !matches!(error, AuthError::MissingHeader) {
+            tracing::warn!(error = %error, "authorization failed");
+        }
+        if matches!(error, AuthError::CrossSiteRequest) {
+            return (StatusCode::FORBIDDEN, "Forbidden").into_response();
+        }
+        unauthorized_response(&error)
+    })
+}
+
+// Browsers attach the cookie to cross-site requests too, so state-changing
+// requests must come from an allowed origin and the token must have been
+// issued to one.
+async fn verify_session_cookie(
+    state: &AppState,
+    method: &Method,
+    headers: &HeaderMap,
+    token: &str,
+) -> Result<ClerkClaims, AuthError> {
+    let origins = &state.config.clerk_session_cookie_origins;
+    if !method.is_safe() && !is_same_site_request(headers, origins) {
+        return Err(AuthError::CrossSiteRequest);
+    }
+
+    let claims = state.auth.verify_token(token).await?;
+    match claims.azp.as_deref() {
+        Some(azp)
+            if origins
+                .iter()
+                .any(|origin| origin == azp.trim_end_matches('/')) =>
+        {
+            Ok(claims)
+        }
+        other => Err(AuthError::UntrustedParty(
+            other.unwrap_or("none").to_string(),
+        )),
+    }
+}
+
+// Requests without either header (non-browser clients) are treated as
+// cross-site.
+fn is_same_site_request(headers: &HeaderMap, origins: &[String]) -> bool {
+    let origin_allowed = headers
+        .get(ORIGIN)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|origin| origins.iter().any(|allowed| allowed == origin));
+    let same_site = headers
+        .get("sec-fetch-site")
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|site| matches!(site, "same-origin" | "same-site"));
+    origin_allowed || same_site
+}
+
+fn session_cookie(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| key.trim() == name)
+        .map(|(_, value)| value.trim().trim_matches('"').to_string())
+        .filter(|value| !value.is_empty())
+}
+
+fn unauthorized_response(error: &AuthError) -> Response {
+    let mut response = (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+    if let Ok(value) = HeaderValue::from_str(&error.www_authenticate()) {
+        response.headers_mut().insert(WWW_AUTHENTICATE, value);
+    }
+    response
 }
 
 pub async fn require_auth(
@@ -29,21 +195,16 @@ pub async fn require_auth(
     mut request: Request<Body>,
     next: Next,
 ) -> Response {
-    let auth_header = match request
-        .headers()
-        .get(AUTHORIZATION)
-        .and_then(|value| value.to_str().ok())
-    {
-        Some(value) => value,
-        None => return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response(),
-    };
+    if let Some(dev_user) = &state.config.dev_auth_bypass {
+        request.extensions_mut().insert(AuthenticatedUser {
+            clerk_id: dev_user.clerk_id.clone(),
+        });
+        return next.run(request).await;
+    }
 
-    let claims = match state.auth.verify_bearer_token(auth_header).await {
+    let claims = match verify_request_token(&state, request.method(), request.headers()).await {
         Ok(claims) => claims,
-        Err(error) => {
-            tracing::warn!(error = %error, "authorization failed");
-            return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
-        }
+        Err(response) => return response,
     };
 
     request.extensions_mut().insert(AuthenticatedUser {
@@ -58,42 +219,57 @@ pub async fn require_auth_and_sync(
     mut request: Request<Body>,
     next: Next,
 ) -> Response {
-    let auth_header = match request
-        .headers()
-        .get(AUTHORIZATION)
-        .and_then(|value| value.to_str().ok())
-    {
-        Some(value) => value,
-        None => return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response(),
-    };
+    if let Some(dev_user) = &state.config.dev_auth_bypass {
+        // There is no Clerk user to read the email from, so the Convex row is
+        // written from config the first time.
+        if let Some(UserSyncDue::FirstSeen) = state.claim_user_sync(&dev_user.clerk_id) {
+            let _guard = state.lock_first_user_sync(&dev_user.clerk_id).await;
+            if !state.user_synced(&dev_user.clerk_id) {
+                match state
+                    .convex_api
+                    .sync_user(&dev_user.clerk_id, &dev_user.email)
+                    .await
+                {
+                    Ok(()) => state.mark_user_synced(&dev_user.clerk_id),
+                    Err(error) => {
+                        tracing::error!(error = %error, "failed to sync dev auth user to Convex")
+                    }
+                }
+            }
+        }
+        request.extensions_mut().insert(AuthenticatedUser {
+            clerk_id: dev_user.clerk_id.clone(),
+        });
+        return next.run(request).await;
+    }
 
-    let claims = match state.auth.verify_bearer_token(auth_header).await {
+    let claims = match verify_request_token(&state, request.method(), request.headers()).await {
         Ok(claims) => claims,
-        Err(error) => {
-            tracing::warn!(error = %error, "authorization failed");
-            return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
-        }
+        Err(response) => return response,
     };
 
     let clerk_id = claims.sub;
 
     if state.config.clerk_secret_key.is_some() {
-        match state.clerk.get_primary_email(&clerk_id).await {
-            Ok(Some(email)) => {
-                if let Err(error) = state
-                    .convex
-                    .action_value("users:sync", json!({ "clerkId": clerk_id, "email": email }))
-                    .await
-                {
-                    tracing::error!(error = %error, "failed to sync user to Convex");
+        match state.claim_user_sync(&clerk_id) {
+            // The user row must exist before handlers look it up, so
+            // concurrent first requests wait for whichever one is syncing.
+            Some(UserSyncDue::FirstSeen) => {
+                let _guard = state.lock_first_user_sync(&clerk_id).await;
+                if !state.user_synced(&clerk_id) && sync_user(&state, &clerk_id, false).await {
+                    state.mark_user_synced(&clerk_id);
                 }
             }
-            Ok(None) => {
-                tracing::warn!(user_id = %clerk_id, "user has no primary email in Clerk");
-            }
-            Err(error) => {
-                tracing::error!(error = %error, user_id = %clerk_id, "failed to load Clerk user");
+            Some(UserSyncDue::Stale) => {
+                let state = state.clone();
+                let clerk_id = clerk_id.clone();
+                tokio::spawn(async move {
+                    // Periodic re-syncs exist to pick up email changes, so
+                    // skip the email cache here.
+                    sync_user(&state, &clerk_id, true).await;
+                });
             }
+            None => {}
         }
     }
 
@@ -104,6 +280,56 @@ pub async fn require_auth_and_sync(
     next.run(request).await
 }
 
+async fn sync_user(state: &AppState, clerk_id: &str, refresh_email: bool) -> bool {
+    let email = if refresh_email {
+        state.clerk.refresh_primary_email(clerk_id).await
+    } else {
+        state.clerk.get_primary_email(clerk_id).await
+    };
+
+    match email {
+        Ok(Some(email)) => {
+            if let Err(error) = state.convex_api.sync_user(clerk_id, &email).await {
+                tracing::error!(error = %error, "failed to sync user to Convex");
+                return false;
+            }
+            true
+        }
+        Ok(None) => {
+            tracing::warn!(user_id = %clerk_id, "user has no primary email in Clerk");
+            true
+        }
+        Err(error) => {
+            tracing::error!(error = %error, user_id = %clerk_id, "failed to load Clerk user");
+            false
+        }
+    }
+}
+
+// Admin routes look like they don't exist unless ADMIN_TOKEN is set.
+pub async fn require_admin(
+    State(state): State<AppState>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let Some(expected) = state.config.admin_token.as_deref() else {
+        return (StatusCode::NOT_FOUND, "Not Found").into_response();
+    };
+
+    let provided = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| crate::auth::extract_bearer_token(value).ok());
+    let authorized =
+        provided.is_some_and(|token| bool::from(token.as_bytes().ct_eq(expected.as_bytes())));
+    if !authorized {
+        return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+    }
+
+    next.run(request).await
+}
+
 pub async fn api_key_auth(
     State(state): State<AppState>,
     mut request: Request<Body>,
@@ -125,32 +351,49 @@ pub async fn api_key_auth(
         }
     };
 
-    let user_value = match state
-        .convex
-        .action_value(
-            "apiKeys:authenticateAndTrackUsage",
-            json!({ "key": api_key }),
-        )
-        .await
-    {
-        Ok(value) => value,
+    let user = match state.convex_api.authenticate_api_key(api_key).await {
+        Ok(Some(user)) => user,
+        Ok(None) => {
+            return (StatusCode::UNAUTHORIZED, "Unauthorized: Invalid API Key.").into_response();
+        }
         Err(error) => {
             tracing::error!(error = %error, "API key authentication failed");
             return (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response();
         }
     };
 
-    if user_value.is_null() {
-        return (StatusCode::UNAUTHORIZED, "Unauthorized: Invalid API Key.").into_response();
+    if user
+        .expires_at
+        .is_some_and(|expires_at| expires_at <= chrono::Utc::now().timestamp_millis())
+    {
+        return (
+            StatusCode::UNAUTHORIZED,
+            "Unauthorized: API Key has expired.",
+        )
+            .into_response();
     }
 
-    let user: ConvexUser = match serde_json::from_value(user_value) {
-        Ok(value) => value,
-        Err(error) => {
-            tracing::error!(error = %error, "failed to decode Convex user from API key auth");
-            return (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response();
-        }
+    // Nested routers see a stripped URI, so match on the original one.
+    let path = request
+        .extensions()
+        .get::<OriginalUri>()
+        .map_or_else(|| request.uri().path(), |uri| uri.path());
+    let denied = match required_api_scope(path) {
+        ApiScope::Required(scope) => (!user.allows(scope)).then(|| json!(scope)),
+        ApiScope::Open => None,
+        ApiScope::Unlisted => user.scopes.is_some().then_some(serde_json::Value::Null),
     };
+    if let Some(required_scope) = denied {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({
+                "error": "API key is not permitted to use this operation.",
+                "code": MessageCode::ScopeNotPermitted.code(),
+                "requiredScope": required_scope,
+            })),
+        )
+            .into_response();
+    }
 
     request.extensions_mut().insert(user);
 
@@ -172,7 +415,7 @@ pub async fn preflight_test_rate_limit(
                 .get::<ConnectInfo<SocketAddr>>()
                 .map(|value| value.0)
         });
-    let key = client_identity(request.headers(), socket_addr, state.config.trust_proxy);
+    let key = client_identity(request.headers(), socket_addr, &state.config);
 
     if !state.preflight_test_limiter.check_and_count(&key) {
         return (
@@ -185,6 +428,217 @@ pub async fn preflight_test_rate_limit(
     next.run(request).await
 }
 
+const OVERLOAD_RETRY_AFTER_SECS: u64 = 1;
+
+pub async fn global_concurrency_limit(
+    State(state): State<AppState>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let semaphore = state.request_semaphore.clone();
+    let permit = match state.config.concurrency_overflow {
+        ConcurrencyOverflow::Reject => semaphore.try_acquire_owned().ok(),
+        ConcurrencyOverflow::Queue => semaphore.acquire_owned().await.ok(),
+    };
+    let Some(_permit) = permit else {
+        tracing::warn!(
+            limit = state.config.max_concurrent_requests,
+            "rejecting request: concurrency limit reached"
+        );
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(RETRY_AFTER, OVERLOAD_RETRY_AFTER_SECS.to_string())],
+            Json(json!({
+                "error": "Server is busy, please retry shortly.",
+                "code": MessageCode::ServerBusy.code(),
+            })),
+        )
+            .into_response();
+    };
+
+    next.run(request).await
+}
+
+// End-to-end budget so a chain of slow-but-not-timed-out calls can't outlast
+// the client. Temp files created under the request are removed on expiry.
+pub async fn request_deadline(
+    State(state): State<AppState>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let path = request.uri().path();
+    let is_processing = ["/process", "/api/process", "/api/uploads"]
+        .iter()
+        .any(|prefix| path == *prefix || path.starts_with(&format!("{}/", prefix)));
+    // OCR runs far longer than the other operations.
+    let is_ocr = path == "/process/ocr" || path == "/api/process/ocr";
+    let deadline_secs = if is_ocr {
+        state.config.ocr_request_deadline_secs
+    } else if is_processing {
+        state.config.process_request_deadline_secs
+    } else {
+        state.config.request_deadline_secs
+    };
+    if deadline_secs == 0 {
+        return next.run(request).await;
+    }
+
+    let method = request.method().clone();
+    let path = path.to_string();
+    let temp_paths = TrackedTempPaths::default();
+    let run = scope_temp_paths(temp_paths.clone(), next.run(request));
+    match tokio::time::timeout(Duration::from_secs(deadline_secs), run).await {
+        Ok(response) => response,
+        Err(_) => {
+            remove_tracked_temp_paths(&temp_paths).await;
+            tracing::warn!(
+                method = %method,
+                path = %path,
+                deadline_secs,
+                "request deadline exceeded"
+            );
+            (
+                StatusCode::GATEWAY_TIMEOUT,
+                Json(json!({
+                    "error": "The request took too long to complete.",
+                    "code": MessageCode::RequestTimeout.code(),
+                })),
+            )
+                .into_response()
+        }
+    }
+}
+
+// Error bodies are small JSON objects; anything bigger, or of unknown length,
+// is passed through as is.
+const LOCALIZED_BODY_LIMIT_BYTES: usize = 64 * 1024;
+
+// Rewrites the `error` text of coded JSON error responses into the language
+// picked from Accept-Language. `code` and every other field stay unchanged.
+pub async fn localize_errors(request: Request<Body>, next: Next) -> Response {
+    let language = request
+        .headers()
+        .get(ACCEPT_LANGUAGE)
+        .and_then(|value| value.to_str().ok())
+        .map(Language::from_accept_language)
+        .unwrap_or_default();
+
+    let mut response = next.run(request).await;
+    let is_json_error = (response.status().is_client_error()
+        || response.status().is_server_error())
+        && response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("application/json"));
+    if !is_json_error {
+        return response;
+    }
+    // Any JSON error could have come back translated, so caches must key on
+    // the header even when this one wasn't.
+    response
+        .headers_mut()
+        .append(VARY, HeaderValue::from_static("accept-language"));
+    if language == Language::En {
+        return response;
+    }
+    let fits_limit = response
+        .body()
+        .size_hint()
+        .upper()
+        .is_some_and(|len| len <= LOCALIZED_BODY_LIMIT_BYTES as u64);
+    if !fits_limit {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let bytes = match axum::body::to_bytes(body, LOCALIZED_BODY_LIMIT_BYTES).await {
+        Ok(bytes) => bytes,
+        Err(error) => {
+            tracing::warn!(error = %error, "failed to buffer error body for localization");
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response();
+        }
+    };
+    let mut value = match serde_json::from_slice::<serde_json::Value>(&bytes) {
+        Ok(value) => value,
+        Err(_) => return Response::from_parts(parts, Body::from(bytes)),
+    };
+    let translation = value
+        .get("code")
+        .and_then(|code| code.as_str())
+        .and_then(MessageCode::from_code)
+        .and_then(|code| code.translation(language));
+    let Some(translation) = translation else {
+        return Response::from_parts(parts, Body::from(bytes));
+    };
+
+    value["error"] = json!(translation);
+    let body = serde_json::to_vec(&value).unwrap_or_else(|_| bytes.to_vec());
+    parts.headers.remove(CONTENT_LENGTH);
+    parts
+        .headers
+        .insert(CONTENT_LANGUAGE, HeaderValue::from_static(language.tag()));
+    Response::from_parts(parts, Body::from(body))
+}
+
+// Uses Content-Length, or Upload-Length for resumable uploads, as the declared
+// size so a full work dir is reported up front instead of as an IO error.
+pub async fn disk_space_preflight(request: Request<Body>, next: Next) -> Response {
+    if request.method() == Method::POST || request.method() == Method::PATCH {
+        let declared = [CONTENT_LENGTH.as_str(), "upload-length"]
+            .iter()
+            .filter_map(|name| request.headers().get(*name))
+            .filter_map(|value| value.to_str().ok()?.trim().parse::<u64>().ok())
+            .max()
+            .unwrap_or(0);
+        if let Err(error) = ensure_disk_space(declared) {
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(json!({
+                    "error": error.to_string(),
+                    "code": MessageCode::InsufficientStorage.code(),
+                })),
+            )
+                .into_response();
+        }
+    }
+
+    next.run(request).await
+}
+
+const GHOSTSCRIPT_SHED_RETRY_AFTER_SECS: u64 = 5;
+
+// Every POST under the process routers ends up in the Ghostscript queue, so
+// shed them before the upload is buffered rather than after.
+pub async fn ghostscript_load_shed(
+    State(state): State<AppState>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let max_depth = state.config.ghostscript_max_queue_depth;
+    if max_depth > 0 && request.method() == Method::POST {
+        let depth = state.ghostscript_queue_depth();
+        if depth >= max_depth {
+            tracing::warn!(
+                depth,
+                max_depth,
+                "shedding request: Ghostscript queue is full"
+            );
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                [(RETRY_AFTER, GHOSTSCRIPT_SHED_RETRY_AFTER_SECS.to_string())],
+                Json(json!({
+                    "error": "Processing queue is full, please retry shortly.",
+                    "code": MessageCode::QueueFull.code(),
+                })),
+            )
+                .into_response();
+        }
+    }
+
+    next.run(request).await
+}
+
 pub async fn api_rate_limit(
     State(state): State<AppState>,
     request: Request<Body>,
@@ -200,7 +654,7 @@ pub async fn api_rate_limit(
                 .get::<ConnectInfo<SocketAddr>>()
                 .map(|value| value.0)
         });
-    let key = client_identity(request.headers(), socket_addr, state.config.trust_proxy);
+    let key = client_identity(request.headers(), socket_addr, &state.config);
 
     if !state.api_limiter.check_and_count(&key) {
         return (
@@ -216,33 +670,357 @@ pub async fn api_rate_limit(
 fn client_identity(
     headers: &HeaderMap,
     socket_addr: Option<SocketAddr>,
-    trust_proxy: bool,
+    config: &Config,
 ) -> String {
-    if trust_proxy {
-        if let Some(value) = headers
-            .get("x-forwarded-for")
-            .and_then(|value| value.to_str().ok())
-        {
-            if let Some(first) = value.split(',').next() {
-                let candidate = first.trim();
-                if !candidate.is_empty() {
-                    return candidate.to_string();
-                }
-            }
+    client_ip(headers, socket_addr, config)
+        .map(|address| rate_limit_bucket(address, config))
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+fn client_ip(
+    headers: &HeaderMap,
+    socket_addr: Option<SocketAddr>,
+    config: &Config,
+) -> Option<IpAddr> {
+    let socket_ip = socket_addr.map(|address| address.ip());
+    // Forwarded headers are only as trustworthy as the peer that set them.
+    if !socket_ip.is_some_and(|address| config.is_trusted_proxy(address)) {
+        return socket_ip;
+    }
+
+    if let Some(value) = headers
+        .get("x-forwarded-for")
+        .and_then(|value| value.to_str().ok())
+    {
+        return forwarded_client_ip(value, config.trusted_proxy_hops).or(socket_ip);
+    }
+
+    headers
+        .get("x-real-ip")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<IpAddr>().ok())
+        .or(socket_ip)
+}
+
+// IPv6 clients usually control a whole /64, so limits key on the prefix.
+fn rate_limit_bucket(address: IpAddr, config: &Config) -> String {
+    let address = match address {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(address),
+        IpAddr::V4(_) => address,
+    };
+    let prefix = match address {
+        IpAddr::V4(_) => config.rate_limit_ipv4_prefix,
+        IpAddr::V6(_) => config.rate_limit_ipv6_prefix,
+    };
+    match IpNet::new(address, prefix) {
+        Ok(network) if network.prefix_len() < network.max_prefix_len() => {
+            network.trunc().to_string()
         }
+        _ => address.to_string(),
+    }
+}
+
+// Each trusted proxy appends the peer it saw, so the client is the entry
+// `hops` positions from the right; anything further left is client-supplied.
+fn forwarded_client_ip(header: &str, hops: usize) -> Option<IpAddr> {
+    let entries = header.split(',').map(str::trim).collect::<Vec<_>>();
+    let index = entries.len().checked_sub(hops.max(1))?;
+    entries.get(index)?.parse::<IpAddr>().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(trusted: &str, hops: usize) -> Config {
+        let mut config = Config::for_tests();
+        config.trusted_proxies = trusted
+            .split(',')
+            .filter(|value| !value.is_empty())
+            .map(|value| value.parse().expect("range"))
+            .collect();
+        config.trusted_proxy_hops = hops;
+        config.rate_limit_ipv4_prefix = 32;
+        config.rate_limit_ipv6_prefix = 64;
+        config
+    }
+
+    fn forwarded(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            HeaderValue::from_str(value).expect("header"),
+        );
+        headers
+    }
+
+    fn peer(address: &str) -> Option<SocketAddr> {
+        Some(SocketAddr::new(address.parse().expect("peer"), 443))
+    }
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().expect("ip")
+    }
+
+    #[test]
+    fn forwarded_client_ip_counts_hops_from_the_right() {
+        assert_eq!(
+            forwarded_client_ip("203.0.113.7", 1),
+            Some(ip("203.0.113.7"))
+        );
+        assert_eq!(
+            forwarded_client_ip("198.51.100.1, 203.0.113.7", 1),
+            Some(ip("203.0.113.7"))
+        );
+        assert_eq!(
+            forwarded_client_ip("198.51.100.1, 203.0.113.7, 10.0.0.2", 2),
+            Some(ip("203.0.113.7"))
+        );
+        assert_eq!(
+            forwarded_client_ip("203.0.113.7", 0),
+            Some(ip("203.0.113.7"))
+        );
+        assert_eq!(forwarded_client_ip("203.0.113.7", 2), None);
+        assert_eq!(forwarded_client_ip("", 1), None);
+        assert_eq!(forwarded_client_ip("not-an-ip", 1), None);
+        assert_eq!(
+            forwarded_client_ip("2001:db8::1", 1),
+            Some(ip("2001:db8::1"))
+        );
+    }
+
+    #[test]
+    fn untrusted_peer_ignores_forwarded_headers() {
+        let config = config("10.0.0.0/8", 1);
+        let mut headers = forwarded("198.51.100.9");
+        headers.insert("x-real-ip", HeaderValue::from_static("198.51.100.10"));
+
+        assert_eq!(
+            client_ip(&headers, peer("203.0.113.50"), &config),
+            Some(ip("203.0.113.50"))
+        );
+        assert_eq!(client_ip(&headers, None, &config), None);
+    }
+
+    #[test]
+    fn spoofed_leftmost_entry_is_not_used() {
+        let config = config("10.0.0.0/8", 1);
+        // The client sent `X-Forwarded-For: 1.2.3.4`; the proxy appended the real peer.
+        let headers = forwarded("1.2.3.4, 203.0.113.7");
+
+        assert_eq!(
+            client_ip(&headers, peer("10.0.0.5"), &config),
+            Some(ip("203.0.113.7"))
+        );
+    }
+
+    #[test]
+    fn all_trusted_chain_uses_the_configured_hop() {
+        let config = config("10.0.0.0/8", 2);
+        let headers = forwarded("1.2.3.4, 203.0.113.7, 10.0.0.3");
+
+        assert_eq!(
+            client_ip(&headers, peer("10.0.0.5"), &config),
+            Some(ip("203.0.113.7"))
+        );
+        // Fewer entries than hops falls back to the peer rather than trusting the header.
+        assert_eq!(
+            client_ip(&forwarded("10.0.0.3"), peer("10.0.0.5"), &config),
+            Some(ip("10.0.0.5"))
+        );
+    }
 
-        if let Some(value) = headers
-            .get("x-real-ip")
+    #[test]
+    fn trusted_peer_without_forwarded_for_uses_real_ip() {
+        let config = config("10.0.0.0/8", 1);
+        let mut headers = HeaderMap::new();
+        headers.insert("x-real-ip", HeaderValue::from_static(" 203.0.113.8 "));
+
+        assert_eq!(
+            client_ip(&headers, peer("10.0.0.5"), &config),
+            Some(ip("203.0.113.8"))
+        );
+        assert_eq!(
+            client_ip(&HeaderMap::new(), peer("10.0.0.5"), &config),
+            Some(ip("10.0.0.5"))
+        );
+    }
+
+    #[test]
+    fn api_scopes_match_full_paths() {
+        assert_eq!(
+            required_api_scope("/api/process/extract-pages"),
+            ApiScope::Required("extract_pages")
+        );
+        assert_eq!(
+            required_api_scope("/api/process/ocr/"),
+            ApiScope::Required("ocr")
+        );
+        assert_eq!(required_api_scope("/api/process/jobs/abc"), ApiScope::Open);
+        assert_eq!(
+            required_api_scope("/api/process/result/abc"),
+            ApiScope::Open
+        );
+        assert_eq!(required_api_scope("/api/uploads"), ApiScope::Open);
+        assert_eq!(required_api_scope("/api/uploads/abc"), ApiScope::Open);
+        // A trailing segment that happens to match a scope name is not enough.
+        assert_eq!(
+            required_api_scope("/api/process/result/ocr/extra"),
+            ApiScope::Unlisted
+        );
+        assert_eq!(
+            required_api_scope("/api/process/convert"),
+            ApiScope::Unlisted
+        );
+    }
+
+    #[test]
+    fn cookie_requests_need_an_allowed_origin_or_same_site_fetch() {
+        let origins = vec!["https://app.example.com".to_string()];
+        let with = |name: &'static str, value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(name, HeaderValue::from_static(value));
+            headers
+        };
+
+        assert!(is_same_site_request(
+            &with("origin", "https://app.example.com"),
+            &origins
+        ));
+        assert!(is_same_site_request(
+            &with("sec-fetch-site", "same-site"),
+            &origins
+        ));
+        assert!(!is_same_site_request(
+            &with("origin", "https://evil.example"),
+            &origins
+        ));
+        assert!(!is_same_site_request(
+            &with("sec-fetch-site", "cross-site"),
+            &origins
+        ));
+        assert!(!is_same_site_request(&HeaderMap::new(), &origins));
+    }
+
+    #[test]
+    fn rate_limit_buckets_by_family() {
+        let mut config = config("", 1);
+
+        assert_eq!(rate_limit_bucket(ip("203.0.113.7"), &config), "203.0.113.7");
+        assert_eq!(
+            rate_limit_bucket(ip("2001:db8:1:2:aaaa::1"), &config),
+            "2001:db8:1:2::/64"
+        );
+        assert_eq!(
+            rate_limit_bucket(ip("2001:db8:1:2:ffff:ffff:ffff:ffff"), &config),
+            rate_limit_bucket(ip("2001:db8:1:2::5"), &config)
+        );
+        assert_ne!(
+            rate_limit_bucket(ip("2001:db8:1:2::1"), &config),
+            rate_limit_bucket(ip("2001:db8:1:3::1"), &config)
+        );
+        // IPv4-mapped addresses share the IPv4 client's bucket.
+        assert_eq!(
+            rate_limit_bucket(ip("::ffff:203.0.113.7"), &config),
+            "203.0.113.7"
+        );
+
+        config.rate_limit_ipv4_prefix = 24;
+        config.rate_limit_ipv6_prefix = 128;
+        assert_eq!(
+            rate_limit_bucket(ip("203.0.113.7"), &config),
+            "203.0.113.0/24"
+        );
+        assert_eq!(rate_limit_bucket(ip("2001:db8::1"), &config), "2001:db8::1");
+    }
+
+    async fn localize(
+        status: StatusCode,
+        body: serde_json::Value,
+        language: &'static str,
+    ) -> Response {
+        use tower::Service;
+
+        let mut router = axum::Router::new()
+            .route(
+                "/",
+                axum::routing::get(move || async move { (status, Json(body)) }),
+            )
+            .layer(axum::middleware::from_fn(localize_errors));
+        let request = Request::builder()
+            .uri("/")
+            .header(ACCEPT_LANGUAGE, language)
+            .body(Body::empty())
+            .expect("request");
+        router.call(request).await.expect("response")
+    }
+
+    async fn localized(
+        status: StatusCode,
+        body: serde_json::Value,
+    ) -> (StatusCode, Option<String>, Vec<u8>) {
+        let response = localize(status, body, "de").await;
+        let status = response.status();
+        let language = response
+            .headers()
+            .get(CONTENT_LANGUAGE)
             .and_then(|value| value.to_str().ok())
-        {
-            let candidate = value.trim();
-            if !candidate.is_empty() {
-                return candidate.to_string();
-            }
-        }
+            .map(ToString::to_string);
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("body");
+        (status, language, bytes.to_vec())
     }
 
-    socket_addr
-        .map(|address| address.ip().to_string())
-        .unwrap_or_else(|| "unknown".to_string())
+    #[tokio::test]
+    async fn small_coded_errors_are_translated() {
+        let (status, language, body) = localized(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            json!({ "error": "This file is not a valid PDF.", "code": "invalid_pdf" }),
+        )
+        .await;
+        let body: serde_json::Value = serde_json::from_slice(&body).expect("json");
+
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(language.as_deref(), Some("de"));
+        assert_eq!(body["code"], "invalid_pdf");
+        assert_ne!(body["error"], "This file is not a valid PDF.");
+    }
+
+    #[tokio::test]
+    async fn oversized_error_bodies_pass_through_unchanged() {
+        let original = json!({
+            "error": "This file is not a valid PDF.",
+            "code": "invalid_pdf",
+            "details": "x".repeat(LOCALIZED_BODY_LIMIT_BYTES),
+        });
+        let (status, language, body) =
+            localized(StatusCode::UNPROCESSABLE_ENTITY, original.clone()).await;
+
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(language, None);
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&body).expect("json"),
+            original
+        );
+    }
+
+    #[tokio::test]
+    async fn every_json_error_varies_on_accept_language() {
+        for (language, body) in [
+            ("en", json!({ "error": "Bad", "code": "invalid_option" })),
+            ("de", json!({ "error": "Bad", "code": "invalid_option" })),
+            ("de", json!({ "error": "Bad" })),
+        ] {
+            let response = localize(StatusCode::BAD_REQUEST, body, language).await;
+            assert_eq!(
+                response.headers().get(VARY).map(|value| value.as_bytes()),
+                Some(&b"accept-language"[..]),
+                "{language}"
+            );
+        }
+
+        let response = localize(StatusCode::OK, json!({ "ok": true }), "de").await;
+        assert!(response.headers().get(VARY).is_none());
+    }
 }
@@ -0,0 +1,110 @@
+use std::{path::Path, process::Stdio, time::Duration};
+
+use anyhow::{anyhow, Context};
+use regex::Regex;
+use tokio::{process::Command, time::timeout};
+
+use crate::ghostscript::PdfInputError;
+
+pub const DEFAULT_OCR_LANGUAGE: &str = "eng";
+
+// OCR runs tesseract over every page, so it gets a longer default than gs.
+static OCRMYPDF_COMMAND_TIMEOUT: once_cell::sync::Lazy<Duration> =
+    once_cell::sync::Lazy::new(|| {
+        let timeout_ms = std::env::var("OCRMYPDF_COMMAND_TIMEOUT_MS")
+            .ok()
+            .and_then(|value| value.parse::<u64>().ok())
+            .filter(|value| *value > 0)
+            .unwrap_or(600_000);
+        Duration::from_millis(timeout_ms)
+    });
+
+pub fn command_timeout() -> Duration {
+    *OCRMYPDF_COMMAND_TIMEOUT
+}
+
+// ocrmypdf exit codes for problems with the input file.
+const EXIT_INPUT_FILE: i32 = 2;
+const EXIT_ENCRYPTED_PDF: i32 = 8;
+
+pub fn ocrmypdf_program() -> String {
+    std::env::var("OCRMYPDF_BIN").unwrap_or_else(|_| "ocrmypdf".to_string())
+}
+
+// Tesseract language codes such as `eng`, `chi_sim` or `eng+deu`.
+pub fn validate_language(raw: Option<&str>) -> Result<String, &'static str> {
+    static LANGUAGE_RE: once_cell::sync::Lazy<Regex> = once_cell::sync::Lazy::new(|| {
+        Regex::new(r"^[a-z]{3}(_[a-z]+)?(\+[a-z]{3}(_[a-z]+)?){0,4}$").expect("valid regex")
+    });
+
+    let value = raw.map(str::trim).unwrap_or_default();
+    if value.is_empty() {
+        return Ok(DEFAULT_OCR_LANGUAGE.to_string());
+    }
+    let value = value.to_ascii_lowercase();
+    if LANGUAGE_RE.is_match(&value) {
+        Ok(value)
+    } else {
+        Err("Invalid language. Use Tesseract codes such as \"eng\" or \"eng+deu\".")
+    }
+}
+
+// Pages that already have text are left alone, so mixed documents only pay
+// for the scanned pages.
+pub async fn ocr_pdf(input_path: &Path, output_path: &Path, language: &str) -> anyhow::Result<()> {
+    let program = ocrmypdf_program();
+    let args = vec![
+        "--skip-text".to_string(),
+        "--output-type".to_string(),
+        "pdf".to_string(),
+        "--jobs".to_string(),
+        "1".to_string(),
+        "-l".to_string(),
+        language.to_string(),
+        input_path.to_string_lossy().to_string(),
+        output_path.to_string_lossy().to_string(),
+    ];
+
+    let child = Command::new(&program)
+        .args(&args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|error| {
+            if error.kind() == std::io::ErrorKind::NotFound {
+                return anyhow!("ocrmypdf-not-found");
+            }
+            anyhow!(error).context(format!("failed to execute {}", program))
+        })?;
+    let output = timeout(*OCRMYPDF_COMMAND_TIMEOUT, child.wait_with_output())
+        .await
+        .map_err(|_| {
+            anyhow!(
+                "{} timed out after {} ms",
+                program,
+                OCRMYPDF_COMMAND_TIMEOUT.as_millis()
+            )
+        })?
+        .with_context(|| format!("failed to execute {}", program))?;
+
+    if output.status.success() {
+        return Ok(());
+    }
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let reason = stderr
+        .lines()
+        .rev()
+        .find(|line| !line.trim().is_empty())
+        .map(|line| line.trim().to_string())
+        .unwrap_or_else(|| format!("{} failed with status {}", program, output.status));
+
+    Err(match output.status.code() {
+        Some(EXIT_ENCRYPTED_PDF) => {
+            anyhow::Error::new(PdfInputError::PasswordRequired).context(reason)
+        }
+        Some(EXIT_INPUT_FILE) => anyhow::Error::new(PdfInputError::Invalid).context(reason),
+        _ => anyhow!(reason),
+    })
+}
@@ -0,0 +1,197 @@
+use axum::Json;
+use once_cell::sync::Lazy;
+use utoipa::{
+    openapi::{
+        schema::{KnownFormat, ObjectBuilder, SchemaFormat, Type},
+        security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme},
+        RefOr, Schema,
+    },
+    Modify, OpenApi, PartialSchema, ToSchema,
+};
+
+use crate::{ghostscript, handlers, preflight, stripe_api};
+
+// Built once; the spec only changes with the binary.
+static SPEC: Lazy<utoipa::openapi::OpenApi> = Lazy::new(ApiDoc::openapi);
+
+pub async fn openapi_json() -> Json<utoipa::openapi::OpenApi> {
+    Json(SPEC.clone())
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "GhostServer API",
+        description = "PDF preflight and print conversion. `/process/*` takes a Clerk session token, `/api/process/*` an API key."
+    ),
+    paths(
+        handlers::test_document,
+        handlers::preflight_document,
+        handlers::preflight_document_from_url,
+        handlers::convert_document_to_grayscale,
+        handlers::extract_pages,
+        handlers::sanitize_document,
+        handlers::ocr_document,
+        handlers::get_result,
+        handlers::conversion_capabilities,
+        handlers::process_document_api,
+        handlers::convert_document_to_grayscale_api,
+        handlers::get_job_api,
+        handlers::extract_pages_api,
+        handlers::sanitize_document_api,
+        handlers::ocr_document_api,
+        handlers::get_result_api,
+        handlers::generate_api_key,
+        handlers::list_api_keys,
+        handlers::delete_api_key,
+        handlers::get_subscription,
+        handlers::change_subscription_plan,
+        handlers::resync_subscription,
+        handlers::create_checkout_session,
+        handlers::sync_stripe_session,
+        handlers::create_customer_portal_session,
+        handlers::get_usage,
+        handlers::get_profile,
+        handlers::list_plans,
+    ),
+    components(schemas(
+        ghostscript::PdfAnalysis,
+        ghostscript::ColorProfile,
+        ghostscript::ColorProfilePercent,
+        ghostscript::PdfMetadata,
+        preflight::PreflightWarning,
+        preflight::PreflightWarningCode,
+        stripe_api::ProrationBehavior,
+        handlers::QuotaExceededBody,
+        handlers::UsageSummaryBody,
+        handlers::ProfileBody,
+        handlers::ApiKeySummary,
+        handlers::PlanSummary,
+        handlers::PlanListBody,
+        ErrorBody,
+        PdfFile,
+    )),
+    modifiers(&SecuritySchemes),
+    tags(
+        (name = "process", description = "Session-authenticated processing for the web app"),
+        (name = "api", description = "API-key processing for integrations"),
+        (name = "account", description = "API keys, usage and plans"),
+        (name = "billing", description = "Stripe checkout, portal and plan changes"),
+    )
+)]
+struct ApiDoc;
+
+struct SecuritySchemes;
+
+impl Modify for SecuritySchemes {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "clerk",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .bearer_format("JWT")
+                    .build(),
+            ),
+        );
+        components.add_security_scheme(
+            "api_key",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("X-API-Key"))),
+        );
+    }
+}
+
+// Most error responses; `code` is set where clients are expected to branch on it.
+#[allow(dead_code)]
+#[derive(ToSchema)]
+pub struct ErrorBody {
+    error: String,
+    code: Option<String>,
+}
+
+// A PDF body, used for downloads and the `file` part of uploads.
+pub struct PdfFile;
+
+impl PartialSchema for PdfFile {
+    fn schema() -> RefOr<Schema> {
+        ObjectBuilder::new()
+            .schema_type(Type::String)
+            .format(Some(SchemaFormat::KnownFormat(KnownFormat::Binary)))
+            .into()
+    }
+}
+
+impl ToSchema for PdfFile {}
+
+// Multipart bodies are read field by field in upload.rs; these only describe
+// the fields each route looks at. `uploadId` refers to a finished resumable
+// upload and replaces `file`.
+#[allow(dead_code)]
+#[derive(ToSchema)]
+#[schema(rename_all = "camelCase")]
+pub struct AnalyzeForm {
+    file: Option<PdfFile>,
+    /// Not accepted by `/process/preflight-test`.
+    upload_id: Option<String>,
+}
+
+#[allow(dead_code)]
+#[derive(ToSchema)]
+#[schema(rename_all = "camelCase")]
+pub struct GrayscaleForm {
+    file: Option<PdfFile>,
+    upload_id: Option<String>,
+    /// `preview` (default) or `production`.
+    mode: Option<String>,
+    /// `ghostscript` (default) or `mupdf`.
+    engine: Option<String>,
+    first_page: Option<String>,
+    last_page: Option<String>,
+    /// Report what conversion would do without converting. Runs a full ink
+    /// coverage analysis and is billed like preflight, two units per page.
+    dry_run: Option<String>,
+    retain: Option<String>,
+    force_black_text: Option<String>,
+    force_black_vector: Option<String>,
+    black_threshold_l: Option<String>,
+    black_threshold_c: Option<String>,
+    /// Warn in `x-processing-warnings` if the document's bookmarks don't survive.
+    preserve_bookmarks: Option<String>,
+    /// Keep links and annotations and warn if they don't survive. Defaults to
+    /// `true` in production mode.
+    preserve_annotations: Option<String>,
+    /// Output PDF version, `1.3` to `1.7`. Ghostscript engine only.
+    pdf_version: Option<String>,
+}
+
+#[allow(dead_code)]
+#[derive(ToSchema)]
+#[schema(rename_all = "camelCase")]
+pub struct ExtractPagesForm {
+    file: Option<PdfFile>,
+    upload_id: Option<String>,
+    first_page: Option<String>,
+    last_page: Option<String>,
+}
+
+#[allow(dead_code)]
+#[derive(ToSchema)]
+#[schema(rename_all = "camelCase")]
+pub struct SanitizeForm {
+    file: Option<PdfFile>,
+    upload_id: Option<String>,
+    flatten: Option<String>,
+    strip_javascript: Option<String>,
+    strip_metadata: Option<String>,
+}
+
+#[allow(dead_code)]
+#[derive(ToSchema)]
+#[schema(rename_all = "camelCase")]
+pub struct OcrForm {
+    file: Option<PdfFile>,
+    upload_id: Option<String>,
+    /// Tesseract language codes, e.g. `eng` or `eng+deu`.
+    language: Option<String>,
+}
@@ -15,6 +15,24 @@ pub enum PlanId {
 }
 
 impl PlanId {
+    pub const ALL: [PlanId; 5] = [
+        PlanId::Free,
+        PlanId::Starter,
+        PlanId::Pro,
+        PlanId::Business,
+        PlanId::Enterprise,
+    ];
+
+    pub fn display_name(self) -> &'static str {
+        match self {
+            PlanId::Free => "Free",
+            PlanId::Starter => "Starter",
+            PlanId::Pro => "Pro",
+            PlanId::Business => "Business",
+            PlanId::Enterprise => "Enterprise",
+        }
+    }
+
     pub fn as_str(self) -> &'static str {
         match self {
             PlanId::Free => "free",
@@ -77,35 +95,45 @@ pub fn is_subscription_active(status: Option<&str>) -> bool {
     )
 }
 
+// What a plan change does when this month's usage is already over the
+// target plan's quota.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum DowngradePolicy {
+    #[default]
+    Block,
+    Warn,
+}
+
+impl DowngradePolicy {
+    pub fn parse(raw: &str) -> Result<Self, &'static str> {
+        match raw.trim().to_ascii_lowercase().as_str() {
+            "" | "block" => Ok(Self::Block),
+            "warn" => Ok(Self::Warn),
+            _ => Err("expected \"block\" or \"warn\""),
+        }
+    }
+}
+
+// Each plan takes a list of price IDs. The first is the one offered for new
+// checkouts and plan changes; the rest are legacy prices that existing
+// subscribers may still be on, and only resolve back to the plan.
 #[derive(Clone, Debug)]
 pub struct PriceMap {
     by_price_id: HashMap<String, PlanId>,
+    offered: HashMap<PlanId, String>,
 }
 
 impl PriceMap {
     pub fn from_config(config: &Config) -> Self {
-        let mut by_price_id = HashMap::new();
-        insert_price(
-            &mut by_price_id,
-            config.stripe_price_id_starter.clone(),
-            PlanId::Starter,
-        );
-        insert_price(
-            &mut by_price_id,
-            config.stripe_price_id_pro.clone(),
-            PlanId::Pro,
-        );
-        insert_price(
-            &mut by_price_id,
-            config.stripe_price_id_business.clone(),
-            PlanId::Business,
-        );
-        insert_price(
-            &mut by_price_id,
-            config.stripe_price_id_enterprise.clone(),
-            PlanId::Enterprise,
-        );
-        Self { by_price_id }
+        let mut map = Self {
+            by_price_id: HashMap::new(),
+            offered: HashMap::new(),
+        };
+        map.insert_prices(&config.stripe_price_ids_starter, PlanId::Starter);
+        map.insert_prices(&config.stripe_price_ids_pro, PlanId::Pro);
+        map.insert_prices(&config.stripe_price_ids_business, PlanId::Business);
+        map.insert_prices(&config.stripe_price_ids_enterprise, PlanId::Enterprise);
+        map
     }
 
     pub fn get_plan_for_price_id(&self, price_id: Option<&str>) -> Option<PlanId> {
@@ -115,12 +143,131 @@ impl PriceMap {
         }
         self.by_price_id.get(price_id).copied()
     }
+
+    // The plan a price buys if it is still offered; legacy prices give `None`.
+    pub fn get_offered_plan_for_price_id(&self, price_id: &str) -> Option<PlanId> {
+        let price_id = price_id.trim();
+        let plan_id = self.by_price_id.get(price_id).copied()?;
+        (self.offered.get(&plan_id).map(String::as_str) == Some(price_id)).then_some(plan_id)
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = (&str, PlanId)> {
+        self.by_price_id
+            .iter()
+            .map(|(price_id, plan_id)| (price_id.as_str(), *plan_id))
+    }
+
+    // The offered price first, then legacy prices sorted.
+    pub fn price_ids_for_plan(&self, plan_id: PlanId) -> Vec<String> {
+        let offered = self.offered.get(&plan_id);
+        let mut legacy = self
+            .by_price_id
+            .iter()
+            .filter(|(price_id, candidate)| **candidate == plan_id && Some(*price_id) != offered)
+            .map(|(price_id, _)| price_id.clone())
+            .collect::<Vec<_>>();
+        legacy.sort();
+        offered.cloned().into_iter().chain(legacy).collect()
+    }
+
+    fn insert_prices(&mut self, price_ids: &[String], plan_id: PlanId) {
+        for price_id in price_ids {
+            if let Some(previous) = self.by_price_id.insert(price_id.clone(), plan_id) {
+                if previous != plan_id {
+                    tracing::warn!(
+                        price_id = %price_id,
+                        overwritten_plan = previous.as_str(),
+                        plan = plan_id.as_str(),
+                        "Stripe price ID is configured for more than one plan; the later plan wins"
+                    );
+                    if self.offered.get(&previous) == Some(price_id) {
+                        self.offered.remove(&previous);
+                    }
+                }
+            }
+        }
+        if let Some(price_id) = price_ids.first() {
+            self.offered.insert(plan_id, price_id.clone());
+        }
+    }
 }
 
-fn insert_price(map: &mut HashMap<String, PlanId>, price_id: Option<String>, plan_id: PlanId) {
-    if let Some(price_id) = price_id.map(|v| v.trim().to_string()) {
-        if !price_id.is_empty() {
-            map.insert(price_id, plan_id);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn price_map(pro: &[&str], business: &[&str]) -> PriceMap {
+        let mut config = Config::for_tests();
+        let ids = |values: &[&str]| values.iter().map(ToString::to_string).collect();
+        config.stripe_price_ids_starter = Vec::new();
+        config.stripe_price_ids_pro = ids(pro);
+        config.stripe_price_ids_business = ids(business);
+        config.stripe_price_ids_enterprise = Vec::new();
+        PriceMap::from_config(&config)
+    }
+
+    #[test]
+    fn every_listed_price_resolves_to_its_plan() {
+        let map = price_map(
+            &["price_pro_new", "price_pro_old", "price_pro_2019"],
+            &["price_biz"],
+        );
+
+        for price_id in [
+            "price_pro_new",
+            "price_pro_old",
+            "price_pro_2019",
+            " price_pro_old ",
+        ] {
+            assert_eq!(map.get_plan_for_price_id(Some(price_id)), Some(PlanId::Pro));
         }
+        assert_eq!(
+            map.get_plan_for_price_id(Some("price_biz")),
+            Some(PlanId::Business)
+        );
+        assert_eq!(map.get_plan_for_price_id(Some("price_unknown")), None);
+        assert_eq!(map.get_plan_for_price_id(Some("")), None);
+        assert_eq!(map.get_plan_for_price_id(None), None);
+    }
+
+    #[test]
+    fn only_the_first_price_is_offered() {
+        let map = price_map(
+            &["price_pro_new", "price_pro_old", "price_pro_2019"],
+            &["price_biz"],
+        );
+
+        assert_eq!(
+            map.get_offered_plan_for_price_id("price_pro_new"),
+            Some(PlanId::Pro)
+        );
+        assert_eq!(map.get_offered_plan_for_price_id("price_pro_old"), None);
+        assert_eq!(
+            map.price_ids_for_plan(PlanId::Pro),
+            vec!["price_pro_new", "price_pro_2019", "price_pro_old"]
+        );
+        assert_eq!(map.price_ids_for_plan(PlanId::Business), vec!["price_biz"]);
+        assert!(map.price_ids_for_plan(PlanId::Starter).is_empty());
+        assert_eq!(map.entries().count(), 4);
+    }
+
+    #[test]
+    fn a_price_listed_for_two_plans_belongs_to_the_later_one() {
+        let map = price_map(
+            &["price_shared", "price_pro_old"],
+            &["price_biz", "price_shared"],
+        );
+
+        assert_eq!(
+            map.get_plan_for_price_id(Some("price_shared")),
+            Some(PlanId::Business)
+        );
+        // Pro lost its offered price rather than offering a Business price.
+        assert_eq!(map.get_offered_plan_for_price_id("price_shared"), None);
+        assert_eq!(map.price_ids_for_plan(PlanId::Pro), vec!["price_pro_old"]);
+        assert_eq!(
+            map.get_offered_plan_for_price_id("price_biz"),
+            Some(PlanId::Business)
+        );
     }
 }
@@ -0,0 +1,238 @@
+use std::{collections::BTreeSet, path::Path, process::Stdio, sync::OnceLock};
+
+use serde::Serialize;
+use tokio::process::Command;
+use utoipa::ToSchema;
+
+use crate::ghostscript::{get_page_boxes, PageBoxes, PdfAnalysis, PdfBox};
+
+// Images below this effective resolution tend to print soft or pixelated.
+const MIN_IMAGE_PPI: f64 = 150.0;
+const POINTS_PER_MM: f64 = 72.0 / 25.4;
+// Box coordinates are printed with two decimals.
+const BOX_EPSILON_PT: f64 = 0.01;
+
+// Minimum bleed in millimetres; 0 disables the geometry checks.
+static MIN_BLEED_MM: OnceLock<f64> = OnceLock::new();
+
+pub fn configure_min_bleed(min_bleed_mm: f64) {
+    let _ = MIN_BLEED_MM.set(min_bleed_mm);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PreflightWarningCode {
+    FormFieldsPresent,
+    LowResolutionImage,
+    RgbImage,
+    SpotColorImage,
+    MissingTrimBox,
+    InsufficientBleed,
+}
+
+// Non-fatal findings for the frontend checklist. `pages` is empty when the
+// check can't tell which pages are affected.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct PreflightWarning {
+    pub code: PreflightWarningCode,
+    pub message: String,
+    pub pages: Vec<i64>,
+}
+
+impl PreflightWarning {
+    fn new(code: PreflightWarningCode, message: impl Into<String>, pages: Vec<i64>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            pages,
+        }
+    }
+}
+
+pub async fn collect_warnings(file_path: &Path, analysis: &PdfAnalysis) -> Vec<PreflightWarning> {
+    let mut warnings = Vec::new();
+
+    if analysis.has_formfields {
+        warnings.push(PreflightWarning::new(
+            PreflightWarningCode::FormFieldsPresent,
+            "The PDF contains form fields. Flatten them so their contents print as shown.",
+            Vec::new(),
+        ));
+    }
+
+    match list_images(file_path).await {
+        Ok(images) => warnings.extend(image_warnings(&images)),
+        Err(error) => tracing::debug!(error = %error, "skipping image preflight checks"),
+    }
+
+    let min_bleed_mm = MIN_BLEED_MM.get().copied().unwrap_or(3.0);
+    if min_bleed_mm > 0.0 {
+        match get_page_boxes(file_path, analysis.page_count).await {
+            Ok(pages) => warnings.extend(bleed_warnings(&pages, min_bleed_mm)),
+            Err(error) => tracing::debug!(error = %error, "skipping bleed preflight checks"),
+        }
+    }
+
+    warnings
+}
+
+struct ImageInfo {
+    page: i64,
+    color: String,
+    min_ppi: f64,
+}
+
+async fn list_images(file_path: &Path) -> Result<Vec<ImageInfo>, String> {
+    let output = Command::new("pdfimages")
+        .arg("-list")
+        .arg(file_path)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .output()
+        .await
+        .map_err(|error| format!("spawn failed: {}", error))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(if stderr.trim().is_empty() {
+            format!("exit={}", output.status)
+        } else {
+            stderr.trim().to_string()
+        });
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(parse_image_line)
+        .collect())
+}
+
+// page num type width height color comp bpc enc interp object ID x-ppi y-ppi size ratio
+fn parse_image_line(line: &str) -> Option<ImageInfo> {
+    let tokens = line.split_whitespace().collect::<Vec<_>>();
+    if tokens.len() < 14 || tokens[2] != "image" {
+        return None;
+    }
+    let page = tokens[0].parse::<i64>().ok()?;
+    let x_ppi = tokens[12].parse::<f64>().ok()?;
+    let y_ppi = tokens[13].parse::<f64>().ok()?;
+    Some(ImageInfo {
+        page,
+        color: tokens[5].to_ascii_lowercase(),
+        min_ppi: x_ppi.min(y_ppi),
+    })
+}
+
+fn image_warnings(images: &[ImageInfo]) -> Vec<PreflightWarning> {
+    let pages_where = |predicate: &dyn Fn(&ImageInfo) -> bool| {
+        images
+            .iter()
+            .filter(|image| predicate(image))
+            .map(|image| image.page)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>()
+    };
+
+    let mut warnings = Vec::new();
+
+    let low_resolution = pages_where(&|image| image.min_ppi < MIN_IMAGE_PPI);
+    if !low_resolution.is_empty() {
+        let lowest = images
+            .iter()
+            .map(|image| image.min_ppi)
+            .fold(f64::INFINITY, f64::min);
+        warnings.push(PreflightWarning::new(
+            PreflightWarningCode::LowResolutionImage,
+            format!(
+                "Some images are below {} ppi (lowest {} ppi) and may print blurry.",
+                MIN_IMAGE_PPI, lowest
+            ),
+            low_resolution,
+        ));
+    }
+
+    let rgb = pages_where(&|image| image.color == "rgb");
+    if !rgb.is_empty() {
+        warnings.push(PreflightWarning::new(
+            PreflightWarningCode::RgbImage,
+            "Some images use RGB color and will be converted for CMYK printing.",
+            rgb,
+        ));
+    }
+
+    let spot = pages_where(&|image| matches!(image.color.as_str(), "sep" | "devn"));
+    if !spot.is_empty() {
+        warnings.push(PreflightWarning::new(
+            PreflightWarningCode::SpotColorImage,
+            "Some images use spot colors. Confirm the separations with your printer.",
+            spot,
+        ));
+    }
+
+    warnings
+}
+
+fn bleed_warnings(pages: &[PageBoxes], min_bleed_mm: f64) -> Vec<PreflightWarning> {
+    let mut missing_trim = Vec::new();
+    let mut short_bleed = Vec::new();
+    let mut smallest_mm = f64::INFINITY;
+
+    for page in pages {
+        if same_box(&page.trim_box, &page.media_box) {
+            missing_trim.push(page.page);
+            continue;
+        }
+        let bleed_mm = bleed_beyond_trim(page) / POINTS_PER_MM;
+        if bleed_mm + BOX_EPSILON_PT / POINTS_PER_MM < min_bleed_mm {
+            short_bleed.push(page.page);
+            smallest_mm = smallest_mm.min(bleed_mm);
+        }
+    }
+
+    let mut warnings = Vec::new();
+    if !missing_trim.is_empty() {
+        warnings.push(PreflightWarning::new(
+            PreflightWarningCode::MissingTrimBox,
+            "No TrimBox is set, so the page size is used as the trim size and there is no bleed.",
+            missing_trim,
+        ));
+    }
+    if !short_bleed.is_empty() {
+        warnings.push(PreflightWarning::new(
+            PreflightWarningCode::InsufficientBleed,
+            format!(
+                "Bleed is below the required {} mm (smallest {:.1} mm).",
+                min_bleed_mm,
+                smallest_mm.max(0.0)
+            ),
+            short_bleed,
+        ));
+    }
+
+    warnings
+}
+
+// Smallest margin between the TrimBox and the usable bleed area, which is the
+// BleedBox clipped to the MediaBox.
+fn bleed_beyond_trim(page: &PageBoxes) -> f64 {
+    let bleed = PdfBox {
+        x0: page.bleed_box.x0.max(page.media_box.x0),
+        y0: page.bleed_box.y0.max(page.media_box.y0),
+        x1: page.bleed_box.x1.min(page.media_box.x1),
+        y1: page.bleed_box.y1.min(page.media_box.y1),
+    };
+    let trim = page.trim_box;
+    (trim.x0 - bleed.x0)
+        .min(trim.y0 - bleed.y0)
+        .min(bleed.x1 - trim.x1)
+        .min(bleed.y1 - trim.y1)
+}
+
+fn same_box(a: &PdfBox, b: &PdfBox) -> bool {
+    (a.x0 - b.x0).abs() < BOX_EPSILON_PT
+        && (a.y0 - b.y0).abs() < BOX_EPSILON_PT
+        && (a.x1 - b.x1).abs() < BOX_EPSILON_PT
+        && (a.y1 - b.y1).abs() < BOX_EPSILON_PT
+}
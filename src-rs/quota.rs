@@ -1,74 +1,49 @@
+use std::time::Duration;
+
 use anyhow::Context;
-use serde::Deserialize;
-use serde_json::json;
+use chrono::Utc;
 
 use crate::{
-    convex::ConvexClient,
-    plans::{is_subscription_active, plan_definition, resolve_plan_id, PlanId},
-    serde_convex::{de_i64_from_number, de_opt_i64_from_number},
+    convex_api::{CommitReservationResult, ConvexApi},
+    plans::{plan_definition, PlanId},
+    subscription::effective_plan,
 };
 
 #[derive(Debug, Clone)]
 pub struct QuotaReservation {
     pub allowed: bool,
     pub reservation_id: Option<String>,
+    // When this reservation lapses if it is neither committed nor released.
+    pub expires_at: Option<i64>,
+    // Earliest expiry among the user's other pending reservations.
+    pub next_pending_expires_at: Option<i64>,
     pub plan_id: PlanId,
     pub monthly_quota: Option<i64>,
     pub total_this_month: i64,
     pub pending_units: i64,
 }
 
-#[derive(Debug, Deserialize)]
-struct SubscriptionRecord {
-    pub plan: Option<String>,
-    pub status: Option<String>,
-}
-
-#[derive(Debug, Deserialize)]
-struct ReserveResult {
-    pub allowed: bool,
-    #[serde(rename = "reservationId")]
-    pub reservation_id: Option<String>,
-    #[serde(rename = "totalThisMonth")]
-    #[serde(deserialize_with = "de_i64_from_number")]
-    pub total_this_month: i64,
-    #[serde(rename = "pendingUnits")]
-    #[serde(default, deserialize_with = "de_opt_i64_from_number")]
-    pub pending_units: Option<i64>,
-}
-
-#[derive(Debug, Deserialize)]
-pub struct CommitReservationResult {
-    pub committed: bool,
-}
-
 pub async fn reserve_units_for_clerk_user(
-    convex: &ConvexClient,
+    convex: &ConvexApi,
     clerk_id: &str,
     units: i64,
+    ttl_secs: u64,
 ) -> anyhow::Result<QuotaReservation> {
-    let subscription: Option<SubscriptionRecord> = convex
-        .query("subscriptions:get", json!({ "userId": clerk_id }))
+    let subscription = convex
+        .get_subscription(clerk_id)
         .await
         .context("failed to fetch subscription for quota reservation")?;
 
-    let plan_id = match subscription {
-        Some(subscription) if is_subscription_active(subscription.status.as_deref()) => {
-            resolve_plan_id(subscription.plan.as_deref())
-        }
-        _ => PlanId::Free,
-    };
+    let plan_id = effective_plan(subscription.as_ref());
 
     let monthly_quota = plan_definition(plan_id).monthly_units;
 
-    let reserve_result: ReserveResult = convex
-        .action(
-            "usage:reserveForClerkUser",
-            json!({
-                "clerkId": clerk_id,
-                "units": units,
-                "monthlyQuota": monthly_quota,
-            }),
+    let reserve_result = convex
+        .reserve_units(
+            clerk_id,
+            units,
+            monthly_quota,
+            i64::try_from(ttl_secs.saturating_mul(1000)).unwrap_or(i64::MAX),
         )
         .await
         .with_context(|| {
@@ -81,6 +56,8 @@ pub async fn reserve_units_for_clerk_user(
     Ok(QuotaReservation {
         allowed: reserve_result.allowed,
         reservation_id: reserve_result.reservation_id,
+        expires_at: reserve_result.expires_at,
+        next_pending_expires_at: reserve_result.next_pending_expires_at,
         plan_id,
         monthly_quota,
         total_this_month: reserve_result.total_this_month,
@@ -89,37 +66,135 @@ pub async fn reserve_units_for_clerk_user(
 }
 
 pub async fn commit_reservation_for_clerk_user(
-    convex: &ConvexClient,
+    convex: &ConvexApi,
     clerk_id: &str,
     reservation_id: &str,
 ) -> anyhow::Result<CommitReservationResult> {
     convex
-        .action(
-            "usage:commitReservationForClerkUser",
-            json!({
-                "clerkId": clerk_id,
-                "reservationId": reservation_id,
-            }),
-        )
+        .commit_reservation(clerk_id, reservation_id)
         .await
         .context("failed to commit usage reservation")
 }
 
 pub async fn release_reservation_for_clerk_user(
-    convex: &ConvexClient,
+    convex: &ConvexApi,
     clerk_id: &str,
     reservation_id: &str,
 ) -> anyhow::Result<()> {
-    let _value: serde_json::Value = convex
-        .action(
-            "usage:releaseReservationForClerkUser",
-            json!({
-                "clerkId": clerk_id,
-                "reservationId": reservation_id,
-            }),
-        )
+    convex
+        .release_reservation(clerk_id, reservation_id)
         .await
-        .context("failed to release usage reservation")?;
+        .map(|_| ())
+        .context("failed to release usage reservation")
+}
+
+// Releases the reservation if dropped before `disarm`, so a handler cancelled
+// by the request deadline doesn't hold the units until the reservation lapses.
+pub struct ReservationGuard {
+    convex: ConvexApi,
+    clerk_id: String,
+    reservation_id: Option<String>,
+}
 
-    Ok(())
+impl ReservationGuard {
+    pub fn new(convex: &ConvexApi, clerk_id: &str, reservation_id: &str) -> Self {
+        Self {
+            convex: convex.clone(),
+            clerk_id: clerk_id.to_string(),
+            reservation_id: Some(reservation_id.to_string()),
+        }
+    }
+
+    pub fn disarm(mut self) {
+        self.reservation_id = None;
+    }
+}
+
+impl Drop for ReservationGuard {
+    fn drop(&mut self) {
+        let Some(reservation_id) = self.reservation_id.take() else {
+            return;
+        };
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let convex = self.convex.clone();
+        let clerk_id = std::mem::take(&mut self.clerk_id);
+        runtime.spawn(async move {
+            if let Err(error) =
+                release_reservation_for_clerk_user(&convex, &clerk_id, &reservation_id).await
+            {
+                tracing::warn!(error = ?error, "failed to release abandoned reservation");
+            }
+        });
+    }
+}
+
+const RESERVATION_CLEANUP_BATCH: usize = 100;
+
+// Releases reservations that expired within the last `lookback` while still
+// pending, which means a commit or release was missed (crash, dropped
+// request). Returns the number of reservations and units reclaimed.
+pub async fn release_expired_reservations(
+    convex: &ConvexApi,
+    lookback: Duration,
+) -> anyhow::Result<(usize, i64)> {
+    let now = Utc::now().timestamp_millis();
+    let expired_after = now - i64::try_from(lookback.as_millis()).unwrap_or(i64::MAX / 2);
+    let mut released = 0usize;
+    let mut units = 0i64;
+
+    loop {
+        let batch = convex
+            .get_expired_pending_reservations(expired_after, now, RESERVATION_CLEANUP_BATCH)
+            .await
+            .context("failed to list expired reservations")?;
+        let batch_len = batch.len();
+        let mut released_in_batch = 0usize;
+
+        for reservation in batch {
+            match convex
+                .release_reservation(&reservation.clerk_id, &reservation.reservation_id)
+                .await
+            {
+                Ok(result) if result.released => {
+                    released_in_batch += 1;
+                    units += reservation.units;
+                }
+                Ok(_) => {}
+                Err(error) => tracing::warn!(
+                    error = %error,
+                    reservation_id = %reservation.reservation_id,
+                    "failed to release expired reservation"
+                ),
+            }
+        }
+
+        released += released_in_batch;
+        // Released rows drop out of the query, so a short batch or one that
+        // made no progress means there is nothing more to do this run.
+        if batch_len < RESERVATION_CLEANUP_BATCH || released_in_batch == 0 {
+            break;
+        }
+    }
+
+    Ok((released, units))
+}
+
+// Runs once right away so reservations abandoned by a crash are cleaned up
+// on startup, then every `interval`.
+pub fn spawn_reservation_cleanup(convex: ConvexApi, interval: Duration, lookback: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            match release_expired_reservations(&convex, lookback).await {
+                Ok((0, _)) => {}
+                Ok((released, units)) => {
+                    tracing::info!(released, units, "released abandoned usage reservations")
+                }
+                Err(error) => tracing::error!(error = ?error, "reservation cleanup failed"),
+            }
+        }
+    });
 }
@@ -0,0 +1,198 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use parking_lot::Mutex;
+use uuid::Uuid;
+
+#[derive(Debug, Clone)]
+pub struct StoredResult {
+    pub owner: String,
+    pub path: PathBuf,
+    pub file_name: String,
+    pub expires_at: Instant,
+}
+
+#[derive(Clone)]
+pub struct ResultStore {
+    dir: PathBuf,
+    ttl: Duration,
+    entries: Arc<Mutex<HashMap<Uuid, StoredResult>>>,
+}
+
+impl ResultStore {
+    pub fn new(dir: PathBuf, ttl: Duration) -> Self {
+        Self {
+            dir,
+            ttl,
+            entries: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    pub async fn retain(
+        &self,
+        owner: &str,
+        source_path: &Path,
+        file_name: &str,
+    ) -> anyhow::Result<Uuid> {
+        tokio::fs::create_dir_all(&self.dir)
+            .await
+            .with_context(|| format!("failed to create result dir {}", self.dir.display()))?;
+
+        let job_id = Uuid::new_v4();
+        let path = self.dir.join(format!("{}.pdf", job_id));
+        if tokio::fs::rename(source_path, &path).await.is_err() {
+            // Rename fails across filesystems; fall back to copy + delete.
+            tokio::fs::copy(source_path, &path)
+                .await
+                .context("failed to store conversion result")?;
+            let _ = tokio::fs::remove_file(source_path).await;
+        }
+
+        self.entries.lock().insert(
+            job_id,
+            StoredResult {
+                owner: owner.to_string(),
+                path,
+                file_name: file_name.to_string(),
+                expires_at: Instant::now() + self.ttl,
+            },
+        );
+
+        Ok(job_id)
+    }
+
+    pub fn get(&self, job_id: &Uuid, owner: &str) -> Option<StoredResult> {
+        let entries = self.entries.lock();
+        entries
+            .get(job_id)
+            .filter(|entry| entry.owner == owner && entry.expires_at > Instant::now())
+            .cloned()
+    }
+
+    pub async fn sweep_expired(&self) {
+        let now = Instant::now();
+        let expired = {
+            let mut entries = self.entries.lock();
+            let expired_ids = entries
+                .iter()
+                .filter(|(_, entry)| entry.expires_at <= now)
+                .map(|(id, _)| *id)
+                .collect::<Vec<_>>();
+            expired_ids
+                .into_iter()
+                .filter_map(|id| entries.remove(&id))
+                .collect::<Vec<_>>()
+        };
+
+        for entry in expired {
+            if let Err(error) = tokio::fs::remove_file(&entry.path).await {
+                if error.kind() != std::io::ErrorKind::NotFound {
+                    tracing::error!(path = %entry.path.display(), error = %error, "failed to delete expired result");
+                }
+            }
+        }
+
+        self.sweep_orphaned_files().await;
+    }
+
+    // The index lives in memory, so results stored before a restart can never
+    // be served again. Their files are removed once they are older than the
+    // retention period; younger ones may belong to another instance sharing
+    // the work dir.
+    async fn sweep_orphaned_files(&self) {
+        let mut dir = match tokio::fs::read_dir(&self.dir).await {
+            Ok(dir) => dir,
+            Err(error) => {
+                if error.kind() != std::io::ErrorKind::NotFound {
+                    tracing::warn!(path = %self.dir.display(), error = %error, "failed to list result dir");
+                }
+                return;
+            }
+        };
+
+        let mut removed = 0usize;
+        while let Ok(Some(file)) = dir.next_entry().await {
+            let path = file.path();
+            let tracked = self.entries.lock().values().any(|entry| entry.path == path);
+            let old_enough = file
+                .metadata()
+                .await
+                .ok()
+                .filter(|metadata| metadata.is_file())
+                .and_then(|metadata| metadata.modified().ok())
+                .and_then(|modified| modified.elapsed().ok())
+                .is_some_and(|age| age >= self.ttl);
+            if tracked || !old_enough {
+                continue;
+            }
+            match tokio::fs::remove_file(&path).await {
+                Ok(()) => removed += 1,
+                Err(error) => {
+                    tracing::warn!(path = %path.display(), error = %error, "failed to delete orphaned result")
+                }
+            }
+        }
+        if removed > 0 {
+            tracing::info!(removed, "deleted orphaned result files");
+        }
+    }
+
+    pub fn spawn_sweeper(&self, interval: Duration) {
+        let store = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                store.sweep_expired().await;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn sweep_removes_untracked_files_older_than_the_ttl() {
+        let dir = std::env::temp_dir().join(format!("ghost-results-test-{}", Uuid::new_v4()));
+        tokio::fs::create_dir_all(&dir).await.expect("dir");
+        let orphan = dir.join("orphan.pdf");
+        tokio::fs::write(&orphan, b"%PDF").await.expect("orphan");
+
+        // Younger than the TTL: possibly another instance's, so kept.
+        let store = ResultStore::new(dir.clone(), Duration::from_secs(3600));
+        store.sweep_expired().await;
+        assert!(orphan.exists());
+
+        let store = ResultStore::new(dir.clone(), Duration::ZERO);
+        let source = dir.join("source.pdf");
+        tokio::fs::write(&source, b"%PDF").await.expect("source");
+        let kept = store
+            .retain("user_1", &source, "out.pdf")
+            .await
+            .expect("retain");
+        let kept_path = store.entries.lock()[&kept].path.clone();
+        store
+            .entries
+            .lock()
+            .get_mut(&kept)
+            .expect("entry")
+            .expires_at = Instant::now() + Duration::from_secs(3600);
+
+        store.sweep_expired().await;
+
+        assert!(!orphan.exists());
+        assert!(kept_path.exists());
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+    }
+}
@@ -1,23 +1,73 @@
-use std::{future::Future, sync::Arc, time::Instant};
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
-use tokio::sync::Semaphore;
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use serde::Serialize;
+use tokio::sync::{OwnedMutexGuard, Semaphore};
 
 use crate::{
-    auth::AuthService, clerk::ClerkClient, config::Config, convex::ConvexClient, plans::PriceMap,
-    rate_limit::InMemoryRateLimiter, stripe_api::StripeApi,
+    auth::AuthService, clerk::ClerkClient, config::Config, convex::ConvexClient,
+    convex_api::ConvexApi, features::FeatureFlags, jobs::JobStore, plans::PriceMap,
+    rate_limit::InMemoryRateLimiter, results::ResultStore, stripe_api::StripeApi, tus::TusStore,
 };
 
+pub type LogFilterHandle =
+    tracing_subscriber::reload::Handle<tracing_subscriber::EnvFilter, tracing_subscriber::Registry>;
+
+// When the check ran and what it returned.
+pub type ConvexHealthCheck = (Instant, Result<String, String>);
+
 #[derive(Clone)]
 pub struct AppState {
     pub config: Arc<Config>,
-    pub convex: ConvexClient,
+    pub convex_api: ConvexApi,
     pub auth: AuthService,
     pub clerk: ClerkClient,
     pub stripe: StripeApi,
+    pub fetch_http: reqwest::Client,
     pub price_map: PriceMap,
     pub ghostscript_semaphore: Arc<Semaphore>,
+    pub ghostscript_waiting: Arc<AtomicUsize>,
+    pub ghostscript_timings: Arc<Mutex<VecDeque<JobTiming>>>,
+    pub request_semaphore: Arc<Semaphore>,
     pub preflight_test_limiter: Arc<InMemoryRateLimiter>,
     pub api_limiter: Arc<InMemoryRateLimiter>,
+    pub results: ResultStore,
+    pub jobs: JobStore,
+    pub uploads: TusStore,
+    pub customer_creation_locks: Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>>,
+    pub user_syncs: Arc<Mutex<HashMap<String, Instant>>>,
+    pub user_sync_locks: Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>>,
+    pub log_filter: LogFilterHandle,
+    pub features: Arc<FeatureFlags>,
+    pub convex_health: Arc<tokio::sync::Mutex<Option<ConvexHealthCheck>>>,
+}
+
+const USER_SYNC_MAP_SOFT_LIMIT: usize = 10_000;
+const RECENT_JOB_TIMINGS: usize = 128;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobTiming {
+    pub task: String,
+    pub wait_ms: u128,
+    pub run_ms: u128,
+    pub ok: bool,
+    pub finished_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UserSyncDue {
+    FirstSeen,
+    Stale,
 }
 
 impl AppState {
@@ -27,10 +77,32 @@ impl AppState {
         auth: AuthService,
         clerk: ClerkClient,
         stripe: StripeApi,
+        fetch_http: reqwest::Client,
+        log_filter: LogFilterHandle,
     ) -> Self {
         let price_map = PriceMap::from_config(&config);
+        let results = ResultStore::new(
+            config.work_dir.join("ghost-results"),
+            std::time::Duration::from_secs(config.result_retention_secs),
+        );
+        let jobs = JobStore::new(std::time::Duration::from_secs(config.result_retention_secs));
         Self {
+            results,
+            jobs,
+            uploads: TusStore::new(
+                std::time::Duration::from_secs(config.tus_upload_ttl_secs),
+                config.tus_max_uploads_per_user,
+            ),
+            customer_creation_locks: Arc::new(Mutex::new(HashMap::new())),
+            user_syncs: Arc::new(Mutex::new(HashMap::new())),
+            user_sync_locks: Arc::new(Mutex::new(HashMap::new())),
+            log_filter,
+            features: Arc::new(FeatureFlags::new(&config.disabled_features)),
+            convex_health: Arc::new(tokio::sync::Mutex::new(None)),
             ghostscript_semaphore: Arc::new(Semaphore::new(config.ghostscript_concurrency)),
+            ghostscript_waiting: Arc::new(AtomicUsize::new(0)),
+            ghostscript_timings: Arc::new(Mutex::new(VecDeque::with_capacity(RECENT_JOB_TIMINGS))),
+            request_semaphore: Arc::new(Semaphore::new(config.max_concurrent_requests)),
             preflight_test_limiter: Arc::new(InMemoryRateLimiter::new(
                 std::time::Duration::from_secs(15 * 60),
                 5,
@@ -40,14 +112,109 @@ impl AppState {
                 100,
             )),
             config: Arc::new(config),
-            convex,
+            convex_api: ConvexApi::new(convex),
             auth,
             clerk,
             stripe,
+            fetch_http,
             price_map,
         }
     }
 
+    // The lock is held across the query, so probes that arrive while a check
+    // is running wait for it and share its result instead of each hitting
+    // Convex.
+    pub async fn convex_health(&self) -> Result<String, String> {
+        let ttl = Duration::from_secs(self.config.health_cache_secs);
+        let mut cached = self.convex_health.lock().await;
+        if let Some((checked_at, result)) = cached.as_ref() {
+            if checked_at.elapsed() < ttl {
+                return result.clone();
+            }
+        }
+
+        let result = self.convex_api.health().await.map_err(|error| {
+            tracing::error!(error = %error, "failed to connect to Convex");
+            error.to_string()
+        });
+        *cached = Some((Instant::now(), result.clone()));
+        result
+    }
+
+    // Serializes Stripe customer creation per user so concurrent checkouts
+    // cannot each create a customer.
+    pub async fn lock_customer_creation(&self, clerk_id: &str) -> OwnedMutexGuard<()> {
+        lock_per_user(&self.customer_creation_locks, clerk_id).await
+    }
+
+    // A stale re-sync runs in the background, so its slot is claimed up front
+    // to keep concurrent requests from each starting one. A first sync is
+    // only recorded once it succeeds; callers serialize on
+    // `lock_first_user_sync` and check `user_synced` again after waiting.
+    pub fn claim_user_sync(&self, clerk_id: &str) -> Option<UserSyncDue> {
+        let interval = Duration::from_secs(self.config.user_sync_interval_secs);
+        let now = Instant::now();
+        let mut syncs = self.user_syncs.lock();
+        match syncs.get(clerk_id) {
+            None => return Some(UserSyncDue::FirstSeen),
+            Some(last) if now.duration_since(*last) >= interval => {}
+            Some(_) => return None,
+        }
+
+        syncs.insert(clerk_id.to_string(), now);
+        Some(UserSyncDue::Stale)
+    }
+
+    pub async fn lock_first_user_sync(&self, clerk_id: &str) -> OwnedMutexGuard<()> {
+        lock_per_user(&self.user_sync_locks, clerk_id).await
+    }
+
+    pub fn user_synced(&self, clerk_id: &str) -> bool {
+        self.user_syncs.lock().contains_key(clerk_id)
+    }
+
+    pub fn mark_user_synced(&self, clerk_id: &str) {
+        let interval = Duration::from_secs(self.config.user_sync_interval_secs);
+        let now = Instant::now();
+        let mut syncs = self.user_syncs.lock();
+        if syncs.len() >= USER_SYNC_MAP_SOFT_LIMIT {
+            syncs.retain(|_, last| now.duration_since(*last) < interval);
+        }
+        syncs.insert(clerk_id.to_string(), now);
+    }
+
+    pub fn forget_user_sync(&self, clerk_id: &str) {
+        self.user_syncs.lock().remove(clerk_id);
+    }
+
+    pub fn ghostscript_queue_depth(&self) -> usize {
+        self.ghostscript_waiting.load(Ordering::Relaxed)
+    }
+
+    pub fn recent_ghostscript_timings(&self) -> Vec<JobTiming> {
+        self.ghostscript_timings.lock().iter().cloned().collect()
+    }
+
+    // Takes a normal permit so it never competes with more than the
+    // configured concurrency. Failures are logged, never fatal.
+    pub async fn warmup_ghostscript(&self) -> anyhow::Result<Duration> {
+        let result = self
+            .run_ghostscript_job("warmup", crate::ghostscript::warmup)
+            .await;
+        match &result {
+            Ok(duration) => tracing::info!(
+                duration_ms = duration.as_millis() as u64,
+                "Ghostscript warmup finished"
+            ),
+            Err(error) => tracing::warn!(error = %error, "Ghostscript warmup failed"),
+        }
+        result
+    }
+
+    // Cancellation-safe: if the caller's future is dropped (client disconnect,
+    // request deadline), `task` is dropped with it, which kills any `gs` child
+    // (spawned with kill_on_drop) and releases the permit right away. Nothing
+    // here may be moved onto a spawned task or that guarantee is lost.
     pub async fn run_ghostscript_job<F, Fut, T>(
         &self,
         task_name: &str,
@@ -58,19 +225,34 @@ impl AppState {
         Fut: Future<Output = anyhow::Result<T>>,
     {
         let enqueued_at = Instant::now();
-        let permit = self
-            .ghostscript_semaphore
-            .acquire()
-            .await
-            .map_err(|_| anyhow::anyhow!("ghostscript queue closed"))?;
+        let waiting = WaitingGuard::enter(&self.ghostscript_waiting);
+        let permit = self.ghostscript_semaphore.acquire().await;
+        drop(waiting);
+        let permit = permit.map_err(|_| anyhow::anyhow!("ghostscript queue closed"))?;
         let started_at = Instant::now();
         let wait_ms = started_at.duration_since(enqueued_at).as_millis();
 
+        let running = CancelGuard::arm(task_name, started_at);
         let result = task().await;
+        running.disarm();
 
         let run_ms = Instant::now().duration_since(started_at).as_millis();
         drop(permit);
 
+        {
+            let mut timings = self.ghostscript_timings.lock();
+            if timings.len() >= RECENT_JOB_TIMINGS {
+                timings.pop_front();
+            }
+            timings.push_back(JobTiming {
+                task: task_name.to_string(),
+                wait_ms,
+                run_ms,
+                ok: result.is_ok(),
+                finished_at: Utc::now(),
+            });
+        }
+
         if self.config.log_task_queue_timings {
             let available = self.ghostscript_semaphore.available_permits();
             let running = self
@@ -90,3 +272,180 @@ impl AppState {
         result
     }
 }
+
+// Keeps the queue depth honest when a waiting request is dropped by the
+// deadline middleware or a client disconnect.
+struct WaitingGuard<'a>(&'a AtomicUsize);
+
+impl<'a> WaitingGuard<'a> {
+    fn enter(counter: &'a AtomicUsize) -> Self {
+        counter.fetch_add(1, Ordering::Relaxed);
+        Self(counter)
+    }
+}
+
+impl Drop for WaitingGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Logs jobs whose future was dropped mid-run so cancellations show up next to
+// the regular queue timings.
+struct CancelGuard<'a> {
+    task: &'a str,
+    started_at: Instant,
+    armed: bool,
+}
+
+impl<'a> CancelGuard<'a> {
+    fn arm(task: &'a str, started_at: Instant) -> Self {
+        Self {
+            task,
+            started_at,
+            armed: true,
+        }
+    }
+
+    fn disarm(mut self) {
+        self.armed = false;
+    }
+}
+
+impl Drop for CancelGuard<'_> {
+    fn drop(&mut self) {
+        if self.armed {
+            tracing::warn!(
+                queue = "ghostscript",
+                task = self.task,
+                run_ms = self.started_at.elapsed().as_millis(),
+                "ghostscript job cancelled; child process killed and permit released"
+            );
+        }
+    }
+}
+
+async fn lock_per_user(
+    locks: &Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+    clerk_id: &str,
+) -> OwnedMutexGuard<()> {
+    let lock = {
+        let mut locks = locks.lock();
+        locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+        locks
+            .entry(clerk_id.to_string())
+            .or_insert_with(|| Arc::new(tokio::sync::Mutex::new(())))
+            .clone()
+    };
+    lock.lock_owned().await
+}
+
+#[cfg(test)]
+impl AppState {
+    // Clients point at the config's URLs but are never reached unless a test
+    // mocks them; the log filter handle is detached from any subscriber.
+    pub fn for_tests(config: Config) -> Self {
+        let convex = ConvexClient::new(config.convex_url.clone(), None).expect("convex client");
+        let auth = AuthService::new(
+            config.clerk_issuer.clone(),
+            config.clerk_jwt_leeway_secs,
+            config.clerk_token_cache_size,
+            config.jwks_max_keys,
+            config.clerk_issuer_domains.clone(),
+        )
+        .expect("auth service");
+        let clerk = ClerkClient::new(
+            config.clerk_api_base.clone(),
+            config.clerk_secret_key.as_deref(),
+            config.clerk_webhook_secret.clone(),
+            Duration::from_secs(config.clerk_email_cache_ttl_secs),
+        )
+        .expect("clerk client");
+        let stripe = StripeApi::new(
+            config.stripe_secret_key.clone(),
+            config.stripe_webhook_secret.clone(),
+            crate::stripe_api::StripeRetryPolicy::from_config(&config),
+        )
+        .expect("stripe client");
+        let (_, log_filter) =
+            tracing_subscriber::reload::Layer::new(tracing_subscriber::EnvFilter::new("info"));
+        Self::new(
+            config,
+            convex,
+            auth,
+            clerk,
+            stripe,
+            reqwest::Client::new(),
+            log_filter,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn cancelled_ghostscript_job_returns_its_permit() {
+        let mut config = Config::for_tests();
+        config.ghostscript_concurrency = 1;
+        let state = AppState::for_tests(config);
+
+        let running = tokio::spawn({
+            let state = state.clone();
+            async move {
+                state
+                    .run_ghostscript_job("test", || async {
+                        tokio::time::sleep(Duration::from_secs(3600)).await;
+                        Ok(())
+                    })
+                    .await
+            }
+        });
+        while state.ghostscript_semaphore.available_permits() > 0 {
+            tokio::task::yield_now().await;
+        }
+
+        let queued = tokio::spawn({
+            let state = state.clone();
+            async move {
+                state
+                    .run_ghostscript_job("queued", || async { Ok(()) })
+                    .await
+            }
+        });
+        while state.ghostscript_waiting.load(Ordering::SeqCst) == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        queued.abort();
+        assert!(queued.await.unwrap_err().is_cancelled());
+        assert_eq!(state.ghostscript_waiting.load(Ordering::SeqCst), 0);
+
+        running.abort();
+        assert!(running.await.unwrap_err().is_cancelled());
+        assert_eq!(state.ghostscript_semaphore.available_permits(), 1);
+    }
+
+    #[tokio::test]
+    async fn customer_creation_is_serialized_per_user() {
+        let state = AppState::for_tests(Config::for_tests());
+        let held = state.lock_customer_creation("user_1").await;
+
+        let second = tokio::spawn({
+            let state = state.clone();
+            async move {
+                let _guard = state.lock_customer_creation("user_1").await;
+            }
+        });
+        // Another user's lock is independent.
+        drop(state.lock_customer_creation("user_2").await);
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        assert!(!second.is_finished());
+
+        drop(held);
+        second.await.expect("second locker");
+    }
+}
@@ -1,11 +1,20 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use anyhow::{anyhow, Context};
 use chrono::Utc;
 use hmac::{Hmac, Mac};
+use parking_lot::Mutex;
 use serde::{de::DeserializeOwned, Deserialize};
 use sha2::Sha256;
 use subtle::ConstantTimeEq;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::config::Config;
 
 #[derive(Clone)]
 pub struct StripeApi {
@@ -13,10 +22,65 @@ pub struct StripeApi {
     secret_key: Option<String>,
     webhook_secret: Option<String>,
     base_url: String,
+    seen_webhook_signatures: Arc<Mutex<VecDeque<(i64, String)>>>,
+    retry_policy: StripeRetryPolicy,
+    circuit: Arc<Mutex<CircuitState>>,
+}
+
+const WEBHOOK_REPLAY_CACHE_SIZE: usize = 512;
+
+#[derive(Debug, Clone, Copy)]
+pub struct StripeRetryPolicy {
+    pub max_retries: u32,
+    pub base_backoff: Duration,
+    pub max_backoff: Duration,
+    pub circuit_failure_threshold: usize,
+    pub circuit_cooldown: Duration,
+}
+
+impl StripeRetryPolicy {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            max_retries: config.stripe_max_retries,
+            base_backoff: Duration::from_millis(config.stripe_retry_base_ms),
+            max_backoff: Duration::from_millis(config.stripe_retry_max_ms),
+            circuit_failure_threshold: config.stripe_circuit_failure_threshold,
+            circuit_cooldown: Duration::from_secs(config.stripe_circuit_cooldown_secs),
+        }
+    }
+
+    fn backoff(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        let exponential = self
+            .base_backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_backoff);
+        match retry_after {
+            Some(value) => value.min(self.max_backoff).max(exponential),
+            None => exponential,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct CircuitState {
+    consecutive_failures: usize,
+    open_until: Option<Instant>,
+}
+
+enum StripeAttempt {
+    Response(reqwest::Response),
+    Retry {
+        error: anyhow::Error,
+        retry_after: Option<Duration>,
+    },
 }
 
 impl StripeApi {
-    pub fn new(secret_key: Option<String>, webhook_secret: Option<String>) -> anyhow::Result<Self> {
+    pub fn new(
+        secret_key: Option<String>,
+        webhook_secret: Option<String>,
+        retry_policy: StripeRetryPolicy,
+    ) -> anyhow::Result<Self> {
         let http = reqwest::Client::builder()
             .build()
             .context("failed to create Stripe HTTP client")?;
@@ -26,6 +90,11 @@ impl StripeApi {
             secret_key,
             webhook_secret,
             base_url: "https://api.stripe.com/v1".to_string(),
+            seen_webhook_signatures: Arc::new(Mutex::new(VecDeque::with_capacity(
+                WEBHOOK_REPLAY_CACHE_SIZE,
+            ))),
+            retry_policy,
+            circuit: Arc::new(Mutex::new(CircuitState::default())),
         })
     }
 
@@ -81,6 +150,17 @@ impl StripeApi {
             return Err(anyhow!("Invalid Stripe signature."));
         }
 
+        let mut seen = self.seen_webhook_signatures.lock();
+        if seen.iter().any(|(seen_timestamp, seen_signature)| {
+            *seen_timestamp == timestamp && *seen_signature == expected
+        }) {
+            return Err(anyhow!("Stripe webhook replay detected."));
+        }
+        if seen.len() >= WEBHOOK_REPLAY_CACHE_SIZE {
+            seen.pop_front();
+        }
+        seen.push_back((timestamp, expected));
+
         Ok(())
     }
 
@@ -153,6 +233,73 @@ impl StripeApi {
             .await
     }
 
+    pub async fn retrieve_price(&self, price_id: &str) -> anyhow::Result<StripePrice> {
+        self.get_json(&format!("prices/{}", price_id), &[]).await
+    }
+
+    // Newest first, including canceled ones.
+    pub async fn list_customer_subscriptions(
+        &self,
+        customer_id: &str,
+    ) -> anyhow::Result<Vec<StripeSubscription>> {
+        let list: StripeSubscriptionList = self
+            .get_json(
+                "subscriptions",
+                &[
+                    ("customer", customer_id),
+                    ("status", "all"),
+                    ("limit", "100"),
+                ],
+            )
+            .await?;
+        Ok(list.data)
+    }
+
+    // Swaps the price on one item in place, keeping the billing cycle.
+    pub async fn update_subscription_price(
+        &self,
+        subscription_id: &str,
+        subscription_item_id: &str,
+        new_price_id: &str,
+        proration_behavior: ProrationBehavior,
+    ) -> anyhow::Result<StripeSubscription> {
+        let params = vec![
+            ("items[0][id]".to_string(), subscription_item_id.to_string()),
+            ("items[0][price]".to_string(), new_price_id.to_string()),
+            (
+                "proration_behavior".to_string(),
+                proration_behavior.as_str().to_string(),
+            ),
+            // Cancels a pending cancellation, as the portal does on a plan change.
+            ("cancel_at_period_end".to_string(), "false".to_string()),
+        ];
+        self.post_form(&format!("subscriptions/{}", subscription_id), &params)
+            .await
+    }
+
+    // Uses `action=increment`, so the metered price must aggregate with `sum`.
+    // The caller passes an idempotency key so a report that is retried after
+    // Stripe accepted it isn't added twice.
+    pub async fn report_usage(
+        &self,
+        subscription_item_id: &str,
+        quantity: i64,
+        timestamp: i64,
+        idempotency_key: &str,
+    ) -> anyhow::Result<StripeUsageRecord> {
+        let params = vec![
+            ("quantity".to_string(), quantity.to_string()),
+            ("timestamp".to_string(), timestamp.to_string()),
+            ("action".to_string(), "increment".to_string()),
+        ];
+        self.post_form_with_key(
+            &format!("subscription_items/{}/usage_records", subscription_item_id),
+            &params,
+            idempotency_key,
+        )
+        .await
+    }
+
     fn require_secret_key(&self) -> anyhow::Result<&str> {
         self.secret_key
             .as_deref()
@@ -164,18 +311,31 @@ impl StripeApi {
         &self,
         path: &str,
         params: &[(String, String)],
+    ) -> anyhow::Result<T> {
+        // One key per logical call so a retried POST cannot create a second object.
+        let idempotency_key = Uuid::new_v4().to_string();
+        self.post_form_with_key(path, params, &idempotency_key)
+            .await
+    }
+
+    async fn post_form_with_key<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        params: &[(String, String)],
+        idempotency_key: &str,
     ) -> anyhow::Result<T> {
         let key = self.require_secret_key()?;
         let url = format!("{}/{}", self.base_url, path);
 
         let response = self
-            .http
-            .post(url)
-            .bearer_auth(key)
-            .form(params)
-            .send()
-            .await
-            .with_context(|| format!("Stripe POST failed for {}", path))?;
+            .send_with_retry("POST", path, || {
+                self.http
+                    .post(&url)
+                    .bearer_auth(key)
+                    .header("Idempotency-Key", idempotency_key)
+                    .form(params)
+            })
+            .await?;
 
         parse_stripe_response(response, path).await
     }
@@ -189,16 +349,118 @@ impl StripeApi {
         let url = format!("{}/{}", self.base_url, path);
 
         let response = self
-            .http
-            .get(url)
-            .bearer_auth(key)
-            .query(query)
-            .send()
-            .await
-            .with_context(|| format!("Stripe GET failed for {}", path))?;
+            .send_with_retry("GET", path, || {
+                self.http.get(&url).bearer_auth(key).query(query)
+            })
+            .await?;
 
         parse_stripe_response(response, path).await
     }
+
+    // Retries 429s (honouring Retry-After) and 5xx/transport errors with
+    // exponential backoff. Other 4xx responses are returned as-is for
+    // `parse_stripe_response` to surface.
+    async fn send_with_retry(
+        &self,
+        method: &str,
+        path: &str,
+        build: impl Fn() -> reqwest::RequestBuilder,
+    ) -> anyhow::Result<reqwest::Response> {
+        self.check_circuit()?;
+
+        let mut attempt = 0;
+        loop {
+            let outcome = match build().send().await {
+                Ok(response) => {
+                    let status = response.status();
+                    if status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+                    {
+                        StripeAttempt::Retry {
+                            retry_after: parse_retry_after(response.headers()),
+                            error: anyhow!(
+                                "Stripe API {} failed with status {}: {}",
+                                path,
+                                status,
+                                response.text().await.unwrap_or_default()
+                            ),
+                        }
+                    } else {
+                        StripeAttempt::Response(response)
+                    }
+                }
+                Err(error) => StripeAttempt::Retry {
+                    error: anyhow::Error::new(error)
+                        .context(format!("Stripe {} failed for {}", method, path)),
+                    retry_after: None,
+                },
+            };
+
+            match outcome {
+                StripeAttempt::Response(response) => {
+                    self.record_success();
+                    return Ok(response);
+                }
+                StripeAttempt::Retry { error, retry_after } => {
+                    if attempt >= self.retry_policy.max_retries {
+                        self.record_failure();
+                        return Err(error);
+                    }
+                    let delay = self.retry_policy.backoff(attempt, retry_after);
+                    tracing::warn!(
+                        path,
+                        attempt = attempt + 1,
+                        delay_ms = delay.as_millis() as u64,
+                        error = %error,
+                        "retrying Stripe request"
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    fn check_circuit(&self) -> anyhow::Result<()> {
+        let mut circuit = self.circuit.lock();
+        match circuit.open_until {
+            Some(until) if Instant::now() < until => {
+                Err(anyhow!("Stripe is temporarily unavailable; circuit open."))
+            }
+            Some(_) => {
+                // Cooldown elapsed: let this request through as a probe.
+                circuit.open_until = None;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn record_success(&self) {
+        let mut circuit = self.circuit.lock();
+        circuit.consecutive_failures = 0;
+        circuit.open_until = None;
+    }
+
+    fn record_failure(&self) {
+        let mut circuit = self.circuit.lock();
+        circuit.consecutive_failures += 1;
+        if circuit.consecutive_failures >= self.retry_policy.circuit_failure_threshold {
+            tracing::error!(
+                failures = circuit.consecutive_failures,
+                cooldown_secs = self.retry_policy.circuit_cooldown.as_secs(),
+                "Stripe circuit opened"
+            );
+            circuit.open_until = Some(Instant::now() + self.retry_policy.circuit_cooldown);
+        }
+    }
+}
+
+fn parse_retry_after(headers: &reqwest::header::HeaderMap) -> Option<Duration> {
+    headers
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
 }
 
 async fn parse_stripe_response<T: DeserializeOwned>(
@@ -252,9 +514,16 @@ pub struct StripeSubscription {
     pub customer: IdOrObject,
     pub status: String,
     pub current_period_end: Option<i64>,
+    #[serde(default)]
+    pub trial_end: Option<i64>,
     pub items: StripeSubscriptionItems,
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct StripeSubscriptionList {
+    pub data: Vec<StripeSubscription>,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct StripeSubscriptionItems {
     pub data: Vec<StripeSubscriptionItem>,
@@ -262,12 +531,57 @@ pub struct StripeSubscriptionItems {
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct StripeSubscriptionItem {
+    #[serde(default)]
+    pub id: Option<String>,
     pub price: Option<StripePrice>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct StripePrice {
     pub id: Option<String>,
+    #[serde(default)]
+    pub active: Option<bool>,
+    #[serde(default)]
+    pub recurring: Option<StripePriceRecurring>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct StripePriceRecurring {
+    #[serde(default)]
+    pub aggregate_usage: Option<String>,
+}
+
+impl StripePrice {
+    pub fn aggregate_usage(&self) -> Option<&str> {
+        self.recurring
+            .as_ref()
+            .and_then(|recurring| recurring.aggregate_usage.as_deref())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ProrationBehavior {
+    #[default]
+    CreateProrations,
+    AlwaysInvoice,
+    None,
+}
+
+impl ProrationBehavior {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::CreateProrations => "create_prorations",
+            Self::AlwaysInvoice => "always_invoice",
+            Self::None => "none",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct StripeUsageRecord {
+    pub id: String,
+    pub quantity: i64,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -310,5 +624,82 @@ pub struct StripeEventData {
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct StripeInvoice {
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub customer: Option<IdOrObject>,
     pub subscription: Option<IdOrObject>,
+    #[serde(default)]
+    pub hosted_invoice_url: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "whsec_test_secret";
+
+    fn api() -> StripeApi {
+        let policy = StripeRetryPolicy {
+            max_retries: 0,
+            base_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+            circuit_failure_threshold: 1,
+            circuit_cooldown: Duration::from_secs(1),
+        };
+        StripeApi::new(None, Some(SECRET.to_string()), policy).expect("stripe api")
+    }
+
+    fn signature_header(timestamp: i64, payload: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(SECRET.as_bytes()).expect("hmac key");
+        mac.update(format!("{}.{}", timestamp, payload).as_bytes());
+        format!(
+            "t={},v1={}",
+            timestamp,
+            hex::encode(mac.finalize().into_bytes())
+        )
+    }
+
+    #[test]
+    fn same_signed_payload_is_rejected_the_second_time() {
+        let api = api();
+        let payload = r#"{"id":"evt_1","type":"invoice.paid"}"#;
+        let header = signature_header(Utc::now().timestamp(), payload);
+
+        api.verify_webhook_signature(&header, payload.as_bytes())
+            .expect("first delivery");
+        let error = api
+            .verify_webhook_signature(&header, payload.as_bytes())
+            .expect_err("replayed delivery");
+        assert!(error.to_string().contains("replay"));
+    }
+
+    #[test]
+    fn stripe_retry_with_a_new_timestamp_is_accepted() {
+        let api = api();
+        let payload = r#"{"id":"evt_1","type":"invoice.paid"}"#;
+        let now = Utc::now().timestamp();
+
+        api.verify_webhook_signature(&signature_header(now - 10, payload), payload.as_bytes())
+            .expect("first delivery");
+        api.verify_webhook_signature(&signature_header(now, payload), payload.as_bytes())
+            .expect("retry");
+    }
+
+    #[test]
+    fn bad_signature_and_stale_timestamp_are_rejected() {
+        let api = api();
+        let payload = r#"{"id":"evt_1"}"#;
+        let now = Utc::now().timestamp();
+
+        let tampered = signature_header(now, payload);
+        assert!(api
+            .verify_webhook_signature(&tampered, br#"{"id":"evt_2"}"#)
+            .is_err());
+        let stale = signature_header(now - 301, payload);
+        let error = api
+            .verify_webhook_signature(&stale, payload.as_bytes())
+            .expect_err("stale");
+        assert!(error.to_string().contains("tolerance"));
+    }
 }
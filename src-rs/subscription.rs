@@ -0,0 +1,70 @@
+use serde::Deserialize;
+
+use crate::plans::{is_subscription_active, resolve_plan_id, PlanId};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Subscription {
+    pub plan: Option<String>,
+    pub status: Option<String>,
+    #[serde(rename = "stripeSubscriptionId", default)]
+    pub stripe_subscription_id: Option<String>,
+    #[serde(rename = "stripePriceId", default)]
+    pub stripe_price_id: Option<String>,
+    #[serde(rename = "stripeMeteredItemId", default)]
+    pub stripe_metered_item_id: Option<String>,
+}
+
+impl Subscription {
+    pub fn is_active(&self) -> bool {
+        is_subscription_active(self.status.as_deref())
+    }
+
+    pub fn plan_id(&self) -> PlanId {
+        resolve_plan_id(self.plan.as_deref())
+    }
+
+    pub fn effective_plan(&self) -> PlanId {
+        if self.is_active() {
+            self.plan_id()
+        } else {
+            PlanId::Free
+        }
+    }
+}
+
+pub fn effective_plan(subscription: Option<&Subscription>) -> PlanId {
+    subscription
+        .map(Subscription::effective_plan)
+        .unwrap_or(PlanId::Free)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subscription(plan: &str, status: Option<&str>) -> Subscription {
+        Subscription {
+            plan: Some(plan.to_string()),
+            status: status.map(str::to_string),
+            stripe_subscription_id: None,
+            stripe_price_id: None,
+            stripe_metered_item_id: None,
+        }
+    }
+
+    #[test]
+    fn effective_plan_only_counts_live_subscriptions() {
+        let cases = [
+            (Some("active"), PlanId::Pro),
+            (Some("trialing"), PlanId::Pro),
+            (Some("canceled"), PlanId::Free),
+            (Some("past_due"), PlanId::Free),
+            (None, PlanId::Free),
+        ];
+        for (status, expected) in cases {
+            let pro = subscription("pro", status);
+            assert_eq!(effective_plan(Some(&pro)), expected, "{status:?}");
+        }
+        assert_eq!(effective_plan(None), PlanId::Free);
+    }
+}
@@ -0,0 +1,158 @@
+use std::time::Duration;
+
+use tokio::{process::Command, sync::OnceCell, time::timeout};
+
+// -dOmitXMP/-dOmitID (sanitize) need 9.50+; inkcov and pdfwrite predate it.
+const MIN_GHOSTSCRIPT_VERSION: (u32, u32) = (9, 50);
+const TOOL_PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct DependencyReport {
+    pub ghostscript_version: Option<String>,
+    pub inkcov_device: bool,
+    pub pdfwrite_device: bool,
+    pub pdfinfo_version: Option<String>,
+    pub mutool_version: Option<String>,
+    pub ocrmypdf_version: Option<String>,
+}
+
+impl DependencyReport {
+    pub fn ghostscript_problem(&self) -> Option<String> {
+        let version = match self.ghostscript_version.as_deref() {
+            Some(value) => value,
+            None => return Some("Ghostscript (`gs`) was not found".to_string()),
+        };
+
+        match parse_version(version) {
+            Some(parsed) if parsed < MIN_GHOSTSCRIPT_VERSION => {
+                return Some(format!(
+                    "Ghostscript {} is older than the required {}.{}",
+                    version, MIN_GHOSTSCRIPT_VERSION.0, MIN_GHOSTSCRIPT_VERSION.1
+                ))
+            }
+            None => return Some(format!("could not parse Ghostscript version `{}`", version)),
+            Some(_) => {}
+        }
+
+        if !self.inkcov_device {
+            return Some("Ghostscript is missing the inkcov device".to_string());
+        }
+        if !self.pdfwrite_device {
+            return Some("Ghostscript is missing the pdfwrite device".to_string());
+        }
+
+        None
+    }
+}
+
+pub async fn verify_dependencies() -> DependencyReport {
+    let ghostscript_version = probe_version("gs", &["--version"]).await;
+    let devices = match ghostscript_version {
+        Some(_) => probe_output("gs", &["-h"]).await.unwrap_or_default(),
+        None => String::new(),
+    };
+    let device_names = ghostscript_devices(&devices);
+
+    let mutool = std::env::var("MUTOOL_BIN").unwrap_or_else(|_| "mutool".to_string());
+    let report = DependencyReport {
+        ghostscript_version,
+        inkcov_device: device_names.iter().any(|name| name == "inkcov"),
+        pdfwrite_device: device_names.iter().any(|name| name == "pdfwrite"),
+        pdfinfo_version: probe_version("pdfinfo", &["-v"]).await,
+        mutool_version: probe_version(&mutool, &["-v"]).await,
+        ocrmypdf_version: probe_version(&crate::ocr::ocrmypdf_program(), &["--version"]).await,
+    };
+
+    tracing::info!(
+        ghostscript = report.ghostscript_version.as_deref().unwrap_or("missing"),
+        inkcov = report.inkcov_device,
+        pdfwrite = report.pdfwrite_device,
+        pdfinfo = report.pdfinfo_version.as_deref().unwrap_or("missing"),
+        mutool = report.mutool_version.as_deref().unwrap_or("missing"),
+        ocrmypdf = report.ocrmypdf_version.as_deref().unwrap_or("missing"),
+        "external tool check"
+    );
+
+    report
+}
+
+// Probed once per process; installing mutool needs a restart anyway.
+pub async fn mupdf_recolor_available() -> bool {
+    static AVAILABLE: OnceCell<bool> = OnceCell::const_new();
+    *AVAILABLE
+        .get_or_init(|| async { crate::mupdf::ensure_mutool_recolor_support().await.is_ok() })
+        .await
+}
+
+pub async fn ocr_available() -> bool {
+    static AVAILABLE: OnceCell<bool> = OnceCell::const_new();
+    *AVAILABLE
+        .get_or_init(|| async {
+            probe_version(&crate::ocr::ocrmypdf_program(), &["--version"])
+                .await
+                .is_some()
+        })
+        .await
+}
+
+async fn probe_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = timeout(
+        TOOL_PROBE_TIMEOUT,
+        Command::new(program).args(args).kill_on_drop(true).output(),
+    )
+    .await
+    .ok()?
+    .ok()?;
+
+    Some(format!(
+        "{}\n{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    ))
+}
+
+// pdfinfo and mutool print their banner to stderr and may exit non-zero for
+// `-v`, so only the output text is trusted here.
+async fn probe_version(program: &str, args: &[&str]) -> Option<String> {
+    let output = probe_output(program, args).await?;
+    output
+        .split_whitespace()
+        .find(|token| token.starts_with(|c: char| c.is_ascii_digit()) && token.contains('.'))
+        .map(ToString::to_string)
+}
+
+fn parse_version(raw: &str) -> Option<(u32, u32)> {
+    let mut parts = raw.trim().split('.');
+    let major = parts.next()?.parse::<u32>().ok()?;
+    let minor = parts
+        .next()
+        .map(|value| {
+            value
+                .chars()
+                .take_while(char::is_ascii_digit)
+                .collect::<String>()
+        })
+        .and_then(|value| value.parse::<u32>().ok())
+        .unwrap_or(0);
+    Some((major, minor))
+}
+
+// `gs -h` lists devices as whitespace-separated names under
+// "Available devices:" until the next blank-line-terminated section.
+fn ghostscript_devices(help: &str) -> Vec<String> {
+    let mut devices = Vec::new();
+    let mut in_devices = false;
+    for line in help.lines() {
+        if line.trim_start().starts_with("Available devices:") {
+            in_devices = true;
+            continue;
+        }
+        if in_devices {
+            if !line.starts_with(char::is_whitespace) {
+                break;
+            }
+            devices.extend(line.split_whitespace().map(ToString::to_string));
+        }
+    }
+    devices
+}
@@ -0,0 +1,208 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::upload::{remove_file_if_exists, UploadKind, UploadedFile};
+
+pub const TUS_VERSION: &str = "1.0.0";
+
+#[derive(Debug, Error)]
+pub enum TusError {
+    #[error("Upload not found")]
+    NotFound,
+    #[error("Upload-Offset does not match the current offset")]
+    OffsetMismatch,
+    #[error("Upload is already receiving data")]
+    Busy,
+    #[error("Too many unfinished uploads")]
+    TooManyUploads,
+}
+
+#[derive(Debug, Clone)]
+struct TusUpload {
+    owner: String,
+    path: PathBuf,
+    kind: UploadKind,
+    file_name: String,
+    length: u64,
+    offset: u64,
+    busy: bool,
+    created_at: Instant,
+    updated_at: Instant,
+}
+
+#[derive(Debug, Clone)]
+pub struct TusPatch {
+    pub path: PathBuf,
+    pub offset: u64,
+    pub remaining: u64,
+}
+
+#[derive(Clone)]
+pub struct TusStore {
+    ttl: Duration,
+    max_per_owner: usize,
+    entries: Arc<Mutex<HashMap<Uuid, TusUpload>>>,
+}
+
+impl TusStore {
+    pub fn new(ttl: Duration, max_per_owner: usize) -> Self {
+        Self {
+            ttl,
+            max_per_owner,
+            entries: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // Checked before the temp file is created so a client at its cap can't
+    // leave files behind.
+    pub fn has_capacity(&self, owner: &str) -> bool {
+        self.count_for(&self.entries.lock(), owner) < self.max_per_owner
+    }
+
+    fn count_for(&self, entries: &HashMap<Uuid, TusUpload>, owner: &str) -> usize {
+        entries
+            .values()
+            .filter(|upload| upload.owner == owner)
+            .count()
+    }
+
+    pub fn create(
+        &self,
+        owner: &str,
+        path: PathBuf,
+        kind: UploadKind,
+        file_name: String,
+        length: u64,
+    ) -> Result<Uuid, TusError> {
+        let upload_id = Uuid::new_v4();
+        let now = Instant::now();
+        let mut entries = self.entries.lock();
+        if self.count_for(&entries, owner) >= self.max_per_owner {
+            return Err(TusError::TooManyUploads);
+        }
+        entries.insert(
+            upload_id,
+            TusUpload {
+                owner: owner.to_string(),
+                path,
+                kind,
+                file_name,
+                length,
+                offset: 0,
+                busy: false,
+                created_at: now,
+                updated_at: now,
+            },
+        );
+        Ok(upload_id)
+    }
+
+    // Returns (offset, length).
+    pub fn status(&self, upload_id: &Uuid, owner: &str) -> Option<(u64, u64)> {
+        self.entries
+            .lock()
+            .get(upload_id)
+            .filter(|upload| upload.owner == owner)
+            .map(|upload| (upload.offset, upload.length))
+    }
+
+    pub fn begin_patch(
+        &self,
+        upload_id: &Uuid,
+        owner: &str,
+        offset: u64,
+    ) -> Result<TusPatch, TusError> {
+        let mut entries = self.entries.lock();
+        let upload = entries
+            .get_mut(upload_id)
+            .filter(|upload| upload.owner == owner)
+            .ok_or(TusError::NotFound)?;
+        if upload.busy {
+            return Err(TusError::Busy);
+        }
+        if upload.offset != offset {
+            return Err(TusError::OffsetMismatch);
+        }
+
+        upload.busy = true;
+        Ok(TusPatch {
+            path: upload.path.clone(),
+            offset: upload.offset,
+            remaining: upload.length - upload.offset,
+        })
+    }
+
+    pub fn finish_patch(&self, upload_id: &Uuid, written: u64) -> Option<u64> {
+        let mut entries = self.entries.lock();
+        let upload = entries.get_mut(upload_id)?;
+        upload.busy = false;
+        upload.offset += written;
+        upload.updated_at = Instant::now();
+        Some(upload.offset)
+    }
+
+    // Hands a fully received upload to the regular processing flow; the store
+    // forgets it so the handler owns (and deletes) the temp file from here on.
+    pub fn take_completed(&self, upload_id: &Uuid, owner: &str) -> Option<UploadedFile> {
+        let mut entries = self.entries.lock();
+        let ready = entries.get(upload_id).is_some_and(|upload| {
+            upload.owner == owner && !upload.busy && upload.offset == upload.length
+        });
+        if !ready {
+            return None;
+        }
+
+        let upload = entries.remove(upload_id)?;
+        Some(UploadedFile {
+            temp_path: upload.path,
+            kind: upload.kind,
+            original_name: upload.file_name,
+            size_bytes: upload.length as usize,
+            upload_ms: upload
+                .updated_at
+                .duration_since(upload.created_at)
+                .as_millis(),
+        })
+    }
+
+    pub async fn sweep_expired(&self) {
+        let now = Instant::now();
+        let expired = {
+            let mut entries = self.entries.lock();
+            let expired_ids = entries
+                .iter()
+                .filter(|(_, upload)| {
+                    !upload.busy && now.duration_since(upload.updated_at) >= self.ttl
+                })
+                .map(|(id, _)| *id)
+                .collect::<Vec<_>>();
+            expired_ids
+                .into_iter()
+                .filter_map(|id| entries.remove(&id))
+                .collect::<Vec<_>>()
+        };
+
+        for upload in expired {
+            remove_file_if_exists(&upload.path).await;
+        }
+    }
+
+    pub fn spawn_sweeper(&self, interval: Duration) {
+        let store = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                store.sweep_expired().await;
+            }
+        });
+    }
+}
@@ -1,208 +1,680 @@
-use std::{path::PathBuf, time::SystemTime};
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+    time::{Duration, Instant, SystemTime},
+};
 
-use axum::extract::Multipart;
+use anyhow::Context;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+
+use axum::extract::{multipart::Field, Multipart};
 use thiserror::Error;
-use tokio::io::AsyncWriteExt;
+use tokio::{io::AsyncWriteExt, time::timeout};
 use uuid::Uuid;
 
+use crate::{net_guard::ensure_public_url, tus::TusStore};
+
+static MULTIPART_MAX_PARTS: Lazy<usize> = Lazy::new(|| {
+    std::env::var("MULTIPART_MAX_PARTS")
+        .ok()
+        .and_then(|value| value.parse::<usize>().ok())
+        .filter(|value| *value > 0)
+        .unwrap_or(32)
+});
+static MIN_FREE_DISK_BYTES: Lazy<u64> = Lazy::new(|| {
+    std::env::var("MIN_FREE_DISK_MB")
+        .ok()
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .unwrap_or(256)
+        .saturating_mul(1024 * 1024)
+});
+static UPLOAD_STALL_TIMEOUT: Lazy<Duration> = Lazy::new(|| {
+    let timeout_ms = std::env::var("UPLOAD_STALL_TIMEOUT_MS")
+        .ok()
+        .and_then(|value| value.parse::<u64>().ok())
+        .filter(|value| *value > 0)
+        .unwrap_or(30_000);
+    Duration::from_millis(timeout_ms)
+});
+// PDF stays the only accepted type unless operators opt in to more.
+static ALLOWED_UPLOAD_KINDS: Lazy<Vec<UploadKind>> = Lazy::new(|| {
+    let kinds = std::env::var("UPLOAD_ALLOWED_TYPES")
+        .ok()
+        .map(|value| {
+            value
+                .split(',')
+                .filter_map(|entry| {
+                    let kind = UploadKind::from_extension(entry.trim().trim_start_matches('.'));
+                    if kind.is_none() && !entry.trim().is_empty() {
+                        tracing::warn!(entry = entry.trim(), "ignoring unknown upload type");
+                    }
+                    kind
+                })
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    if kinds.is_empty() {
+        vec![UploadKind::Pdf]
+    } else {
+        kinds
+    }
+});
+const MAX_TEXT_FIELD_BYTES: usize = 64;
+const MAX_ORIGINAL_NAME_CHARS: usize = 255;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UploadKind {
+    Pdf,
+    PostScript,
+    Eps,
+}
+
+impl UploadKind {
+    fn from_extension(extension: &str) -> Option<Self> {
+        match extension.to_ascii_lowercase().as_str() {
+            "pdf" => Some(Self::Pdf),
+            "ps" => Some(Self::PostScript),
+            "eps" | "epsf" => Some(Self::Eps),
+            _ => None,
+        }
+    }
+
+    fn from_mime(mime_type: &str) -> Option<Self> {
+        match mime_type.trim().to_ascii_lowercase().as_str() {
+            "application/pdf" => Some(Self::Pdf),
+            "application/postscript" => Some(Self::PostScript),
+            "application/eps" | "application/x-eps" | "image/eps" | "image/x-eps" => {
+                Some(Self::Eps)
+            }
+            _ => None,
+        }
+    }
+
+    fn detect(mime_type: Option<&str>, file_name: &str) -> Option<Self> {
+        // The extension wins because browsers label EPS as application/postscript.
+        let from_name = std::path::Path::new(file_name)
+            .extension()
+            .and_then(|value| value.to_str())
+            .and_then(Self::from_extension);
+        from_name.or_else(|| mime_type.and_then(Self::from_mime))
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Pdf => "pdf",
+            Self::PostScript => "ps",
+            Self::Eps => "eps",
+        }
+    }
+
+    pub fn is_pdf(self) -> bool {
+        self == Self::Pdf
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct UploadedFile {
     pub temp_path: PathBuf,
+    pub kind: UploadKind,
     pub original_name: String,
+    pub size_bytes: usize,
+    pub upload_ms: u128,
 }
 
 #[derive(Debug, Clone)]
 pub struct UploadedPdfRequest {
     pub temp_path: PathBuf,
+    pub kind: UploadKind,
     pub original_name: String,
+    pub size_bytes: usize,
+    pub upload_ms: u128,
     pub mode: Option<String>,
     pub engine: Option<String>,
+    pub first_page: Option<String>,
+    pub last_page: Option<String>,
+    pub dry_run: Option<String>,
+    pub retain: Option<String>,
+    pub flatten: Option<String>,
+    pub preserve_bookmarks: Option<String>,
+    pub preserve_annotations: Option<String>,
+    pub pdf_version: Option<String>,
+    pub strip_javascript: Option<String>,
+    pub strip_metadata: Option<String>,
+    pub force_black_text: Option<String>,
+    pub force_black_vector: Option<String>,
+    pub black_threshold_l: Option<String>,
+    pub black_threshold_c: Option<String>,
+    pub language: Option<String>,
 }
 
 #[derive(Debug, Error)]
 pub enum UploadError {
     #[error("File not found")]
     MissingFile,
-    #[error("Only PDF files are supported")]
+    #[error("Unsupported file type")]
     UnsupportedFileType,
     #[error("File is too large")]
     FileTooLarge,
     #[error("Failed to parse upload")]
     MultipartError,
+    // Too many parts, or a text field over its length limit.
+    #[error("Form exceeds its size limits")]
+    FormTooLarge,
     #[error("Failed to persist upload")]
     IoError,
+    #[error("Only public https URLs are supported")]
+    InvalidUrl,
+    #[error("Failed to download file")]
+    DownloadFailed,
+    #[error("Upload stalled")]
+    Stalled,
+    #[error("Unknown or incomplete upload")]
+    UnknownUpload,
+    #[error("Not enough free disk space to accept this upload")]
+    InsufficientStorage,
 }
 
+// `resumable_uploads` is the store and owner to resolve `uploadId` against;
+// without it only a `file` part is accepted.
 pub async fn save_pdf_from_multipart(
     mut multipart: Multipart,
     max_size_bytes: usize,
+    resumable_uploads: Option<(&TusStore, &str)>,
 ) -> Result<UploadedFile, UploadError> {
+    let mut upload_id: Option<String> = None;
+    let mut parts_seen = 0usize;
     while let Some(field) = multipart
         .next_field()
         .await
         .map_err(|_| UploadError::MultipartError)?
     {
-        if field.name() != Some("file") {
-            continue;
+        parts_seen += 1;
+        if parts_seen > *MULTIPART_MAX_PARTS {
+            tracing::warn!(
+                limit = *MULTIPART_MAX_PARTS,
+                "multipart part limit exceeded"
+            );
+            return Err(UploadError::FormTooLarge);
         }
 
-        let original_name = field
-            .file_name()
-            .map(ToString::to_string)
-            .unwrap_or_else(|| "document.pdf".to_string());
-        let mime_type = field.content_type().map(ToString::to_string);
-
-        let is_pdf = mime_type.as_deref() == Some("application/pdf")
-            || original_name.to_ascii_lowercase().ends_with(".pdf");
-
-        if !is_pdf {
-            return Err(UploadError::UnsupportedFileType);
-        }
-
-        let temp_path = std::env::temp_dir().join(format!(
-            "ghost-upload-{}-{}.pdf",
-            Uuid::new_v4(),
-            SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .map(|duration| duration.as_millis())
-                .unwrap_or(0)
-        ));
-
-        let mut file = tokio::fs::File::create(&temp_path)
-            .await
-            .map_err(|_| UploadError::IoError)?;
-
-        let mut total_size = 0usize;
-        let mut field = field;
-        while let Some(chunk) = field
-            .chunk()
-            .await
-            .map_err(|_| UploadError::MultipartError)?
-        {
-            total_size += chunk.len();
-            if total_size > max_size_bytes {
-                let _ = tokio::fs::remove_file(&temp_path).await;
-                return Err(UploadError::FileTooLarge);
+        match field.name() {
+            Some("file") => return persist_pdf_field(field, max_size_bytes).await,
+            Some("uploadId") if resumable_uploads.is_some() => {
+                upload_id = read_text_field(field).await?
             }
-            file.write_all(&chunk)
-                .await
-                .map_err(|_| UploadError::IoError)?;
+            _ => {}
         }
+    }
 
-        file.flush().await.map_err(|_| UploadError::IoError)?;
-
-        return Ok(UploadedFile {
-            temp_path,
-            original_name,
-        });
+    match (upload_id, resumable_uploads) {
+        (Some(upload_id), Some((store, owner))) => take_resumable_upload(store, owner, &upload_id),
+        _ => Err(UploadError::MissingFile),
     }
+}
 
-    Err(UploadError::MissingFile)
+fn take_resumable_upload(
+    resumable_uploads: &TusStore,
+    owner: &str,
+    upload_id: &str,
+) -> Result<UploadedFile, UploadError> {
+    Uuid::parse_str(upload_id)
+        .ok()
+        .and_then(|upload_id| resumable_uploads.take_completed(&upload_id, owner))
+        .map(|mut upload| {
+            upload.temp_path = track_temp_path(upload.temp_path);
+            upload
+        })
+        .ok_or(UploadError::UnknownUpload)
 }
 
+// `uploadId` may stand in for the `file` part when the document was sent
+// through the resumable upload endpoints.
 pub async fn save_pdf_with_mode_from_multipart(
     mut multipart: Multipart,
     max_size_bytes: usize,
+    resumable_uploads: &TusStore,
+    owner: &str,
 ) -> Result<UploadedPdfRequest, UploadError> {
     let mut uploaded: Option<UploadedFile> = None;
+    let mut upload_id: Option<String> = None;
     let mut mode: Option<String> = None;
     let mut engine: Option<String> = None;
+    let mut first_page: Option<String> = None;
+    let mut last_page: Option<String> = None;
+    let mut dry_run: Option<String> = None;
+    let mut retain: Option<String> = None;
+    let mut flatten: Option<String> = None;
+    let mut preserve_bookmarks: Option<String> = None;
+    let mut preserve_annotations: Option<String> = None;
+    let mut pdf_version: Option<String> = None;
+    let mut strip_javascript: Option<String> = None;
+    let mut strip_metadata: Option<String> = None;
+    let mut force_black_text: Option<String> = None;
+    let mut force_black_vector: Option<String> = None;
+    let mut black_threshold_l: Option<String> = None;
+    let mut black_threshold_c: Option<String> = None;
+    let mut language: Option<String> = None;
 
+    let mut parts_seen = 0usize;
     while let Some(field) = multipart
         .next_field()
         .await
         .map_err(|_| UploadError::MultipartError)?
     {
+        parts_seen += 1;
+        if parts_seen > *MULTIPART_MAX_PARTS {
+            tracing::warn!(
+                limit = *MULTIPART_MAX_PARTS,
+                "multipart part limit exceeded"
+            );
+            return Err(UploadError::FormTooLarge);
+        }
+
         match field.name() {
             Some("file") => {
                 if uploaded.is_some() {
                     continue;
                 }
 
-                let original_name = field
-                    .file_name()
-                    .map(ToString::to_string)
-                    .unwrap_or_else(|| "document.pdf".to_string());
-                let mime_type = field.content_type().map(ToString::to_string);
+                uploaded = Some(persist_pdf_field(field, max_size_bytes).await?);
+            }
+            Some("uploadId") => upload_id = read_text_field(field).await?,
+            Some("mode") => mode = read_text_field(field).await?,
+            Some("engine") => engine = read_text_field(field).await?,
+            Some("firstPage") => first_page = read_text_field(field).await?,
+            Some("lastPage") => last_page = read_text_field(field).await?,
+            Some("dryRun") => dry_run = read_text_field(field).await?,
+            Some("retain") => retain = read_text_field(field).await?,
+            Some("flatten") => flatten = read_text_field(field).await?,
+            Some("preserveBookmarks") => preserve_bookmarks = read_text_field(field).await?,
+            Some("preserveAnnotations") => preserve_annotations = read_text_field(field).await?,
+            Some("pdfVersion") => pdf_version = read_text_field(field).await?,
+            Some("stripJavascript") => strip_javascript = read_text_field(field).await?,
+            Some("stripMetadata") => strip_metadata = read_text_field(field).await?,
+            Some("forceBlackText") => force_black_text = read_text_field(field).await?,
+            Some("forceBlackVector") => force_black_vector = read_text_field(field).await?,
+            Some("blackThresholdL") => black_threshold_l = read_text_field(field).await?,
+            Some("blackThresholdC") => black_threshold_c = read_text_field(field).await?,
+            Some("language") => language = read_text_field(field).await?,
+            _ => {}
+        }
+    }
 
-                let is_pdf = mime_type.as_deref() == Some("application/pdf")
-                    || original_name.to_ascii_lowercase().ends_with(".pdf");
+    let uploaded = match (uploaded, upload_id) {
+        (Some(uploaded), _) => uploaded,
+        (None, Some(upload_id)) => take_resumable_upload(resumable_uploads, owner, &upload_id)?,
+        (None, None) => return Err(UploadError::MissingFile),
+    };
 
-                if !is_pdf {
-                    return Err(UploadError::UnsupportedFileType);
-                }
+    Ok(UploadedPdfRequest {
+        temp_path: uploaded.temp_path,
+        kind: uploaded.kind,
+        original_name: uploaded.original_name,
+        size_bytes: uploaded.size_bytes,
+        upload_ms: uploaded.upload_ms,
+        mode,
+        engine,
+        first_page,
+        last_page,
+        dry_run,
+        retain,
+        flatten,
+        preserve_bookmarks,
+        preserve_annotations,
+        pdf_version,
+        strip_javascript,
+        strip_metadata,
+        force_black_text,
+        force_black_vector,
+        black_threshold_l,
+        black_threshold_c,
+        language,
+    })
+}
 
-                let temp_path = std::env::temp_dir().join(format!(
-                    "ghost-upload-{}-{}.pdf",
-                    Uuid::new_v4(),
-                    SystemTime::now()
-                        .duration_since(SystemTime::UNIX_EPOCH)
-                        .map(|duration| duration.as_millis())
-                        .unwrap_or(0)
-                ));
-
-                let mut file = tokio::fs::File::create(&temp_path)
-                    .await
-                    .map_err(|_| UploadError::IoError)?;
-
-                let mut total_size = 0usize;
-                let mut field = field;
-                while let Some(chunk) = field
-                    .chunk()
-                    .await
-                    .map_err(|_| UploadError::MultipartError)?
-                {
-                    total_size += chunk.len();
-                    if total_size > max_size_bytes {
-                        let _ = tokio::fs::remove_file(&temp_path).await;
-                        return Err(UploadError::FileTooLarge);
-                    }
-                    file.write_all(&chunk)
-                        .await
-                        .map_err(|_| UploadError::IoError)?;
-                }
+async fn persist_pdf_field(
+    mut field: Field<'_>,
+    max_size_bytes: usize,
+) -> Result<UploadedFile, UploadError> {
+    let started_at = Instant::now();
+    let original_name = sanitize_original_name(field.file_name().unwrap_or_default());
+    let mime_type = field.content_type().map(ToString::to_string);
+
+    let kind = detect_upload_kind(mime_type.as_deref(), &original_name)?;
 
-                file.flush().await.map_err(|_| UploadError::IoError)?;
+    let temp_path = track_temp_path(new_temp_upload_path(kind));
+    let mut file = tokio::fs::File::create(&temp_path)
+        .await
+        .map_err(|_| UploadError::IoError)?;
 
-                uploaded = Some(UploadedFile {
-                    temp_path,
-                    original_name,
-                });
+    let mut total_size = 0usize;
+    loop {
+        let chunk = match timeout(*UPLOAD_STALL_TIMEOUT, field.chunk()).await {
+            Ok(Ok(Some(chunk))) => chunk,
+            Ok(Ok(None)) => break,
+            Ok(Err(_)) => {
+                let _ = tokio::fs::remove_file(&temp_path).await;
+                return Err(UploadError::MultipartError);
             }
-            Some("mode") => {
-                let value = field
-                    .text()
-                    .await
-                    .map_err(|_| UploadError::MultipartError)?;
-                let trimmed = value.trim();
-                if !trimmed.is_empty() {
-                    mode = Some(trimmed.to_string());
-                }
+            Err(_) => {
+                tracing::warn!(
+                    received_bytes = total_size,
+                    stall_timeout_ms = UPLOAD_STALL_TIMEOUT.as_millis(),
+                    "upload stalled; aborting"
+                );
+                let _ = tokio::fs::remove_file(&temp_path).await;
+                return Err(UploadError::Stalled);
             }
-            Some("engine") => {
-                let value = field
-                    .text()
-                    .await
-                    .map_err(|_| UploadError::MultipartError)?;
-                let trimmed = value.trim();
-                if !trimmed.is_empty() {
-                    engine = Some(trimmed.to_string());
-                }
+        };
+
+        if total_size + chunk.len() > max_size_bytes {
+            let _ = tokio::fs::remove_file(&temp_path).await;
+            return Err(UploadError::FileTooLarge);
+        }
+        total_size += chunk.len();
+
+        if file.write_all(&chunk).await.is_err() {
+            let _ = tokio::fs::remove_file(&temp_path).await;
+            return Err(UploadError::IoError);
+        }
+    }
+
+    file.flush().await.map_err(|_| UploadError::IoError)?;
+
+    Ok(UploadedFile {
+        temp_path,
+        kind,
+        original_name,
+        size_bytes: total_size,
+        upload_ms: started_at.elapsed().as_millis(),
+    })
+}
+
+pub fn detect_upload_kind(
+    mime_type: Option<&str>,
+    file_name: &str,
+) -> Result<UploadKind, UploadError> {
+    match UploadKind::detect(mime_type, file_name) {
+        Some(kind) if ALLOWED_UPLOAD_KINDS.contains(&kind) => Ok(kind),
+        _ => Err(UploadError::UnsupportedFileType),
+    }
+}
+
+pub fn allowed_upload_extensions() -> Vec<&'static str> {
+    ALLOWED_UPLOAD_KINDS
+        .iter()
+        .map(|kind| kind.extension())
+        .collect()
+}
+
+static WORK_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+// Creates the scratch dir and proves it is writable before any upload lands.
+pub async fn configure_work_dir(dir: &Path) -> anyhow::Result<()> {
+    tokio::fs::create_dir_all(dir)
+        .await
+        .with_context(|| format!("failed to create WORK_DIR {}", dir.display()))?;
+    let probe = dir.join(format!(".ghost-write-check-{}", Uuid::new_v4()));
+    tokio::fs::write(&probe, b"ok")
+        .await
+        .with_context(|| format!("WORK_DIR {} is not writable", dir.display()))?;
+    let _ = tokio::fs::remove_file(&probe).await;
+    let _ = WORK_DIR.set(dir.to_path_buf());
+    Ok(())
+}
+
+// Rejects before anything is written when the work dir can't hold the
+// declared bytes plus the configured headroom. If free space can't be read
+// the upload is let through rather than failing every request.
+pub fn ensure_disk_space(declared_bytes: u64) -> Result<(), UploadError> {
+    let min_free = *MIN_FREE_DISK_BYTES;
+    if min_free == 0 {
+        return Ok(());
+    }
+
+    let dir = work_dir();
+    let available = match fs2::available_space(&dir) {
+        Ok(value) => value,
+        Err(error) => {
+            tracing::warn!(path = %dir.display(), error = %error, "failed to read free disk space");
+            return Ok(());
+        }
+    };
+    let required = min_free.saturating_add(declared_bytes);
+    if available < required {
+        tracing::warn!(
+            path = %dir.display(),
+            available_bytes = available,
+            required_bytes = required,
+            "rejecting upload: insufficient disk space"
+        );
+        return Err(UploadError::InsufficientStorage);
+    }
+
+    Ok(())
+}
+
+// Falls back to the OS temp dir until `configure_work_dir` has run.
+pub fn work_dir() -> PathBuf {
+    WORK_DIR.get().cloned().unwrap_or_else(std::env::temp_dir)
+}
+
+pub type TrackedTempPaths = Arc<Mutex<Vec<PathBuf>>>;
+
+tokio::task_local! {
+    static REQUEST_TEMP_PATHS: TrackedTempPaths;
+}
+
+// Remembers a per-request temp file so the deadline middleware can delete it
+// if the handler future is dropped before its own cleanup runs. Outside a
+// request scope (e.g. spawned jobs) this is a no-op.
+pub fn track_temp_path(path: PathBuf) -> PathBuf {
+    let _ = REQUEST_TEMP_PATHS.try_with(|paths| paths.lock().push(path.clone()));
+    path
+}
+
+pub async fn scope_temp_paths<F: Future>(paths: TrackedTempPaths, future: F) -> F::Output {
+    REQUEST_TEMP_PATHS.scope(paths, future).await
+}
+
+pub async fn remove_tracked_temp_paths(paths: &TrackedTempPaths) {
+    let tracked = std::mem::take(&mut *paths.lock());
+    for path in tracked {
+        remove_file_if_exists(&path).await;
+    }
+}
+
+pub fn new_temp_upload_path(kind: UploadKind) -> PathBuf {
+    work_dir().join(format!(
+        "ghost-upload-{}-{}.{}",
+        Uuid::new_v4(),
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|duration| duration.as_millis())
+            .unwrap_or(0),
+        kind.extension()
+    ))
+}
+
+async fn read_text_field(mut field: Field<'_>) -> Result<Option<String>, UploadError> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = field
+        .chunk()
+        .await
+        .map_err(|_| UploadError::MultipartError)?
+    {
+        if bytes.len() + chunk.len() > MAX_TEXT_FIELD_BYTES {
+            tracing::warn!(
+                field = field.name().unwrap_or_default(),
+                limit = MAX_TEXT_FIELD_BYTES,
+                "multipart text field too long"
+            );
+            return Err(UploadError::FormTooLarge);
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    let value = String::from_utf8(bytes).map_err(|_| UploadError::MultipartError)?;
+    let trimmed = value.trim();
+    if trimmed.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(trimmed.to_string()))
+}
+
+// The client's filename is echoed in JSON and logs, so drop control and bidi
+// override characters and cap its length. The extension is kept when
+// truncating because upload kind detection reads it.
+pub fn sanitize_original_name(raw: &str) -> String {
+    let cleaned: String = raw
+        .chars()
+        .filter(|ch| {
+            !ch.is_control() && !matches!(ch, '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}')
+        })
+        .collect();
+    let cleaned = cleaned.trim();
+    if cleaned.is_empty() {
+        return "document.pdf".to_string();
+    }
+    if cleaned.chars().count() <= MAX_ORIGINAL_NAME_CHARS {
+        return cleaned.to_string();
+    }
+
+    let extension = std::path::Path::new(cleaned)
+        .extension()
+        .and_then(|value| value.to_str())
+        .filter(|value| value.chars().count() <= 8)
+        .map(|value| format!(".{value}"))
+        .unwrap_or_default();
+    let stem: String = cleaned
+        .chars()
+        .take(MAX_ORIGINAL_NAME_CHARS - extension.chars().count())
+        .collect();
+    format!("{}{}", stem.trim_end(), extension)
+}
+
+pub async fn save_pdf_from_url(
+    http: &reqwest::Client,
+    raw_url: &str,
+    max_size_bytes: usize,
+) -> Result<UploadedFile, UploadError> {
+    let url = ensure_public_url(raw_url).await.map_err(|error| {
+        tracing::warn!(error = %error, "rejected PDF download URL");
+        UploadError::InvalidUrl
+    })?;
+
+    let started_at = Instant::now();
+    let original_name = sanitize_original_name(
+        url.path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .unwrap_or_default(),
+    );
+
+    let mut response = http.get(url).send().await.map_err(|error| {
+        tracing::warn!(error = %error, "failed to download PDF from URL");
+        UploadError::DownloadFailed
+    })?;
+
+    if !response.status().is_success() {
+        tracing::warn!(status = %response.status(), "PDF download returned non-success status");
+        return Err(UploadError::DownloadFailed);
+    }
+
+    if response
+        .content_length()
+        .is_some_and(|length| length > max_size_bytes as u64)
+    {
+        return Err(UploadError::FileTooLarge);
+    }
+
+    let mime_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| {
+            value
+                .split(';')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_ascii_lowercase()
+        });
+    if let Some(mime_type) = mime_type.as_deref() {
+        if mime_type != "application/pdf" && mime_type != "application/octet-stream" {
+            return Err(UploadError::UnsupportedFileType);
+        }
+    }
+
+    ensure_disk_space(response.content_length().unwrap_or(0))?;
+
+    let temp_path = track_temp_path(new_temp_upload_path(UploadKind::Pdf));
+
+    let mut file = tokio::fs::File::create(&temp_path)
+        .await
+        .map_err(|_| UploadError::IoError)?;
+
+    let mut header = Vec::with_capacity(PDF_MAGIC.len());
+    let mut total_size = 0usize;
+    loop {
+        let chunk = match timeout(*UPLOAD_STALL_TIMEOUT, response.chunk()).await {
+            Ok(Ok(Some(chunk))) => chunk,
+            Ok(Ok(None)) => break,
+            Ok(Err(error)) => {
+                tracing::warn!(error = %error, "PDF download interrupted");
+                let _ = tokio::fs::remove_file(&temp_path).await;
+                return Err(UploadError::DownloadFailed);
             }
-            _ => {}
+            Err(_) => {
+                tracing::warn!(
+                    received_bytes = total_size,
+                    "PDF download stalled; aborting"
+                );
+                let _ = tokio::fs::remove_file(&temp_path).await;
+                return Err(UploadError::Stalled);
+            }
+        };
+
+        if total_size + chunk.len() > max_size_bytes {
+            let _ = tokio::fs::remove_file(&temp_path).await;
+            return Err(UploadError::FileTooLarge);
+        }
+        total_size += chunk.len();
+
+        if header.len() < PDF_MAGIC.len() {
+            let needed = PDF_MAGIC.len() - header.len();
+            header.extend_from_slice(&chunk[..needed.min(chunk.len())]);
+            if header.len() == PDF_MAGIC.len() && header != PDF_MAGIC {
+                let _ = tokio::fs::remove_file(&temp_path).await;
+                return Err(UploadError::UnsupportedFileType);
+            }
+        }
+
+        if let Err(_error) = file.write_all(&chunk).await {
+            let _ = tokio::fs::remove_file(&temp_path).await;
+            return Err(UploadError::IoError);
         }
     }
 
-    let uploaded = uploaded.ok_or(UploadError::MissingFile)?;
+    if header != PDF_MAGIC {
+        let _ = tokio::fs::remove_file(&temp_path).await;
+        return Err(UploadError::UnsupportedFileType);
+    }
 
-    Ok(UploadedPdfRequest {
-        temp_path: uploaded.temp_path,
-        original_name: uploaded.original_name,
-        mode,
-        engine,
+    file.flush().await.map_err(|_| UploadError::IoError)?;
+
+    Ok(UploadedFile {
+        temp_path,
+        kind: UploadKind::Pdf,
+        original_name,
+        size_bytes: total_size,
+        upload_ms: started_at.elapsed().as_millis(),
     })
 }
 
+const PDF_MAGIC: &[u8] = b"%PDF-";
+
 pub async fn remove_file_if_exists(path: &PathBuf) {
     if let Err(error) = tokio::fs::remove_file(path).await {
         if error.kind() != std::io::ErrorKind::NotFound {
@@ -210,3 +682,92 @@ pub async fn remove_file_if_exists(path: &PathBuf) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize_original_name_strips_control_and_bidi_characters() {
+        assert_eq!(sanitize_original_name("report.pdf"), "report.pdf");
+        assert_eq!(
+            sanitize_original_name("line one\nline two\r\n.pdf"),
+            "line oneline two.pdf"
+        );
+        assert_eq!(sanitize_original_name("tab\there\u{0}.pdf"), "tabhere.pdf");
+        assert_eq!(
+            sanitize_original_name("invoice\u{202E}fdp.exe"),
+            "invoicefdp.exe"
+        );
+        assert_eq!(sanitize_original_name("a\u{2066}b\u{2069}.pdf"), "ab.pdf");
+        assert_eq!(sanitize_original_name("  spaced.pdf  "), "spaced.pdf");
+        assert_eq!(sanitize_original_name("résumé 📄.pdf"), "résumé 📄.pdf");
+        assert_eq!(sanitize_original_name(""), "document.pdf");
+        assert_eq!(sanitize_original_name("\n\r\t\u{202E}"), "document.pdf");
+    }
+
+    #[test]
+    fn sanitize_original_name_caps_long_names_and_keeps_the_extension() {
+        let long = format!("{}.pdf", "a".repeat(10 * 1024));
+        let sanitized = sanitize_original_name(&long);
+        assert_eq!(sanitized.chars().count(), MAX_ORIGINAL_NAME_CHARS);
+        assert!(sanitized.ends_with("aaaa.pdf"));
+
+        let multibyte = format!("{}.ps", "é".repeat(10 * 1024));
+        let sanitized = sanitize_original_name(&multibyte);
+        assert_eq!(sanitized.chars().count(), MAX_ORIGINAL_NAME_CHARS);
+        assert!(sanitized.ends_with(".ps"));
+
+        // An "extension" too long to be one is not preserved.
+        let sanitized = sanitize_original_name(&format!("name.{}", "x".repeat(10 * 1024)));
+        assert_eq!(sanitized.chars().count(), MAX_ORIGINAL_NAME_CHARS);
+        assert!(sanitized.starts_with("name.xxx"));
+
+        let exact = "b".repeat(MAX_ORIGINAL_NAME_CHARS);
+        assert_eq!(sanitize_original_name(&exact), exact);
+    }
+
+    async fn multipart(fields: &[(&str, String)]) -> Multipart {
+        use axum::extract::FromRequest;
+
+        let boundary = "test-boundary";
+        let mut body = String::new();
+        for (name, value) in fields {
+            body.push_str(&format!(
+                "--{boundary}\r\nContent-Disposition: form-data; name=\"{name}\"\r\n\r\n{value}\r\n"
+            ));
+        }
+        body.push_str(&format!("--{boundary}--\r\n"));
+        let request = axum::http::Request::builder()
+            .header(
+                "content-type",
+                format!("multipart/form-data; boundary={boundary}"),
+            )
+            .body(axum::body::Body::from(body))
+            .expect("request");
+        Multipart::from_request(request, &())
+            .await
+            .expect("multipart")
+    }
+
+    #[tokio::test]
+    async fn oversized_text_field_is_a_form_error() {
+        let uploads = TusStore::new(Duration::from_secs(60), 1);
+        let form = multipart(&[("mode", "p".repeat(MAX_TEXT_FIELD_BYTES + 1))]).await;
+
+        let result = save_pdf_with_mode_from_multipart(form, 1024, &uploads, "user_1").await;
+
+        assert!(matches!(result, Err(UploadError::FormTooLarge)));
+    }
+
+    #[tokio::test]
+    async fn too_many_parts_is_a_form_error() {
+        let fields = (0..=*MULTIPART_MAX_PARTS)
+            .map(|index| ("extra", index.to_string()))
+            .collect::<Vec<_>>();
+
+        let result = save_pdf_from_multipart(multipart(&fields).await, 1024, None).await;
+
+        assert!(matches!(result, Err(UploadError::FormTooLarge)));
+    }
+}